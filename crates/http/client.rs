@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::io::{Result, Write};
+use std::net::TcpStream;
+use std::url::Url;
+
+use system::error::{Error, ELOOP, EPROTONOSUPPORT};
+
+use super::eq_ignore_case;
+use super::request::Request;
+use super::response::Response;
+use super::stream::Stream;
+
+/// Redirects followed by default
+const MAX_REDIRECTS: usize = 8;
+
+/// An HTTP client, which keeps connections open between requests
+pub struct Client {
+    /// Idle connections, by host and port
+    connections: BTreeMap<(String, u16), Stream>,
+    pub max_redirects: usize,
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client {
+            connections: BTreeMap::new(),
+            max_redirects: MAX_REDIRECTS,
+        }
+    }
+
+    /// Send a GET request
+    pub fn get(&mut self, url: &str) -> Result<Response> {
+        self.send(Request::get(url))
+    }
+
+    /// Send a request, following redirects
+    pub fn send(&mut self, request: Request) -> Result<Response> {
+        let mut body = Vec::new();
+        let mut response = try!(self.send_to(request, &mut body));
+        if response.is_success() {
+            response.body = body;
+        }
+        Ok(response)
+    }
+
+    /// Send a request, following redirects. The body of a successful response is written to
+    /// `output` as it is received, the bodies of other responses are kept in `Response::body`
+    pub fn send_to(&mut self, mut request: Request, output: &mut Write) -> Result<Response> {
+        let mut redirects = 0;
+        loop {
+            let response = try!(self.exchange(&request, output, true));
+            if !response.is_redirect() {
+                return Ok(response);
+            }
+
+            if redirects >= self.max_redirects {
+                return Err(Error::new(ELOOP));
+            }
+            redirects += 1;
+
+            let location = resolve(&request.url, response.header("Location").unwrap_or(""));
+            if request.url.host() != location.host() {
+                request.headers.retain(|&(ref name, _)| !eq_ignore_case(name, "Host"));
+            }
+            request.url = location;
+
+            // See other, and historically moved and found, repeat as GET
+            if response.status == 303 ||
+               (request.method == "POST" && (response.status == 301 || response.status == 302)) {
+                request.method = "GET".to_string();
+                request.body.clear();
+                request.headers.retain(|&(ref name, _)| {
+                    !eq_ignore_case(name, "Content-Length") && !eq_ignore_case(name, "Content-Type")
+                });
+            }
+        }
+    }
+
+    /// Send a request without following redirects
+    pub fn send_once(&mut self, request: &Request) -> Result<Response> {
+        let mut body = Vec::new();
+        let mut response = try!(self.exchange(request, &mut body, false));
+        response.body = body;
+        Ok(response)
+    }
+
+    /// Send a request and read its response. The body of a successful response is written to
+    /// `output`, as well as the bodies of other responses unless `success_only` is set
+    fn exchange(&mut self,
+                request: &Request,
+                output: &mut Write,
+                success_only: bool)
+                -> Result<Response> {
+        if request.url.scheme() != "http" {
+            return Err(Error::new(EPROTONOSUPPORT));
+        }
+
+        let key = (request.url.host(), request.port());
+
+        let mut head = None;
+        if let Some(stream) = self.connections.remove(&key) {
+            if !stream.is_stale() {
+                match send_head(stream, request) {
+                    Ok(ok) => head = Some(ok),
+                    // The server may have closed the idle connection, retry safe methods. Nothing
+                    // was written to the output yet
+                    Err(err) => if request.method != "GET" && request.method != "HEAD" {
+                        return Err(err);
+                    },
+                }
+            }
+        }
+
+        let (mut stream, mut response) = match head {
+            Some(head) => head,
+            None => {
+                let tcp = try!(TcpStream::connect((&key.0[..], key.1)));
+                try!(send_head(Stream::new(tcp), request))
+            }
+        };
+
+        if response.is_success() || !success_only {
+            try!(response.read_body(&mut stream, &request.method, output));
+        } else {
+            let mut body = Vec::new();
+            try!(response.read_body(&mut stream, &request.method, &mut body));
+            response.body = body;
+        }
+
+        if response.keep_alive && !stream.is_stale() {
+            self.connections.insert(key, stream);
+        }
+        Ok(response)
+    }
+}
+
+/// Send a request and read the head of its response
+fn send_head(mut stream: Stream, request: &Request) -> Result<(Stream, Response)> {
+    try!(stream.write_all(&request.to_bytes()));
+    let response = try!(Response::read(&mut stream));
+    Ok((stream, response))
+}
+
+/// Resolve the location of a redirect against the URL of the request
+fn resolve(base: &Url, location: &str) -> Url {
+    if location.contains("://") {
+        Url::from_str(location)
+    } else if location.starts_with("//") {
+        Url::from_string(base.scheme() + ":" + location)
+    } else {
+        let mut authority = base.host();
+        if !base.port().is_empty() {
+            authority = authority + ":" + &base.port();
+        }
+
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let base_path = base.path();
+            let dir = match base_path.split('?').next().unwrap_or("").rfind('/') {
+                Some(i) => format!("/{}/", &base_path[..i]),
+                None => "/".to_string(),
+            };
+            dir + location
+        };
+
+        Url::from_string(base.scheme() + "://" + &authority + &path)
+    }
+}
@@ -0,0 +1,23 @@
+//! A minimal HTTP/1.1 client, using the `tcp:` scheme
+
+#![crate_type="lib"]
+
+extern crate system;
+
+pub use client::Client;
+pub use request::Request;
+pub use response::Response;
+
+pub mod client;
+pub mod request;
+pub mod response;
+mod stream;
+
+/// Compare header names, which are case insensitive
+pub fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.len() == b.len() &&
+    a.bytes().zip(b.bytes()).all(|(a, b)| {
+        let lower = |c: u8| if c >= b'A' && c <= b'Z' { c + 32 } else { c };
+        lower(a) == lower(b)
+    })
+}
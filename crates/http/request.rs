@@ -0,0 +1,92 @@
+use std::to_num::ToNum;
+use std::url::Url;
+
+use super::eq_ignore_case;
+
+/// An HTTP request
+pub struct Request {
+    pub method: String,
+    pub url: Url,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Create a request without headers or body
+    pub fn new(method: &str, url: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            url: Url::from_str(url),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Create a GET request
+    pub fn get(url: &str) -> Request {
+        Request::new("GET", url)
+    }
+
+    /// Create a HEAD request
+    pub fn head(url: &str) -> Request {
+        Request::new("HEAD", url)
+    }
+
+    /// Create a POST request with a body
+    pub fn post(url: &str, body: Vec<u8>) -> Request {
+        let mut request = Request::new("POST", url);
+        request.body = body;
+        request
+    }
+
+    /// Set a header, replacing any header with the same name
+    pub fn header(mut self, name: &str, value: &str) -> Request {
+        self.headers.retain(|&(ref header, _)| !eq_ignore_case(header, name));
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The host, with the port if it is not the default
+    pub fn host(&self) -> String {
+        let port = self.url.port();
+        if port.is_empty() || port == "80" {
+            self.url.host()
+        } else {
+            self.url.host() + ":" + &port
+        }
+    }
+
+    /// The port to connect to, 80 by default
+    pub fn port(&self) -> u16 {
+        let port = self.url.port();
+        if port.is_empty() {
+            80
+        } else {
+            port.to_num() as u16
+        }
+    }
+
+    /// Serialize the request line, headers, and body
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{} /{} HTTP/1.1\r\n", self.method, self.url.path());
+
+        let has = |name: &str| {
+            self.headers.iter().any(|&(ref header, _)| eq_ignore_case(header, name))
+        };
+        if !has("Host") {
+            head = head + "Host: " + &self.host() + "\r\n";
+        }
+        if !has("Content-Length") && (!self.body.is_empty() || self.method == "POST" ||
+                                      self.method == "PUT") {
+            head = head + &format!("Content-Length: {}\r\n", self.body.len());
+        }
+        for &(ref name, ref value) in self.headers.iter() {
+            head = head + name + ": " + value + "\r\n";
+        }
+        head = head + "\r\n";
+
+        let mut bytes = head.into_bytes();
+        bytes.push_all(&self.body);
+        bytes
+    }
+}
@@ -0,0 +1,293 @@
+use std::io::{Read, Result, Write};
+use std::to_num::ToNum;
+
+use system::error::{Error, EPROTO};
+
+use super::eq_ignore_case;
+use super::stream::Stream;
+
+/// An HTTP response
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    /// Empty if the body was written to the output of `Client::send_to`
+    pub body: Vec<u8>,
+    /// The connection can be used for another request
+    pub keep_alive: bool,
+}
+
+impl Response {
+    /// The value of the first header with a name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref header, _)| eq_ignore_case(header, name))
+            .map(|&(_, ref value)| &value[..])
+    }
+
+    /// The status is 2xx
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    /// The status is a redirect with a location
+    pub fn is_redirect(&self) -> bool {
+        match self.status {
+            301 | 302 | 303 | 307 | 308 => self.header("Location").is_some(),
+            _ => false,
+        }
+    }
+
+    /// Read the status line and headers of the final response, skipping informational ones
+    pub fn read<S: Read + Write>(stream: &mut Stream<S>) -> Result<Response> {
+        loop {
+            let response = try!(Response::read_head(stream));
+            if response.status >= 200 || response.status == 101 {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Copy the body of the response to a request with `method` to `output`
+    pub fn read_body<S: Read + Write>(&mut self,
+                                      stream: &mut Stream<S>,
+                                      method: &str,
+                                      output: &mut Write)
+                                      -> Result<()> {
+        if method == "HEAD" || self.status < 200 || self.status == 204 || self.status == 304 {
+            return Ok(());
+        }
+
+        let chunked = match self.header("Transfer-Encoding") {
+            Some(encoding) => {
+                let last = encoding.split(',').last().unwrap_or("");
+                eq_ignore_case(last.trim(), "chunked")
+            }
+            None => false,
+        };
+
+        let length = self.header("Content-Length").map(|len| parse_num(len.trim(), 10));
+
+        if chunked {
+            try!(self.read_chunked(stream, output));
+        } else if let Some(length) = length {
+            let length = try!(length.ok_or(Error::new(EPROTO)));
+            try!(stream.copy_exact(length, output));
+        } else {
+            // The body ends when the connection is closed
+            try!(stream.copy_to_end(output));
+            self.keep_alive = false;
+        }
+
+        Ok(())
+    }
+
+    /// Read the status line and headers
+    fn read_head<S: Read + Write>(stream: &mut Stream<S>) -> Result<Response> {
+        let status_line = try!(stream.read_line());
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        if !version.starts_with("HTTP/1.") {
+            return Err(Error::new(EPROTO));
+        }
+        let status = parts.next().unwrap_or("");
+        if status.len() != 3 || !status.chars().all(|c| c.is_digit(10)) {
+            return Err(Error::new(EPROTO));
+        }
+
+        let mut response = Response {
+            status: status.to_num() as u16,
+            reason: parts.next().unwrap_or("").to_string(),
+            headers: try!(read_headers(stream)),
+            body: Vec::new(),
+            keep_alive: version != "HTTP/1.0",
+        };
+
+        if let Some(connection) = response.header("Connection") {
+            for option in connection.split(',') {
+                if eq_ignore_case(option.trim(), "close") {
+                    response.keep_alive = false;
+                } else if eq_ignore_case(option.trim(), "keep-alive") {
+                    response.keep_alive = true;
+                }
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// Decode a chunked body, trailers are added to the headers
+    fn read_chunked<S: Read + Write>(&mut self,
+                                     stream: &mut Stream<S>,
+                                     output: &mut Write)
+                                     -> Result<()> {
+        loop {
+            let line = try!(stream.read_line());
+            // Chunk extensions follow a semicolon
+            let size = try!(parse_num(line.split(';').next().unwrap_or("").trim(), 16)
+                                .ok_or(Error::new(EPROTO)));
+            if size == 0 {
+                break;
+            }
+
+            try!(stream.copy_exact(size, output));
+            if !try!(stream.read_line()).is_empty() {
+                return Err(Error::new(EPROTO));
+            }
+        }
+
+        let trailers = try!(read_headers(stream));
+        self.headers.extend(trailers);
+        Ok(())
+    }
+}
+
+/// Parse a number of digits in `radix`, with nothing else, that does not overflow
+fn parse_num(string: &str, radix: u32) -> Option<usize> {
+    if string.is_empty() {
+        return None;
+    }
+
+    let mut num: usize = 0;
+    for c in string.chars() {
+        let digit = match c.to_digit(radix) {
+            Some(digit) => digit as usize,
+            None => return None,
+        };
+        num = match num.checked_mul(radix as usize).and_then(|num| num.checked_add(digit)) {
+            Some(num) => num,
+            None => return None,
+        };
+    }
+
+    Some(num)
+}
+
+/// Read headers up to an empty line
+fn read_headers<S: Read + Write>(stream: &mut Stream<S>) -> Result<Vec<(String, String)>> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    loop {
+        let line = try!(stream.read_line());
+        if line.is_empty() {
+            return Ok(headers);
+        }
+
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Obsolete line folding continues the last header
+            match headers.last_mut() {
+                Some(&mut (_, ref mut value)) => {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+                None => return Err(Error::new(EPROTO)),
+            }
+        } else {
+            match line.find(':') {
+                Some(i) => {
+                    headers.push((line[..i].trim().to_string(), line[i + 1..].trim().to_string()))
+                }
+                None => return Err(Error::new(EPROTO)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::{Read, Result, Write};
+
+    use super::Response;
+    use super::super::stream::Stream;
+
+    /// What the server sent, writes to it are discarded
+    struct Server {
+        data: Vec<u8>,
+    }
+
+    impl Read for Server {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let count = cmp::min(buf.len(), self.data.len());
+            for (b, d) in buf.iter_mut().zip(self.data.drain(..count)) {
+                *b = d;
+            }
+            Ok(count)
+        }
+    }
+
+    impl Write for Server {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Read a response to a GET, returning the body
+    fn get(text: &str) -> Result<Vec<u8>> {
+        let mut stream = Stream::new(Server { data: text.as_bytes().to_vec() });
+        let mut response = try!(Response::read(&mut stream));
+        let mut body = Vec::new();
+        try!(response.read_body(&mut stream, "GET", &mut body));
+        Ok(body)
+    }
+
+    const CHUNKED: &'static str = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    #[test]
+    fn chunked() {
+        let text = CHUNKED.to_string() + "5;ext=1\r\nhello\r\n1\r\n!\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        assert_eq!(get(&text).unwrap(), b"hello!".to_vec());
+    }
+
+    #[test]
+    fn chunked_truncated() {
+        assert!(get(&(CHUNKED.to_string() + "5\r\nhel")).is_err());
+        assert!(get(&(CHUNKED.to_string() + "5\r\nhello\r\n")).is_err());
+        assert!(get(&(CHUNKED.to_string() + "5\r\nhello")).is_err());
+        assert!(get(&(CHUNKED.to_string() + "0\r\n")).is_err());
+    }
+
+    #[test]
+    fn chunked_malformed() {
+        assert!(get(&(CHUNKED.to_string() + "5\r\nhelloworld\r\n0\r\n\r\n")).is_err());
+        assert!(get(&(CHUNKED.to_string() + "x\r\n")).is_err());
+        assert!(get(&(CHUNKED.to_string() + "\r\n")).is_err());
+        assert!(get(&(CHUNKED.to_string() + "-1\r\n")).is_err());
+        assert!(get(&(CHUNKED.to_string() + "FFFFFFFFFFFFFFFFFFFF\r\n")).is_err());
+    }
+
+    #[test]
+    fn content_length() {
+        assert_eq!(get("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi").unwrap(),
+                   b"hi".to_vec());
+        assert!(get("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhi").is_err());
+        assert!(get("HTTP/1.1 200 OK\r\nContent-Length: 2x\r\n\r\nhi").is_err());
+        assert!(get("HTTP/1.1 200 OK\r\nContent-Length: 99999999999999999999999\r\n\r\n")
+                    .is_err());
+    }
+
+    #[test]
+    fn head_malformed() {
+        assert!(get("").is_err());
+        assert!(get("HTTP/1.1 200 OK").is_err());
+        assert!(get("HTTP/1.1 200 OK\r\n").is_err());
+        assert!(get("SPDY/3 200 OK\r\n\r\n").is_err());
+        assert!(get("HTTP/1.1 20 OK\r\n\r\n").is_err());
+        assert!(get("HTTP/1.1 2x0 OK\r\n\r\n").is_err());
+        assert!(get("HTTP/1.1 200 OK\r\nNo colon\r\n\r\n").is_err());
+        assert!(get("HTTP/1.1 200 OK\r\n folded\r\n\r\n").is_err());
+        // A line longer than the limit, without an end
+        let long: String = (0..10000).map(|_| 'a').collect();
+        assert!(get(&("HTTP/1.1 200 OK\r\nX: ".to_string() + &long)).is_err());
+    }
+
+    #[test]
+    fn informational_skipped() {
+        let text = "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n!";
+        assert_eq!(get(text).unwrap(), b"!".to_vec());
+    }
+}
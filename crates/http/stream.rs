@@ -0,0 +1,100 @@
+use std::cmp;
+use std::io::{Read, Result, Write};
+use std::net::TcpStream;
+
+use system::error::{Error, EPROTO};
+
+/// Longest line accepted in the status line, headers, and chunk sizes
+const MAX_LINE: usize = 8192;
+
+/// A buffered connection to a server
+pub struct Stream<S = TcpStream> {
+    inner: S,
+    buffer: Vec<u8>,
+    /// The server closed the connection
+    eof: bool,
+}
+
+impl<S: Read + Write> Stream<S> {
+    pub fn new(inner: S) -> Stream<S> {
+        Stream {
+            inner: inner,
+            buffer: Vec::new(),
+            eof: false,
+        }
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        try!(self.inner.write_all(buf));
+        self.inner.flush()
+    }
+
+    /// Read more data into the buffer, returning false at end of file
+    fn fill(&mut self) -> Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+
+        let mut bytes = [0; 8192];
+        let count = try!(self.inner.read(&mut bytes));
+        if count == 0 {
+            self.eof = true;
+            Ok(false)
+        } else {
+            self.buffer.push_all(&bytes[..count]);
+            Ok(true)
+        }
+    }
+
+    /// Read a line, without the CRLF
+    pub fn read_line(&mut self) -> Result<String> {
+        loop {
+            if let Some(i) = self.buffer.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.buffer.drain(..i + 1).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return String::from_utf8(line).map_err(|_| Error::new(EPROTO));
+            }
+
+            if self.buffer.len() > MAX_LINE || !try!(self.fill()) {
+                return Err(Error::new(EPROTO));
+            }
+        }
+    }
+
+    /// Copy exactly `len` bytes to `output`, as they are received
+    pub fn copy_exact(&mut self, mut len: usize, output: &mut Write) -> Result<()> {
+        loop {
+            let count = cmp::min(len, self.buffer.len());
+            try!(output.write_all(&self.buffer[..count]));
+            self.buffer.drain(..count);
+            len -= count;
+
+            if len == 0 {
+                return Ok(());
+            }
+            if !try!(self.fill()) {
+                return Err(Error::new(EPROTO));
+            }
+        }
+    }
+
+    /// Copy to `output` until the server closes the connection
+    pub fn copy_to_end(&mut self, output: &mut Write) -> Result<()> {
+        loop {
+            try!(output.write_all(&self.buffer));
+            self.buffer.clear();
+
+            if !try!(self.fill()) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The server closed the connection, or sent data that was not asked for
+    pub fn is_stale(&self) -> bool {
+        self.eof || !self.buffer.is_empty()
+    }
+}
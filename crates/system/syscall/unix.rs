@@ -11,6 +11,8 @@ pub const SYS_CLONE: usize = 120;
 pub const SYS_CLOCK_GETTIME: usize = 265;
     pub const CLOCK_REALTIME: usize = 1;
     pub const CLOCK_MONOTONIC: usize = 4;
+pub const SYS_CLOCK_SETTIME: usize = 264;
+pub const SYS_ADJTIME: usize = 3012; //Extra to slew the realtime clock, as in BSD
 pub const SYS_DUP: usize = 41;
 pub const SYS_EXECVE: usize = 11;
 pub const SYS_SPAWNVE: usize = 3011; //Extra to fix scheme execve
@@ -19,14 +21,59 @@ pub const SYS_FPATH: usize = 3001;
 pub const SYS_FSTAT: usize = 28;
 pub const SYS_FSYNC: usize = 118;
 pub const SYS_FTRUNCATE: usize = 93;
+pub const SYS_GETPGID: usize = 132;
 pub const SYS_GETPID: usize = 20;
+pub const SYS_GETPRIORITY: usize = 96; //Returns 20 - nice, as on Linux, so it is never negative
+    pub const PRIO_PROCESS: usize = 0;
+    pub const PRIO_PGRP: usize = 1;
+    pub const PRIO_USER: usize = 2;
+    pub const PRIO_MIN: isize = -20;
+    pub const PRIO_MAX: isize = 19;
+pub const SYS_KILL: usize = 37;
+    pub const SIGHUP: usize = 1;
+    pub const SIGINT: usize = 2;
+    pub const SIGQUIT: usize = 3;
+    pub const SIGILL: usize = 4;
+    pub const SIGTRAP: usize = 5;
+    pub const SIGABRT: usize = 6;
+    pub const SIGBUS: usize = 7;
+    pub const SIGFPE: usize = 8;
+    pub const SIGKILL: usize = 9;
+    pub const SIGUSR1: usize = 10;
+    pub const SIGSEGV: usize = 11;
+    pub const SIGUSR2: usize = 12;
+    pub const SIGPIPE: usize = 13;
+    pub const SIGALRM: usize = 14;
+    pub const SIGTERM: usize = 15;
+    pub const SIGCHLD: usize = 17;
+    pub const SIGCONT: usize = 18;
+    pub const SIGSTOP: usize = 19;
+    pub const SIGTSTP: usize = 20;
+    pub const SIGTTIN: usize = 21;
+    pub const SIGTTOU: usize = 22;
+    pub const SIGURG: usize = 23;
+    pub const SIGWINCH: usize = 28;
+    pub const SIGSYS: usize = 31;
+    pub const NSIG: usize = 32;
 pub const SYS_LINK: usize = 9;
 pub const SYS_LSEEK: usize = 19;
 pub const SEEK_SET: usize = 0;
 pub const SEEK_CUR: usize = 1;
 pub const SEEK_END: usize = 2;
 pub const SYS_MKDIR: usize = 39;
+pub const SYS_MMAP: usize = 90; //Takes the arguments in MmapArgs, as old_mmap on i386
+    pub const PROT_NONE: usize = 0;
+    pub const PROT_READ: usize = 1;
+    pub const PROT_WRITE: usize = 2;
+    pub const PROT_EXEC: usize = 4;
+    pub const MAP_SHARED: usize = 1;
+    pub const MAP_PRIVATE: usize = 2;
+    pub const MAP_FIXED: usize = 0x10;
+    pub const MAP_ANONYMOUS: usize = 0x20;
+pub const SYS_MPROTECT: usize = 125;
+pub const SYS_MUNMAP: usize = 91;
 pub const SYS_NANOSLEEP: usize = 162;
+pub const SYS_NICE: usize = 34;
 pub const SYS_OPEN: usize = 5;
     pub const O_RDONLY: usize = 0;
     pub const O_WRONLY: usize = 1;
@@ -42,8 +89,26 @@ pub const SYS_OPEN: usize = 5;
     pub const O_EXCL: usize = 0x800;
 pub const SYS_PIPE2: usize = 331;
 pub const SYS_READ: usize = 3;
+pub const SYS_SETPGID: usize = 57;
+pub const SYS_SETPRIORITY: usize = 97;
+pub const SYS_SETSID: usize = 66;
+pub const SYS_SIGACTION: usize = 67;
+    pub const SIG_DFL: usize = 0;
+    pub const SIG_IGN: usize = 1;
+    pub const SA_NODEFER: usize = 0x40000000;
+    pub const SA_RESETHAND: usize = 0x80000000;
+pub const SYS_SIGPROCMASK: usize = 126;
+    pub const SIG_BLOCK: usize = 0;
+    pub const SIG_UNBLOCK: usize = 1;
+    pub const SIG_SETMASK: usize = 2;
+pub const SYS_SIGRETURN: usize = 119;
+pub const SYS_TCGETPGRP: usize = 3013; //Extra, the TIOCGPGRP ioctl on Linux
+pub const SYS_TCSETPGRP: usize = 3014; //Extra, the TIOCSPGRP ioctl on Linux
 pub const SYS_UNLINK: usize = 10;
 pub const SYS_WAITPID: usize = 7;
+    pub const WNOHANG: usize = 1;
+    pub const WUNTRACED: usize = 2;
+    pub const WCONTINUED: usize = 8;
 pub const SYS_WRITE: usize = 4;
 pub const SYS_YIELD: usize = 158;
 
@@ -53,6 +118,68 @@ pub struct TimeSpec {
     pub tv_nsec: i32,
 }
 
+/// The arguments of `sys_mmap`
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct MmapArgs {
+    /// Where to place the map, a hint unless `MAP_FIXED`
+    pub addr: usize,
+    pub len: usize,
+    pub prot: usize,
+    pub flags: usize,
+    /// The file to read the map from, unless `MAP_ANONYMOUS`
+    pub fd: usize,
+    /// Page aligned offset in the file
+    pub offset: usize,
+}
+
+/// A signal handler, `sa_handler` is called with the signal number and returns to `sa_restorer`,
+/// which must call `sys_sigreturn`
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SigAction {
+    pub sa_handler: usize,
+    /// Signals blocked while the handler runs, as bits `1 << signal`
+    pub sa_mask: usize,
+    pub sa_flags: usize,
+    pub sa_restorer: usize,
+}
+
+/// The status of a child that exited
+pub fn wifexited(status: usize) -> bool {
+    status & 0x7F == 0
+}
+
+/// The exit code of a child that exited
+pub fn wexitstatus(status: usize) -> usize {
+    (status >> 8) & 0xFF
+}
+
+/// The status of a child that was terminated by a signal
+pub fn wifsignaled(status: usize) -> bool {
+    status & 0x7F != 0 && status & 0x7F != 0x7F
+}
+
+/// The signal that terminated a child
+pub fn wtermsig(status: usize) -> usize {
+    status & 0x7F
+}
+
+/// The status of a child that was stopped, reported with `WUNTRACED`
+pub fn wifstopped(status: usize) -> bool {
+    status & 0xFF == 0x7F
+}
+
+/// The signal that stopped a child
+pub fn wstopsig(status: usize) -> usize {
+    (status >> 8) & 0xFF
+}
+
+/// The status of a child that was continued, reported with `WCONTINUED`
+pub fn wifcontinued(status: usize) -> bool {
+    status == 0xFFFF
+}
+
 #[no_mangle]
 pub unsafe fn sys_brk(addr: usize) -> usize {
     syscall(SYS_BRK, addr, 0, 0)
@@ -78,6 +205,16 @@ pub unsafe fn sys_clock_gettime(clock: usize, tp: *mut TimeSpec) -> usize {
     syscall(SYS_CLOCK_GETTIME, clock, tp as usize, 0)
 }
 
+#[no_mangle]
+pub unsafe fn sys_clock_settime(clock: usize, tp: *const TimeSpec) -> usize {
+    syscall(SYS_CLOCK_SETTIME, clock, tp as usize, 0)
+}
+
+#[no_mangle]
+pub unsafe fn sys_adjtime(delta: *const TimeSpec, olddelta: *mut TimeSpec) -> usize {
+    syscall(SYS_ADJTIME, delta as usize, olddelta as usize, 0)
+}
+
 #[no_mangle]
 pub unsafe fn sys_dup(fd: usize) -> usize {
     syscall(SYS_DUP, fd, 0, 0)
@@ -115,16 +252,33 @@ pub unsafe fn sys_ftruncate(fd: usize, len: usize) -> usize {
     syscall(SYS_FTRUNCATE, fd, len, 0)
 }
 
+/// Get the process group of a process, or of the current one with a `pid` of 0
+#[no_mangle]
+pub unsafe fn sys_getpgid(pid: usize) -> usize {
+    syscall(SYS_GETPGID, pid, 0, 0)
+}
+
 #[no_mangle]
 pub unsafe fn sys_getpid() -> usize {
     syscall(SYS_GETPID, 0, 0, 0)
 }
 
+/// Get the nice value of a process, as `20 - nice`
+#[no_mangle]
+pub unsafe fn sys_getpriority(which: usize, who: usize) -> usize {
+    syscall(SYS_GETPRIORITY, which, who, 0)
+}
+
 #[no_mangle]
 pub unsafe fn sys_link(old: *const u8, new: *const u8) -> usize {
     syscall(SYS_LINK, old as usize, new as usize, 0)
 }
 
+#[no_mangle]
+pub unsafe fn sys_kill(pid: isize, sig: usize) -> usize {
+    syscall(SYS_KILL, pid as usize, sig, 0)
+}
+
 #[no_mangle]
 pub unsafe fn sys_lseek(fd: usize, offset: isize, whence: usize) -> usize {
     syscall(SYS_LSEEK, fd, offset as usize, whence)
@@ -135,11 +289,32 @@ pub unsafe fn sys_mkdir(path: *const u8, mode: usize) -> usize {
     syscall(SYS_MKDIR, path as usize, 0, mode)
 }
 
+#[no_mangle]
+pub unsafe fn sys_mmap(args: *const MmapArgs) -> usize {
+    syscall(SYS_MMAP, args as usize, 0, 0)
+}
+
+#[no_mangle]
+pub unsafe fn sys_mprotect(addr: usize, len: usize, prot: usize) -> usize {
+    syscall(SYS_MPROTECT, addr, len, prot)
+}
+
+#[no_mangle]
+pub unsafe fn sys_munmap(addr: usize, len: usize) -> usize {
+    syscall(SYS_MUNMAP, addr, len, 0)
+}
+
 #[no_mangle]
 pub unsafe fn sys_nanosleep(req: *const TimeSpec, rem: *mut TimeSpec) -> usize {
     syscall(SYS_NANOSLEEP, req as usize, rem as usize, 0)
 }
 
+/// Add `inc` to the nice value of the current process
+#[no_mangle]
+pub unsafe fn sys_nice(inc: isize) -> usize {
+    syscall(SYS_NICE, inc as usize, 0, 0)
+}
+
 #[no_mangle]
 pub unsafe fn sys_open(path: *const u8, flags: usize, mode: usize) -> usize {
     syscall(SYS_OPEN, path as usize, flags, mode)
@@ -155,6 +330,59 @@ pub unsafe fn sys_read(fd: usize, buf: *mut u8, count: usize) -> usize {
     syscall(SYS_READ, fd, buf as usize, count)
 }
 
+/// Move a process to the process group `pgid` of its session, or to a new one with its own
+/// PID with a `pgid` of 0
+#[no_mangle]
+pub unsafe fn sys_setpgid(pid: usize, pgid: usize) -> usize {
+    syscall(SYS_SETPGID, pid, pgid, 0)
+}
+
+#[no_mangle]
+pub unsafe fn sys_setpriority(which: usize, who: usize, prio: isize) -> usize {
+    syscall(SYS_SETPRIORITY, which, who, prio as usize)
+}
+
+/// Create a session and a process group with the PID of the current process, without a
+/// controlling terminal
+#[no_mangle]
+pub unsafe fn sys_setsid() -> usize {
+    syscall(SYS_SETSID, 0, 0, 0)
+}
+
+#[no_mangle]
+pub unsafe fn sys_sigaction(sig: usize, act: *const SigAction, oldact: *mut SigAction) -> usize {
+    syscall(SYS_SIGACTION, sig, act as usize, oldact as usize)
+}
+
+#[no_mangle]
+pub unsafe fn sys_sigprocmask(how: usize, set: *const usize, oldset: *mut usize) -> usize {
+    syscall(SYS_SIGPROCMASK, how, set as usize, oldset as usize)
+}
+
+/// Return from a signal handler, restoring the registers of the interrupted code
+#[no_mangle]
+pub unsafe fn sys_sigreturn() -> usize {
+    syscall(SYS_SIGRETURN, 0, 0, 0)
+}
+
+/// A `sa_restorer` for handlers, which handlers return to
+pub extern "C" fn sig_restorer() {
+    unsafe { sys_sigreturn() };
+}
+
+/// Get the foreground process group of the controlling terminal `fd`
+#[no_mangle]
+pub unsafe fn sys_tcgetpgrp(fd: usize) -> usize {
+    syscall(SYS_TCGETPGRP, fd, 0, 0)
+}
+
+/// Set the foreground process group of the controlling terminal `fd`, which gets the signals
+/// of the terminal
+#[no_mangle]
+pub unsafe fn sys_tcsetpgrp(fd: usize, pgid: usize) -> usize {
+    syscall(SYS_TCSETPGRP, fd, pgid, 0)
+}
+
 #[no_mangle]
 pub unsafe fn sys_unlink(path: *const u8) -> usize {
     syscall(SYS_UNLINK, path as usize, 0, 0)
@@ -198,7 +198,7 @@ impl AC97 {
         let module = box AC97 {
             audio: pci.read(0x10) as usize & 0xFFFFFFF0,
             bus_master: pci.read(0x14) as usize & 0xFFFFFFF0,
-            irq: pci.read(0x3C) as u8 & 0xF,
+            irq: pci.irq(),
         };
 
         debug!("AC97 on: {:X}, {:X}, IRQ: {:X}\n",
@@ -123,6 +123,11 @@ impl Ipv4Addr {
         true
     }
 
+    /// Check if the address is in 127.0.0.0/8, which is routed to the loopback interface
+    pub fn is_loopback(&self) -> bool {
+        self.bytes[0] == 127
+    }
+
     pub fn from_string(string: &String) -> Self {
         let mut addr = Ipv4Addr { bytes: [0, 0, 0, 0] };
 
@@ -176,6 +181,8 @@ impl Ipv6Addr {
     }
 }
 
+pub static ANY_IP_ADDR: Ipv4Addr = Ipv4Addr { bytes: [0, 0, 0, 0] };
+
 pub static BROADCAST_IP_ADDR: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 255] };
 
 pub static IP_ADDR: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 2] };
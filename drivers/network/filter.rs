@@ -0,0 +1,499 @@
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use core::mem;
+
+use common::to_num::ToNum;
+
+use network::common::*;
+use network::ethernet::*;
+use network::ipv4::*;
+
+use schemes::Url;
+
+use sync::Intex;
+
+use syscall::O_NONBLOCK;
+
+/// The ICMP code of a destination that is administratively prohibited
+const ICMP_PROHIBITED: u8 = 13;
+
+/// The direction of a packet
+#[derive(Copy, Clone, PartialEq)]
+pub enum FilterDirection {
+    In,
+    Out,
+}
+
+/// What to do with a matching packet
+#[derive(Copy, Clone, PartialEq)]
+pub enum FilterAction {
+    Accept,
+    /// Discard silently
+    Drop,
+    /// Discard, telling the sender with an ICMP error or a failed write
+    Reject,
+}
+
+impl FilterAction {
+    fn from_str(string: &str) -> Option<Self> {
+        match string {
+            "accept" => Some(FilterAction::Accept),
+            "drop" => Some(FilterAction::Drop),
+            "reject" => Some(FilterAction::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FilterAction::Accept => "accept",
+            FilterAction::Drop => "drop",
+            FilterAction::Reject => "reject",
+        }
+    }
+}
+
+/// Parse a decimal number of at most five digits, with nothing else
+fn number(string: &str) -> Option<usize> {
+    if !string.is_empty() && string.len() <= 5 && string.chars().all(|c| c.is_digit(10)) {
+        Some(string.to_num())
+    } else {
+        None
+    }
+}
+
+/// An address and prefix length, `0.0.0.0/0` matches every address
+#[derive(Copy, Clone)]
+pub struct FilterNet {
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl FilterNet {
+    fn from_str(string: &str) -> Option<Self> {
+        if string == "any" {
+            return Some(FilterNet {
+                addr: ANY_IP_ADDR,
+                prefix: 0,
+            });
+        }
+
+        let mut parts = string.splitn(2, '/');
+        let addr = parts.next().unwrap_or("");
+        if addr.split('.').count() != 4 ||
+           !addr.split('.').all(|byte| number(byte).map_or(false, |byte| byte <= 255)) {
+            return None;
+        }
+        let prefix = match parts.next() {
+            Some(prefix) => match number(prefix) {
+                Some(prefix) => prefix,
+                None => return None,
+            },
+            None => 32,
+        };
+        if prefix > 32 {
+            return None;
+        }
+
+        Some(FilterNet {
+            addr: Ipv4Addr::from_string(&addr.to_string()),
+            prefix: prefix as u8,
+        })
+    }
+
+    fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = if self.prefix == 0 {
+            0
+        } else {
+            !0u32 << (32 - self.prefix as u32)
+        };
+        (to_u32(self.addr) & mask) == (to_u32(addr) & mask)
+    }
+
+    fn to_string(&self) -> String {
+        format!("{}/{}", self.addr.to_string(), self.prefix)
+    }
+}
+
+fn to_u32(addr: Ipv4Addr) -> u32 {
+    (addr.bytes[0] as u32) << 24 | (addr.bytes[1] as u32) << 16 | (addr.bytes[2] as u32) << 8 |
+    addr.bytes[3] as u32
+}
+
+/// An inclusive range of ports
+#[derive(Copy, Clone)]
+pub struct FilterPorts {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl FilterPorts {
+    fn from_str(string: &str) -> Option<Self> {
+        let mut parts = string.splitn(2, '-');
+        let first = match number(parts.next().unwrap_or("")) {
+            Some(first) => first,
+            None => return None,
+        };
+        let last = match parts.next() {
+            Some(last) => match number(last) {
+                Some(last) => last,
+                None => return None,
+            },
+            None => first,
+        };
+        if first > last || last > 65535 {
+            return None;
+        }
+
+        Some(FilterPorts {
+            first: first as u16,
+            last: last as u16,
+        })
+    }
+
+    fn to_string(&self) -> String {
+        if self.first == self.last {
+            format!("{}", self.first)
+        } else {
+            format!("{}-{}", self.first, self.last)
+        }
+    }
+}
+
+/// A rule, unset fields match every packet
+///
+/// The text form is `action [in|out] [proto p] [from net] [to net] [sport ports] [dport ports]`,
+/// for example `reject in proto tcp to 10.85.85.2 dport 1-1023`
+#[derive(Copy, Clone)]
+pub struct FilterRule {
+    pub action: FilterAction,
+    pub direction: Option<FilterDirection>,
+    pub proto: Option<u8>,
+    pub src: Option<FilterNet>,
+    pub dst: Option<FilterNet>,
+    pub src_port: Option<FilterPorts>,
+    pub dst_port: Option<FilterPorts>,
+    /// Packets matched
+    pub hits: u64,
+}
+
+impl FilterRule {
+    pub fn from_str(string: &str) -> Option<Self> {
+        let mut words = string.split(' ').filter(|word| !word.is_empty());
+
+        let mut rule = FilterRule {
+            action: match words.next().and_then(FilterAction::from_str) {
+                Some(action) => action,
+                None => return None,
+            },
+            direction: None,
+            proto: None,
+            src: None,
+            dst: None,
+            src_port: None,
+            dst_port: None,
+            hits: 0,
+        };
+
+        while let Some(word) = words.next() {
+            match word {
+                "in" => rule.direction = Some(FilterDirection::In),
+                "out" => rule.direction = Some(FilterDirection::Out),
+                _ => {
+                    let value = match words.next() {
+                        Some(value) => value,
+                        None => return None,
+                    };
+
+                    match word {
+                        "proto" => {
+                            rule.proto = Some(match value {
+                                "icmp" => 1,
+                                "tcp" => 6,
+                                "udp" => 0x11,
+                                _ => match number(value) {
+                                    Some(proto) if proto <= 255 => proto as u8,
+                                    _ => return None,
+                                },
+                            })
+                        }
+                        "from" => rule.src = FilterNet::from_str(value),
+                        "to" => rule.dst = FilterNet::from_str(value),
+                        "sport" => rule.src_port = FilterPorts::from_str(value),
+                        "dport" => rule.dst_port = FilterPorts::from_str(value),
+                        _ => return None,
+                    }
+
+                    // A value that did not parse
+                    let parsed = match word {
+                        "from" => rule.src.is_some(),
+                        "to" => rule.dst.is_some(),
+                        "sport" => rule.src_port.is_some(),
+                        "dport" => rule.dst_port.is_some(),
+                        _ => true,
+                    };
+                    if !parsed {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        Some(rule)
+    }
+
+    /// Check a packet, ports are only known for TCP and UDP packets that are not later fragments
+    fn matches(&self,
+               direction: FilterDirection,
+               proto: u8,
+               src: Ipv4Addr,
+               dst: Ipv4Addr,
+               ports: Option<(u16, u16)>)
+               -> bool {
+        if self.direction.map_or(false, |d| d != direction) ||
+           self.proto.map_or(false, |p| p != proto) ||
+           self.src.map_or(false, |net| !net.contains(src)) ||
+           self.dst.map_or(false, |net| !net.contains(dst)) {
+            return false;
+        }
+
+        if self.src_port.is_some() || self.dst_port.is_some() {
+            match ports {
+                Some((src_port, dst_port)) => {
+                    self.src_port.map_or(true, |p| src_port >= p.first && src_port <= p.last) &&
+                    self.dst_port.map_or(true, |p| dst_port >= p.first && dst_port <= p.last)
+                }
+                None => false,
+            }
+        } else {
+            true
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut string = self.action.name().to_string();
+        match self.direction {
+            Some(FilterDirection::In) => string = string + " in",
+            Some(FilterDirection::Out) => string = string + " out",
+            None => (),
+        }
+        match self.proto {
+            Some(1) => string = string + " proto icmp",
+            Some(6) => string = string + " proto tcp",
+            Some(0x11) => string = string + " proto udp",
+            Some(proto) => string = string + &format!(" proto {}", proto),
+            None => (),
+        }
+        if let Some(src) = self.src {
+            string = string + " from " + &src.to_string();
+        }
+        if let Some(dst) = self.dst {
+            string = string + " to " + &dst.to_string();
+        }
+        if let Some(src_port) = self.src_port {
+            string = string + " sport " + &src_port.to_string();
+        }
+        if let Some(dst_port) = self.dst_port {
+            string = string + " dport " + &dst_port.to_string();
+        }
+        string
+    }
+}
+
+/// The rule table, the first matching rule decides, otherwise the policy
+pub struct Filter {
+    pub rules: Vec<FilterRule>,
+    pub policy: FilterAction,
+    /// Packets that matched no rule
+    pub policy_hits: u64,
+}
+
+impl Filter {
+    /// Decide what to do with a packet, counting the hit
+    pub fn check(&mut self,
+                 direction: FilterDirection,
+                 proto: u8,
+                 src: Ipv4Addr,
+                 dst: Ipv4Addr,
+                 ports: Option<(u16, u16)>)
+                 -> FilterAction {
+        for rule in self.rules.iter_mut() {
+            if rule.matches(direction, proto, src, dst, ports) {
+                rule.hits += 1;
+                return rule.action;
+            }
+        }
+
+        self.policy_hits += 1;
+        self.policy
+    }
+
+    /// List the policy and the rules with their index and hits
+    pub fn to_string(&self) -> String {
+        let mut string = format!("policy {} {}\n", self.policy.name(), self.policy_hits);
+        for (i, rule) in self.rules.iter().enumerate() {
+            string = string + &format!("{} {} {}\n", i, rule.hits, rule.to_string());
+        }
+        string
+    }
+
+    /// Run a command, one of `add rule`, `insert index rule`, `delete index`, `flush`, `zero`,
+    /// or `policy action`
+    pub fn command(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        let (command, args) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (line, ""),
+        };
+
+        match command {
+            "add" => match FilterRule::from_str(args) {
+                Some(rule) => self.rules.push(rule),
+                None => return false,
+            },
+            "insert" => {
+                let (index, rule) = match args.find(' ') {
+                    Some(i) => (number(&args[..i]), FilterRule::from_str(&args[i + 1..])),
+                    None => return false,
+                };
+                match (index, rule) {
+                    (Some(index), Some(rule)) if index <= self.rules.len() => {
+                        self.rules.insert(index, rule)
+                    }
+                    _ => return false,
+                }
+            }
+            "delete" => match number(args) {
+                Some(index) if index < self.rules.len() => {
+                    self.rules.remove(index);
+                }
+                _ => return false,
+            },
+            "flush" => self.rules.clear(),
+            "zero" => {
+                for rule in self.rules.iter_mut() {
+                    rule.hits = 0;
+                }
+                self.policy_hits = 0;
+            }
+            "policy" => match FilterAction::from_str(args) {
+                Some(action) => self.policy = action,
+                None => return false,
+            },
+            "" => (),
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+/// The rule table of the IP layer, created when first used
+static mut FILTER: Option<Filter> = None;
+
+/// Use the rule table with interrupts excluded
+pub fn with_filter<T, F: FnOnce(&mut Filter) -> T>(f: F) -> T {
+    let _intex = Intex::static_lock();
+    unsafe {
+        if FILTER.is_none() {
+            FILTER = Some(Filter {
+                rules: Vec::new(),
+                policy: FilterAction::Accept,
+                policy_hits: 0,
+            });
+        }
+        match FILTER {
+            Some(ref mut filter) => f(filter),
+            None => unreachable!(),
+        }
+    }
+}
+
+/// Check a packet against the rule table, `data` starts with the header of the protocol
+pub fn filter_packet(direction: FilterDirection,
+                     proto: u8,
+                     src: Ipv4Addr,
+                     dst: Ipv4Addr,
+                     data: Option<&[u8]>)
+                     -> FilterAction {
+    // TCP and UDP both start with the source and destination ports
+    let ports = match data {
+        Some(data) if (proto == 6 || proto == 0x11) && data.len() >= 4 => {
+            Some(((data[0] as u16) << 8 | data[1] as u16,
+                  (data[2] as u16) << 8 | data[3] as u16))
+        }
+        _ => None,
+    };
+
+    with_filter(|filter| filter.check(direction, proto, src, dst, ports))
+}
+
+/// Check a frame received by a link against the rule table, once for all of its resources.
+/// `ethernet` is false for links that carry IPv4 packets without a frame, like the loopback
+/// interface. Returns false if the frame is discarded, rejected packets are added to `rejected`.
+/// Later fragments carry no ports and are not checked, they are never reassembled without the
+/// first one
+pub fn filter_inbound(bytes: &Vec<u8>, ethernet: bool, rejected: &mut Vec<Ipv4>) -> bool {
+    let packet_bytes = if ethernet {
+        match EthernetII::from_bytes(bytes.clone()) {
+            Some(frame) if frame.header.ethertype.get() == 0x800 => frame.data,
+            _ => return true,
+        }
+    } else {
+        bytes.clone()
+    };
+
+    let packet = match Ipv4::from_bytes(packet_bytes) {
+        Some(packet) => packet,
+        None => return true,
+    };
+
+    // Only packets to this host, the others are never received
+    let to_host = if ethernet {
+        packet.header.dst.equals(IP_ADDR)
+    } else {
+        packet.header.dst.is_loopback()
+    };
+    if !to_host || packet.header.flags_fragment.get() & IPV4_FRAGMENT_OFFSET != 0 {
+        return true;
+    }
+
+    match filter_packet(FilterDirection::In,
+                        packet.header.proto,
+                        packet.header.src,
+                        packet.header.dst,
+                        Some(&packet.data)) {
+        FilterAction::Accept => true,
+        FilterAction::Drop => false,
+        FilterAction::Reject => {
+            rejected.push(packet);
+            false
+        }
+    }
+}
+
+/// Answer rejected packets with ICMP errors. The link calls this once it released its
+/// resources, as the errors are sent through the IP layer
+pub fn filter_reject(rejected: Vec<Ipv4>) {
+    for packet in rejected.iter() {
+        // ICMP is never answered with ICMP errors
+        if packet.header.proto == 1 {
+            continue;
+        }
+
+        let mut bytes = vec![3, ICMP_PROHIBITED, 0, 0, 0, 0, 0, 0];
+        bytes.push_all(&packet.to_bytes());
+        bytes.truncate(8 + mem::size_of::<Ipv4Header>() + packet.options.len() + 8);
+
+        unsafe {
+            let checksum = Checksum::compile(Checksum::sum(bytes.as_ptr() as usize, bytes.len()));
+            *(bytes.as_mut_ptr().offset(2) as *mut u16) = checksum;
+        }
+
+        let url = format!("ip:{}/1", packet.header.src.to_string());
+        if let Ok(mut ip) = ::env().open(&Url::from_string(url), O_NONBLOCK) {
+            let _ = ip.write(&bytes);
+        }
+    }
+}
@@ -4,19 +4,29 @@ use collections::slice;
 use collections::vec::Vec;
 use collections::vec_deque::VecDeque;
 
-use core::ptr;
+use core::{cmp, mem, ptr};
 
 use common::{debug, memory};
 
 use drivers::pci::config::PciConfig;
 
 use network::common::*;
+use network::filter::{filter_inbound, filter_reject};
 use network::scheme::*;
 
-use schemes::{Result, KScheme, Resource, Url};
+use schemes::{Result, KScheme, Resource, Url, VecResource};
 
 use sync::Intex;
 
+/// Receive descriptors, each with a buffer of `RX_BUFFER_SIZE`
+const RX_RING_LENGTH: usize = 256;
+const RX_BUFFER_SIZE: usize = 16384;
+/// Transmit descriptors, each with a buffer of `TX_BUFFER_SIZE`
+const TX_RING_LENGTH: usize = 256;
+const TX_BUFFER_SIZE: usize = 4096;
+/// Frames waiting for free transmit descriptors, more are dropped
+const TX_QUEUE_LENGTH: usize = 1024;
+
 const CTRL: u32 = 0x00;
 const CTRL_LRST: u32 = 1 << 3;
 const CTRL_ASDE: u32 = 1 << 5;
@@ -26,6 +36,10 @@ const CTRL_VME: u32 = 1 << 30;
 const CTRL_PHY_RST: u32 = 1 << 31;
 
 const STATUS: u32 = 0x08;
+const STATUS_LU: u32 = 1 << 1;
+const STATUS_SPEED: u32 = 0b11 << 6;
+const STATUS_SPEED_10: u32 = 0b00 << 6;
+const STATUS_SPEED_100: u32 = 0b01 << 6;
 
 const FCAL: u32 = 0x28;
 const FCAH: u32 = 0x2C;
@@ -33,6 +47,12 @@ const FCT: u32 = 0x30;
 const FCTTV: u32 = 0x170;
 
 const ICR: u32 = 0xC0;
+const ICR_TXDW: u32 = 1;
+const ICR_TXQE: u32 = 1 << 1;
+const ICR_LSC: u32 = 1 << 2;
+const ICR_RXDMT: u32 = 1 << 4;
+const ICR_RXO: u32 = 1 << 6;
+const ICR_RXT: u32 = 1 << 7;
 
 const IMS: u32 = 0xD0;
 const IMS_TXDW: u32 = 1;
@@ -60,6 +80,14 @@ const RDBAH: u32 = 0x2804;
 const RDLEN: u32 = 0x2808;
 const RDH: u32 = 0x2810;
 const RDT: u32 = 0x2818;
+const RDTR: u32 = 0x2820;
+
+const CRCERRS: u32 = 0x4000;
+const ALGNERRC: u32 = 0x4004;
+const RXERRC: u32 = 0x400C;
+const MPC: u32 = 0x4010;
+const ECOL: u32 = 0x4018;
+const LATECOL: u32 = 0x4020;
 
 const RAL0: u32 = 0x5400;
 const RAH0: u32 = 0x5404;
@@ -109,6 +137,22 @@ pub struct Intel8254x {
     pub resources: Intex<Vec<*mut NetworkResource>>,
     pub inbound: VecDeque<Vec<u8>>,
     pub outbound: VecDeque<Vec<u8>>,
+    pub stats: NetworkStats,
+    pub link_up: bool,
+    /// The link speed, in Mb/s
+    pub speed: usize,
+    receive_ring: *mut Rd,
+    /// The next receive descriptor to be filled by the hardware
+    receive_index: usize,
+    /// The frame being received, when it spans several descriptors
+    receive_frame: Vec<u8>,
+    /// Discard descriptors until the end of a frame with errors
+    receive_discard: bool,
+    transmit_ring: *mut Td,
+    /// The next free transmit descriptor, the tail of the ring
+    transmit_index: usize,
+    /// The oldest transmit descriptor not yet reclaimed
+    transmit_clean: usize,
 }
 
 impl KScheme for Intel8254x {
@@ -116,13 +160,40 @@ impl KScheme for Intel8254x {
         "network"
     }
 
-    fn open(&mut self, _: &Url, _: usize) -> Result<Box<Resource>> {
-        Ok(NetworkResource::new(self))
+    fn open(&mut self, url: &Url, flags: usize) -> Result<Box<Resource>> {
+        if url.reference() == "stats" {
+            let _intex = Intex::static_lock();
+            unsafe { self.update_stats() };
+            let string = netstat("Intel 8254x", self.link_up, Some(self.speed), &self.stats);
+            return Ok(box VecResource::new(url.clone(), string.into_bytes()));
+        }
+
+        let mut resource = NetworkResource::new(self, flags);
+        resource.capture = url.reference() == "capture";
+        Ok(resource)
     }
 
     fn on_irq(&mut self, irq: u8) {
         if irq == self.irq {
-            unsafe { self.read(ICR) };
+            unsafe {
+                let icr = self.read(ICR);
+
+                if icr & ICR_LSC == ICR_LSC {
+                    self.update_link();
+                }
+
+                if icr & (ICR_RXT | ICR_RXDMT | ICR_RXO) != 0 {
+                    self.receive_inbound();
+                }
+
+                if icr & ICR_RXO == ICR_RXO {
+                    self.update_stats();
+                }
+
+                if icr & (ICR_TXDW | ICR_TXQE) != 0 {
+                    self.send_outbound();
+                }
+            }
 
             self.sync();
         }
@@ -160,32 +231,38 @@ impl NetworkScheme for Intel8254x {
         }
     }
 
+    /// Queue the frames of the resources for transmit, and deliver the frames received by the
+    /// interrupt handler
     fn sync(&mut self) {
+        let mut rejected = Vec::new();
         unsafe {
-            {
-                let resources = self.resources.lock();
+            let resources = self.resources.lock();
 
-                for resource in resources.iter() {
-                    while let Some(bytes) = (**resource).outbound.lock().pop_front() {
+            for resource in resources.iter() {
+                while let Some(bytes) = (**resource).outbound.lock().pop_front() {
+                    capture(&resources, &bytes);
+                    if self.outbound.len() < TX_QUEUE_LENGTH {
                         self.outbound.push_back(bytes);
+                    } else {
+                        self.stats.tx_dropped += 1;
                     }
                 }
             }
 
             self.send_outbound();
 
-            self.receive_inbound();
-
-            {
-                let resources = self.resources.lock();
-
-                while let Some(bytes) = self.inbound.pop_front() {
-                    for resource in resources.iter() {
+            // The filter sees every frame once, capturing resources get the discarded ones too
+            while let Some(bytes) = self.inbound.pop_front() {
+                let accepted = filter_inbound(&bytes, true, &mut rejected);
+                for resource in resources.iter() {
+                    if accepted || (**resource).capture {
                         (**resource).inbound.lock().push_back(bytes.clone());
                     }
                 }
             }
         }
+
+        filter_reject(rejected);
     }
 }
 
@@ -197,10 +274,20 @@ impl Intel8254x {
             pci: pci,
             base: base & 0xFFFFFFF0,
             memory_mapped: base & 1 == 0,
-            irq: pci.read(0x3C) as u8 & 0xF,
+            irq: pci.irq(),
             resources: Intex::new(Vec::new()),
             inbound: VecDeque::new(),
             outbound: VecDeque::new(),
+            stats: NetworkStats::default(),
+            link_up: false,
+            speed: 0,
+            receive_ring: 0 as *mut Rd,
+            receive_index: 0,
+            receive_frame: Vec::new(),
+            receive_discard: false,
+            transmit_ring: 0 as *mut Td,
+            transmit_index: 0,
+            transmit_clean: 0,
         };
 
         module.init();
@@ -208,83 +295,131 @@ impl Intel8254x {
         module
     }
 
+    /// Take the frames of all filled receive descriptors, and give the descriptors back
     pub unsafe fn receive_inbound(&mut self) {
-        let receive_ring = self.read(RDBAL) as *mut Rd;
-        let length = self.read(RDLEN);
-
-        for tail in 0..length / 16 {
-            let rd = &mut *receive_ring.offset(tail as isize);
-            if rd.status & RD_DD == RD_DD {
-                debug::d("Recv ");
-                debug::dh(rd as *mut Rd as usize);
-                debug::d(" ");
-                debug::dh(rd.status as usize);
-                debug::d(" ");
-                debug::dh(rd.buffer as usize);
-                debug::d(" ");
-                debug::dh(rd.length as usize);
-                debug::dl();
-
-                self.inbound.push_back(Vec::from(slice::from_raw_parts(rd.buffer as *const u8,
-                                                                       rd.length as usize)));
-
-                rd.status = 0;
+        loop {
+            let rd = &mut *self.receive_ring.offset(self.receive_index as isize);
+            if rd.status & RD_DD != RD_DD {
+                break;
+            }
+
+            if rd.error != 0 {
+                self.receive_discard = true;
+                self.receive_frame.clear();
+            } else if !self.receive_discard {
+                self.receive_frame.push_all(slice::from_raw_parts(rd.buffer as *const u8,
+                                                                  rd.length as usize));
+            }
+
+            if rd.status & RD_EOP == RD_EOP {
+                if self.receive_discard {
+                    self.stats.rx_errors += 1;
+                    self.receive_discard = false;
+                } else {
+                    let frame = mem::replace(&mut self.receive_frame, Vec::new());
+                    self.stats.rx_packets += 1;
+                    self.stats.rx_bytes += frame.len() as u64;
+                    self.inbound.push_back(frame);
+                }
             }
+
+            rd.status = 0;
+
+            // The tail is the last descriptor owned by the driver
+            self.write(RDT, self.receive_index as u32);
+            self.receive_index = (self.receive_index + 1) % RX_RING_LENGTH;
         }
     }
 
+    /// The number of transmit descriptors that can be filled
+    fn transmit_free(&self) -> usize {
+        (self.transmit_clean + TX_RING_LENGTH - self.transmit_index - 1) % TX_RING_LENGTH
+    }
+
+    /// Reclaim the sent descriptors, then fill free descriptors with waiting frames. Frames larger
+    /// than a buffer use several descriptors
     pub unsafe fn send_outbound(&mut self) {
-        while let Some(bytes) = self.outbound.pop_front() {
-            let transmit_ring = self.read(TDBAL) as *mut Td;
-            let length = self.read(TDLEN);
+        while self.transmit_clean != self.transmit_index {
+            let td = &mut *self.transmit_ring.offset(self.transmit_clean as isize);
+            if td.status & TD_DD != TD_DD {
+                break;
+            }
+            td.status = 0;
+            self.transmit_clean = (self.transmit_clean + 1) % TX_RING_LENGTH;
+        }
 
-            loop {
-                let head = self.read(TDH);
-                let mut tail = self.read(TDT);
-                let old_tail = tail;
+        let old_index = self.transmit_index;
+        while let Some(bytes) = self.outbound.pop_front() {
+            let count = cmp::max(1, (bytes.len() + TX_BUFFER_SIZE - 1) / TX_BUFFER_SIZE);
+            if count > TX_RING_LENGTH - 1 {
+                self.stats.tx_errors += 1;
+                continue;
+            }
+            if count > self.transmit_free() {
+                self.outbound.push_front(bytes);
+                break;
+            }
 
-                tail += 1;
-                if tail >= length / 16 {
-                    tail = 0;
+            let mut offset = 0;
+            for i in 0..count {
+                let length = cmp::min(TX_BUFFER_SIZE, bytes.len() - offset);
+
+                let td = &mut *self.transmit_ring.offset(self.transmit_index as isize);
+                ::memcpy(td.buffer as *mut u8, bytes.as_ptr().offset(offset as isize), length);
+                td.length = length as u16;
+                td.cso = 0;
+                td.command = TD_CMD_IFCS | TD_CMD_RS;
+                if i == count - 1 {
+                    td.command |= TD_CMD_EOP;
                 }
+                td.status = 0;
+                td.css = 0;
+                td.special = 0;
 
-                if tail != head {
-                    if bytes.len() < 16384 {
-                        let td = &mut *transmit_ring.offset(old_tail as isize);
-
-                        debug::d("Send ");
-                        debug::dh(old_tail as usize);
-                        debug::d(" ");
-                        debug::dh(td.status as usize);
-                        debug::d(" ");
-                        debug::dh(td.buffer as usize);
-                        debug::d(" ");
-                        debug::dh(bytes.len() & 0x3FFF);
-                        debug::dl();
-
-                        ::memcpy(td.buffer as *mut u8, bytes.as_ptr(), bytes.len());
-                        td.length = (bytes.len() & 0x3FFF) as u16;
-                        td.cso = 0;
-                        td.command = TD_CMD_EOP | TD_CMD_IFCS | TD_CMD_RS;
-                        td.status = 0;
-                        td.css = 0;
-                        td.special = 0;
-
-                        self.write(TDT, tail);
-                    } else {
-                        // TODO: More than one TD
-                        debug::dl();
-                        debug::d("Intel 8254x: Frame too long for transmit: ");
-                        debug::dd(bytes.len());
-                        debug::dl();
-                    }
+                offset += length;
+                self.transmit_index = (self.transmit_index + 1) % TX_RING_LENGTH;
+            }
 
-                    break;
-                }
+            self.stats.tx_packets += 1;
+            self.stats.tx_bytes += bytes.len() as u64;
+        }
+
+        if self.transmit_index != old_index {
+            self.write(TDT, self.transmit_index as u32);
+        }
+    }
+
+    /// Read the link state after a link status change
+    pub unsafe fn update_link(&mut self) {
+        let status = self.read(STATUS);
+        let link_up = status & STATUS_LU == STATUS_LU;
+        self.speed = match status & STATUS_SPEED {
+            STATUS_SPEED_10 => 10,
+            STATUS_SPEED_100 => 100,
+            _ => 1000,
+        };
+
+        if link_up != self.link_up {
+            self.link_up = link_up;
+
+            debug::d("Intel 8254x: Link ");
+            if link_up {
+                debug::d("up");
+            } else {
+                debug::d("down");
             }
+            debug::dl();
         }
     }
 
+    /// Add the error counters of the hardware, which are cleared when read
+    pub unsafe fn update_stats(&mut self) {
+        self.stats.rx_dropped += self.read(MPC) as u64;
+        self.stats.rx_errors += (self.read(CRCERRS) + self.read(ALGNERRC) +
+                                 self.read(RXERRC)) as u64;
+        self.stats.tx_errors += (self.read(ECOL) + self.read(LATECOL)) as u64;
+    }
+
     pub unsafe fn read(&self, register: u32) -> u32 {
         if self.memory_mapped {
             ptr::read((self.base + register as usize) as *mut u32)
@@ -341,7 +476,9 @@ impl Intel8254x {
         debug::d(" CTRL ");
         debug::dh(self.read(CTRL) as usize);
 
-        // TODO: Clear statistical counters
+        // Clear the statistical counters
+        self.update_stats();
+        self.stats = NetworkStats::default();
 
         debug::d(" MAC: ");
         let mac_low = self.read(RAL0);
@@ -361,10 +498,10 @@ impl Intel8254x {
         //
 
         // Receive Buffer
-        let receive_ring_length = 1024;
+        let receive_ring_length = RX_RING_LENGTH;
         let receive_ring = memory::alloc(receive_ring_length * 16) as *mut Rd;
         for i in 0..receive_ring_length {
-            let receive_buffer = memory::alloc(16384);
+            let receive_buffer = memory::alloc(RX_BUFFER_SIZE);
             ptr::write(receive_ring.offset(i as isize),
                        Rd {
                            buffer: receive_buffer as u64,
@@ -381,12 +518,16 @@ impl Intel8254x {
         self.write(RDLEN, (receive_ring_length * 16) as u32);
         self.write(RDH, 0);
         self.write(RDT, receive_ring_length as u32 - 1);
+        // Interrupt as soon as a frame is received
+        self.write(RDTR, 0);
+        self.receive_ring = receive_ring;
+        self.receive_index = 0;
 
         // Transmit Buffer
-        let transmit_ring_length = 64;
+        let transmit_ring_length = TX_RING_LENGTH;
         let transmit_ring = memory::alloc(transmit_ring_length * 16) as *mut Td;
         for i in 0..transmit_ring_length {
-            let transmit_buffer = memory::alloc(16384);
+            let transmit_buffer = memory::alloc(TX_BUFFER_SIZE);
             ptr::write(transmit_ring.offset(i as isize),
                        Td {
                            buffer: transmit_buffer as u64,
@@ -404,6 +545,9 @@ impl Intel8254x {
         self.write(TDLEN, (transmit_ring_length * 16) as u32);
         self.write(TDH, 0);
         self.write(TDT, 0);
+        self.transmit_ring = transmit_ring;
+        self.transmit_index = 0;
+        self.transmit_clean = 0;
 
         self.write(IMS,
                    IMS_RXT | IMS_RX | IMS_RXDMT | IMS_RXSEQ | IMS_LSC | IMS_TXQE | IMS_TXDW);
@@ -438,5 +582,7 @@ impl Intel8254x {
         debug::dh(self.read(TCTL) as usize);
 
         debug::dl();
+
+        self.update_link();
     }
 }
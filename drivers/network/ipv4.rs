@@ -22,6 +22,13 @@ pub struct Ipv4Header {
     pub dst: Ipv4Addr,
 }
 
+/// More fragments follow this one
+pub const IPV4_MF: u16 = 0x2000;
+/// Do not fragment
+pub const IPV4_DF: u16 = 0x4000;
+/// The fragment offset, in units of 8 bytes
+pub const IPV4_FRAGMENT_OFFSET: u16 = 0x1FFF;
+
 pub struct Ipv4 {
     pub header: Ipv4Header,
     pub options: Vec<u8>,
@@ -34,12 +41,13 @@ impl FromBytes for Ipv4 {
             unsafe {
                 let header = *(bytes.as_ptr() as *const Ipv4Header);
                 let header_len = ((header.ver_hlen & 0xF) << 2) as usize;
+                // Frames may be padded past the end of the packet
+                let total_len = header.len.get() as usize;
 
                 return Some(Ipv4 {
                     header: header,
-                    options: bytes.get_slice(Some(mem::size_of::<Ipv4Header>()), Some(header_len))
-                                  .to_vec(),
-                    data: bytes.get_slice(Some(header_len), None).to_vec(),
+                    options: bytes.get_slice(mem::size_of::<Ipv4Header>()..header_len).to_vec(),
+                    data: bytes.get_slice(header_len..total_len).to_vec(),
                 });
             }
         }
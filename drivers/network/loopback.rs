@@ -0,0 +1,81 @@
+use alloc::boxed::Box;
+
+use collections::vec::Vec;
+
+use network::filter::{filter_inbound, filter_reject};
+use network::scheme::*;
+
+use schemes::{Result, KScheme, Resource, Url, VecResource};
+
+use sync::Intex;
+
+/// A loopback interface, every packet written is read back by all resources
+pub struct Loopback {
+    resources: Intex<Vec<*mut NetworkResource>>,
+    stats: NetworkStats,
+}
+
+impl Loopback {
+    pub fn new() -> Box<Self> {
+        box Loopback {
+            resources: Intex::new(Vec::new()),
+            stats: NetworkStats::default(),
+        }
+    }
+}
+
+impl KScheme for Loopback {
+    fn scheme(&self) -> &str {
+        "loopback"
+    }
+
+    fn open(&mut self, url: &Url, flags: usize) -> Result<Box<Resource>> {
+        if url.reference() == "stats" {
+            let _intex = Intex::static_lock();
+            let string = netstat("Loopback", true, None, &self.stats);
+            return Ok(box VecResource::new(url.clone(), string.into_bytes()));
+        }
+
+        Ok(NetworkResource::new(self, flags))
+    }
+}
+
+impl NetworkScheme for Loopback {
+    fn add(&mut self, resource: *mut NetworkResource) {
+        self.resources.lock().push(resource);
+    }
+
+    fn remove(&mut self, resource: *mut NetworkResource) {
+        self.resources.lock().retain(|ptr| *ptr != resource);
+    }
+
+    fn sync(&mut self) {
+        let mut rejected = Vec::new();
+        unsafe {
+            let resources = self.resources.lock();
+
+            let mut packets = Vec::new();
+            for resource in resources.iter() {
+                while let Some(bytes) = (**resource).outbound.lock().pop_front() {
+                    packets.push(bytes);
+                }
+            }
+
+            for bytes in packets.iter() {
+                self.stats.tx_packets += 1;
+                self.stats.tx_bytes += bytes.len() as u64;
+                self.stats.rx_packets += 1;
+                self.stats.rx_bytes += bytes.len() as u64;
+
+                // The filter sees every packet once
+                if filter_inbound(bytes, false, &mut rejected) {
+                    for resource in resources.iter() {
+                        (**resource).inbound.lock().push_back(bytes.clone());
+                    }
+                }
+            }
+        }
+
+        filter_reject(rejected);
+    }
+}
@@ -1,7 +1,34 @@
+use collections::vec::Vec;
+
+use core::cell::UnsafeCell;
+
+use env::Environment;
+
+use self::loopback::Loopback;
+use self::schemes::arp::ArpScheme;
+use self::schemes::ethernet::EthernetScheme;
+use self::schemes::filter::FilterScheme;
+use self::schemes::ip::IpScheme;
+use self::schemes::pcap::PcapScheme;
+
 pub mod common;
 pub mod ethernet;
+pub mod filter;
 pub mod intel8254x;
 pub mod ipv4;
 pub mod ipv6;
+pub mod loopback;
 pub mod rtl8139;
 pub mod scheme;
+pub mod schemes;
+
+/// Register the schemes of the network stack that do not belong to a device, the device
+/// drivers are registered by PCI
+pub unsafe fn network_init(env: &mut Environment) {
+    env.schemes.push(UnsafeCell::new(box EthernetScheme));
+    env.schemes.push(UnsafeCell::new(box ArpScheme));
+    env.schemes.push(UnsafeCell::new(box IpScheme { arp: Vec::new() }));
+    env.schemes.push(UnsafeCell::new(Loopback::new()));
+    env.schemes.push(UnsafeCell::new(box PcapScheme));
+    env.schemes.push(UnsafeCell::new(box FilterScheme));
+}
@@ -13,9 +13,10 @@ use drivers::pci::config::PciConfig;
 use drivers::io::{Io, Pio};
 
 use network::common::*;
+use network::filter::{filter_inbound, filter_reject};
 use network::scheme::*;
 
-use schemes::{Result, KScheme, Resource, Url};
+use schemes::{Result, KScheme, Resource, Url, VecResource};
 
 use sync::Intex;
 
@@ -39,6 +40,11 @@ const RTL8139_ISR_ROK: u16 = 1 << 0;
 
 const RTL8139_TCR_IFG: u32 = 0b11 << 24;
 
+const RTL8139_MSR_LINKB: u8 = 1 << 2;
+const RTL8139_MSR_SPEED_10: u8 = 1 << 3;
+
+const RTL8139_RX_ROK: usize = 1 << 0;
+
 const RTL8139_RCR_WRAP: u32 = 1 << 7;
 const RTL8139_RCR_AR: u32 = 1 << 4;
 const RTL8139_RCR_AB: u32 = 1 << 3;
@@ -62,7 +68,9 @@ pub struct Rtl8139Port {
     pub isr: Pio<u16>,
     pub tcr: Pio<u32>,
     pub rcr: Pio<u32>,
+    pub mpc: Pio<u32>,
     pub config1: Pio<u8>,
+    pub msr: Pio<u8>,
 }
 
 impl Rtl8139Port {
@@ -82,7 +90,9 @@ impl Rtl8139Port {
             isr: Pio::<u16>::new(base + 0x3E),
             tcr: Pio::<u32>::new(base + 0x40),
             rcr: Pio::<u32>::new(base + 0x44),
+            mpc: Pio::<u32>::new(base + 0x4C),
             config1: Pio::<u8>::new(base + 0x52),
+            msr: Pio::<u8>::new(base + 0x58),
         };
     }
 }
@@ -98,6 +108,10 @@ pub struct Rtl8139 {
     txds: Vec<Txd>,
     txd_i: usize,
     port: Rtl8139Port,
+    stats: NetworkStats,
+    link_up: bool,
+    /// The link speed, in Mb/s
+    speed: usize,
 }
 
 impl Rtl8139 {
@@ -109,7 +123,7 @@ impl Rtl8139 {
         }
 
         let base = unsafe { pci.read(0x10) as usize };
-        let irq = unsafe { pci.read(0x3C) as u8 & 0xF };
+        let irq = unsafe { pci.irq() };
 
         let mut module = box Rtl8139 {
             pci: pci,
@@ -122,6 +136,9 @@ impl Rtl8139 {
             txds: Vec::new(),
             txd_i: 0,
             port: Rtl8139Port::new((base & 0xFFFFFFF0) as u16),
+            stats: NetworkStats::default(),
+            link_up: false,
+            speed: 0,
         };
 
         unsafe { module.init() };
@@ -170,7 +187,12 @@ impl Rtl8139 {
             });
         }
 
-        self.port.imr.write(RTL8139_ISR_TOK | RTL8139_ISR_ROK);
+        // Clear the missed packet counter
+        self.port.mpc.write(0);
+
+        self.port.imr.write(RTL8139_ISR_PUN_LINKCHG | RTL8139_ISR_FOVW | RTL8139_ISR_RXOVW |
+                            RTL8139_ISR_TER | RTL8139_ISR_TOK | RTL8139_ISR_RER |
+                            RTL8139_ISR_ROK);
         debug::d(" IMR: ");
         debug::dh(self.port.imr.read() as usize);
 
@@ -188,6 +210,37 @@ impl Rtl8139 {
         debug::dh(self.port.tcr.read() as usize);
 
         debug::dl();
+
+        self.update_link();
+    }
+
+    /// Read the link state after a link change
+    unsafe fn update_link(&mut self) {
+        let msr = self.port.msr.read();
+        let link_up = msr & RTL8139_MSR_LINKB == 0;
+        self.speed = if msr & RTL8139_MSR_SPEED_10 == RTL8139_MSR_SPEED_10 {
+            10
+        } else {
+            100
+        };
+
+        if link_up != self.link_up {
+            self.link_up = link_up;
+
+            debug::d("RTL8139: Link ");
+            if link_up {
+                debug::d("up");
+            } else {
+                debug::d("down");
+            }
+            debug::dl();
+        }
+    }
+
+    /// Add the frames missed by the hardware, the counter is cleared by a write
+    unsafe fn update_stats(&mut self) {
+        self.stats.rx_dropped += (self.port.mpc.read() & 0xFFFFFF) as u64;
+        self.port.mpc.write(0);
     }
 
     unsafe fn receive_inbound(&mut self) {
@@ -212,9 +265,15 @@ impl Rtl8139 {
             debug::dh(frame_len);
             debug::dl();
 
-            self.inbound
-                .push_back(Vec::from(slice::from_raw_parts(frame_addr as *const u8,
-                                                           frame_len - 4)));
+            if frame_status & RTL8139_RX_ROK == RTL8139_RX_ROK && frame_len >= 4 {
+                self.stats.rx_packets += 1;
+                self.stats.rx_bytes += (frame_len - 4) as u64;
+                self.inbound
+                    .push_back(Vec::from(slice::from_raw_parts(frame_addr as *const u8,
+                                                               frame_len - 4)));
+            } else {
+                self.stats.rx_errors += 1;
+            }
 
             capr = capr + frame_len + 4;
             capr = (capr + 3) & (0xFFFFFFFF - 3);
@@ -247,8 +306,12 @@ impl Rtl8139 {
                     txd.address_port.write(txd.buffer as u32);
                     txd.status_port.write(bytes.len() as u32 & 0xFFF);
 
+                    self.stats.tx_packets += 1;
+                    self.stats.tx_bytes += bytes.len() as u64;
+
                     self.txd_i = (self.txd_i + 1) % 4;
                 } else {
+                    self.stats.tx_errors += 1;
                     debug::dl();
                     debug::d("RTL8139: Frame too long for transmit: ");
                     debug::dd(bytes.len());
@@ -267,8 +330,17 @@ impl KScheme for Rtl8139 {
         "network"
     }
 
-    fn open(&mut self, _: &Url, _: usize) -> Result<Box<Resource>> {
-        Ok(NetworkResource::new(self))
+    fn open(&mut self, url: &Url, flags: usize) -> Result<Box<Resource>> {
+        if url.reference() == "stats" {
+            let _intex = Intex::static_lock();
+            unsafe { self.update_stats() };
+            let string = netstat("RTL8139", self.link_up, Some(self.speed), &self.stats);
+            return Ok(box VecResource::new(url.clone(), string.into_bytes()));
+        }
+
+        let mut resource = NetworkResource::new(self, flags);
+        resource.capture = url.reference() == "capture";
+        Ok(resource)
     }
 
     fn on_irq(&mut self, irq: u8) {
@@ -276,6 +348,20 @@ impl KScheme for Rtl8139 {
             let isr = self.port.isr.read();
             self.port.isr.write(isr);
 
+            unsafe {
+                if isr & RTL8139_ISR_PUN_LINKCHG == RTL8139_ISR_PUN_LINKCHG {
+                    self.update_link();
+                }
+
+                if isr & (RTL8139_ISR_RXOVW | RTL8139_ISR_FOVW) != 0 {
+                    self.update_stats();
+                }
+            }
+
+            if isr & RTL8139_ISR_TER == RTL8139_ISR_TER {
+                self.stats.tx_errors += 1;
+            }
+
             // dh(isr as usize);
             // dl();
 
@@ -322,6 +408,7 @@ impl NetworkScheme for Rtl8139 {
 
                 for resource in resources.iter() {
                     while let Some(bytes) = (**resource).outbound.lock().pop_front() {
+                        capture(&resources, &bytes);
                         self.outbound.push_back(bytes);
                     }
                 }
@@ -331,15 +418,22 @@ impl NetworkScheme for Rtl8139 {
 
             self.receive_inbound();
 
+            // The filter sees every frame once, capturing resources get the discarded ones too
+            let mut rejected = Vec::new();
             {
                 let resources = self.resources.lock();
 
                 while let Some(bytes) = self.inbound.pop_front() {
+                    let accepted = filter_inbound(&bytes, true, &mut rejected);
                     for resource in resources.iter() {
-                        (**resource).inbound.lock().push_back(bytes.clone());
+                        if accepted || (**resource).capture {
+                            (**resource).inbound.lock().push_back(bytes.clone());
+                        }
                     }
                 }
             }
+
+            filter_reject(rejected);
         }
     }
 }
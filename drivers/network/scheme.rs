@@ -1,5 +1,6 @@
 use alloc::boxed::Box;
 
+use collections::string::String;
 use collections::vec::Vec;
 use collections::vec_deque::VecDeque;
 
@@ -10,10 +11,51 @@ use common::debug;
 
 use schemes::{Result, Resource, ResourceSeek, Url};
 
-use syscall::{Error, EBADF};
+use syscall::{Error, EBADF, O_NONBLOCK};
 
 use sync::Intex;
 
+/// The counters of a network interface
+#[derive(Copy, Clone, Default)]
+pub struct NetworkStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Frames lost because no buffer was free
+    pub rx_dropped: u64,
+    /// Frames received with errors, such as a bad CRC
+    pub rx_errors: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Frames discarded because the transmit queue was full
+    pub tx_dropped: u64,
+    /// Frames that could not be sent, such as after excessive collisions
+    pub tx_errors: u64,
+}
+
+impl NetworkStats {
+    pub fn to_string(&self) -> String {
+        format!("RX packets:{} bytes:{} dropped:{} errors:{}\n\
+                 TX packets:{} bytes:{} dropped:{} errors:{}\n",
+                self.rx_packets,
+                self.rx_bytes,
+                self.rx_dropped,
+                self.rx_errors,
+                self.tx_packets,
+                self.tx_bytes,
+                self.tx_dropped,
+                self.tx_errors)
+    }
+}
+
+/// Describe an interface, with its link state and counters, for the `stats` resource of its scheme
+pub fn netstat(name: &str, link_up: bool, speed: Option<usize>, stats: &NetworkStats) -> String {
+    let mut string = format!("{}\nlink:{}", name, if link_up { "up" } else { "down" });
+    if let Some(speed) = speed {
+        string = string + &format!(" speed:{}Mb/s", speed);
+    }
+    string + "\n" + &stats.to_string()
+}
+
 pub trait NetworkScheme {
     fn add(&mut self, resource: *mut NetworkResource);
     fn remove(&mut self, resource: *mut NetworkResource);
@@ -25,15 +67,30 @@ pub struct NetworkResource {
     pub ptr: *mut NetworkResource,
     pub inbound: Intex<VecDeque<Vec<u8>>>,
     pub outbound: Intex<VecDeque<Vec<u8>>>,
+    /// The flags used to open the resource, `O_NONBLOCK` makes reads return 0 when empty
+    pub flags: usize,
+    /// Also receive the frames sent by other resources, used for packet capture
+    pub capture: bool,
+}
+
+/// Pass a frame that is being sent to the capturing resources
+pub unsafe fn capture(resources: &[*mut NetworkResource], bytes: &Vec<u8>) {
+    for resource in resources.iter() {
+        if (**resource).capture {
+            (**resource).inbound.lock().push_back(bytes.clone());
+        }
+    }
 }
 
 impl NetworkResource {
-    pub fn new(nic: *mut NetworkScheme) -> Box<Self> {
+    pub fn new(nic: *mut NetworkScheme, flags: usize) -> Box<Self> {
         let mut ret = box NetworkResource {
             nic: nic,
             ptr: 0 as *mut NetworkResource,
             inbound: Intex::new(VecDeque::new()),
             outbound: Intex::new(VecDeque::new()),
+            flags: flags,
+            capture: false,
         };
 
         unsafe {
@@ -53,6 +110,8 @@ impl Resource for NetworkResource {
             ptr: 0 as *mut NetworkResource,
             inbound: Intex::new(self.inbound.lock().clone()),
             outbound: Intex::new(self.outbound.lock().clone()),
+            flags: self.flags,
+            capture: self.capture,
         };
 
         unsafe {
@@ -65,7 +124,11 @@ impl Resource for NetworkResource {
     }
 
     fn url(&self) -> Url {
-        Url::from_str("network:")
+        if self.capture {
+            Url::from_str("network:capture")
+        } else {
+            Url::from_str("network:")
+        }
     }
 
     fn read(&mut self, _: &mut [u8]) -> Result<usize> {
@@ -87,6 +150,10 @@ impl Resource for NetworkResource {
                     }
                 }
 
+                if self.flags & O_NONBLOCK == O_NONBLOCK {
+                    return Ok(0);
+                }
+
                 context_switch(false);
             }
         }
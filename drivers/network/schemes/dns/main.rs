@@ -0,0 +1,570 @@
+use std::boxed::Box;
+use std::fs::File;
+use std::io::{Result, Read, Write, SeekFrom};
+use std::mem;
+use std::net::*;
+use std::rand;
+use std::slice;
+use std::string::{String, ToString};
+use std::thread;
+use std::time::Duration;
+use std::vec::Vec;
+use std::url::Url;
+
+use system::error::{Error, ENOENT, ESPIPE};
+use system::syscall::{sys_open, O_NONBLOCK, O_RDWR};
+
+/// The hosts file, checked before any query is sent
+pub const HOSTS_PATH: &'static str = "file:/etc/hosts";
+/// The resolver configuration, one `nameserver` line per server
+pub const RESOLV_PATH: &'static str = "file:/etc/resolv.conf";
+
+pub const DNS_TYPE_A: u16 = 1;
+pub const DNS_TYPE_CNAME: u16 = 5;
+pub const DNS_TYPE_AAAA: u16 = 28;
+pub const DNS_CLASS_IN: u16 = 1;
+
+/// Recursion desired
+pub const DNS_RD: u16 = 1 << 8;
+/// Response
+pub const DNS_QR: u16 = 1 << 15;
+/// Response code mask
+pub const DNS_RCODE: u16 = 0xF;
+
+/// The maximum number of CNAME records followed for one lookup
+pub const DNS_MAX_CNAME: usize = 8;
+/// Seconds to wait for a response before the query is sent again
+pub const DNS_TIMEOUT: i64 = 2;
+/// The number of times a query is sent to one server before the next one is asked
+pub const DNS_TRIES: usize = 3;
+/// The longest name, in the text form (RFC 1035)
+pub const DNS_MAX_NAME: usize = 253;
+
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct DnsHeader {
+    pub id: n16,
+    pub flags: n16,
+    pub qdcount: n16,
+    pub ancount: n16,
+    pub nscount: n16,
+    pub arcount: n16,
+}
+
+/// A resource record from the answer section
+pub struct DnsAnswer {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+    /// The decompressed name for CNAME records
+    pub cname: String,
+}
+
+pub struct Dns {
+    pub header: DnsHeader,
+    pub answers: Vec<DnsAnswer>,
+}
+
+/// Read a possibly compressed name starting at `i`, returning the name and the offset after it
+fn parse_name(bytes: &[u8], mut i: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = 0;
+    let mut jumps = 0;
+
+    loop {
+        let len = match bytes.get(i) {
+            Some(len) => *len as usize,
+            None => return None,
+        };
+
+        if len == 0 {
+            if end == 0 {
+                end = i + 1;
+            }
+            break;
+        } else if len & 0xC0 == 0xC0 {
+            let low = match bytes.get(i + 1) {
+                Some(low) => *low as usize,
+                None => return None,
+            };
+
+            if end == 0 {
+                end = i + 2;
+            }
+
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+
+            i = ((len & 0x3F) << 8) | low;
+        } else if len & 0xC0 != 0 {
+            // Other label types are reserved
+            return None;
+        } else {
+            if i + 1 + len > bytes.len() || name.len() + 1 + len > DNS_MAX_NAME {
+                return None;
+            }
+
+            if !name.is_empty() {
+                name.push('.');
+            }
+            for b in bytes[i + 1..i + 1 + len].iter() {
+                name.push(*b as char);
+            }
+
+            i += 1 + len;
+        }
+    }
+
+    Some((name, end))
+}
+
+impl FromBytes for Dns {
+    fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        if bytes.len() >= mem::size_of::<DnsHeader>() {
+            let header = unsafe { *(bytes.as_ptr() as *const DnsHeader) };
+
+            let mut i = mem::size_of::<DnsHeader>();
+
+            // Skip the questions
+            for _ in 0..header.qdcount.get() {
+                match parse_name(&bytes, i) {
+                    Some((_, end)) => i = end + 4,
+                    None => return None,
+                }
+            }
+
+            let mut answers = Vec::new();
+            for _ in 0..header.ancount.get() {
+                let (name, end) = match parse_name(&bytes, i) {
+                    Some(result) => result,
+                    None => return None,
+                };
+                i = end;
+
+                if i + 10 > bytes.len() {
+                    return None;
+                }
+
+                let rtype = ((bytes[i] as u16) << 8) | bytes[i + 1] as u16;
+                let class = ((bytes[i + 2] as u16) << 8) | bytes[i + 3] as u16;
+                let ttl = ((bytes[i + 4] as u32) << 24) | ((bytes[i + 5] as u32) << 16) |
+                          ((bytes[i + 6] as u32) << 8) | bytes[i + 7] as u32;
+                let len = ((bytes[i + 8] as usize) << 8) | bytes[i + 9] as usize;
+                i += 10;
+
+                if i + len > bytes.len() {
+                    return None;
+                }
+
+                let cname = if rtype == DNS_TYPE_CNAME {
+                    match parse_name(&bytes, i) {
+                        Some((cname, _)) => cname,
+                        None => return None,
+                    }
+                } else {
+                    String::new()
+                };
+
+                answers.push(DnsAnswer {
+                    name: name,
+                    rtype: rtype,
+                    class: class,
+                    ttl: ttl,
+                    data: bytes[i..i + len].to_vec(),
+                    cname: cname,
+                });
+
+                i += len;
+            }
+
+            return Some(Dns {
+                header: header,
+                answers: answers,
+            });
+        }
+        None
+    }
+}
+
+/// Build a query for a single name
+pub fn dns_query(id: u16, name: &str, rtype: u16) -> Vec<u8> {
+    let header = DnsHeader {
+        id: n16::new(id),
+        flags: n16::new(DNS_RD),
+        qdcount: n16::new(1),
+        ancount: n16::new(0),
+        nscount: n16::new(0),
+        arcount: n16::new(0),
+    };
+
+    let mut ret = unsafe {
+        let header_ptr: *const DnsHeader = &header;
+        Vec::from(slice::from_raw_parts(header_ptr as *const u8, mem::size_of::<DnsHeader>()))
+    };
+
+    for label in name.split('.') {
+        if !label.is_empty() {
+            ret.push(label.len() as u8);
+            ret.push_all(label.as_bytes());
+        }
+    }
+    ret.push(0);
+
+    ret.push((rtype >> 8) as u8);
+    ret.push(rtype as u8);
+    ret.push((DNS_CLASS_IN >> 8) as u8);
+    ret.push(DNS_CLASS_IN as u8);
+
+    ret
+}
+
+/// Format the record data of an A or AAAA answer
+fn format_addr(rtype: u16, data: &[u8]) -> Option<String> {
+    if rtype == DNS_TYPE_A && data.len() == 4 {
+        Some(format!("{}.{}.{}.{}", data[0], data[1], data[2], data[3]))
+    } else if rtype == DNS_TYPE_AAAA && data.len() == 16 {
+        let mut string = String::new();
+        for i in 0..8 {
+            if i > 0 {
+                string.push(':');
+            }
+            string = string + &format!("{:x}", ((data[i * 2] as u16) << 8) | data[i * 2 + 1] as u16);
+        }
+        Some(string)
+    } else {
+        None
+    }
+}
+
+/// Open a resource that returns zero from read when nothing is waiting
+fn open_nonblock(path: &str) -> Result<File> {
+    let path_c = path.to_string() + "\0";
+    unsafe { File::from_fd(sys_open(path_c.as_ptr(), O_RDWR | O_NONBLOCK, 0)) }
+}
+
+/// A DNS resource, reading it returns one address per line
+pub struct Resource {
+    name: String,
+    rtype: u16,
+    data: Vec<u8>,
+    seek: usize,
+}
+
+impl Resource {
+    pub fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box Resource {
+            name: self.name.clone(),
+            rtype: self.rtype,
+            data: self.data.clone(),
+            seek: self.seek,
+        })
+    }
+
+    pub fn path(&self) -> Result<String> {
+        if self.rtype == DNS_TYPE_AAAA {
+            Ok(format!("dns://{}/aaaa", self.name))
+        } else {
+            Ok(format!("dns://{}", self.name))
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && self.seek < self.data.len() {
+            buf[i] = self.data[self.seek];
+            self.seek += 1;
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    pub fn write(&mut self, _: &[u8]) -> Result<usize> {
+        Err(Error::new(ESPIPE))
+    }
+
+    pub fn seek(&mut self, _: SeekFrom) -> Result<u64> {
+        Err(Error::new(ESPIPE))
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A cached lookup
+pub struct DnsCacheEntry {
+    name: String,
+    rtype: u16,
+    addrs: Vec<String>,
+    expires: Duration,
+}
+
+/// A DNS scheme
+pub struct Scheme {
+    cache: Vec<DnsCacheEntry>,
+}
+
+impl Scheme {
+    pub fn new() -> Box<Scheme> {
+        box Scheme { cache: Vec::new() }
+    }
+
+    /// Look up a name in the hosts file
+    fn hosts(&self, name: &str, rtype: u16) -> Vec<String> {
+        let mut addrs = Vec::new();
+
+        if let Ok(mut file) = File::open(HOSTS_PATH) {
+            let mut string = String::new();
+            if file.read_to_string(&mut string).is_ok() {
+                for line in string.lines() {
+                    let line = line.split('#').next().unwrap_or("");
+                    let mut parts = line.split(|c| c == ' ' || c == '\t').filter(|part| !part.is_empty());
+                    if let Some(addr) = parts.next() {
+                        let ipv4 = IPv4Addr::parse(addr).is_some();
+                        if (rtype == DNS_TYPE_A && ipv4) || (rtype == DNS_TYPE_AAAA && !ipv4) {
+                            if parts.any(|alias| alias.to_lowercase() == name) {
+                                addrs.push(addr.to_string());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        addrs
+    }
+
+    /// Read the configured name servers
+    fn nameservers(&self) -> Vec<String> {
+        let mut servers = Vec::new();
+
+        if let Ok(mut file) = File::open(RESOLV_PATH) {
+            let mut string = String::new();
+            if file.read_to_string(&mut string).is_ok() {
+                for line in string.lines() {
+                    let mut parts = line.split(' ').filter(|part| !part.is_empty());
+                    if parts.next() == Some("nameserver") {
+                        if let Some(server) = parts.next() {
+                            servers.push(server.to_string());
+                        }
+                    }
+                }
+            }
+        }
+
+        servers
+    }
+
+    /// Send a query to one server and wait for the matching response. The query is sent again
+    /// after `DNS_TIMEOUT` seconds without one, up to `DNS_TRIES` times
+    fn query(&self, server: &str, name: &str, rtype: u16) -> Option<Dns> {
+        if let Ok(mut udp) = open_nonblock(&format!("udp://{}:53", server)) {
+            let id = rand() as u16;
+            let query = dns_query(id, name, rtype);
+
+            for _ in 0..DNS_TRIES {
+                if udp.write(&query).is_err() {
+                    return None;
+                }
+
+                let deadline = Duration::monotonic() + Duration::new(DNS_TIMEOUT, 0);
+                while Duration::monotonic() < deadline {
+                    let mut bytes = [0; 4096];
+                    match udp.read(&mut bytes) {
+                        Ok(0) => thread::yield_now(),
+                        Ok(count) => {
+                            if let Some(response) = Dns::from_bytes(bytes[..count].to_vec()) {
+                                let flags = response.header.flags.get();
+                                if response.header.id.get() == id && flags & DNS_QR == DNS_QR {
+                                    if flags & DNS_RCODE == 0 {
+                                        return Some(response);
+                                    } else {
+                                        return None;
+                                    }
+                                }
+                            }
+                        }
+                        Err(_) => return None,
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Resolve a name, following CNAME records, and return the addresses and the minimum TTL
+    fn resolve(&self, name: &str, rtype: u16) -> Option<(Vec<String>, u32)> {
+        for server in self.nameservers().iter() {
+            let mut target = name.to_string();
+            let mut ttl = 0xFFFFFFFF;
+
+            for _ in 0..DNS_MAX_CNAME {
+                let response = match self.query(server, &target, rtype) {
+                    Some(response) => response,
+                    None => break,
+                };
+
+                // Follow the CNAME chain inside of this response, a cycle ends after
+                // DNS_MAX_CNAME records
+                for _ in 0..DNS_MAX_CNAME {
+                    let cname = response.answers.iter().find(|answer| {
+                        answer.rtype == DNS_TYPE_CNAME && answer.name == target
+                    });
+
+                    match cname {
+                        Some(answer) => {
+                            target = answer.cname.clone();
+                            if answer.ttl < ttl {
+                                ttl = answer.ttl;
+                            }
+                        }
+                        None => break,
+                    }
+                }
+
+                let mut addrs = Vec::new();
+                for answer in response.answers.iter() {
+                    if answer.rtype == rtype && answer.class == DNS_CLASS_IN &&
+                       answer.name == target {
+                        if let Some(addr) = format_addr(answer.rtype, &answer.data) {
+                            addrs.push(addr);
+                            if answer.ttl < ttl {
+                                ttl = answer.ttl;
+                            }
+                        }
+                    }
+                }
+
+                if !addrs.is_empty() {
+                    return Some((addrs, ttl));
+                }
+
+                // Only a CNAME was returned, ask again for the new target
+                if target == name {
+                    break;
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn open(&mut self, url_str: &str, _: usize) -> Result<Box<Resource>> {
+        let url = Url::from_str(&url_str);
+
+        let name = url.host().to_lowercase();
+        let rtype = if url.path() == "aaaa" {
+            DNS_TYPE_AAAA
+        } else {
+            DNS_TYPE_A
+        };
+
+        if name.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let mut addrs = if rtype == DNS_TYPE_A && IPv4Addr::parse(&name).is_some() {
+            vec![name.clone()]
+        } else {
+            self.hosts(&name, rtype)
+        };
+
+        if addrs.is_empty() {
+            let now = Duration::monotonic();
+            self.cache.retain(|entry| entry.expires > now);
+
+            for entry in self.cache.iter() {
+                if entry.name == name && entry.rtype == rtype {
+                    addrs = entry.addrs.clone();
+                    break;
+                }
+            }
+
+            if addrs.is_empty() {
+                if let Some((resolved, ttl)) = self.resolve(&name, rtype) {
+                    self.cache.push(DnsCacheEntry {
+                        name: name.clone(),
+                        rtype: rtype,
+                        addrs: resolved.clone(),
+                        expires: now + Duration::new(ttl as i64, 0),
+                    });
+                    addrs = resolved;
+                }
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let mut data = Vec::new();
+        for addr in addrs.iter() {
+            data.push_all(addr.as_bytes());
+            data.push(b'\n');
+        }
+
+        Ok(box Resource {
+            name: name,
+            rtype: rtype,
+            data: data,
+            seek: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_name;
+
+    #[test]
+    fn name() {
+        let bytes = b"\x03www\x07example\x03com\x00";
+        assert_eq!(parse_name(bytes, 0), Some(("www.example.com".to_string(), 17)));
+    }
+
+    #[test]
+    fn name_compressed() {
+        // "mail" followed by a pointer to "example.com" at offset 4
+        let bytes = b"\x00\x00\x00\x00\x07example\x03com\x00\x04mail\xC0\x04";
+        assert_eq!(parse_name(bytes, 17), Some(("mail.example.com".to_string(), 24)));
+    }
+
+    #[test]
+    fn name_compression_loop() {
+        // A pointer to itself
+        assert_eq!(parse_name(b"\xC0\x00", 0), None);
+        // Two pointers to each other
+        assert_eq!(parse_name(b"\xC0\x02\xC0\x00", 0), None);
+        // A label, then a pointer back to it
+        assert_eq!(parse_name(b"\x01a\xC0\x00", 0), None);
+    }
+
+    #[test]
+    fn name_truncated() {
+        assert_eq!(parse_name(b"", 0), None);
+        assert_eq!(parse_name(b"\x03ww", 0), None);
+        assert_eq!(parse_name(b"\x03www", 0), None);
+        assert_eq!(parse_name(b"\xC0", 0), None);
+        assert_eq!(parse_name(b"\xC0\x10", 0), None);
+    }
+
+    #[test]
+    fn name_malformed() {
+        // Reserved label types
+        assert_eq!(parse_name(b"\x40\x00", 0), None);
+        assert_eq!(parse_name(b"\x80\x00", 0), None);
+        // Longer than a name can be
+        let mut bytes = Vec::new();
+        for _ in 0..5 {
+            bytes.push(63);
+            bytes.extend((0..63).map(|_| b'a'));
+        }
+        bytes.push(0);
+        assert_eq!(parse_name(&bytes, 0), None);
+    }
+}
@@ -11,9 +11,11 @@ use common::to_num::ToNum;
 use network::common::*;
 use network::ethernet::*;
 
+use scheduler::context::context_switch;
+
 use schemes::{Result, KScheme, Resource, Url};
 
-use syscall::{Error, EBADF, ENOENT};
+use syscall::{Error, ENOENT};
 
 /// A ethernet resource
 pub struct EthernetResource {
@@ -46,9 +48,19 @@ impl Resource for EthernetResource {
                                  self.ethertype))
     }
 
-    fn read(&mut self, _: &mut [u8]) -> Result<usize> {
-        debug::d("TODO: Implement read for ethernet:\n");
-        Err(Error::new(EBADF))
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut bytes: Vec<u8> = Vec::new();
+        match self.read_to_end(&mut bytes) {
+            Ok(_) => {
+                let mut i = 0;
+                while i < buf.len() && i < bytes.len() {
+                    buf[i] = bytes[i];
+                    i += 1;
+                }
+                Ok(i)
+            }
+            Err(err) => Err(err),
+        }
     }
 
     fn read_to_end(&mut self, vec: &mut Vec<u8>) -> Result<usize> {
@@ -62,6 +74,7 @@ impl Resource for EthernetResource {
         loop {
             let mut bytes: Vec<u8> = Vec::new();
             match self.network.read_to_end(&mut bytes) {
+                Ok(0) => return Ok(0),
                 Ok(_) => {
                     if let Some(frame) = EthernetII::from_bytes(bytes) {
                         if frame.header.ethertype.get() == self.ethertype &&
@@ -108,11 +121,11 @@ impl KScheme for EthernetScheme {
         "ethernet"
     }
 
-    fn open(&mut self, url: &Url, _: usize) -> Result<Box<Resource>> {
+    fn open(&mut self, url: &Url, flags: usize) -> Result<Box<Resource>> {
         let parts: Vec<&str> = url.reference().split("/").collect();
         if let Some(host_string) = parts.get(0) {
             if let Some(ethertype_string) = parts.get(1) {
-                if let Ok(mut network) = Url::from_str("network:").open() {
+                if let Ok(mut network) = ::env().open(&Url::from_str("network:"), flags) {
                     let ethertype = ethertype_string.to_num_radix(16) as u16;
 
                     if !host_string.is_empty() {
@@ -126,6 +139,7 @@ impl KScheme for EthernetScheme {
                         loop {
                             let mut bytes: Vec<u8> = Vec::new();
                             match network.read_to_end(&mut bytes) {
+                                Ok(0) => unsafe { context_switch(false) },
                                 Ok(_) => {
                                     if let Some(frame) = EthernetII::from_bytes(bytes) {
                                         if frame.header.ethertype.get() == ethertype &&
@@ -0,0 +1,104 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use core::str;
+
+use network::filter::*;
+
+use schemes::{Result, KScheme, Resource, Url};
+
+use syscall::{Error, EINVAL};
+
+/// A resource of the packet filter, reading lists the rules with their hits, writing runs
+/// commands, one per line
+pub struct FilterResource {
+    /// The listing when opened
+    data: Vec<u8>,
+    seek: usize,
+    /// A command not yet ended by a newline
+    command: String,
+}
+
+impl FilterResource {
+    fn run(command: &str) -> Result<()> {
+        if with_filter(|filter| filter.command(command)) {
+            Ok(())
+        } else {
+            Err(Error::new(EINVAL))
+        }
+    }
+}
+
+impl Resource for FilterResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box FilterResource {
+            data: self.data.clone(),
+            seek: self.seek,
+            command: self.command.clone(),
+        })
+    }
+
+    fn url(&self) -> Url {
+        Url::from_str("filter:")
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && self.seek < self.data.len() {
+            buf[i] = self.data[self.seek];
+            self.seek += 1;
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match str::from_utf8(buf) {
+            Ok(string) => self.command.push_str(string),
+            Err(_) => return Err(Error::new(EINVAL)),
+        }
+
+        while let Some(i) = self.command.find('\n') {
+            let line: String = self.command.drain(..i + 1).collect();
+            try!(FilterResource::run(&line));
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Run a command that was not ended by a newline
+    fn sync(&mut self) -> Result<()> {
+        if self.command.is_empty() {
+            return Ok(());
+        }
+
+        let command = self.command.clone();
+        self.command.clear();
+        FilterResource::run(&command)
+    }
+}
+
+impl Drop for FilterResource {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+/// The packet filter scheme, `filter:`
+pub struct FilterScheme;
+
+impl KScheme for FilterScheme {
+    fn scheme(&self) -> &str {
+        "filter"
+    }
+
+    fn open(&mut self, _: &Url, _: usize) -> Result<Box<Resource>> {
+        Ok(box FilterResource {
+            data: with_filter(|filter| filter.to_string()).into_bytes(),
+            seek: 0,
+            command: String::new(),
+        })
+    }
+}
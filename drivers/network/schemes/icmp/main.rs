@@ -1,5 +1,10 @@
+use alloc::boxed::Box;
+
 use common::get_slice::GetSlice;
+use common::time::Duration;
+use common::to_num::ToNum;
 
+use collections::string::ToString;
 use collections::vec::Vec;
 
 use core::{mem, slice};
@@ -8,7 +13,24 @@ use scheduler::context::context_switch;
 
 use network::common::*;
 
-use schemes::{KScheme, Url};
+use schemes::{Result, KScheme, Resource, Url};
+
+use syscall::{Error, EINVAL, ENOENT, O_NONBLOCK};
+
+/// An echo reply
+pub const ICMP_ECHO_REPLY: u8 = 0x00;
+/// A destination was unreachable
+pub const ICMP_UNREACHABLE: u8 = 0x03;
+/// An echo request
+pub const ICMP_ECHO_REQUEST: u8 = 0x08;
+/// The time to live of a datagram was exceeded
+pub const ICMP_TIME_EXCEEDED: u8 = 0x0B;
+/// A datagram had a bad header
+pub const ICMP_PARAMETER_PROBLEM: u8 = 0x0C;
+/// The type of an echo that was not answered in time, never sent on the network
+pub const ICMP_ECHO_TIMEOUT: u8 = 0xFF;
+/// Seconds to wait for an echo reply
+pub const ICMP_ECHO_TIMEOUT_SECS: i64 = 1;
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -50,12 +72,384 @@ impl ToBytes for Icmp {
     }
 }
 
-pub struct IcmpScheme;
+impl Icmp {
+    /// Create a message, computing the checksum
+    pub fn new(_type: u8, code: u8, header_data: [u8; 4], data: Vec<u8>) -> Self {
+        let mut message = Icmp {
+            header: IcmpHeader {
+                _type: _type,
+                code: code,
+                checksum: Checksum { data: 0 },
+                data: header_data,
+            },
+            data: data,
+        };
+
+        unsafe {
+            let header_ptr: *const IcmpHeader = &message.header;
+            message.header.checksum.data = Checksum::compile(
+                Checksum::sum(header_ptr as usize, mem::size_of::<IcmpHeader>()) +
+                Checksum::sum(message.data.as_ptr() as usize, message.data.len())
+            );
+        }
+
+        message
+    }
+
+    /// The protocol, source, destination, and offset of the payload of the datagram that caused
+    /// an error. At least 4 bytes of the payload follow, which are the ports for TCP and UDP
+    fn original(&self) -> Option<(u8, Ipv4Addr, Ipv4Addr, usize)> {
+        if self.data.len() < 20 {
+            return None;
+        }
+
+        let header_len = ((self.data[0] & 0xF) as usize) * 4;
+        if header_len < 20 || self.data.len() < header_len + 4 {
+            return None;
+        }
+
+        let mut src = Ipv4Addr { bytes: [0; 4] };
+        let mut dst = Ipv4Addr { bytes: [0; 4] };
+        for i in 0..4 {
+            src.bytes[i] = self.data[12 + i];
+            dst.bytes[i] = self.data[16 + i];
+        }
+
+        Some((self.data[9], src, dst, header_len))
+    }
+
+    fn is_error(&self) -> bool {
+        self.header._type == ICMP_UNREACHABLE || self.header._type == ICMP_TIME_EXCEEDED ||
+        self.header._type == ICMP_PARAMETER_PROBLEM
+    }
+}
+
+/// The result of an echo, read from an `icmp:echo/` resource before the echoed data
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct IcmpEcho {
+    pub sequence: n16,
+    /// `ICMP_ECHO_REPLY`, an error type, or `ICMP_ECHO_TIMEOUT`
+    pub _type: u8,
+    pub code: u8,
+    /// Round trip time, in microseconds
+    pub rtt: n32,
+}
+
+/// An error about a datagram sent with some protocol, read from an `icmp:error/` resource
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct IcmpError {
+    pub _type: u8,
+    pub code: u8,
+    /// The host that reported the error
+    pub reporter: Ipv4Addr,
+    /// The source and destination of the datagram
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    /// The ports of the datagram
+    pub src_port: n16,
+    pub dst_port: n16,
+}
+
+fn struct_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Read one message from a wildcard IP resource, with its source
+fn receive(ip: &mut Box<Resource>) -> Result<Option<(Ipv4Addr, Icmp)>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    if try!(ip.read_to_end(&mut bytes)) == 0 {
+        return Ok(None);
+    }
+
+    // The URL of a wildcard resource has the source of the last packet
+    let url = ip.url();
+    let mut reference = url.reference();
+    if reference.starts_with("//") {
+        reference = &reference[2..];
+    }
+    let src = Ipv4Addr::from_string(&reference.split('/').next().unwrap_or("").to_string());
+
+    Ok(Icmp::from_bytes(bytes).map(|message| (src, message)))
+}
+
+fn open_any(flags: usize) -> Result<Box<Resource>> {
+    ::env().open(&Url::from_string("ip:".to_string() + &ANY_IP_ADDR.to_string() + "/1"),
+                 flags | O_NONBLOCK)
+}
+
+/// An echo client, every write sends a request with the written data. Every read returns an
+/// `IcmpEcho` followed by the echoed data, for a reply, an error, or a timeout
+pub struct IcmpEchoResource {
+    /// The resource used to send requests
+    ip: Box<Resource>,
+    /// The resource used to receive replies and errors from any host
+    any: Box<Resource>,
+    peer_addr: Ipv4Addr,
+    identifier: u16,
+    sequence: u16,
+    /// Requests not yet answered, with the time they were sent
+    sent: Vec<(u16, Duration)>,
+    flags: usize,
+}
+
+impl IcmpEchoResource {
+    /// Take the request with a sequence number, returning an echo result
+    fn answer(&mut self, sequence: u16, _type: u8, code: u8) -> Option<IcmpEcho> {
+        match self.sent.iter().position(|&(seq, _)| seq == sequence) {
+            Some(i) => {
+                let (_, time) = self.sent.remove(i);
+                let rtt = Duration::monotonic() - time;
+                Some(IcmpEcho {
+                    sequence: n16::new(sequence),
+                    _type: _type,
+                    code: code,
+                    rtt: n32::new((rtt.secs * 1000000 + rtt.nanos as i64 / 1000) as u32),
+                })
+            }
+            None => None,
+        }
+    }
+
+    /// Check a message for an answer to one of our requests
+    fn process(&mut self, src: Ipv4Addr, message: &Icmp) -> Option<(IcmpEcho, Vec<u8>)> {
+        let identifier = n16::new(self.identifier);
+        if message.header._type == ICMP_ECHO_REPLY && src.equals(self.peer_addr) &&
+           &message.header.data[0..2] == &identifier.bytes[..] {
+            let sequence = ((message.header.data[2] as u16) << 8) | message.header.data[3] as u16;
+            return self.answer(sequence, ICMP_ECHO_REPLY, 0)
+                       .map(|echo| (echo, message.data.clone()));
+        }
+
+        if message.is_error() {
+            if let Some((proto, _, dst, payload)) = message.original() {
+                // The identifier and sequence follow the type, code, and checksum of the request
+                let offset = payload + 4;
+                if proto == 1 && dst.equals(self.peer_addr) &&
+                   message.data[payload] == ICMP_ECHO_REQUEST &&
+                   message.data.len() >= offset + 4 &&
+                   &message.data[offset..offset + 2] == &identifier.bytes[..] {
+                    let sequence = ((message.data[offset + 2] as u16) << 8) |
+                                   message.data[offset + 3] as u16;
+                    return self.answer(sequence, message.header._type, message.header.code)
+                               .map(|echo| (echo, Vec::new()));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The oldest request that timed out
+    fn timeout(&mut self) -> Option<IcmpEcho> {
+        let timeout = Duration::new(ICMP_ECHO_TIMEOUT_SECS, 0);
+        let sequence = match self.sent.first() {
+            Some(&(sequence, time)) if Duration::monotonic() - time > timeout => sequence,
+            _ => return None,
+        };
+        self.answer(sequence, ICMP_ECHO_TIMEOUT, 0)
+    }
+}
+
+impl Resource for IcmpEchoResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box IcmpEchoResource {
+            ip: try!(self.ip.dup()),
+            any: try!(self.any.dup()),
+            peer_addr: self.peer_addr,
+            identifier: self.identifier,
+            sequence: self.sequence,
+            sent: self.sent.clone(),
+            flags: self.flags,
+        })
+    }
+
+    fn url(&self) -> Url {
+        Url::from_string(format!("icmp:echo/{}", self.peer_addr.to_string()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < mem::size_of::<IcmpEcho>() {
+            return Err(Error::new(EINVAL));
+        }
+
+        loop {
+            let mut result = None;
+            while let Some((src, message)) = try!(receive(&mut self.any)) {
+                result = self.process(src, &message);
+                if result.is_some() {
+                    break;
+                }
+            }
+
+            if result.is_none() {
+                result = self.timeout().map(|echo| (echo, Vec::new()));
+            }
+
+            if let Some((echo, data)) = result {
+                let mut i = 0;
+                for b in struct_bytes(&echo).iter().chain(data.iter()) {
+                    if i >= buf.len() {
+                        break;
+                    }
+                    buf[i] = *b;
+                    i += 1;
+                }
+                return Ok(i);
+            }
+
+            // Nothing will arrive without a request
+            if self.sent.is_empty() || self.flags & O_NONBLOCK == O_NONBLOCK {
+                return Ok(0);
+            }
+
+            unsafe { context_switch(false) };
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let identifier = n16::new(self.identifier);
+        let sequence = n16::new(self.sequence);
+        let request = Icmp::new(ICMP_ECHO_REQUEST,
+                                0,
+                                [identifier.bytes[0],
+                                 identifier.bytes[1],
+                                 sequence.bytes[0],
+                                 sequence.bytes[1]],
+                                buf.to_vec());
+
+        self.sent.push((self.sequence, Duration::monotonic()));
+        try!(self.ip.write(&request.to_bytes()));
+        Ok(buf.len())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.ip.sync()
+    }
+}
+
+/// A resource reading the ICMP errors about datagrams of one protocol, as `IcmpError`
+pub struct IcmpErrorResource {
+    any: Box<Resource>,
+    proto: u8,
+    flags: usize,
+}
+
+impl Resource for IcmpErrorResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box IcmpErrorResource {
+            any: try!(self.any.dup()),
+            proto: self.proto,
+            flags: self.flags,
+        })
+    }
+
+    fn url(&self) -> Url {
+        Url::from_string(format!("icmp:error/{:X}", self.proto))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < mem::size_of::<IcmpError>() {
+            return Err(Error::new(EINVAL));
+        }
+
+        loop {
+            while let Some((src, message)) = try!(receive(&mut self.any)) {
+                if !message.is_error() {
+                    continue;
+                }
+
+                if let Some((proto, orig_src, orig_dst, i)) = message.original() {
+                    if proto == self.proto {
+                        let payload = &message.data[i..i + 4];
+                        let error = IcmpError {
+                            _type: message.header._type,
+                            code: message.header.code,
+                            reporter: src,
+                            src: orig_src,
+                            dst: orig_dst,
+                            src_port: n16 { bytes: [payload[0], payload[1]] },
+                            dst_port: n16 { bytes: [payload[2], payload[3]] },
+                        };
+
+                        let bytes = struct_bytes(&error);
+                        for i in 0..bytes.len() {
+                            buf[i] = bytes[i];
+                        }
+                        return Ok(bytes.len());
+                    }
+                }
+            }
+
+            if self.flags & O_NONBLOCK == O_NONBLOCK {
+                return Ok(0);
+            }
+
+            unsafe { context_switch(false) };
+        }
+    }
+
+    fn write(&mut self, _: &[u8]) -> Result<usize> {
+        Err(Error::new(EINVAL))
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.any.sync()
+    }
+}
+
+/// The ICMP scheme, `icmp:echo/host` for echo clients and `icmp:error/proto` for the errors of
+/// a protocol, in hex
+pub struct IcmpScheme {
+    next_identifier: u16,
+}
+
+impl IcmpScheme {
+    pub fn new() -> Box<Self> {
+        box IcmpScheme { next_identifier: 1 }
+    }
+}
 
 impl KScheme for IcmpScheme {
     fn scheme(&self) -> &str {
         "icmp"
     }
+
+    fn open(&mut self, url: &Url, flags: usize) -> Result<Box<Resource>> {
+        let mut parts = url.reference().splitn(2, '/');
+        match (parts.next(), parts.next()) {
+            (Some("echo"), Some(host)) if !host.is_empty() => {
+                let peer_addr = Ipv4Addr::from_string(&host.to_string());
+                let ip = try!(::env().open(&Url::from_string("ip:".to_string() + host + "/1"),
+                                           flags));
+                let any = try!(open_any(flags));
+
+                let identifier = self.next_identifier;
+                self.next_identifier = self.next_identifier.wrapping_add(1);
+
+                Ok(box IcmpEchoResource {
+                    ip: ip,
+                    any: any,
+                    peer_addr: peer_addr,
+                    identifier: identifier,
+                    sequence: 0,
+                    sent: Vec::new(),
+                    flags: flags,
+                })
+            }
+            (Some("error"), Some(proto)) if !proto.is_empty() => {
+                Ok(box IcmpErrorResource {
+                    any: try!(open_any(flags)),
+                    proto: proto.to_num_radix(16) as u8,
+                    flags: flags,
+                })
+            }
+            _ => Err(Error::new(ENOENT)),
+        }
+    }
 }
 
 impl IcmpScheme {
@@ -1,51 +1,313 @@
 use alloc::boxed::Box;
 
+use collections::BTreeMap;
 use collections::string::ToString;
 use collections::vec::Vec;
 
-use core::mem;
+use core::{cmp, mem};
 
 use network::common::*;
+use network::filter::*;
 use network::ipv4::*;
+use network::schemes::arp::{Arp, ArpHeader};
 
 use common::{debug, random};
+use common::time::Duration;
 use common::to_num::ToNum;
 
-use schemes::arp::{Arp, ArpHeader};
+use scheduler::context::context_switch;
+
 use schemes::{Result, KScheme, Resource, Url};
 
-use syscall::{Error, EBADF, ENOENT};
+use syscall::{Error, EBADF, ENOENT, EPERM, O_NONBLOCK};
+
+/// The MTU of the ethernet link
+pub const IP_MTU: usize = 1500;
+/// The MTU of the loopback interface
+pub const IP_LOOPBACK_MTU: usize = 65535;
+/// Seconds to wait for the missing fragments of a datagram
+pub const IP_FRAGMENT_TIMEOUT: i64 = 30;
+/// Datagrams that can be reassembled at the same time, per resource
+pub const IP_FRAGMENT_DATAGRAMS: usize = 16;
+/// The maximum size of a reassembled datagram
+pub const IP_FRAGMENT_SIZE: usize = 65535 - 20;
+
+/// The fragments of a datagram, waiting for the rest
+#[derive(Clone)]
+pub struct IpFragments {
+    src: Ipv4Addr,
+    id: u16,
+    /// Received fragments, by offset in bytes
+    parts: BTreeMap<usize, Vec<u8>>,
+    /// The size of the datagram, known once the last fragment arrived
+    len: Option<usize>,
+    expires: Duration,
+}
+
+impl IpFragments {
+    /// Join the fragments if the datagram is complete
+    fn join(&self) -> Option<Vec<u8>> {
+        if let Some(len) = self.len {
+            let mut covered = 0;
+            for (offset, data) in self.parts.iter() {
+                if *offset > covered {
+                    return None;
+                }
+                covered = cmp::max(covered, *offset + data.len());
+            }
+
+            if covered >= len {
+                let mut datagram = vec![0; len];
+                for (offset, data) in self.parts.iter() {
+                    for (i, b) in data.iter().enumerate() {
+                        if *offset + i < len {
+                            datagram[*offset + i] = *b;
+                        }
+                    }
+                }
+                return Some(datagram);
+            }
+        }
+
+        None
+    }
+}
 
 /// A IP (internet protocole) resource
 pub struct IpResource {
     link: Box<Resource>,
+    /// The loopback interface, also read when accepting packets from any host
+    loopback: Option<Box<Resource>>,
     data: Vec<u8>,
     peer_addr: Ipv4Addr,
+    /// The local address, on the loopback interface this is the peer address
+    host_addr: Ipv4Addr,
     proto: u8,
     id: u16,
+    /// Accept packets from any host, `peer_addr` is the source of the last packet
+    any: bool,
+    /// Datagrams being reassembled
+    fragments: Vec<IpFragments>,
+    flags: usize,
+}
+
+impl IpResource {
+    pub fn new(link: Box<Resource>, peer_addr: Ipv4Addr, proto: u8, flags: usize) -> Box<Self> {
+        box IpResource {
+            link: link,
+            loopback: None,
+            data: Vec::new(),
+            peer_addr: peer_addr,
+            host_addr: if peer_addr.is_loopback() {
+                peer_addr
+            } else {
+                IP_ADDR
+            },
+            proto: proto,
+            id: (random::rand() % 65536) as u16,
+            any: peer_addr.equals(ANY_IP_ADDR),
+            fragments: Vec::new(),
+            flags: flags,
+        }
+    }
+
+    /// Check a packet read from a link, returns the data once a datagram is complete
+    fn receive(&mut self, bytes: Vec<u8>, loopback: bool) -> Option<Vec<u8>> {
+        if let Some(packet) = Ipv4::from_bytes(bytes) {
+            let to_host = if loopback && self.any {
+                packet.header.dst.is_loopback()
+            } else {
+                packet.header.dst.equals(self.host_addr)
+            };
+
+            if packet.header.proto == self.proto && to_host &&
+               (self.any || packet.header.src.equals(self.peer_addr)) {
+                let src = packet.header.src;
+                if let Some(data) = self.reassemble(packet) {
+                    if self.any {
+                        self.peer_addr = src;
+                    }
+                    return Some(data);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Reassemble fragmented datagrams, returns the data once a datagram is complete
+    fn reassemble(&mut self, packet: Ipv4) -> Option<Vec<u8>> {
+        let flags_fragment = packet.header.flags_fragment.get();
+        let offset = ((flags_fragment & IPV4_FRAGMENT_OFFSET) as usize) << 3;
+        let more = flags_fragment & IPV4_MF == IPV4_MF;
+
+        if offset == 0 && !more {
+            return Some(packet.data);
+        }
+
+        let now = Duration::monotonic();
+        self.fragments.retain(|fragments| fragments.expires > now);
+
+        let src = packet.header.src;
+        let id = packet.header.id.get();
+
+        let mut index = None;
+        for (i, fragments) in self.fragments.iter().enumerate() {
+            if fragments.src.equals(src) && fragments.id == id {
+                index = Some(i);
+                break;
+            }
+        }
+
+        let i = match index {
+            Some(i) => i,
+            None => {
+                // Make room by dropping the oldest datagram
+                if self.fragments.len() >= IP_FRAGMENT_DATAGRAMS {
+                    self.fragments.remove(0);
+                }
+                self.fragments.push(IpFragments {
+                    src: src,
+                    id: id,
+                    parts: BTreeMap::new(),
+                    len: None,
+                    expires: now + Duration::new(IP_FRAGMENT_TIMEOUT, 0),
+                });
+                self.fragments.len() - 1
+            }
+        };
+
+        let end = offset + packet.data.len();
+        if end > IP_FRAGMENT_SIZE || (more && packet.data.len() % 8 != 0) {
+            self.fragments.remove(i);
+            return None;
+        }
+
+        let datagram = {
+            let fragments = &mut self.fragments[i];
+            if !more {
+                fragments.len = Some(end);
+            }
+            fragments.parts.insert(offset, packet.data);
+            fragments.join()
+        };
+
+        if datagram.is_some() {
+            self.fragments.remove(i);
+        }
+
+        datagram
+    }
+
+    /// Send a datagram, split into fragments that fit the MTU
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        let mtu = if self.host_addr.is_loopback() {
+            IP_LOOPBACK_MTU
+        } else {
+            IP_MTU
+        };
+
+        match filter_packet(FilterDirection::Out,
+                            self.proto,
+                            self.host_addr,
+                            self.peer_addr,
+                            Some(data)) {
+            FilterAction::Accept => (),
+            FilterAction::Drop => return Ok(()),
+            FilterAction::Reject => return Err(Error::new(EPERM)),
+        }
+
+        // Fragment data must be a multiple of 8 bytes, except for the last fragment
+        let max_len = (mtu - mem::size_of::<Ipv4Header>()) & !7;
+
+        self.id += 1;
+
+        let mut offset = 0;
+        loop {
+            let len = cmp::min(data.len() - offset, max_len);
+            let more = offset + len < data.len();
+
+            let mut ip = Ipv4 {
+                header: Ipv4Header {
+                    ver_hlen: 0x40 | (mem::size_of::<Ipv4Header>() / 4 & 0xF) as u8, // No Options
+                    services: 0,
+                    len: n16::new((mem::size_of::<Ipv4Header>() + len) as u16), // No Options
+                    id: n16::new(self.id),
+                    flags_fragment: n16::new(if more {
+                        IPV4_MF
+                    } else {
+                        0
+                    } | (offset >> 3) as u16),
+                    ttl: 128,
+                    proto: self.proto,
+                    checksum: Checksum { data: 0 },
+                    src: self.host_addr,
+                    dst: self.peer_addr,
+                },
+                options: Vec::new(),
+                data: data[offset..offset + len].to_vec(),
+            };
+
+            unsafe {
+                let header_ptr: *const Ipv4Header = &ip.header;
+                ip.header.checksum.data =
+                    Checksum::compile(Checksum::sum(header_ptr as usize,
+                                                    mem::size_of::<Ipv4Header>()) +
+                                      Checksum::sum(ip.options.as_ptr() as usize,
+                                                    ip.options.len()));
+            }
+
+            try!(self.link.write(&ip.to_bytes()));
+
+            offset += len;
+            if !more {
+                return Ok(());
+            }
+        }
+    }
 }
 
 impl Resource for IpResource {
     fn dup(&self) -> Result<Box<Resource>> {
+        let loopback = match self.loopback {
+            Some(ref loopback) => Some(try!(loopback.dup())),
+            None => None,
+        };
+
         match self.link.dup() {
             Ok(link) => Ok(box IpResource {
                 link: link,
+                loopback: loopback,
                 data: self.data.clone(),
                 peer_addr: self.peer_addr,
+                host_addr: self.host_addr,
                 proto: self.proto,
                 id: self.id,
+                any: self.any,
+                fragments: self.fragments.clone(),
+                flags: self.flags,
             }),
             Err(err) => Err(err),
         }
     }
 
     fn url(&self) -> Url {
-        Url::from_string(format!("ip:{}/{:X}", self.peer_addr.to_string(), self.proto))
+        Url::from_string(format!("ip://{}/{:X}", self.peer_addr.to_string(), self.proto))
     }
 
-    fn read(&mut self, _: &mut [u8]) -> Result<usize> {
-        debug::d("TODO: Implement read for ip:\n");
-        Err(Error::new(EBADF))
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut bytes: Vec<u8> = Vec::new();
+        match self.read_to_end(&mut bytes) {
+            Ok(_) => {
+                let mut i = 0;
+                while i < buf.len() && i < bytes.len() {
+                    buf[i] = bytes[i];
+                    i += 1;
+                }
+                Ok(i)
+            }
+            Err(err) => Err(err),
+        }
     }
 
     fn read_to_end(&mut self, vec: &mut Vec<u8>) -> Result<usize> {
@@ -57,51 +319,46 @@ impl Resource for IpResource {
         }
 
         loop {
+            let mut received = false;
+
             let mut bytes: Vec<u8> = Vec::new();
-            match self.link.read_to_end(&mut bytes) {
-                Ok(_) => {
-                    if let Some(packet) = Ipv4::from_bytes(bytes) {
-                        if packet.header.proto == self.proto && packet.header.dst.equals(IP_ADDR) &&
-                           packet.header.src.equals(self.peer_addr) {
-                            vec.push_all(&packet.data);
-                            return Ok(packet.data.len());
-                        }
-                    }
+            if try!(self.link.read_to_end(&mut bytes)) > 0 {
+                received = true;
+                if let Some(data) = self.receive(bytes, false) {
+                    vec.push_all(&data);
+                    return Ok(data.len());
                 }
-                Err(err) => return Err(err),
+            }
+
+            let mut bytes: Vec<u8> = Vec::new();
+            let count = match self.loopback {
+                Some(ref mut loopback) => try!(loopback.read_to_end(&mut bytes)),
+                None => 0,
+            };
+            if count > 0 {
+                received = true;
+                if let Some(data) = self.receive(bytes, true) {
+                    vec.push_all(&data);
+                    return Ok(data.len());
+                }
+            }
+
+            if !received {
+                if self.flags & O_NONBLOCK == O_NONBLOCK {
+                    return Ok(0);
+                }
+
+                unsafe { context_switch(false) };
             }
         }
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let ip_data = Vec::from(buf);
-
-        self.id += 1;
-        let mut ip = Ipv4 {
-            header: Ipv4Header {
-                ver_hlen: 0x40 | (mem::size_of::<Ipv4Header>() / 4 & 0xF) as u8, // No Options
-                services: 0,
-                len: n16::new((mem::size_of::<Ipv4Header>() + ip_data.len()) as u16), // No Options
-                id: n16::new(self.id),
-                flags_fragment: n16::new(0),
-                ttl: 128,
-                proto: self.proto,
-                checksum: Checksum { data: 0 },
-                src: IP_ADDR,
-                dst: self.peer_addr,
-            },
-            options: Vec::new(),
-            data: ip_data,
-        };
-
-        unsafe {
-            let header_ptr: *const Ipv4Header = &ip.header;
-            ip.header.checksum.data =
-                Checksum::compile(Checksum::sum(header_ptr as usize, mem::size_of::<Ipv4Header>()) +
-                                  Checksum::sum(ip.options.as_ptr() as usize, ip.options.len()));
+        if self.any {
+            return Err(Error::new(EBADF));
         }
 
-        match self.link.write(&ip.to_bytes()) {
+        match self.send(buf) {
             Ok(_) => Ok(buf.len()),
             Err(err) => Err(err),
         }
@@ -128,8 +385,14 @@ impl KScheme for IpScheme {
         "ip"
     }
 
-    fn open(&mut self, url: &Url, _: usize) -> Result<Box<Resource>> {
-        let parts: Vec<&str> = url.reference().split('/').collect();
+    fn open(&mut self, url: &Url, flags: usize) -> Result<Box<Resource>> {
+        // Both `ip:host/proto` and `ip://host/proto` are accepted
+        let mut reference = url.reference();
+        if reference.starts_with("//") {
+            reference = &reference[2..];
+        }
+
+        let parts: Vec<&str> = reference.split('/').collect();
         if let Some(host_string) = parts.get(0) {
             if let Some(proto_string) = parts.get(1) {
                 let proto = proto_string.to_num_radix(16) as u8;
@@ -138,6 +401,33 @@ impl KScheme for IpScheme {
                     let peer_addr = Ipv4Addr::from_string(&host_string.to_string());
                     let mut peer_mac = BROADCAST_MAC_ADDR;
 
+                    // Receive from any host, used by listening sockets. Both links do not block,
+                    // so that one does not starve the other
+                    if peer_addr.equals(ANY_IP_ADDR) {
+                        if let Ok(link) = ::env().open(&Url::from_string("ethernet:".to_string() +
+                                                                           &peer_mac.to_string() +
+                                                                           "/800"),
+                                                       flags | O_NONBLOCK) {
+                            let mut resource = IpResource::new(link, peer_addr, proto, flags);
+                            if let Ok(loopback) = ::env().open(&Url::from_str("loopback:"),
+                                                               flags | O_NONBLOCK) {
+                                resource.loopback = Some(loopback);
+                            }
+                            return Ok(resource);
+                        }
+
+                        return Err(Error::new(ENOENT));
+                    }
+
+                    // 127.0.0.0/8 does not leave the host
+                    if peer_addr.is_loopback() {
+                        if let Ok(link) = ::env().open(&Url::from_str("loopback:"), flags) {
+                            return Ok(IpResource::new(link, peer_addr, proto, flags));
+                        }
+
+                        return Err(Error::new(ENOENT));
+                    }
+
                     for entry in self.arp.iter() {
                         if entry.ip.equals(peer_addr) {
                             peer_mac = entry.mac;
@@ -188,33 +478,32 @@ impl KScheme for IpScheme {
                         }
                     }
 
-                    if let Ok(link) = Url::from_string("ethernet:".to_string() +
-                                                         &peer_mac.to_string() +
-                                                         "/800")
-                                            .open() {
-                        return Ok(box IpResource {
-                            link: link,
-                            data: Vec::new(),
-                            peer_addr: peer_addr,
-                            proto: proto,
-                            id: (random::rand() % 65536) as u16,
-                        });
+                    if let Ok(link) = ::env().open(&Url::from_string("ethernet:".to_string() +
+                                                                       &peer_mac.to_string() +
+                                                                       "/800"),
+                                                   flags) {
+                        return Ok(IpResource::new(link, peer_addr, proto, flags));
                     }
                 } else {
-                    while let Ok(mut link) = Url::from_str("ethernet:/800").open() {
+                    while let Ok(mut link) = ::env().open(&Url::from_str("ethernet:/800"), flags) {
                         let mut bytes: Vec<u8> = Vec::new();
                         match link.read_to_end(&mut bytes) {
+                            Ok(0) => unsafe { context_switch(false) },
                             Ok(_) => {
                                 if let Some(packet) = Ipv4::from_bytes(bytes) {
                                     if packet.header.proto == proto &&
                                        packet.header.dst.equals(IP_ADDR) {
-                                        return Ok(box IpResource {
-                                            link: link,
-                                            data: packet.data,
-                                            peer_addr: packet.header.src,
-                                            proto: proto,
-                                            id: (random::rand() % 65536) as u16,
-                                        });
+                                        let mut resource = IpResource::new(link,
+                                                                           packet.header.src,
+                                                                           proto,
+                                                                           flags);
+
+                                        // The first packet may be a fragment
+                                        if let Some(data) = resource.reassemble(packet) {
+                                            resource.data = data;
+                                        }
+
+                                        return Ok(resource);
                                     }
                                 }
                             }
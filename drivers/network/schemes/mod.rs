@@ -0,0 +1,10 @@
+/// ARP scheme
+pub mod arp;
+/// Ethernet scheme
+pub mod ethernet;
+/// Packet filter scheme
+pub mod filter;
+/// IP scheme
+pub mod ip;
+/// Packet capture scheme
+pub mod pcap;
@@ -0,0 +1,217 @@
+use alloc::boxed::Box;
+
+use collections::slice;
+use collections::string::ToString;
+use collections::vec::Vec;
+
+use core::mem;
+
+use common::time::Duration;
+use common::to_num::ToNum;
+
+use network::common::*;
+use network::ethernet::*;
+use network::ipv4::*;
+
+use schemes::{Result, KScheme, Resource, Url};
+
+use syscall::{Error, EBADF};
+
+/// The magic number of a libpcap file, with microsecond timestamps
+pub const PCAP_MAGIC: u32 = 0xA1B2C3D4;
+/// The link type of ethernet frames
+pub const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+/// The largest frame captured
+pub const PCAP_SNAPLEN: u32 = 65535;
+
+/// The header at the start of a libpcap file
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct PcapHeader {
+    pub magic_number: u32,
+    pub version_major: u16,
+    pub version_minor: u16,
+    pub thiszone: i32,
+    pub sigfigs: u32,
+    pub snaplen: u32,
+    pub network: u32,
+}
+
+/// The header before every captured frame
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct PcapRecordHeader {
+    pub ts_sec: u32,
+    pub ts_usec: u32,
+    pub incl_len: u32,
+    pub orig_len: u32,
+}
+
+fn struct_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// The header of a libpcap stream of ethernet frames
+pub fn pcap_header() -> Vec<u8> {
+    let header = PcapHeader {
+        magic_number: PCAP_MAGIC,
+        version_major: 2,
+        version_minor: 4,
+        thiszone: 0,
+        sigfigs: 0,
+        snaplen: PCAP_SNAPLEN,
+        network: PCAP_LINKTYPE_ETHERNET,
+    };
+    struct_bytes(&header).to_vec()
+}
+
+/// The record of a frame captured at `time`, frames longer than `PCAP_SNAPLEN` are truncated
+pub fn pcap_record(bytes: &[u8], time: Duration) -> Vec<u8> {
+    let len = bytes.len() as u32;
+    let incl_len = if len > PCAP_SNAPLEN {
+        PCAP_SNAPLEN
+    } else {
+        len
+    };
+
+    let record = PcapRecordHeader {
+        ts_sec: time.secs as u32,
+        ts_usec: (time.nanos / 1000) as u32,
+        incl_len: incl_len,
+        orig_len: len,
+    };
+
+    let mut data = struct_bytes(&record).to_vec();
+    data.push_all(&bytes[..incl_len as usize]);
+    data
+}
+
+/// Check a frame against the filters of a capture
+pub fn pcap_matches(bytes: &Vec<u8>, ethertype: Option<u16>, proto: Option<u8>) -> bool {
+    if ethertype.is_none() && proto.is_none() {
+        return true;
+    }
+
+    if let Some(frame) = EthernetII::from_bytes(bytes.clone()) {
+        let frame_ethertype = frame.header.ethertype.get();
+        if let Some(filter) = ethertype {
+            if frame_ethertype != filter {
+                return false;
+            }
+        }
+
+        if let Some(filter) = proto {
+            if frame_ethertype != 0x800 {
+                return false;
+            }
+            match Ipv4::from_bytes(frame.data) {
+                Some(packet) => return packet.header.proto == filter,
+                None => return false,
+            }
+        }
+
+        return true;
+    }
+
+    false
+}
+
+/// A packet capture resource, reading yields a libpcap stream
+pub struct PcapResource {
+    /// The network, opened to receive sent frames too
+    network: Box<Resource>,
+    /// Bytes of the stream not yet read
+    data: Vec<u8>,
+    /// Only capture frames with this ethernet type
+    ethertype: Option<u16>,
+    /// Only capture IPv4 packets with this protocol
+    proto: Option<u8>,
+}
+
+impl Resource for PcapResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        match self.network.dup() {
+            Ok(network) => Ok(box PcapResource {
+                network: network,
+                data: self.data.clone(),
+                ethertype: self.ethertype,
+                proto: self.proto,
+            }),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn url(&self) -> Url {
+        let mut string = "pcap:".to_string();
+        if let Some(ethertype) = self.ethertype {
+            string = string + &format!("{:X}", ethertype);
+        }
+        if let Some(proto) = self.proto {
+            string = string + &format!("/{:X}", proto);
+        }
+        Url::from_string(string)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.data.is_empty() {
+            let mut bytes: Vec<u8> = Vec::new();
+            match self.network.read_to_end(&mut bytes) {
+                Ok(0) => return Ok(0),
+                Ok(_) => if pcap_matches(&bytes, self.ethertype, self.proto) {
+                    let record = pcap_record(&bytes, Duration::realtime());
+                    self.data.push_all(&record);
+                },
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut i = 0;
+        while i < buf.len() && i < self.data.len() {
+            buf[i] = self.data[i];
+            i += 1;
+        }
+        self.data = self.data[i..].to_vec();
+
+        Ok(i)
+    }
+
+    fn write(&mut self, _: &[u8]) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.network.sync()
+    }
+}
+
+/// A packet capture scheme, `pcap:ethertype/proto` with both filters optional and in hex
+pub struct PcapScheme;
+
+impl KScheme for PcapScheme {
+    fn scheme(&self) -> &str {
+        "pcap"
+    }
+
+    fn open(&mut self, url: &Url, flags: usize) -> Result<Box<Resource>> {
+        let parts: Vec<&str> = url.reference().split('/').collect();
+
+        let ethertype = match parts.get(0) {
+            Some(part) if !part.is_empty() => Some(part.to_num_radix(16) as u16),
+            _ => None,
+        };
+
+        let proto = match parts.get(1) {
+            Some(part) if !part.is_empty() => Some(part.to_num_radix(16) as u8),
+            _ => None,
+        };
+
+        let network = try!(::env().open(&Url::from_str("network:capture"), flags));
+
+        Ok(box PcapResource {
+            network: network,
+            data: pcap_header(),
+            ethertype: ethertype,
+            proto: proto,
+        })
+    }
+}
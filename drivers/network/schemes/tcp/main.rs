@@ -1,18 +1,28 @@
 extern crate system;
 
 use std::boxed::Box;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs::File;
 use std::io::{Result, Read, Write, SeekFrom};
 use std::mem;
 use std::net::*;
+use std::ptr;
 use std::rand;
+use std::rc::Rc;
 use std::slice;
+use std::sync::{Arc, Mutex};
 use std::string::{String, ToString};
+use std::thread;
+use std::time::Duration;
 use std::to_num::*;
 use std::vec::Vec;
 use std::url::Url;
 
-use system::error::{Error, ENOENT, EPIPE, ESPIPE};
+use system::error::{Error, EADDRINUSE, ECONNREFUSED, ECONNRESET, EINVAL, ENOENT, ENOTCONN,
+                    EPIPE, ESPIPE, ETIMEDOUT};
+use system::syscall::{sys_open, O_NONBLOCK, O_RDWR};
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -39,6 +49,82 @@ pub const TCP_RST: u16 = 1 << 2;
 pub const TCP_PSH: u16 = 1 << 3;
 pub const TCP_ACK: u16 = 1 << 4;
 
+/// The maximum segment size we accept, fits a 1500 byte ethernet MTU
+pub const TCP_MSS: u32 = 1460;
+/// The maximum segment size assumed when the peer does not send the option
+pub const TCP_MSS_DEFAULT: u32 = 536;
+/// The size of the receive buffer, advertised as the receive window
+pub const TCP_WINDOW: usize = 65535;
+
+/// Initial retransmission timeout in milliseconds (RFC 6298)
+pub const TCP_RTO_INIT: i64 = 1000;
+/// Lower bound of the retransmission timeout in milliseconds
+pub const TCP_RTO_MIN: i64 = 1000;
+/// Upper bound of the retransmission timeout in milliseconds
+pub const TCP_RTO_MAX: i64 = 60000;
+/// Number of retransmissions of a segment before the connection is dropped
+pub const TCP_RETRIES: usize = 12;
+/// Number of SYN or SYN-ACK retransmissions before giving up
+pub const TCP_SYN_RETRIES: usize = 5;
+/// Duplicate acknowledgements that trigger a fast retransmit (RFC 5681)
+pub const TCP_DUP_ACKS: u32 = 3;
+
+/// Connections a listening socket holds before they are accepted
+pub const TCP_BACKLOG: usize = 16;
+/// Time a closed connection stays in TIME_WAIT (2 * MSL) in milliseconds
+pub const TCP_TIME_WAIT: i64 = 60000;
+/// Milliseconds a closing connection sleeps when nothing arrived
+pub const TCP_CLOSE_POLL: u32 = 10;
+/// Truncating a connection to this length shuts down reading, like `SHUT_RD`
+pub const TCP_SHUT_RD: usize = 0;
+/// Truncating a connection to this length shuts down writing, like `SHUT_WR`
+pub const TCP_SHUT_WR: usize = 1;
+/// Truncating a connection to this length shuts down both directions, like `SHUT_RDWR`
+pub const TCP_SHUT_RDWR: usize = 2;
+/// The path option that enables keepalive probes, which are off by default (RFC 1122)
+pub const TCP_KEEPALIVE: &'static str = "keepalive";
+/// Idle time before the first keepalive probe in milliseconds (RFC 1122)
+pub const TCP_KEEPALIVE_IDLE: i64 = 7200000;
+/// Time between keepalive probes in milliseconds
+pub const TCP_KEEPALIVE_INTERVAL: i64 = 75000;
+/// Unanswered keepalive probes before the connection is dropped
+pub const TCP_KEEPALIVE_PROBES: i64 = 9;
+
+/// The states of a connection (RFC 793)
+#[derive(Copy, Clone, PartialEq)]
+pub enum TcpState {
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    Closing,
+    CloseWait,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl TcpState {
+    /// The name of the state, as in RFC 793
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TcpState::Listen => "LISTEN",
+            TcpState::SynSent => "SYN_SENT",
+            TcpState::SynReceived => "SYN_RECEIVED",
+            TcpState::Established => "ESTABLISHED",
+            TcpState::FinWait1 => "FIN_WAIT_1",
+            TcpState::FinWait2 => "FIN_WAIT_2",
+            TcpState::Closing => "CLOSING",
+            TcpState::CloseWait => "CLOSE_WAIT",
+            TcpState::LastAck => "LAST_ACK",
+            TcpState::TimeWait => "TIME_WAIT",
+            TcpState::Closed => "CLOSED",
+        }
+    }
+}
+
 impl FromBytes for Tcp {
     fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
         if bytes.len() >= mem::size_of::<TcpHeader>() {
@@ -46,6 +132,10 @@ impl FromBytes for Tcp {
                 let header = *(bytes.as_ptr() as *const TcpHeader);
                 let header_len = ((header.flags.get() & 0xF000) >> 10) as usize;
 
+                if header_len < mem::size_of::<TcpHeader>() || header_len > bytes.len() {
+                    return None;
+                }
+
                 return Some(Tcp {
                     header: header,
                     options: bytes[mem::size_of::<TcpHeader>()..header_len].to_vec(),
@@ -70,31 +160,442 @@ impl ToBytes for Tcp {
     }
 }
 
-/// A TCP resource
-pub struct Resource {
+impl Tcp {
+    pub fn new(src: u16,
+               dst: u16,
+               sequence: u32,
+               ack_num: u32,
+               flags: u16,
+               window_size: u16,
+               options: Vec<u8>,
+               data: Vec<u8>)
+               -> Tcp {
+        let data_offset = ((mem::size_of::<TcpHeader>() + options.len()) << 10) & 0xF000;
+
+        Tcp {
+            header: TcpHeader {
+                src: n16::new(src),
+                dst: n16::new(dst),
+                sequence: n32::new(sequence),
+                ack_num: n32::new(ack_num),
+                flags: n16::new(data_offset as u16 | flags),
+                window_size: n16::new(window_size),
+                checksum: Checksum { data: 0 },
+                urgent_pointer: n16::new(0),
+            },
+            options: options,
+            data: data,
+        }
+    }
+
+    /// The length of the segment in sequence space
+    pub fn len(&self) -> u32 {
+        let flags = self.header.flags.get();
+        let mut len = self.data.len() as u32;
+        if flags & TCP_SYN == TCP_SYN {
+            len += 1;
+        }
+        if flags & TCP_FIN == TCP_FIN {
+            len += 1;
+        }
+        len
+    }
+
+    /// Calculate the checksum, including the IPv4 pseudo header
+    pub fn checksum(&mut self, src_addr: &IPv4Addr, dst_addr: &IPv4Addr) {
+        self.header.checksum.data = 0;
+
+        unsafe {
+            let proto = n16::new(0x06);
+            let segment_len = n16::new((mem::size_of::<TcpHeader>() + self.options.len() +
+                                        self.data.len()) as u16);
+            self.header.checksum.data =
+                Checksum::compile(Checksum::sum((src_addr as *const IPv4Addr) as usize,
+                                                mem::size_of::<IPv4Addr>()) +
+                                  Checksum::sum((dst_addr as *const IPv4Addr) as usize,
+                                                mem::size_of::<IPv4Addr>()) +
+                                  Checksum::sum((&proto as *const n16) as usize,
+                                                mem::size_of::<n16>()) +
+                                  Checksum::sum((&segment_len as *const n16) as usize,
+                                                mem::size_of::<n16>()) +
+                                  Checksum::sum((&self.header as *const TcpHeader) as usize,
+                                                mem::size_of::<TcpHeader>()) +
+                                  Checksum::sum(self.options.as_ptr() as usize, self.options.len()) +
+                                  Checksum::sum(self.data.as_ptr() as usize, self.data.len()));
+        }
+    }
+
+    /// Find the maximum segment size option
+    pub fn mss(&self) -> Option<u32> {
+        let mut i = 0;
+        while i < self.options.len() {
+            match self.options[i] {
+                0 => break,
+                1 => i += 1,
+                kind => {
+                    let len = match self.options.get(i + 1) {
+                        Some(len) if *len >= 2 => *len as usize,
+                        _ => break,
+                    };
+                    if kind == 2 && len == 4 && i + 4 <= self.options.len() {
+                        return Some(((self.options[i + 2] as u32) << 8) |
+                                    self.options[i + 3] as u32);
+                    }
+                    i += len;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Compare sequence numbers, allowing for wrap around
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+fn millis(duration: Duration) -> i64 {
+    duration.secs * 1000 + (duration.nanos / 1000000) as i64
+}
+
+/// Open a resource that returns zero from read when nothing is waiting
+fn open_nonblock(path: &str) -> Result<File> {
+    let path_c = path.to_string() + "\0";
+    unsafe { File::from_fd(sys_open(path_c.as_ptr(), O_RDWR | O_NONBLOCK, 0)) }
+}
+
+/// A segment that was sent, but not acknowledged
+#[derive(Clone)]
+pub struct TcpSegment {
+    pub sequence: u32,
+    pub flags: u16,
+    pub data: Vec<u8>,
+    /// Time of the first transmission, used for round trip samples
+    pub sent: Duration,
+    pub retransmits: usize,
+}
+
+impl TcpSegment {
+    /// The length of the segment in sequence space
+    pub fn len(&self) -> u32 {
+        let mut len = self.data.len() as u32;
+        if self.flags & TCP_SYN == TCP_SYN {
+            len += 1;
+        }
+        if self.flags & TCP_FIN == TCP_FIN {
+            len += 1;
+        }
+        len
+    }
+}
+
+/// The maximum segment size option sent with SYN segments
+fn mss_option() -> Vec<u8> {
+    vec![2, 4, (TCP_MSS >> 8) as u8, TCP_MSS as u8]
+}
+
+/// Send a segment that does not belong to a connection
+fn send_reply(peer_addr: IPv4Addr,
+              host_port: u16,
+              peer_port: u16,
+              sequence: u32,
+              acknowledge: u32,
+              flags: u16)
+              -> Result<usize> {
+    let mut ip = try!(open_nonblock(&("ip://".to_string() + &peer_addr.to_string() + "/6")));
+
+    let mut tcp = Tcp::new(host_port,
+                           peer_port,
+                           sequence,
+                           acknowledge,
+                           flags,
+                           0,
+                           Vec::new(),
+                           Vec::new());
+
+    tcp.checksum(&peer_addr.host_addr(), &peer_addr);
+
+    ip.write(&tcp.to_bytes())
+}
+
+/// Reset the sender of a segment that does not belong to any connection
+fn send_reset(peer_addr: IPv4Addr, segment: &Tcp) -> Result<usize> {
+    let flags = segment.header.flags.get();
+    if flags & TCP_RST == TCP_RST {
+        return Ok(0);
+    }
+
+    if flags & TCP_ACK == TCP_ACK {
+        send_reply(peer_addr,
+                   segment.header.dst.get(),
+                   segment.header.src.get(),
+                   segment.header.ack_num.get(),
+                   0,
+                   TCP_RST)
+    } else {
+        send_reply(peer_addr,
+                   segment.header.dst.get(),
+                   segment.header.src.get(),
+                   0,
+                   segment.header.sequence.get().wrapping_add(segment.len()),
+                   TCP_RST | TCP_ACK)
+    }
+}
+
+/// A connection identifier: host port, peer address, and peer port
+pub type TcpKey = (u16, [u8; 4], u16);
+
+/// A closed connection, kept to acknowledge a retransmitted FIN
+pub struct TimeWait {
+    pub key: TcpKey,
+    pub sequence: u32,
+    pub expires: Duration,
+}
+
+/// Ports and connections in use, shared by all resources of the scheme
+pub struct Ports {
+    pub listening: BTreeSet<u16>,
+    /// Open connections, with their last known state
+    pub connections: BTreeMap<TcpKey, TcpState>,
+    pub time_wait: Vec<TimeWait>,
+}
+
+impl Ports {
+    pub fn new() -> Arc<Mutex<Ports>> {
+        Arc::new(Mutex::new(Ports {
+            listening: BTreeSet::new(),
+            connections: BTreeMap::new(),
+            time_wait: Vec::new(),
+        }))
+    }
+
+    /// Find a connection in TIME_WAIT, forgetting the expired ones
+    pub fn time_wait(&mut self, key: &TcpKey) -> Option<u32> {
+        let now = Duration::monotonic();
+        self.time_wait.retain(|time_wait| time_wait.expires > now);
+
+        for time_wait in self.time_wait.iter() {
+            if time_wait.key == *key {
+                return Some(time_wait.sequence);
+            }
+        }
+
+        None
+    }
+
+    /// Check if a connection identifier is used by an open or recently closed connection
+    pub fn in_use(&mut self, key: &TcpKey) -> bool {
+        self.connections.contains_key(key) || self.time_wait(key).is_some()
+    }
+
+    /// List the listening ports and connections, one per line with the local address, peer
+    /// address, and state
+    pub fn status(&mut self) -> String {
+        let mut string = String::new();
+
+        for port in self.listening.iter() {
+            string = string + &format!("{}:{} {}:* {}\n",
+                                       ANY_IP_ADDR.to_string(),
+                                       port,
+                                       ANY_IP_ADDR.to_string(),
+                                       TcpState::Listen.name());
+        }
+
+        let line = |key: &TcpKey, state: TcpState| {
+            let peer_addr = IPv4Addr { bytes: key.1 };
+            format!("{}:{} {}:{} {}\n",
+                    peer_addr.host_addr().to_string(),
+                    key.0,
+                    peer_addr.to_string(),
+                    key.2,
+                    state.name())
+        };
+
+        for (key, state) in self.connections.iter() {
+            string = string + &line(key, *state);
+        }
+
+        let now = Duration::monotonic();
+        self.time_wait.retain(|time_wait| time_wait.expires > now);
+        for time_wait in self.time_wait.iter() {
+            string = string + &line(&time_wait.key, TcpState::TimeWait);
+        }
+
+        string
+    }
+
+    /// Choose an ephemeral port for a connection to a peer
+    pub fn client_port(&mut self, peer_addr: IPv4Addr, peer_port: u16) -> u16 {
+        loop {
+            let host_port = (rand() % 32768 + 32768) as u16;
+            if !self.listening.contains(&host_port) &&
+               !self.in_use(&(host_port, peer_addr.bytes, peer_port)) {
+                return host_port;
+            }
+        }
+    }
+}
+
+/// A TCP connection
+pub struct Connection {
     ip: File,
     peer_addr: IPv4Addr,
     peer_port: u16,
     host_port: u16,
+    state: TcpState,
+    ports: Arc<Mutex<Ports>>,
+    /// The next sequence number to send (SND.NXT)
     sequence: u32,
+    /// The next sequence number expected from the peer (RCV.NXT)
     acknowledge: u32,
+    /// The oldest unacknowledged sequence number (SND.UNA)
+    unacknowledged: u32,
+    /// The window advertised by the peer (SND.WND)
+    send_window: u32,
+    /// The maximum segment size accepted by the peer
+    mss: u32,
+    /// Congestion window, in bytes
+    cwnd: u32,
+    /// Slow start threshold, in bytes
+    ssthresh: u32,
+    /// Duplicate acknowledgements received in a row
+    dup_acks: u32,
+    /// Smoothed round trip time, in milliseconds, zero until the first sample
+    srtt: i64,
+    /// Round trip time variation, in milliseconds
+    rttvar: i64,
+    /// Retransmission timeout, in milliseconds
+    rto: i64,
+    /// Start of the retransmission timer
+    timer: Duration,
+    /// Segments sent but not acknowledged
+    retransmit: VecDeque<TcpSegment>,
+    /// Data written but not yet sent
+    outbound: VecDeque<u8>,
+    /// Data received in order, waiting to be read
+    inbound: VecDeque<u8>,
+    /// Segments received out of order, by sequence number
+    reassembly: BTreeMap<u32, Vec<u8>>,
+    /// The sending direction was shut down, a FIN follows the outbound data
+    shutdown: bool,
+    /// The receiving direction was shut down, received data is discarded
+    read_shutdown: bool,
+    /// The sequence number of our FIN, once sent
+    fin: Option<u32>,
+    /// The sequence number of the FIN of the peer, once received
+    peer_fin: Option<u32>,
+    /// Start of the TIME_WAIT state
+    time_wait: Duration,
+    /// Idle connections are probed
+    keepalive: bool,
+    /// Time of the last segment received, for keepalive
+    last_received: Duration,
+    /// Keepalive probes sent since the last segment was received
+    keepalive_probes: i64,
+    /// ICMP errors about TCP segments, if the ICMP scheme is available
+    errors: Option<File>,
+    /// The last soft ICMP error, reported instead of a timeout
+    soft_error: Option<isize>,
+    /// Segments are read into this buffer
+    buffer: Vec<u8>,
 }
 
-impl Resource {
-    pub fn dup(&self) -> Result<Box<Resource>> {
-        match self.ip.dup() {
-            Ok(ip) => {
-                Ok(box Resource {
-                    ip: ip,
-                    peer_addr: self.peer_addr,
-                    peer_port: self.peer_port,
-                    host_port: self.host_port,
-                    sequence: self.sequence,
-                    acknowledge: self.acknowledge,
-                })
+impl Connection {
+    pub fn new(ip: File,
+               peer_addr: IPv4Addr,
+               peer_port: u16,
+               host_port: u16,
+               ports: Arc<Mutex<Ports>>)
+               -> Connection {
+        ports.lock()
+             .unwrap()
+             .connections
+             .insert((host_port, peer_addr.bytes, peer_port), TcpState::Closed);
+
+        let sequence = rand() as u32;
+        Connection {
+            ip: ip,
+            peer_addr: peer_addr,
+            peer_port: peer_port,
+            host_port: host_port,
+            state: TcpState::Closed,
+            ports: ports,
+            sequence: sequence,
+            acknowledge: 0,
+            unacknowledged: sequence,
+            send_window: TCP_MSS_DEFAULT,
+            mss: TCP_MSS_DEFAULT,
+            cwnd: TCP_MSS_DEFAULT,
+            ssthresh: 65535,
+            dup_acks: 0,
+            srtt: 0,
+            rttvar: 0,
+            rto: TCP_RTO_INIT,
+            timer: Duration::monotonic(),
+            retransmit: VecDeque::new(),
+            outbound: VecDeque::new(),
+            inbound: VecDeque::new(),
+            reassembly: BTreeMap::new(),
+            shutdown: false,
+            read_shutdown: false,
+            fin: None,
+            peer_fin: None,
+            time_wait: Duration::monotonic(),
+            keepalive: false,
+            last_received: Duration::monotonic(),
+            keepalive_probes: 0,
+            errors: open_nonblock("icmp:error/6").ok(),
+            soft_error: None,
+            buffer: vec![0; 65536],
+        }
+    }
+
+    /// Etablish client, sending SYN and waiting for SYN-ACK
+    pub fn connect(&mut self) -> Result<()> {
+        self.state = TcpState::SynSent;
+        try!(self.queue(TCP_SYN, Vec::new()));
+
+        while self.state == TcpState::SynSent {
+            if !try!(self.update()) {
+                thread::yield_now();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer a SYN received by a listening socket, the handshake completes in `update`
+    pub fn listen(&mut self, syn: &Tcp) -> Result<()> {
+        self.state = TcpState::SynReceived;
+        self.acknowledge = syn.header.sequence.get().wrapping_add(1);
+        self.handshake(syn);
+        self.queue(TCP_SYN | TCP_ACK, Vec::new())
+    }
+
+    /// Initialize the windows from the SYN of the peer
+    fn handshake(&mut self, syn: &Tcp) {
+        if let Some(mss) = syn.mss() {
+            if mss < TCP_MSS {
+                self.mss = mss;
+            } else {
+                self.mss = TCP_MSS;
             }
-            Err(err) => Err(err),
         }
+
+        // Initial window (RFC 5681)
+        self.cwnd = if 2 * self.mss > 4380 {
+            2 * self.mss
+        } else if 4 * self.mss < 4380 {
+            4 * self.mss
+        } else {
+            4380
+        };
+
+        self.send_window = syn.header.window_size.get() as u32;
     }
 
     pub fn path(&self) -> Result<String> {
@@ -106,426 +607,1048 @@ impl Resource {
 
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         loop {
-            let mut bytes: Vec<u8> = Vec::new();
-            match self.ip.read_to_end(&mut bytes) {
-                Ok(_) => {
-                    if let Some(segment) = Tcp::from_bytes(bytes) {
-                        if (segment.header.flags.get() & (TCP_PSH | TCP_SYN | TCP_ACK)) ==
-                           (TCP_PSH | TCP_ACK) &&
-                           segment.header.dst.get() == self.host_port &&
-                           segment.header.src.get() == self.peer_port {
-                            // Send ACK
-                            self.sequence = segment.header.ack_num.get();
-                            self.acknowledge = segment.header.sequence.get() +
-                                               segment.data.len() as u32;
-                            let mut tcp = Tcp {
-                                        header: TcpHeader {
-                                            src: n16::new(self.host_port),
-                                            dst: n16::new(self.peer_port),
-                                            sequence: n32::new(self.sequence),
-                                            ack_num: n32::new(self.acknowledge),
-                                            flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | TCP_ACK),
-                                            window_size: n16::new(65535),
-                                            checksum: Checksum {
-                                                data: 0
-                                            },
-                                            urgent_pointer: n16::new(0)
-                                        },
-                                        options: Vec::new(),
-                                        data: Vec::new()
-                                    };
-
-                            unsafe {
-                                let proto = n16::new(0x06);
-                                let segment_len = n16::new((mem::size_of::<TcpHeader>() + tcp.options.len() + tcp.data.len()) as u16);
-                                tcp.header.checksum.data = Checksum::compile(
-                                            Checksum::sum((&IP_ADDR as *const IPv4Addr) as usize, mem::size_of::<IPv4Addr>()) +
-                                            Checksum::sum((&self.peer_addr as *const IPv4Addr) as usize, mem::size_of::<IPv4Addr>()) +
-                                            Checksum::sum((&proto as *const n16) as usize, mem::size_of::<n16>()) +
-                                            Checksum::sum((&segment_len as *const n16) as usize, mem::size_of::<n16>()) +
-                                            Checksum::sum((&tcp.header as *const TcpHeader) as usize, mem::size_of::<TcpHeader>()) +
-                                            Checksum::sum(tcp.options.as_ptr() as usize, tcp.options.len()) +
-                                            Checksum::sum(tcp.data.as_ptr() as usize, tcp.data.len())
-                                            );
-                            }
+            if self.read_shutdown {
+                return Ok(0);
+            }
 
-                            self.ip.write(&tcp.to_bytes());
+            if !self.inbound.is_empty() {
+                let closed = self.receive_window() < self.mss as u16;
 
-                            // TODO: Support broken packets (one packet in two buffers)
-                            let mut i = 0;
-                            while i < buf.len() && i < segment.data.len() {
-                                buf[i] = segment.data[i];
-                                i += 1;
-                            }
-                            return Ok(i);
-                        }
+                let mut i = 0;
+                while i < buf.len() {
+                    match self.inbound.pop_front() {
+                        Some(b) => buf[i] = b,
+                        None => break,
                     }
+                    i += 1;
                 }
-                Err(err) => return Err(err),
+
+                // Tell the peer that the window opened again
+                if closed && self.receive_window() >= self.mss as u16 {
+                    try!(self.send_ack());
+                }
+
+                return Ok(i);
+            }
+
+            // End of file once the peer closed its direction
+            if self.fin_received() {
+                return Ok(0);
+            }
+
+            if self.state == TcpState::Closed {
+                return Err(Error::new(ECONNRESET));
+            }
+
+            if !try!(self.update()) {
+                thread::yield_now();
             }
         }
     }
 
     pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let tcp_data = Vec::from(buf);
+        if self.shutdown ||
+           (self.state != TcpState::Established && self.state != TcpState::CloseWait) {
+            return Err(Error::new(EPIPE));
+        }
 
-        let mut tcp = Tcp {
-            header: TcpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                sequence: n32::new(self.sequence),
-                ack_num: n32::new(self.acknowledge),
-                flags: n16::new((((mem::size_of::<TcpHeader>()) << 10) & 0xF000) as u16 | TCP_PSH |
-                                TCP_ACK),
-                window_size: n16::new(65535),
-                checksum: Checksum { data: 0 },
-                urgent_pointer: n16::new(0),
-            },
-            options: Vec::new(),
-            data: tcp_data,
-        };
+        self.outbound.extend(buf.iter().cloned());
 
-        unsafe {
-            let proto = n16::new(0x06);
-            let segment_len = n16::new((mem::size_of::<TcpHeader>() + tcp.data.len()) as u16);
-            tcp.header.checksum.data =
-                Checksum::compile(Checksum::sum((&IP_ADDR as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&self.peer_addr as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&proto as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&segment_len as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&tcp.header as *const TcpHeader) as usize,
-                                                mem::size_of::<TcpHeader>()) +
-                                  Checksum::sum(tcp.options.as_ptr() as usize, tcp.options.len()) +
-                                  Checksum::sum(tcp.data.as_ptr() as usize, tcp.data.len()));
-        }
-
-        match self.ip.write(&tcp.to_bytes()) {
-            Ok(size) => {
-                loop {
-                    // Wait for ACK
-                    let mut bytes: Vec<u8> = Vec::new();
-                    match self.ip.read_to_end(&mut bytes) {
-                        Ok(_) => {
-                            if let Some(segment) = Tcp::from_bytes(bytes) {
-                                if segment.header.dst.get() == self.host_port &&
-                                   segment.header.src.get() == self.peer_port {
-                                    return if (segment.header.flags.get() &
-                                               (TCP_PSH | TCP_SYN | TCP_ACK)) ==
-                                              TCP_ACK {
-                                        self.sequence = segment.header.ack_num.get();
-                                        self.acknowledge = segment.header.sequence.get();
-                                        Ok(size)
-                                    } else {
-                                        Err(Error::new(EPIPE))
-                                    };
+        // Return once all data is in flight, acknowledgements are handled by later calls
+        while !self.outbound.is_empty() {
+            if !try!(self.update()) {
+                thread::yield_now();
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        while (!self.outbound.is_empty() || !self.retransmit.is_empty()) &&
+              self.state != TcpState::Closed {
+            if !try!(self.update()) {
+                thread::yield_now();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Shut down the sending direction, the peer reads end of file after the remaining data
+    pub fn shutdown(&mut self) -> Result<()> {
+        if !self.shutdown {
+            self.shutdown = true;
+            try!(self.update());
+        }
+
+        Ok(())
+    }
+
+    /// Shut down the receiving direction, reads return end of file and data that arrives is
+    /// acknowledged and discarded
+    pub fn shutdown_read(&mut self) {
+        self.read_shutdown = true;
+        self.inbound.clear();
+    }
+
+    /// Check if the FIN of the peer was received in order
+    fn fin_received(&self) -> bool {
+        match self.peer_fin {
+            Some(fin) => seq_lt(fin, self.acknowledge),
+            None => false,
+        }
+    }
+
+    /// Check if our FIN was acknowledged
+    fn fin_acknowledged(&self) -> bool {
+        match self.fin {
+            Some(fin) => seq_lt(fin, self.unacknowledged),
+            None => false,
+        }
+    }
+
+    /// Check if nothing is left to send, so dropping the connection does not reset it
+    fn closed(&self) -> bool {
+        self.state == TcpState::Closed || self.fin_acknowledged()
+    }
+
+    /// Close the connection after its last resource is gone. A thread of its own delivers the
+    /// remaining data and the FIN, then waits in FIN_WAIT_2 and TIME_WAIT, acknowledging a FIN
+    /// that the peer sends again, so the caller does not wait for the peer
+    pub fn close(mut self) {
+        let state = self.state;
+        match state {
+            TcpState::SynSent | TcpState::SynReceived | TcpState::Closed => (),
+            _ => {
+                self.shutdown = true;
+                thread::spawn(move || {
+                    let start = Duration::monotonic();
+                    loop {
+                        match self.update() {
+                            Ok(received) => {
+                                // A peer that never sends its FIN is given up on like TIME_WAIT
+                                let fin_wait = self.state == TcpState::FinWait2 &&
+                                               millis(Duration::monotonic() - start) >=
+                                               TCP_TIME_WAIT;
+                                if self.state == TcpState::Closed || fin_wait {
+                                    break;
+                                }
+
+                                if !received {
+                                    thread::sleep_ms(TCP_CLOSE_POLL);
                                 }
                             }
+                            Err(_) => break,
                         }
-                        Err(err) => return Err(err),
                     }
-                }
+                });
             }
-            Err(err) => Err(err),
         }
     }
 
-    pub fn seek(&mut self, _: SeekFrom) -> Result<u64> {
-        Err(Error::new(ESPIPE))
+    /// Move to the next state after a FIN was sent, received, or acknowledged
+    fn advance(&mut self) {
+        let fin_received = self.fin_received();
+        let fin_acknowledged = self.fin_acknowledged();
+
+        let state = match self.state {
+            TcpState::Established if fin_received => TcpState::CloseWait,
+            TcpState::FinWait1 if fin_received && fin_acknowledged => TcpState::TimeWait,
+            TcpState::FinWait1 if fin_received => TcpState::Closing,
+            TcpState::FinWait1 if fin_acknowledged => TcpState::FinWait2,
+            TcpState::FinWait2 if fin_received => TcpState::TimeWait,
+            TcpState::Closing if fin_acknowledged => TcpState::TimeWait,
+            TcpState::LastAck if fin_acknowledged => TcpState::Closed,
+            state => state,
+        };
+
+        if state == TcpState::TimeWait && self.state != TcpState::TimeWait {
+            self.time_wait = Duration::monotonic();
+        }
+
+        self.state = state;
     }
 
-    pub fn sync(&mut self) -> Result<()> {
-        self.ip.sync_all()
+    /// The receive window to advertise
+    fn receive_window(&self) -> u16 {
+        (TCP_WINDOW - self.inbound.len()) as u16
     }
 
-    /// Etablish client
-    pub fn client_establish(&mut self) -> bool {
-        // Send SYN
-        let mut tcp = Tcp {
-            header: TcpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                sequence: n32::new(self.sequence),
-                ack_num: n32::new(self.acknowledge),
-                flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | TCP_SYN),
-                window_size: n16::new(65535),
-                checksum: Checksum { data: 0 },
-                urgent_pointer: n16::new(0),
-            },
-            options: Vec::new(),
-            data: Vec::new(),
+    /// The number of bytes sent, but not acknowledged
+    fn flight(&self) -> u32 {
+        self.sequence.wrapping_sub(self.unacknowledged)
+    }
+
+    /// Send one segment
+    fn send(&mut self, sequence: u32, flags: u16, options: Vec<u8>, data: Vec<u8>) -> Result<usize> {
+        let acknowledge = if flags & TCP_ACK == TCP_ACK {
+            self.acknowledge
+        } else {
+            0
         };
 
-        unsafe {
-            let proto = n16::new(0x06);
-            let segment_len = n16::new((mem::size_of::<TcpHeader>() + tcp.options.len() +
-                                        tcp.data
-                                           .len()) as u16);
-            tcp.header.checksum.data =
-                Checksum::compile(Checksum::sum((&IP_ADDR as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&self.peer_addr as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&proto as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&segment_len as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&tcp.header as *const TcpHeader) as usize,
-                                                mem::size_of::<TcpHeader>()) +
-                                  Checksum::sum(tcp.options.as_ptr() as usize, tcp.options.len()) +
-                                  Checksum::sum(tcp.data.as_ptr() as usize, tcp.data.len()));
-        }
-
-        match self.ip.write(&tcp.to_bytes()) {
-            Ok(_) => {
-                loop {
-                    // Wait for SYN-ACK
-                    let mut bytes: Vec<u8> = Vec::new();
-                    match self.ip.read_to_end(&mut bytes) {
-                        Ok(_) => {
-                            if let Some(segment) = Tcp::from_bytes(bytes) {
-                                if segment.header.dst.get() == self.host_port &&
-                                   segment.header.src.get() == self.peer_port {
-                                    return if (segment.header.flags.get() &
-                                               (TCP_PSH | TCP_SYN | TCP_ACK)) ==
-                                              (TCP_SYN | TCP_ACK) {
-                                        self.sequence = segment.header.ack_num.get();
-                                        self.acknowledge = segment.header.sequence.get();
-
-                                        self.acknowledge += 1;
-                                        tcp = Tcp {
-                                                header: TcpHeader {
-                                                    src: n16::new(self.host_port),
-                                                    dst: n16::new(self.peer_port),
-                                                    sequence: n32::new(self.sequence),
-                                                    ack_num: n32::new(self.acknowledge),
-                                                    flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | TCP_ACK),
-                                                    window_size: n16::new(65535),
-                                                    checksum: Checksum {
-                                                        data: 0
-                                                    },
-                                                    urgent_pointer: n16::new(0)
-                                                },
-                                                options: Vec::new(),
-                                                data: Vec::new()
-                                            };
-
-                                        unsafe {
-                                            let proto = n16::new(0x06);
-                                            let segment_len = n16::new((mem::size_of::<TcpHeader>() + tcp.options.len() + tcp.data.len()) as u16);
-                                            tcp.header.checksum.data = Checksum::compile(
-                                                    Checksum::sum((&IP_ADDR as *const IPv4Addr) as usize, mem::size_of::<IPv4Addr>()) +
-                                                    Checksum::sum((&self.peer_addr as *const IPv4Addr) as usize, mem::size_of::<IPv4Addr>()) +
-                                                    Checksum::sum((&proto as *const n16) as usize, mem::size_of::<n16>()) +
-                                                    Checksum::sum((&segment_len as *const n16) as usize, mem::size_of::<n16>()) +
-                                                    Checksum::sum((&tcp.header as *const TcpHeader) as usize, mem::size_of::<TcpHeader>()) +
-                                                    Checksum::sum(tcp.options.as_ptr() as usize, tcp.options.len()) +
-                                                    Checksum::sum(tcp.data.as_ptr() as usize, tcp.data.len())
-                                                    );
-                                        }
-
-                                        self.ip.write(&tcp.to_bytes());
-
-                                        true
-                                    } else {
-                                        false
-                                    };
-                                }
-                            }
+        let mut tcp = Tcp::new(self.host_port,
+                               self.peer_port,
+                               sequence,
+                               acknowledge,
+                               flags,
+                               self.receive_window(),
+                               options,
+                               data);
+
+        tcp.checksum(&self.peer_addr.host_addr(), &self.peer_addr);
+
+        self.ip.write(&tcp.to_bytes())
+    }
+
+    /// Send a segment from the retransmission queue
+    fn send_segment(&mut self, segment: &TcpSegment) -> Result<usize> {
+        let options = if segment.flags & TCP_SYN == TCP_SYN {
+            mss_option()
+        } else {
+            Vec::new()
+        };
+
+        self.send(segment.sequence, segment.flags, options, segment.data.clone())
+    }
+
+    /// Send a new segment and add it to the retransmission queue
+    fn queue(&mut self, flags: u16, data: Vec<u8>) -> Result<()> {
+        let segment = TcpSegment {
+            sequence: self.sequence,
+            flags: flags,
+            data: data,
+            sent: Duration::monotonic(),
+            retransmits: 0,
+        };
+
+        try!(self.send_segment(&segment));
+
+        if self.retransmit.is_empty() {
+            self.timer = segment.sent;
+        }
+        self.sequence = self.sequence.wrapping_add(segment.len());
+        self.retransmit.push_back(segment);
+
+        Ok(())
+    }
+
+    fn send_ack(&mut self) -> Result<usize> {
+        let sequence = self.sequence;
+        self.send(sequence, TCP_ACK, Vec::new(), Vec::new())
+    }
+
+    /// Receive a segment for this connection, if one is waiting
+    fn receive(&mut self) -> Result<Option<Tcp>> {
+        loop {
+            match self.ip.read(&mut self.buffer) {
+                Ok(0) => return Ok(None),
+                Ok(count) => {
+                    if let Some(segment) = Tcp::from_bytes(self.buffer[..count].to_vec()) {
+                        if segment.header.dst.get() == self.host_port &&
+                           segment.header.src.get() == self.peer_port {
+                            return Ok(Some(segment));
                         }
-                        Err(_) => return false,
                     }
                 }
+                Err(err) => return Err(err),
             }
-            Err(_) => false,
         }
     }
 
-    /// Try to establish a server connection
-    pub fn server_establish(&mut self, _: Tcp) -> bool {
-        // Send SYN-ACK
-        self.acknowledge += 1;
-        let mut tcp = Tcp {
-            header: TcpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                sequence: n32::new(self.sequence),
-                ack_num: n32::new(self.acknowledge),
-                flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | TCP_SYN |
-                                TCP_ACK),
-                window_size: n16::new(65535),
-                checksum: Checksum { data: 0 },
-                urgent_pointer: n16::new(0),
-            },
-            options: Vec::new(),
-            data: Vec::new(),
-        };
+    /// Update the round trip estimate with a new sample (RFC 6298)
+    fn sample_rtt(&mut self, rtt: i64) {
+        if self.srtt == 0 {
+            self.srtt = rtt;
+            self.rttvar = rtt / 2;
+        } else {
+            let delta = if self.srtt > rtt {
+                self.srtt - rtt
+            } else {
+                rtt - self.srtt
+            };
+            self.rttvar = (3 * self.rttvar + delta) / 4;
+            self.srtt = (7 * self.srtt + rtt) / 8;
+        }
 
-        unsafe {
-            let proto = n16::new(0x06);
-            let segment_len = n16::new((mem::size_of::<TcpHeader>() + tcp.options.len() +
-                                        tcp.data
-                                           .len()) as u16);
-            tcp.header.checksum.data =
-                Checksum::compile(Checksum::sum((&IP_ADDR as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&self.peer_addr as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&proto as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&segment_len as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&tcp.header as *const TcpHeader) as usize,
-                                                mem::size_of::<TcpHeader>()) +
-                                  Checksum::sum(tcp.options.as_ptr() as usize, tcp.options.len()) +
-                                  Checksum::sum(tcp.data.as_ptr() as usize, tcp.data.len()));
-        }
-
-        match self.ip.write(&tcp.to_bytes()) {
-            Ok(_) => {
-                loop {
-                    // Wait for ACK
-                    let mut bytes: Vec<u8> = Vec::new();
-                    match self.ip.read_to_end(&mut bytes) {
-                        Ok(_) => {
-                            if let Some(segment) = Tcp::from_bytes(bytes) {
-                                if segment.header.dst.get() == self.host_port &&
-                                   segment.header.src.get() == self.peer_port {
-                                    return if (segment.header.flags.get() &
-                                               (TCP_PSH | TCP_SYN | TCP_ACK)) ==
-                                              TCP_ACK {
-                                        self.sequence = segment.header.ack_num.get();
-                                        self.acknowledge = segment.header.sequence.get();
-                                        true
-                                    } else {
-                                        false
-                                    };
-                                }
-                            }
+        self.rto = self.srtt + 4 * self.rttvar;
+        if self.rto < TCP_RTO_MIN {
+            self.rto = TCP_RTO_MIN;
+        }
+        if self.rto > TCP_RTO_MAX {
+            self.rto = TCP_RTO_MAX;
+        }
+    }
+
+    /// Send the oldest unacknowledged segment again
+    fn retransmit_first(&mut self) -> Result<()> {
+        if let Some(mut segment) = self.retransmit.pop_front() {
+            segment.retransmits += 1;
+            let result = self.send_segment(&segment);
+            self.retransmit.push_front(segment);
+            self.timer = Duration::monotonic();
+            try!(result);
+        }
+        Ok(())
+    }
+
+    /// Process an acknowledgement number and window from the peer
+    fn process_ack(&mut self, segment: &Tcp) -> Result<()> {
+        let ack = segment.header.ack_num.get();
+        let window = segment.header.window_size.get() as u32;
+
+        if seq_lt(self.unacknowledged, ack) && seq_le(ack, self.sequence) {
+            let now = Duration::monotonic();
+
+            // Remove the acknowledged data, sampling the round trip time (Karn's algorithm)
+            let mut sample = None;
+            while let Some(mut front) = self.retransmit.pop_front() {
+                let end = front.sequence.wrapping_add(front.len());
+                if seq_le(end, ack) {
+                    if front.retransmits == 0 {
+                        sample = Some(millis(now - front.sent));
+                    }
+                } else {
+                    let acked = ack.wrapping_sub(front.sequence) as usize;
+                    if acked > 0 && acked <= front.data.len() {
+                        front.data = front.data[acked..].to_vec();
+                        front.sequence = ack;
+                    }
+                    self.retransmit.push_front(front);
+                    break;
+                }
+            }
+
+            if let Some(rtt) = sample {
+                self.sample_rtt(rtt);
+            }
+
+            let acked = ack.wrapping_sub(self.unacknowledged);
+            self.unacknowledged = ack;
+
+            if self.dup_acks >= TCP_DUP_ACKS {
+                // Leave fast recovery
+                self.cwnd = self.ssthresh;
+            } else if self.cwnd < self.ssthresh {
+                // Slow start
+                self.cwnd += if acked < self.mss {
+                    acked
+                } else {
+                    self.mss
+                };
+            } else {
+                // Congestion avoidance
+                let increase = self.mss * self.mss / self.cwnd;
+                self.cwnd += if increase > 0 {
+                    increase
+                } else {
+                    1
+                };
+            }
+
+            self.dup_acks = 0;
+            self.timer = now;
+        } else if ack == self.unacknowledged && segment.data.is_empty() &&
+                  segment.header.flags.get() & (TCP_SYN | TCP_FIN) == 0 &&
+                  !self.retransmit.is_empty() && window == self.send_window {
+            self.dup_acks += 1;
+
+            if self.dup_acks == TCP_DUP_ACKS {
+                // Fast retransmit, then fast recovery
+                let flight = self.flight();
+                self.ssthresh = if flight / 2 > 2 * self.mss {
+                    flight / 2
+                } else {
+                    2 * self.mss
+                };
+                try!(self.retransmit_first());
+                self.cwnd = self.ssthresh + TCP_DUP_ACKS * self.mss;
+            } else if self.dup_acks > TCP_DUP_ACKS {
+                self.cwnd += self.mss;
+            }
+        }
+
+        self.send_window = window;
+
+        Ok(())
+    }
+
+    /// Move data from the reassembly queue that is now in order
+    fn reassemble(&mut self) {
+        loop {
+            let mut found = None;
+            for (sequence, data) in self.reassembly.iter() {
+                if seq_le(*sequence, self.acknowledge) {
+                    found = Some((*sequence, data.len()));
+                    break;
+                }
+            }
+
+            match found {
+                Some((sequence, _)) => {
+                    if let Some(data) = self.reassembly.remove(&sequence) {
+                        let skip = self.acknowledge.wrapping_sub(sequence) as usize;
+                        if skip < data.len() {
+                            self.inbound.extend(data[skip..].iter().cloned());
+                            self.acknowledge = self.acknowledge
+                                                   .wrapping_add((data.len() - skip) as u32);
                         }
-                        Err(_) => return false,
                     }
                 }
+                None => break,
+            }
+        }
+    }
+
+    /// Process the data and FIN of a segment
+    fn process_data(&mut self, segment: &Tcp) -> Result<()> {
+        let fin = segment.header.flags.get() & TCP_FIN == TCP_FIN;
+        if segment.data.is_empty() && !fin {
+            return Ok(());
+        }
+
+        let sequence = segment.header.sequence.get();
+        let end = sequence.wrapping_add(segment.data.len() as u32);
+        let window_end = self.acknowledge.wrapping_add(self.receive_window() as u32);
+
+        // Like data, a FIN is only accepted inside the receive window
+        if fin && self.peer_fin.is_none() && seq_le(self.acknowledge, end) &&
+           seq_le(end, window_end) {
+            self.peer_fin = Some(end);
+        }
+
+        if self.fin_received() {
+            // Everything was received, only acknowledge retransmissions
+        } else if seq_le(sequence, self.acknowledge) {
+            if seq_lt(self.acknowledge, end) {
+                let skip = self.acknowledge.wrapping_sub(sequence) as usize;
+                let space = self.receive_window() as usize;
+                let mut data = &segment.data[skip..];
+                if data.len() > space {
+                    data = &data[..space];
+                }
+                self.inbound.extend(data.iter().cloned());
+                self.acknowledge = self.acknowledge.wrapping_add(data.len() as u32);
+                self.reassemble();
+            }
+        } else if seq_lt(sequence, window_end) && !self.reassembly.contains_key(&sequence) {
+            self.reassembly.insert(sequence, segment.data.clone());
+        }
+
+        // The FIN is consumed once all data before it arrived
+        if self.peer_fin == Some(self.acknowledge) {
+            self.acknowledge = self.acknowledge.wrapping_add(1);
+            self.reassembly.clear();
+        }
+
+        // Acknowledge every data segment, out of order segments cause duplicate acknowledgements
+        try!(self.send_ack());
+
+        Ok(())
+    }
+
+    /// Process a segment received on this connection
+    fn process(&mut self, segment: Tcp) -> Result<()> {
+        let flags = segment.header.flags.get();
+        let ack = segment.header.ack_num.get();
+
+        match self.state {
+            TcpState::SynSent => {
+                if flags & TCP_ACK == TCP_ACK && ack != self.sequence {
+                    // Acknowledges something we did not send
+                    if flags & TCP_RST != TCP_RST {
+                        try!(self.send(ack, TCP_RST, Vec::new(), Vec::new()));
+                    }
+                } else if flags & TCP_RST == TCP_RST {
+                    if flags & TCP_ACK == TCP_ACK {
+                        self.state = TcpState::Closed;
+                        return Err(Error::new(ECONNREFUSED));
+                    }
+                } else if flags & (TCP_SYN | TCP_ACK) == (TCP_SYN | TCP_ACK) {
+                    self.acknowledge = segment.header.sequence.get().wrapping_add(1);
+                    self.handshake(&segment);
+                    try!(self.process_ack(&segment));
+                    self.state = TcpState::Established;
+                    try!(self.send_ack());
+                }
+
+                return Ok(());
+            }
+            TcpState::SynReceived => {
+                if flags & TCP_RST == TCP_RST {
+                    self.state = TcpState::Closed;
+                    return Err(Error::new(ECONNRESET));
+                } else if flags & TCP_SYN == TCP_SYN {
+                    // The SYN-ACK was lost, send it again
+                    return self.retransmit_first();
+                } else if flags & TCP_ACK == TCP_ACK && ack == self.sequence {
+                    try!(self.process_ack(&segment));
+                    self.state = TcpState::Established;
+
+                    // The ACK may already carry data or a FIN
+                    try!(self.process_data(&segment));
+                    self.advance();
+                }
+
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        if flags & TCP_RST == TCP_RST {
+            // Only accept a reset inside the receive window, to resist blind resets
+            let sequence = segment.header.sequence.get();
+            let window_end = self.acknowledge.wrapping_add(self.receive_window() as u32);
+            if seq_le(self.acknowledge, sequence) && seq_le(sequence, window_end) {
+                let time_wait = self.state == TcpState::TimeWait;
+                self.state = TcpState::Closed;
+                if !time_wait {
+                    return Err(Error::new(ECONNRESET));
+                }
+            }
+            return Ok(());
+        }
+
+        if self.state == TcpState::Closed {
+            return Ok(());
+        }
+
+        if flags & TCP_ACK == TCP_ACK {
+            try!(self.process_ack(&segment));
+        }
+
+        try!(self.process_data(&segment));
+
+        self.advance();
+
+        Ok(())
+    }
+
+    /// Send data from the outbound buffer, as allowed by the send and congestion windows
+    fn transmit(&mut self) -> Result<()> {
+        while !self.outbound.is_empty() {
+            let window = if self.cwnd < self.send_window {
+                self.cwnd
+            } else {
+                self.send_window
+            };
+
+            let flight = self.flight();
+
+            let mut len = if window > flight {
+                window - flight
+            } else if window == 0 && flight == 0 {
+                // Probe a zero window with one byte, the retransmission timer repeats the probe
+                1
+            } else {
+                break;
+            };
+
+            if len > self.mss {
+                len = self.mss;
+            }
+            if len > self.outbound.len() as u32 {
+                len = self.outbound.len() as u32;
+            }
+
+            let mut data = Vec::with_capacity(len as usize);
+            for _ in 0..len {
+                if let Some(b) = self.outbound.pop_front() {
+                    data.push(b);
+                }
+            }
+
+            try!(self.queue(TCP_ACK | TCP_PSH, data));
+        }
+
+        if self.shutdown && self.fin.is_none() && self.outbound.is_empty() {
+            self.fin = Some(self.sequence);
+            try!(self.queue(TCP_FIN | TCP_ACK, Vec::new()));
+
+            self.state = match self.state {
+                TcpState::CloseWait => TcpState::LastAck,
+                _ => TcpState::FinWait1,
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Retransmit on timeout
+    fn check_timer(&mut self) -> Result<()> {
+        if !self.retransmit.is_empty() && millis(Duration::monotonic() - self.timer) >= self.rto {
+            if let Some(front) = self.retransmit.front() {
+                let retries = if front.flags & TCP_SYN == TCP_SYN {
+                    TCP_SYN_RETRIES
+                } else {
+                    TCP_RETRIES
+                };
+
+                if front.retransmits >= retries {
+                    self.state = TcpState::Closed;
+                    return Err(Error::new(self.soft_error.unwrap_or(ETIMEDOUT)));
+                }
+            }
+
+            let flight = self.flight();
+            self.ssthresh = if flight / 2 > 2 * self.mss {
+                flight / 2
+            } else {
+                2 * self.mss
+            };
+            self.cwnd = self.mss;
+            self.dup_acks = 0;
+
+            self.rto *= 2;
+            if self.rto > TCP_RTO_MAX {
+                self.rto = TCP_RTO_MAX;
+            }
+
+            try!(self.retransmit_first());
+        }
+
+        Ok(())
+    }
+
+    /// Handle ICMP errors about this connection. Hard errors abort it, soft errors are reported
+    /// if it times out (RFC 1122 4.2.3.9)
+    fn check_errors(&mut self) -> Result<()> {
+        let mut buf = [0; 64];
+        loop {
+            let count = match self.errors {
+                Some(ref mut errors) => try!(errors.read(&mut buf)),
+                None => 0,
+            };
+            if count < mem::size_of::<IcmpError>() {
+                return Ok(());
+            }
+
+            let error = unsafe { ptr::read(buf.as_ptr() as *const IcmpError) };
+            if error.src_port.get() == self.host_port && error.dst.equals(self.peer_addr) &&
+               error.dst_port.get() == self.peer_port {
+                if error.is_hard() && self.state != TcpState::TimeWait {
+                    self.state = TcpState::Closed;
+                    return Err(Error::new(error.errno()));
+                }
+                self.soft_error = Some(error.errno());
+            }
+        }
+    }
+
+    /// Probe an idle connection, dropping it when the peer does not answer
+    fn check_keepalive(&mut self) -> Result<()> {
+        if !self.keepalive || self.state != TcpState::Established || !self.retransmit.is_empty() {
+            return Ok(());
+        }
+
+        let idle = millis(Duration::monotonic() - self.last_received);
+        if idle < TCP_KEEPALIVE_IDLE {
+            return Ok(());
+        }
+
+        let probes = (idle - TCP_KEEPALIVE_IDLE) / TCP_KEEPALIVE_INTERVAL + 1;
+        if probes > TCP_KEEPALIVE_PROBES {
+            self.state = TcpState::Closed;
+            return Err(Error::new(ETIMEDOUT));
+        }
+
+        if probes > self.keepalive_probes {
+            // A segment with an old sequence number makes the peer send an ACK
+            self.keepalive_probes = probes;
+            let sequence = self.sequence.wrapping_sub(1);
+            try!(self.send(sequence, TCP_ACK, Vec::new(), Vec::new()));
+        }
+
+        Ok(())
+    }
+
+    /// Handle waiting segments, timers, and outbound data. Returns true if a segment was
+    /// received
+    fn update(&mut self) -> Result<bool> {
+        let result = self.poll();
+
+        // Publish the state for the list of connections
+        let key = (self.host_port, self.peer_addr.bytes, self.peer_port);
+        if let Some(state) = self.ports.lock().unwrap().connections.get_mut(&key) {
+            *state = self.state;
+        }
+
+        result
+    }
+
+    fn poll(&mut self) -> Result<bool> {
+        let mut received = false;
+        while let Some(segment) = try!(self.receive()) {
+            self.last_received = Duration::monotonic();
+            self.keepalive_probes = 0;
+
+            try!(self.process(segment));
+            received = true;
+        }
+
+        if self.read_shutdown {
+            self.inbound.clear();
+        }
+
+        try!(self.check_errors());
+        try!(self.check_timer());
+        try!(self.check_keepalive());
+
+        match self.state {
+            TcpState::Established | TcpState::CloseWait => try!(self.transmit()),
+            TcpState::TimeWait => {
+                if millis(Duration::monotonic() - self.time_wait) >= TCP_TIME_WAIT {
+                    self.state = TcpState::Closed;
+                }
             }
-            Err(_) => false,
+            _ => (),
         }
+
+        Ok(received)
     }
 }
 
-impl Drop for Resource {
+impl Drop for Connection {
+    /// Reset a connection that was not closed, and remember one that is waiting for the FIN of
+    /// the peer
     fn drop(&mut self) {
-        // Send FIN-ACK
-        let mut tcp = Tcp {
-            header: TcpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                sequence: n32::new(self.sequence),
-                ack_num: n32::new(self.acknowledge),
-                flags: n16::new((((mem::size_of::<TcpHeader>()) << 10) & 0xF000) as u16 | TCP_FIN |
-                                TCP_ACK),
-                window_size: n16::new(65535),
-                checksum: Checksum { data: 0 },
-                urgent_pointer: n16::new(0),
-            },
-            options: Vec::new(),
-            data: Vec::new(),
+        if !self.closed() {
+            let sequence = self.sequence;
+            let _ = self.send(sequence, TCP_RST, Vec::new(), Vec::new());
+            self.state = TcpState::Closed;
+        }
+
+        // FIN_WAIT_2 and TIME_WAIT continue without the connection, the listener acknowledges
+        // a FIN retransmitted by the peer
+        if self.state == TcpState::FinWait2 || self.state == TcpState::TimeWait {
+            let expires = if self.state == TcpState::TimeWait {
+                self.time_wait
+            } else {
+                Duration::monotonic()
+            } + Duration::new(TCP_TIME_WAIT / 1000, 0);
+
+            self.ports.lock().unwrap().time_wait.push(TimeWait {
+                key: (self.host_port, self.peer_addr.bytes, self.peer_port),
+                sequence: self.sequence,
+                expires: expires,
+            });
+        }
+
+        let key = (self.host_port, self.peer_addr.bytes, self.peer_port);
+        self.ports.lock().unwrap().connections.remove(&key);
+    }
+}
+
+/// A listening socket, queueing incoming connections until they are accepted
+pub struct Listener {
+    /// Receives segments from any host
+    ip: File,
+    host_port: u16,
+    ports: Arc<Mutex<Ports>>,
+    /// Connections in SYN_RCVD
+    pending: Vec<Connection>,
+    /// Established connections waiting to be accepted
+    backlog: VecDeque<Connection>,
+    /// Accepted connections send keepalive probes
+    keepalive: bool,
+    /// Segments are read into this buffer
+    buffer: Vec<u8>,
+}
+
+impl Listener {
+    pub fn new(ip: File, host_port: u16, keepalive: bool, ports: Arc<Mutex<Ports>>) -> Listener {
+        ports.lock().unwrap().listening.insert(host_port);
+
+        Listener {
+            ip: ip,
+            host_port: host_port,
+            ports: ports,
+            pending: Vec::new(),
+            backlog: VecDeque::new(),
+            keepalive: keepalive,
+            buffer: vec![0; 65536],
+        }
+    }
+
+    pub fn path(&self) -> Result<String> {
+        Ok(format!("tcp:///{}", self.host_port as usize))
+    }
+
+    /// Wait for the next established connection
+    pub fn accept(&mut self) -> Result<Connection> {
+        loop {
+            if let Some(connection) = self.backlog.pop_front() {
+                return Ok(connection);
+            }
+
+            if !try!(self.update()) {
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// Handle waiting segments and advance handshakes. Returns true if anything happened
+    fn update(&mut self) -> Result<bool> {
+        let mut received = false;
+
+        loop {
+            let count = try!(self.ip.read(&mut self.buffer));
+            if count == 0 {
+                break;
+            }
+            received = true;
+
+            if let Some(segment) = Tcp::from_bytes(self.buffer[..count].to_vec()) {
+                if let Ok(path) = self.ip.path() {
+                    let url = Url::from_string(path.to_string());
+                    let peer_addr = IPv4Addr::from_string(&url.host());
+                    self.process(peer_addr, segment);
+                }
+            }
+        }
+
+        let mut i = 0;
+        while i < self.pending.len() {
+            match self.pending[i].update() {
+                Ok(_) => {
+                    if self.pending[i].state != TcpState::SynReceived {
+                        let connection = self.pending.remove(i);
+                        self.backlog.push_back(connection);
+                        received = true;
+                    } else {
+                        i += 1;
+                    }
+                }
+                // Reset or timed out, dropping the connection releases it
+                Err(_) => {
+                    self.pending.remove(i);
+                }
+            }
+        }
+
+        Ok(received)
+    }
+
+    /// Process a segment sent to any port
+    fn process(&mut self, peer_addr: IPv4Addr, segment: Tcp) {
+        let host_port = segment.header.dst.get();
+        let peer_port = segment.header.src.get();
+        let key = (host_port, peer_addr.bytes, peer_port);
+        let flags = segment.header.flags.get();
+
+        let reset = {
+            let mut ports = self.ports.lock().unwrap();
+
+            // Open connections receive their own segments
+            if ports.connections.contains_key(&key) {
+                return;
+            }
+
+            if let Some(sequence) = ports.time_wait(&key) {
+                if flags & TCP_FIN == TCP_FIN {
+                    let _ = send_reply(peer_addr,
+                                       host_port,
+                                       peer_port,
+                                       sequence,
+                                       segment.header.sequence.get().wrapping_add(segment.len()),
+                                       TCP_ACK);
+                }
+                return;
+            }
+
+            if host_port == self.host_port {
+                flags & (TCP_SYN | TCP_ACK | TCP_RST) != TCP_SYN
+            } else {
+                // Only the listener on the lowest port answers segments to closed ports
+                !ports.listening.contains(&host_port) &&
+                ports.listening.iter().next() == Some(&self.host_port)
+            }
         };
 
-        unsafe {
-            let proto = n16::new(0x06);
-            let segment_len = n16::new((mem::size_of::<TcpHeader>() + tcp.options.len() +
-                                        tcp.data
-                                           .len()) as u16);
-            tcp.header.checksum.data =
-                Checksum::compile(Checksum::sum((&IP_ADDR as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&self.peer_addr as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&proto as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&segment_len as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&tcp.header as *const TcpHeader) as usize,
-                                                mem::size_of::<TcpHeader>()) +
-                                  Checksum::sum(tcp.options.as_ptr() as usize, tcp.options.len()) +
-                                  Checksum::sum(tcp.data.as_ptr() as usize, tcp.data.len()));
+        if reset {
+            let _ = send_reset(peer_addr, &segment);
+        } else if host_port == self.host_port {
+            // A full backlog drops the SYN, the peer will retry
+            if self.pending.len() + self.backlog.len() < TCP_BACKLOG {
+                let path = "ip://".to_string() + &peer_addr.to_string() + "/6";
+                if let Ok(ip) = open_nonblock(&path) {
+                    let mut connection = Connection::new(ip,
+                                                         peer_addr,
+                                                         peer_port,
+                                                         host_port,
+                                                         self.ports.clone());
+                    connection.keepalive = self.keepalive;
+                    if connection.listen(&segment).is_ok() {
+                        self.pending.push(connection);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.ports.lock().unwrap().listening.remove(&self.host_port);
+    }
+}
+
+/// A TCP resource
+pub enum Resource {
+    /// Shared by the duplicates of the resource, closed when the last one is dropped
+    Connection(Rc<RefCell<Connection>>),
+    /// Accepts a connection on `dup`
+    Listener(RefCell<Listener>),
+    /// The list of listening ports and connections, from opening `tcp:`
+    Status(Vec<u8>),
+}
+
+impl Resource {
+    /// A resource for a new connection
+    fn connection(connection: Connection) -> Box<Resource> {
+        box Resource::Connection(Rc::new(RefCell::new(connection)))
+    }
+
+    pub fn dup(&self) -> Result<Box<Resource>> {
+        match *self {
+            Resource::Connection(ref connection) => {
+                Ok(box Resource::Connection(connection.clone()))
+            }
+            Resource::Listener(ref listener) => {
+                listener.borrow_mut().accept().map(Resource::connection)
+            }
+            Resource::Status(ref data) => Ok(box Resource::Status(data.clone())),
+        }
+    }
+
+    pub fn path(&self) -> Result<String> {
+        match *self {
+            Resource::Connection(ref connection) => connection.borrow().path(),
+            Resource::Listener(ref listener) => listener.borrow().path(),
+            Resource::Status(_) => Ok("tcp:".to_string()),
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            Resource::Connection(ref connection) => connection.borrow_mut().read(buf),
+            Resource::Listener(_) => Err(Error::new(ENOTCONN)),
+            Resource::Status(ref mut data) => {
+                let count = cmp::min(buf.len(), data.len());
+                for (b, d) in buf.iter_mut().zip(data.drain(..count)) {
+                    *b = d;
+                }
+                Ok(count)
+            }
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match *self {
+            Resource::Connection(ref connection) => connection.borrow_mut().write(buf),
+            Resource::Listener(_) | Resource::Status(_) => Err(Error::new(ENOTCONN)),
+        }
+    }
+
+    pub fn seek(&mut self, _: SeekFrom) -> Result<u64> {
+        Err(Error::new(ESPIPE))
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        match *self {
+            Resource::Connection(ref connection) => connection.borrow_mut().sync(),
+            Resource::Listener(_) | Resource::Status(_) => Ok(()),
+        }
+    }
+
+    /// Shut down a connection, as there is no call for it. `how` is `TCP_SHUT_RD`,
+    /// `TCP_SHUT_WR`, or `TCP_SHUT_RDWR`, and applies to every duplicate of the resource
+    pub fn truncate(&mut self, how: usize) -> Result<()> {
+        match *self {
+            Resource::Connection(ref connection) => {
+                let mut connection = connection.borrow_mut();
+                match how {
+                    TCP_SHUT_RD => {
+                        connection.shutdown_read();
+                        Ok(())
+                    }
+                    TCP_SHUT_WR => connection.shutdown(),
+                    TCP_SHUT_RDWR => {
+                        connection.shutdown_read();
+                        connection.shutdown()
+                    }
+                    _ => Err(Error::new(EINVAL)),
+                }
+            }
+            Resource::Listener(_) | Resource::Status(_) => Err(Error::new(ENOTCONN)),
         }
+    }
+}
 
-        self.ip.write(&tcp.to_bytes());
+impl Drop for Resource {
+    /// Close a connection when its last resource is dropped
+    fn drop(&mut self) {
+        if let Resource::Connection(_) = *self {
+            let resource = mem::replace(self, Resource::Status(Vec::new()));
+            if let Resource::Connection(connection) = resource {
+                if let Ok(connection) = Rc::try_unwrap(connection) {
+                    connection.into_inner().close();
+                }
+            }
+        }
     }
 }
 
 /// A TCP scheme
-pub struct Scheme;
+pub struct Scheme {
+    ports: Arc<Mutex<Ports>>,
+}
 
 impl Scheme {
     pub fn new() -> Box<Scheme> {
-        box Scheme
+        box Scheme { ports: Ports::new() }
     }
 
     pub fn open(&mut self, url_str: &str, _: usize) -> Result<Box<Resource>> {
         let url = Url::from_str(&url_str);
 
         if !url.host().is_empty() && !url.port().is_empty() {
-            let peer_addr = IPv4Addr::from_string(&url.host());
+            let peer_addr = match IPv4Addr::resolve(&url.host()) {
+                Some(peer_addr) => peer_addr,
+                None => return Err(Error::new(ENOENT)),
+            };
             let peer_port = url.port().to_num() as u16;
-            let host_port = (rand() % 32768 + 32768) as u16;
+            let host_port = self.ports.lock().unwrap().client_port(peer_addr, peer_port);
 
-            match File::open(&("ip://".to_string() + &peer_addr.to_string() + "/6")) {
-                Ok(ip) => {
-                    let mut ret = box Resource {
-                        ip: ip,
-                        peer_addr: peer_addr,
-                        peer_port: peer_port,
-                        host_port: host_port,
-                        sequence: rand() as u32,
-                        acknowledge: 0,
-                    };
+            let ip = try!(open_nonblock(&("ip://".to_string() + &peer_addr.to_string() + "/6")));
+            let mut connection = Connection::new(ip,
+                                                 peer_addr,
+                                                 peer_port,
+                                                 host_port,
+                                                 self.ports.clone());
+            connection.keepalive = url.path() == TCP_KEEPALIVE;
+            try!(connection.connect());
 
-                    if ret.client_establish() {
-                        return Ok(ret);
-                    }
-                }
-                Err(err) => return Err(err),
-            }
+            return Ok(Resource::connection(connection));
         } else if !url.path().is_empty() {
-            let host_port = url.path().to_num() as u16;
-
-            while let Ok(mut ip) = File::open("ip:///6") {
-                let mut bytes: Vec<u8> = Vec::new();
-                match ip.read_to_end(&mut bytes) {
-                    Ok(_) => {
-                        if let Some(segment) = Tcp::from_bytes(bytes) {
-                            if segment.header.dst.get() == host_port &&
-                               (segment.header.flags.get() & (TCP_PSH | TCP_SYN | TCP_ACK)) ==
-                               TCP_SYN {
-                                if let Ok(path) = ip.path() {
-                                    let url = Url::from_string(path.to_string());
-
-                                    let peer_addr = IPv4Addr::from_string(&url.host());
-
-                                    let mut ret = box Resource {
-                                        ip: ip,
-                                        peer_addr: peer_addr,
-                                        peer_port: segment.header.src.get(),
-                                        host_port: host_port,
-                                        sequence: rand() as u32,
-                                        acknowledge: segment.header.sequence.get(),
-                                    };
-
-                                    if ret.server_establish(segment) {
-                                        return Ok(ret);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => return Err(err),
-                }
+            let path = url.path();
+            let mut parts = path.split('/');
+            let host_port = parts.next().unwrap_or("").to_num() as u16;
+            let keepalive = parts.next() == Some(TCP_KEEPALIVE);
+
+            if self.ports.lock().unwrap().listening.contains(&host_port) {
+                return Err(Error::new(EADDRINUSE));
             }
+
+            let ip = try!(open_nonblock(&("ip://".to_string() + &ANY_IP_ADDR.to_string() + "/6")));
+            return Ok(box Resource::Listener(RefCell::new(Listener::new(ip,
+                                                                         host_port,
+                                                                         keepalive,
+                                                                         self.ports.clone()))));
+        } else if url.host().is_empty() {
+            let status = self.ports.lock().unwrap().status();
+            return Ok(box Resource::Status(status.into_bytes()));
         }
 
         Err(Error::new(ENOENT))
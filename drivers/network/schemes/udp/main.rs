@@ -1,14 +1,21 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{Result, Read, Write, SeekFrom};
 use std::mem;
 use std::net::*;
 use std::ptr;
 use std::rand;
+use std::rc::Rc;
 use std::slice;
+use std::string::{String, ToString};
+use std::thread;
 use std::to_num::*;
 use std::url::Url;
 
-use system::error::{Error, ENOENT, ESPIPE};
+use system::error::{Error, EADDRINUSE, EBADF, EDESTADDRREQ, EMSGSIZE, ENOENT, ESPIPE};
+use system::syscall::{sys_open, O_NONBLOCK, O_RDWR};
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -51,105 +58,374 @@ impl ToBytes for Udp {
     }
 }
 
-/// UDP resource
-pub struct Resource {
+/// Open a resource that returns zero from read when nothing is waiting
+fn open_nonblock(path: &str) -> Result<File> {
+    let path_c = path.to_string() + "\0";
+    unsafe { File::from_fd(sys_open(path_c.as_ptr(), O_RDWR | O_NONBLOCK, 0)) }
+}
+
+/// Send a datagram to a peer over an IP resource
+fn send(ip: &mut File, peer_addr: IPv4Addr, peer_port: u16, host_port: u16, data: &[u8])
+        -> Result<()> {
+    let mut udp = Udp {
+        header: UdpHeader {
+            src: n16::new(host_port),
+            dst: n16::new(peer_port),
+            len: n16::new((mem::size_of::<UdpHeader>() + data.len()) as u16),
+            checksum: Checksum { data: 0 },
+        },
+        data: Vec::from(data),
+    };
+
+    unsafe {
+        let host_addr = peer_addr.host_addr();
+        let proto = n16::new(0x11);
+        let datagram_len = n16::new((mem::size_of::<UdpHeader>() + udp.data.len()) as u16);
+        udp.header.checksum.data =
+            Checksum::compile(Checksum::sum((&host_addr as *const IPv4Addr) as usize,
+                                            mem::size_of::<IPv4Addr>()) +
+                              Checksum::sum((&peer_addr as *const IPv4Addr) as usize,
+                                            mem::size_of::<IPv4Addr>()) +
+                              Checksum::sum((&proto as *const n16) as usize,
+                                            mem::size_of::<n16>()) +
+                              Checksum::sum((&datagram_len as *const n16) as usize,
+                                            mem::size_of::<n16>()) +
+                              Checksum::sum((&udp.header as *const UdpHeader) as usize,
+                                            mem::size_of::<UdpHeader>()) +
+                              Checksum::sum(udp.data.as_ptr() as usize, udp.data.len()));
+    }
+
+    ip.write(&udp.to_bytes()).map(|_| ())
+}
+
+/// A received datagram, with its source
+pub struct Datagram {
+    pub addr: IPv4Addr,
+    pub port: u16,
+    pub data: Vec<u8>,
+}
+
+/// The open sockets of the scheme, by identifier, with their host port, peer address, and peer
+/// port
+pub struct Sockets {
+    next_id: usize,
+    open: BTreeMap<usize, (u16, IPv4Addr, u16)>,
+}
+
+impl Sockets {
+    pub fn new() -> Rc<RefCell<Sockets>> {
+        Rc::new(RefCell::new(Sockets {
+            next_id: 0,
+            open: BTreeMap::new(),
+        }))
+    }
+
+    fn add(&mut self, host_port: u16, peer_addr: IPv4Addr, peer_port: u16) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open.insert(id, (host_port, peer_addr, peer_port));
+        id
+    }
+
+    /// Check if an unconnected socket is bound to a port, duplicates of it share the port
+    fn bound(&self, host_port: u16) -> bool {
+        self.open.values().any(|&(port, _, peer_port)| port == host_port && peer_port == 0)
+    }
+
+    /// Choose an ephemeral port that no socket uses
+    fn client_port(&self) -> u16 {
+        loop {
+            let host_port = (rand() % 32768 + 32768) as u16;
+            if !self.open.values().any(|&(port, _, _)| port == host_port) {
+                return host_port;
+            }
+        }
+    }
+
+    /// List the sockets, one per line with the local and peer address
+    pub fn status(&self) -> String {
+        let mut string = String::new();
+        for &(host_port, peer_addr, peer_port) in self.open.values() {
+            if peer_port == 0 {
+                string = string + &format!("{}:{} {}:*\n",
+                                           ANY_IP_ADDR.to_string(),
+                                           host_port,
+                                           ANY_IP_ADDR.to_string());
+            } else {
+                string = string + &format!("{}:{} {}:{}\n",
+                                           peer_addr.host_addr().to_string(),
+                                           host_port,
+                                           peer_addr.to_string(),
+                                           peer_port);
+            }
+        }
+        string
+    }
+}
+
+/// A UDP socket. A connected socket exchanges datagrams with one peer, an unconnected socket
+/// receives from and sends to any peer, with a `UdpMeta` before the data of every read and write
+pub struct Socket {
     ip: File,
-    data: Vec<u8>,
+    /// IP resources of the peers an unconnected resource sent to
+    peers: Vec<(IPv4Addr, File)>,
+    /// A datagram that did not fit in the buffer of the last read
+    datagram: Option<Datagram>,
     peer_addr: IPv4Addr,
+    /// The peer port, zero if unconnected
     peer_port: u16,
     host_port: u16,
+    /// ICMP errors about UDP datagrams, read by connected sockets. Their IP resource does not
+    /// block, so that both are checked
+    errors: Option<File>,
+    /// The flags used to open the resource, `O_NONBLOCK` makes reads return 0 when nothing is
+    /// waiting
+    flags: usize,
+    sockets: Rc<RefCell<Sockets>>,
+    id: usize,
 }
 
-impl Resource {
-    pub fn dup(&self) -> Result<Box<Self>> {
+impl Socket {
+    fn new(ip: File,
+           peer_addr: IPv4Addr,
+           peer_port: u16,
+           host_port: u16,
+           errors: Option<File>,
+           flags: usize,
+           sockets: Rc<RefCell<Sockets>>)
+           -> Socket {
+        let id = sockets.borrow_mut().add(host_port, peer_addr, peer_port);
+        Socket {
+            ip: ip,
+            peers: Vec::new(),
+            datagram: None,
+            peer_addr: peer_addr,
+            peer_port: peer_port,
+            host_port: host_port,
+            errors: errors,
+            flags: flags,
+            sockets: sockets,
+            id: id,
+        }
+    }
+
+    pub fn dup(&self) -> Result<Socket> {
         match self.ip.dup() {
             Ok(ip) => {
-                Ok(box Resource {
-                    ip: ip,
-                    data: self.data.clone(),
-                    peer_addr: self.peer_addr,
-                    peer_port: self.peer_port,
-                    host_port: self.host_port,
-                })
+                let errors = match self.errors {
+                    Some(ref errors) => errors.dup().ok(),
+                    None => None,
+                };
+                Ok(Socket::new(ip,
+                               self.peer_addr,
+                               self.peer_port,
+                               self.host_port,
+                               errors,
+                               self.flags,
+                               self.sockets.clone()))
             }
             Err(err) => Err(err),
         }
     }
 
+    fn connected(&self) -> bool {
+        self.peer_port != 0
+    }
+
     pub fn path(&self) -> Result<String> {
-        Ok(format!("udp://{}:{}/{}",
-                   self.peer_addr.to_string(),
-                   self.peer_port,
-                   self.host_port))
+        if self.connected() {
+            Ok(format!("udp://{}:{}/{}",
+                       self.peer_addr.to_string(),
+                       self.peer_port,
+                       self.host_port))
+        } else {
+            Ok(format!("udp:///{}", self.host_port))
+        }
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if !self.data.is_empty() {
-            let mut bytes: Vec<u8> = Vec::new();
-            mem::swap(&mut self.data, &mut bytes);
+    /// Fail with the first ICMP error about a datagram sent to the peer
+    fn check_errors(&mut self) -> Result<()> {
+        let mut buf = [0; 64];
+        loop {
+            let count = match self.errors {
+                Some(ref mut errors) => try!(errors.read(&mut buf)),
+                None => 0,
+            };
+            if count < mem::size_of::<IcmpError>() {
+                return Ok(());
+            }
 
-            // TODO: Allow splitting
-            let i = 0;
-            while i < buf.len() && i < bytes.len() {
-                buf[i] = bytes[i];
+            let error = unsafe { ptr::read(buf.as_ptr() as *const IcmpError) };
+            if error.src_port.get() == self.host_port && error.dst.equals(self.peer_addr) &&
+               error.dst_port.get() == self.peer_port {
+                return Err(Error::new(error.errno()));
             }
-            return Ok(i);
+        }
+    }
+
+    /// Wait for the next datagram to this resource, or return `None` if nothing is waiting and
+    /// the resource does not block
+    fn receive(&mut self) -> Result<Option<Datagram>> {
+        if let Some(datagram) = self.datagram.take() {
+            return Ok(Some(datagram));
         }
 
         loop {
-            let mut bytes: Vec<u8> = Vec::new();
-            match self.ip.read_to_end(&mut bytes) {
-                Ok(_) => {
-                    if let Some(datagram) = Udp::from_bytes(bytes) {
-                        if datagram.header.dst.get() == self.host_port &&
-                           datagram.header.src.get() == self.peer_port {
-                            // TODO: Allow splitting
-                            let i = 0;
-                            while i < buf.len() && i < datagram.data.len() {
-                                buf[i] = datagram.data[i];
-                            }
-                            return Ok(i);
-                        }
-                    }
+            let mut bytes = vec![0; 65536];
+            let count = try!(self.ip.read(&mut bytes));
+            if count == 0 {
+                try!(self.check_errors());
+                if self.flags & O_NONBLOCK == O_NONBLOCK {
+                    return Ok(None);
+                }
+                thread::yield_now();
+                continue;
+            }
+            bytes.truncate(count);
+            if let Some(udp) = Udp::from_bytes(bytes) {
+                if udp.header.dst.get() == self.host_port &&
+                   (!self.connected() || udp.header.src.get() == self.peer_port) {
+                    let addr = if self.connected() {
+                        self.peer_addr
+                    } else {
+                        let path = try!(self.ip.path());
+                        IPv4Addr::from_string(&Url::from_string(path.to_string()).host())
+                    };
+
+                    return Ok(Some(Datagram {
+                        addr: addr,
+                        port: udp.header.src.get(),
+                        data: udp.data,
+                    }));
                 }
-                Err(err) => return Err(err),
             }
         }
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let udp_data = Vec::from(buf);
-
-        let mut udp = Udp {
-            header: UdpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                len: n16::new((mem::size_of::<UdpHeader>() + udp_data.len()) as u16),
-                checksum: Checksum { data: 0 },
-            },
-            data: udp_data,
+    /// Read one datagram. A connected resource fails with `EMSGSIZE` if the datagram does not
+    /// fit, keeping it for the next read. An unconnected resource truncates the data, the `len`
+    /// of the `UdpMeta` is the length before truncation. A resource opened with `O_NONBLOCK`
+    /// returns 0 when nothing is waiting
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let datagram = match try!(self.receive()) {
+            Some(datagram) => datagram,
+            None => return Ok(0),
         };
 
-        unsafe {
-            let proto = n16::new(0x11);
-            let datagram_len = n16::new((mem::size_of::<UdpHeader>() + udp.data.len()) as u16);
-            udp.header.checksum.data =
-                Checksum::compile(Checksum::sum((&IP_ADDR as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&self.peer_addr as *const IPv4Addr) as usize,
-                                                mem::size_of::<IPv4Addr>()) +
-                                  Checksum::sum((&proto as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&datagram_len as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&udp.header as *const UdpHeader) as usize,
-                                                mem::size_of::<UdpHeader>()) +
-                                  Checksum::sum(udp.data.as_ptr() as usize, udp.data.len()));
-        }
-
-        match self.ip.write(&udp.to_bytes()) {
-            Ok(_) => Ok(buf.len()),
-            Err(err) => Err(err),
+        if self.connected() {
+            if datagram.data.len() > buf.len() {
+                self.datagram = Some(datagram);
+                return Err(Error::new(EMSGSIZE));
+            }
+
+            for (b, d) in buf.iter_mut().zip(datagram.data.iter()) {
+                *b = *d;
+            }
+            Ok(datagram.data.len())
+        } else {
+            let meta_size = mem::size_of::<UdpMeta>();
+            if buf.len() < meta_size {
+                self.datagram = Some(datagram);
+                return Err(Error::new(EMSGSIZE));
+            }
+
+            let meta = UdpMeta {
+                addr: datagram.addr,
+                port: n16::new(datagram.port),
+                len: n16::new(datagram.data.len() as u16),
+            };
+            unsafe { ptr::write(buf.as_mut_ptr() as *mut UdpMeta, meta) };
+
+            let mut i = 0;
+            while meta_size + i < buf.len() && i < datagram.data.len() {
+                buf[meta_size + i] = datagram.data[i];
+                i += 1;
+            }
+            Ok(meta_size + i)
+        }
+    }
+
+    /// Write one datagram, after a `UdpMeta` with the destination if unconnected
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.connected() {
+            try!(send(&mut self.ip, self.peer_addr, self.peer_port, self.host_port, buf));
+            return Ok(buf.len());
+        }
+
+        let meta_size = mem::size_of::<UdpMeta>();
+        if buf.len() < meta_size {
+            return Err(Error::new(EDESTADDRREQ));
+        }
+        let meta = unsafe { ptr::read(buf.as_ptr() as *const UdpMeta) };
+        if meta.port.get() == 0 {
+            return Err(Error::new(EDESTADDRREQ));
+        }
+
+        let host_port = self.host_port;
+        let data = &buf[meta_size..];
+        for &mut (ref addr, ref mut ip) in self.peers.iter_mut() {
+            if addr.equals(meta.addr) {
+                try!(send(ip, meta.addr, meta.port.get(), host_port, data));
+                return Ok(buf.len());
+            }
+        }
+
+        let mut ip = try!(File::open(&format!("ip://{}/11", meta.addr.to_string())));
+        try!(send(&mut ip, meta.addr, meta.port.get(), host_port, data));
+        self.peers.push((meta.addr, ip));
+        Ok(buf.len())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.ip.sync_all()
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.sockets.borrow_mut().open.remove(&self.id);
+    }
+}
+
+/// UDP resource
+pub enum Resource {
+    Socket(Socket),
+    /// The list of open sockets, from opening `udp:`
+    Status(Vec<u8>),
+}
+
+impl Resource {
+    pub fn dup(&self) -> Result<Box<Resource>> {
+        match *self {
+            Resource::Socket(ref socket) => socket.dup().map(|socket| box Resource::Socket(socket)),
+            Resource::Status(ref data) => Ok(box Resource::Status(data.clone())),
+        }
+    }
+
+    pub fn path(&self) -> Result<String> {
+        match *self {
+            Resource::Socket(ref socket) => socket.path(),
+            Resource::Status(_) => Ok("udp:".to_string()),
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            Resource::Socket(ref mut socket) => socket.read(buf),
+            Resource::Status(ref mut data) => {
+                let count = cmp::min(buf.len(), data.len());
+                for (b, d) in buf.iter_mut().zip(data.drain(..count)) {
+                    *b = d;
+                }
+                Ok(count)
+            }
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match *self {
+            Resource::Socket(ref mut socket) => socket.write(buf),
+            Resource::Status(_) => Err(Error::new(EBADF)),
         }
     }
 
@@ -158,59 +434,76 @@ impl Resource {
     }
 
     pub fn sync(&mut self) -> Result<()> {
-        self.ip.sync_all()
+        match *self {
+            Resource::Socket(ref mut socket) => socket.sync(),
+            Resource::Status(_) => Ok(()),
+        }
     }
 }
 
 /// UDP scheme
-pub struct Scheme;
+pub struct Scheme {
+    sockets: Rc<RefCell<Sockets>>,
+}
 
 impl Scheme {
     pub fn new() -> Box<Self> {
-        box Scheme
+        box Scheme { sockets: Sockets::new() }
     }
 
-    pub fn open(&mut self, url_str: &str, _: usize) -> Result<Box<Resource>> {
+    pub fn open(&mut self, url_str: &str, flags: usize) -> Result<Box<Resource>> {
         let url = Url::from_str(&url_str);
 
-        // Check host and port vs path
-        if !url.path().is_empty() {
-            let host_port = url.port().to_num();
+        if url.host().is_empty() {
+            if url.path().is_empty() {
+                let status = self.sockets.borrow().status();
+                return Ok(box Resource::Status(status.into_bytes()));
+            }
+
+            // Unconnected, bound to the port in the path
+            let host_port = url.path().to_num();
             if host_port > 0 && host_port < 65536 {
-                if let Ok(mut ip) = File::open("ip:///11") {
-                    let mut bytes: Vec<u8> = Vec::new();
-                    if let Ok(_) = ip.read_to_end(&mut bytes) {
-                        if let Some(datagram) = Udp::from_bytes(bytes) {
-                            if datagram.header.dst.get() as u32 == host_port {
-                                if let Ok(path) = ip.path() {
-                                    let url = Url::from_string(path.to_string());
-
-                                    return Ok(box Resource {
-                                        ip: ip,
-                                        data: datagram.data,
-                                        peer_addr: IPv4Addr::from_string(&url.host()),
-                                        peer_port: datagram.header.src.get(),
-                                        host_port: host_port as u16,
-                                    });
-                                }
-                            }
-                        }
-                    }
+                if self.sockets.borrow().bound(host_port as u16) {
+                    return Err(Error::new(EADDRINUSE));
                 }
+
+                let path = format!("ip://{}/11", ANY_IP_ADDR.to_string());
+                let ip = if flags & O_NONBLOCK == O_NONBLOCK {
+                    try!(open_nonblock(&path))
+                } else {
+                    try!(File::open(&path))
+                };
+                return Ok(box Resource::Socket(Socket::new(ip,
+                                                           ANY_IP_ADDR,
+                                                           0,
+                                                           host_port as u16,
+                                                           None,
+                                                           flags,
+                                                           self.sockets.clone())));
             }
         } else {
+            // Connected to the peer, from the port in the path or a random one
             let peer_port = url.port().to_num();
             if peer_port > 0 && peer_port < 65536 {
-                let host_port = (rand() % 32768 + 32768) as u16;
-
-                if let Ok(ip) = File::open(&format!("ip://{}/11", url.host())) {
-                    return Ok(box Resource {
-                        ip: ip,
-                        data: Vec::new(),
-                        peer_addr: IPv4Addr::from_string(&url.host()),
-                        peer_port: peer_port as u16,
-                        host_port: host_port,
-                    });
+                let host_port = match url.path().to_num() {
+                    port if port > 0 && port < 65536 => port as u16,
+                    _ => self.sockets.borrow().client_port(),
+                };
+
+                let peer_addr = match IPv4Addr::resolve(&url.host()) {
+                    Some(peer_addr) => peer_addr,
+                    None => return Err(Error::new(ENOENT)),
+                };
+
+                if let Ok(ip) = open_nonblock(&format!("ip://{}/11", peer_addr.to_string())) {
+                    let errors = open_nonblock("icmp:error/11").ok();
+                    return Ok(box Resource::Socket(Socket::new(ip,
+                                                               peer_addr,
+                                                               peer_port as u16,
+                                                               host_port,
+                                                               errors,
+                                                               flags,
+                                                               self.sockets.clone())));
                 }
             }
         }
@@ -0,0 +1,85 @@
+extern crate http;
+
+use std::env;
+use std::fs::File;
+use std::io::{Result, Write};
+
+use http::{Client, Request};
+
+/// The file the body is saved to, created when the first data is written so that a failed
+/// request does not leave one behind
+struct Output<'a> {
+    path: &'a str,
+    file: Option<File>,
+    count: usize,
+}
+
+impl<'a> Output<'a> {
+    fn file(&mut self) -> Result<&mut File> {
+        if self.file.is_none() {
+            self.file = Some(try!(File::create(self.path)));
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+impl<'a> Write for Output<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let count = try!(try!(self.file()).write(buf));
+        self.count += count;
+        Ok(count)
+    }
+}
+
+fn main() {
+    let args: Vec<&str> = env::args().map(|arg| *arg).collect();
+
+    let url = match args.get(1) {
+        Some(url) => url,
+        None => {
+            println!("Usage: fetch url [file]");
+            return;
+        }
+    };
+    // Default to the last part of the path
+    let path = match args.get(2) {
+        Some(path) => path.to_string(),
+        None => {
+            let name = url.split('?').next().unwrap_or("").split('/').last().unwrap_or("");
+            if name.is_empty() || url.split('/').count() <= 3 {
+                "index.html".to_string()
+            } else {
+                name.to_string()
+            }
+        }
+    };
+
+    // The body is written to the file as it is received
+    let mut output = Output {
+        path: &path,
+        file: None,
+        count: 0,
+    };
+
+    let mut client = Client::new();
+    let response = match client.send_to(Request::get(url).header("User-Agent", "fetch"),
+                                        &mut output) {
+        Ok(response) => response,
+        Err(err) => {
+            println!("fetch: {}: {}", url, err);
+            return;
+        }
+    };
+
+    if !response.is_success() {
+        println!("fetch: {}: {} {}", url, response.status, response.reason);
+        return;
+    }
+
+    // An empty body still creates the file
+    if let Err(err) = output.file() {
+        println!("fetch: {}: {}", path, err);
+        return;
+    }
+    println!("{}: {} bytes", path, output.count);
+}
@@ -0,0 +1,89 @@
+extern crate system;
+
+use std::env;
+use std::to_num::ToNum;
+
+use system::error::Error;
+use system::syscall::*;
+
+/// Signals by name, without the `SIG` prefix
+const SIGNALS: [(&'static str, usize); 18] = [("HUP", SIGHUP),
+                                              ("INT", SIGINT),
+                                              ("QUIT", SIGQUIT),
+                                              ("ILL", SIGILL),
+                                              ("ABRT", SIGABRT),
+                                              ("FPE", SIGFPE),
+                                              ("KILL", SIGKILL),
+                                              ("USR1", SIGUSR1),
+                                              ("SEGV", SIGSEGV),
+                                              ("USR2", SIGUSR2),
+                                              ("PIPE", SIGPIPE),
+                                              ("ALRM", SIGALRM),
+                                              ("TERM", SIGTERM),
+                                              ("CHLD", SIGCHLD),
+                                              ("CONT", SIGCONT),
+                                              ("STOP", SIGSTOP),
+                                              ("TSTP", SIGTSTP),
+                                              ("WINCH", SIGWINCH)];
+
+fn parse_signal(name: &str) -> Option<usize> {
+    if !name.is_empty() && name.chars().all(|c| c.is_digit(10)) {
+        let sig = name.to_num();
+        if sig < NSIG {
+            return Some(sig);
+        }
+        return None;
+    }
+
+    let name = if name.starts_with("SIG") {
+        &name[3..]
+    } else {
+        name
+    };
+    SIGNALS.iter().find(|&&(signal_name, _)| signal_name == name).map(|&(_, sig)| sig)
+}
+
+fn main() {
+    let args: Vec<&str> = env::args().skip(1).map(|arg| *arg).collect();
+
+    if args.first() == Some(&"-l") {
+        for &(name, sig) in SIGNALS.iter() {
+            println!("{:>2} {}", sig, name);
+        }
+        return;
+    }
+
+    // A signal comes first, as `-s name`, `-name` or `-number`
+    let (name, first) = match args.first() {
+        Some(&"-s") => (args.get(1).map(|name| *name), 2),
+        Some(arg) if arg.starts_with('-') && args.len() > 1 => (Some(&arg[1..]), 1),
+        _ => (None, 0),
+    };
+    let sig = match name {
+        Some(name) => match parse_signal(name) {
+            Some(sig) => sig,
+            None => {
+                println!("kill: {}: Invalid signal", name);
+                return;
+            }
+        },
+        None => SIGTERM,
+    };
+    let pids = if first < args.len() {
+        &args[first..]
+    } else {
+        &[]
+    };
+
+    if pids.is_empty() {
+        println!("Usage: kill [-s signal | -signal] pid...\n       kill -l");
+        return;
+    }
+
+    for pid in pids.iter() {
+        let pid_num = pid.to_num_signed() as isize;
+        if let Err(err) = Error::demux(unsafe { sys_kill(pid_num, sig) }) {
+            println!("kill: {}: {}", pid, err);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::io::Read;
+
+/// Print a resource, or nothing if it can not be opened
+fn show(path: &str) {
+    if let Ok(mut file) = File::open(path) {
+        let mut string = String::new();
+        if file.read_to_string(&mut string).is_ok() {
+            print!("{}", string);
+        }
+    }
+}
+
+/// Print a list of sockets, with a column for the protocol
+fn show_sockets(proto: &str, path: &str) {
+    if let Ok(mut file) = File::open(path) {
+        let mut string = String::new();
+        if file.read_to_string(&mut string).is_ok() {
+            for line in string.lines() {
+                let columns: Vec<&str> = line.split(' ').collect();
+                println!("{:<6}{:<22}{:<22}{}",
+                         proto,
+                         columns.get(0).unwrap_or(&""),
+                         columns.get(1).unwrap_or(&""),
+                         columns.get(2).unwrap_or(&""));
+            }
+        }
+    }
+}
+
+fn main() {
+    println!("Interfaces");
+    show("network:stats");
+    println!("");
+    show("loopback:stats");
+    println!("");
+
+    println!("Connections");
+    println!("{:<6}{:<22}{:<22}{}", "Proto", "Local Address", "Peer Address", "State");
+    show_sockets("tcp", "tcp:");
+    show_sockets("udp", "udp:");
+}
@@ -0,0 +1,123 @@
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::*;
+use std::ptr;
+use std::thread;
+use std::to_num::ToNum;
+
+/// Bytes of data in every request
+const PING_DATA: usize = 56;
+
+fn main() {
+    let args: Vec<&str> = env::args().map(|arg| *arg).collect();
+
+    let host = match args.get(1) {
+        Some(host) => host,
+        None => {
+            println!("Usage: ping host [count]");
+            return;
+        }
+    };
+    let count = match args.get(2) {
+        Some(count) => count.to_num(),
+        None => 4,
+    };
+
+    let addr = match IPv4Addr::resolve(host) {
+        Some(addr) => addr,
+        None => {
+            println!("ping: {}: Could not resolve host", host);
+            return;
+        }
+    };
+
+    let mut icmp = match File::open(&format!("icmp:echo/{}", addr.to_string())) {
+        Ok(icmp) => icmp,
+        Err(err) => {
+            println!("ping: {}: {}", addr.to_string(), err);
+            return;
+        }
+    };
+
+    println!("PING {} ({}) {} bytes of data", host, addr.to_string(), PING_DATA);
+
+    let data: Vec<u8> = (0..PING_DATA).map(|i| i as u8).collect();
+    let mut transmitted = 0;
+    let mut received = 0;
+    let mut min = u32::max_value();
+    let mut max = 0;
+    let mut total: u64 = 0;
+
+    for i in 0..count {
+        if i > 0 {
+            thread::sleep_ms(1000);
+        }
+
+        if let Err(err) = icmp.write(&data) {
+            println!("ping: {}", err);
+            break;
+        }
+        transmitted += 1;
+
+        let mut buf = [0; 65536];
+        let len = match icmp.read(&mut buf) {
+            Ok(len) => len,
+            Err(err) => {
+                println!("ping: {}", err);
+                break;
+            }
+        };
+        if len < mem::size_of::<IcmpEcho>() {
+            continue;
+        }
+
+        let echo = unsafe { ptr::read(buf.as_ptr() as *const IcmpEcho) };
+        let sequence = echo.sequence.get();
+        match echo._type {
+            ICMP_ECHO_REPLY => {
+                let rtt = echo.rtt.get();
+                received += 1;
+                total += rtt as u64;
+                if rtt < min {
+                    min = rtt;
+                }
+                if rtt > max {
+                    max = rtt;
+                }
+
+                println!("{} bytes from {}: icmp_seq={} time={}.{:03} ms",
+                         len - mem::size_of::<IcmpEcho>(),
+                         addr.to_string(),
+                         sequence,
+                         rtt / 1000,
+                         rtt % 1000);
+            }
+            ICMP_ECHO_TIMEOUT => println!("Request timeout for icmp_seq={}", sequence),
+            ICMP_UNREACHABLE => println!("Destination unreachable for icmp_seq={}", sequence),
+            ICMP_TIME_EXCEEDED => println!("Time to live exceeded for icmp_seq={}", sequence),
+            _type => println!("ICMP type {} for icmp_seq={}", _type, sequence),
+        }
+    }
+
+    println!("--- {} ping statistics ---", host);
+    println!("{} packets transmitted, {} received, {}% packet loss",
+             transmitted,
+             received,
+             if transmitted > 0 {
+                 (transmitted - received) * 100 / transmitted
+             } else {
+                 0
+             });
+    if received > 0 {
+        let avg = total / received as u64;
+        println!("rtt min/avg/max = {}.{:03}/{}.{:03}/{}.{:03} ms",
+                 min / 1000,
+                 min % 1000,
+                 avg / 1000,
+                 avg % 1000,
+                 max / 1000,
+                 max % 1000);
+    }
+}
@@ -0,0 +1,185 @@
+extern crate system;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::net::*;
+use std::time::Duration;
+
+use system::syscall::{sys_adjtime, sys_clock_gettime, sys_clock_settime, CLOCK_REALTIME,
+                      TimeSpec};
+
+/// Seconds from 1900-01-01, the NTP epoch, to 1970-01-01
+const NTP_UNIX_OFFSET: i64 = 2208988800;
+const NANOS_PER_SEC: i64 = 1000000000;
+/// Offsets smaller than this, in nanoseconds, are slewed instead of stepped
+const SLEW_LIMIT: i64 = 128000000;
+/// Seconds to wait for a reply before the request is sent again
+const TIMEOUT: i64 = 2;
+/// The number of requests sent before giving up
+const TRIES: usize = 3;
+
+/// Whole seconds of a count of nanoseconds, rounded down
+fn floor_secs(nanos: i64) -> i64 {
+    if nanos < 0 {
+        (nanos + 1) / NANOS_PER_SEC - 1
+    } else {
+        nanos / NANOS_PER_SEC
+    }
+}
+
+/// Nanoseconds since the Unix epoch to an NTP timestamp
+fn to_ntp(nanos: i64) -> u64 {
+    let secs = floor_secs(nanos);
+    let frac = nanos - secs * NANOS_PER_SEC;
+    (((secs + NTP_UNIX_OFFSET) as u64) << 32) | (((frac as u64) << 32) / NANOS_PER_SEC as u64)
+}
+
+/// An NTP timestamp to nanoseconds since the Unix epoch
+fn from_ntp(ntp: u64) -> i64 {
+    let secs = (ntp >> 32) as i64 - NTP_UNIX_OFFSET;
+    let frac = (((ntp & 0xFFFFFFFF) * NANOS_PER_SEC as u64) >> 32) as i64;
+    secs * NANOS_PER_SEC + frac
+}
+
+fn timestamp(packet: &[u8], i: usize) -> u64 {
+    let mut ntp = 0;
+    for b in packet[i..i + 8].iter() {
+        ntp = (ntp << 8) | *b as u64;
+    }
+    ntp
+}
+
+fn now() -> i64 {
+    let mut tp = TimeSpec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { sys_clock_gettime(CLOCK_REALTIME, &mut tp) };
+    tp.tv_sec * NANOS_PER_SEC + tp.tv_nsec as i64
+}
+
+fn timespec(nanos: i64) -> TimeSpec {
+    let secs = floor_secs(nanos);
+    TimeSpec {
+        tv_sec: secs,
+        tv_nsec: (nanos - secs * NANOS_PER_SEC) as i32,
+    }
+}
+
+fn main() {
+    let mut write_rtc = false;
+    let mut server = "pool.ntp.org".to_string();
+    for arg in env::args().skip(1) {
+        if *arg == "-w" {
+            write_rtc = true;
+        } else if arg.starts_with('-') {
+            println!("Usage: sntp [-w] [server]");
+            return;
+        } else {
+            server = arg.to_string();
+        }
+    }
+
+    let addr = match IPv4Addr::resolve(&server) {
+        Some(addr) => addr,
+        None => {
+            println!("sntp: {}: Could not resolve host", server);
+            return;
+        }
+    };
+
+    let mut socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("sntp: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = socket.set_read_timeout(Some(Duration::new(TIMEOUT, 0))) {
+        println!("sntp: {}", err);
+        return;
+    }
+    if let Err(err) = socket.connect(SocketAddr::new(addr, 123)) {
+        println!("sntp: {}: {}", addr.to_string(), err);
+        return;
+    }
+
+    let mut t1 = 0;
+    let mut originate = 0;
+    let mut reply = [0; 48];
+    let mut count = 0;
+    for _ in 0..TRIES {
+        // Leap indicator unknown, version 4, client mode
+        let mut request = [0; 48];
+        request[0] = 0x23;
+        t1 = now();
+        originate = to_ntp(t1);
+        for i in 0..8 {
+            request[40 + i] = (originate >> (56 - i * 8)) as u8;
+        }
+
+        if let Err(err) = socket.send(&request) {
+            println!("sntp: {}", err);
+            return;
+        }
+
+        // Replies to earlier requests do not match the originate timestamp and are skipped
+        match socket.recv(&mut reply) {
+            Ok(n) => {
+                count = n;
+                if n >= 48 && timestamp(&reply, 24) == originate {
+                    break;
+                }
+            }
+            Err(err) => {
+                println!("sntp: {}: {}", addr.to_string(), err);
+                count = 0;
+            }
+        }
+    }
+    if count == 0 {
+        println!("sntp: {}: No reply", addr.to_string());
+        return;
+    }
+    let t4 = now();
+
+    let stratum = reply[1];
+    if count < 48 || reply[0] & 0x7 != 4 || reply[0] >> 6 == 3 || stratum == 0 ||
+       stratum > 15 || timestamp(&reply, 24) != originate {
+        println!("sntp: {}: Invalid reply", addr.to_string());
+        return;
+    }
+
+    let t2 = from_ntp(timestamp(&reply, 32));
+    let t3 = from_ntp(timestamp(&reply, 40));
+    let offset = ((t2 - t1) + (t3 - t4)) / 2;
+    let delay = (t4 - t1) - (t3 - t2);
+
+    if offset.abs() < SLEW_LIMIT {
+        let delta = timespec(offset);
+        let mut olddelta = timespec(0);
+        unsafe { sys_adjtime(&delta, &mut olddelta) };
+    } else {
+        let time = timespec(now() + offset);
+        unsafe { sys_clock_settime(CLOCK_REALTIME, &time) };
+    }
+
+    println!("{}: stratum {} offset {} ms delay {} ms",
+             addr.to_string(),
+             stratum,
+             offset as f64 / 1000000.0,
+             delay as f64 / 1000000.0);
+
+    if write_rtc {
+        let secs = floor_secs(now());
+        match File::open("rtc:") {
+            Ok(mut rtc) => {
+                if let Err(err) = rtc.write(secs.to_string().as_bytes()) {
+                    println!("sntp: rtc: {}", err);
+                }
+            }
+            Err(err) => println!("sntp: rtc: {}", err),
+        }
+    }
+}
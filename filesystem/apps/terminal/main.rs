@@ -27,53 +27,59 @@ macro_rules! readln {
     });
 }
 
-pub fn pipe() -> [usize; 2] {
-    let mut fds = [0; 2];
-    Error::demux(unsafe { sys_pipe2(fds.as_mut_ptr(), 0) }).unwrap();
-    fds
+/// Open a pseudo terminal, returning the master and the path of its slave
+pub fn pty() -> (usize, String) {
+    let master = Error::demux(unsafe { sys_open("pty:\0".as_ptr(), O_RDWR, 0) }).unwrap();
+
+    let mut path = [0; 4096];
+    let count = Error::demux(unsafe { sys_fpath(master, path.as_mut_ptr(), path.len()) }).unwrap();
+
+    (master, String::from_utf8_lossy(&path[..count]).into_owned() + "\0")
 }
 
 fn main() {
-    let to_shell_fds = pipe();
-    let from_shell_fds = pipe();
+    let (master, slave) = pty();
 
     unsafe {
         if Error::demux(sys_clone(0)).unwrap() == 0 {
+            // Lead a session, so the pseudo terminal becomes its controlling terminal
+            sys_setsid();
+
             // Close STDIO
             sys_close(2);
             sys_close(1);
             sys_close(0);
 
-            // Create piped STDIO
-            sys_dup(to_shell_fds[0]);
-            sys_dup(from_shell_fds[1]);
-            sys_dup(from_shell_fds[1]);
+            // Open the pseudo terminal as STDIO
+            sys_open(slave.as_ptr(), O_RDWR, 0);
+            sys_open(slave.as_ptr(), O_RDWR, 0);
+            sys_open(slave.as_ptr(), O_RDWR, 0);
 
-            // Close extra pipes
-            sys_close(to_shell_fds[0]);
-            sys_close(to_shell_fds[1]);
-            sys_close(from_shell_fds[0]);
-            sys_close(from_shell_fds[1]);
+            // Close the master
+            sys_close(master);
 
             // Execute the shell
             let shell = "file:/apps/shell/main.bin\0";
             sys_execve(shell.as_ptr(), 0 as *const *const u8);
             panic!("Shell not found");
-        } else {
-            // Close extra pipes
-            sys_close(to_shell_fds[0]);
-            sys_close(from_shell_fds[1]);
         }
     };
 
+    let to_shell_fd = Error::demux(unsafe { sys_dup(master) }).unwrap();
+
     let window = Arc::new(ConsoleWindow::new(-1, -1, 576, 400, "Terminal"));
 
     let window_weak = Arc::downgrade(&window);
     thread::spawn(move || {
-        let mut from_shell = unsafe { File::from_fd(from_shell_fds[0]).unwrap() };
+        let mut from_shell = unsafe { File::from_fd(master).unwrap() };
         loop {
             let mut output = String::new();
             if let Ok(_) = from_shell.read_to_string(&mut output) {
+                // The shell and its jobs closed the pseudo terminal
+                if output.is_empty() {
+                    break;
+                }
+
                 if let Some(window) = window_weak.upgrade() {
                     let window_ptr =
                         (window.deref() as *const Box<ConsoleWindow>) as *mut Box<ConsoleWindow>;
@@ -89,10 +95,13 @@ fn main() {
     });
 
     {
-        let mut to_shell = unsafe { File::from_fd(to_shell_fds[1]).unwrap() };
+        let mut to_shell = unsafe { File::from_fd(to_shell_fd).unwrap() };
         let window_ptr = (window.deref() as *const Box<ConsoleWindow>) as *mut Box<ConsoleWindow>;
         while let Some(mut string) = unsafe { &mut *window_ptr }.read() {
-            string.push('\n');
+            // Control characters, like an interrupt, are sent without a line
+            if !string.starts_with(|c: char| c.is_control()) {
+                string.push('\n');
+            }
             if let Ok(_) = to_shell.write(&string.into_bytes()) {
 
             } else {
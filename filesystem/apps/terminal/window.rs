@@ -27,6 +27,8 @@ pub struct ConsoleWindow {
     pub scroll_y: i32,
     /// Wrap the text, if true
     pub wrap: bool,
+    /// The control key is held
+    pub ctrl: bool,
 }
 
 impl ConsoleWindow {
@@ -41,6 +43,7 @@ impl ConsoleWindow {
             scroll_x: 0,
             scroll_y: 0,
             wrap: true,
+            ctrl: false,
         })
     }
 
@@ -76,7 +79,22 @@ impl ConsoleWindow {
         while let Some(event) = self.poll() {
             match event.to_option() {
                 EventOption::Key(key_event) => {
-                    if key_event.pressed {
+                    if key_event.scancode == K_CTRL {
+                        self.ctrl = key_event.pressed;
+                    } else if key_event.pressed && self.ctrl {
+                        // Interrupt or suspend the foreground job, dropping the line
+                        let (echo, control) = match key_event.character {
+                            'c' | 'C' => ("^C\n", "\x03"),
+                            'z' | 'Z' => ("^Z\n", "\x1A"),
+                            _ => continue,
+                        };
+
+                        self.offset = 0;
+                        self.history_i = 0;
+                        self.history[0].clear();
+                        self.print(echo, Color::WHITE);
+                        return Some(control.to_string());
+                    } else if key_event.pressed {
                         match key_event.scancode {
                             K_BKSP => {
                                 if self.offset > 0 {
@@ -0,0 +1,38 @@
+use core::ptr;
+
+use super::SDTHeader;
+
+#[repr(packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct HPET {
+    pub header: &'static SDTHeader,
+    pub event_timer_block_id: u32,
+    pub address_space: u8,
+    pub address: u64,
+    pub hpet_number: u8,
+    pub min_tick: u16,
+}
+
+impl HPET {
+    pub fn new(header: &'static SDTHeader) -> Option<Self> {
+        if header.valid("HPET") {
+            let data: &'static [u8] = header.data();
+            if data.len() >= 19 {
+                unsafe {
+                    Some(HPET {
+                        header: header,
+                        event_timer_block_id: ptr::read(data.as_ptr() as *const u32),
+                        address_space: data[4],
+                        address: ptr::read(data.as_ptr().offset(8) as *const u64),
+                        hpet_number: data[16],
+                        min_tick: ptr::read(data.as_ptr().offset(17) as *const u16),
+                    })
+                }
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+}
@@ -3,6 +3,7 @@ use schemes::{Result, KScheme, Resource, Url};
 use syscall::{Error, O_CREAT, ENOENT};
 pub use self::dsdt::DSDT;
 pub use self::fadt::FADT;
+pub use self::hpet::HPET;
 pub use self::madt::MADT;
 pub use self::rsdt::RSDT;
 pub use self::sdt::SDTHeader;
@@ -11,6 +12,7 @@ pub use self::ssdt::SSDT;
 pub mod aml;
 pub mod dsdt;
 pub mod fadt;
+pub mod hpet;
 pub mod madt;
 pub mod rsdt;
 pub mod sdt;
@@ -23,6 +25,7 @@ pub struct Acpi {
     dsdt: Option<DSDT>,
     ssdt: Option<SSDT>,
     madt: Option<MADT>,
+    hpet: Option<HPET>,
 }
 
 impl Acpi {
@@ -37,6 +40,7 @@ impl Acpi {
                     dsdt: None,
                     ssdt: None,
                     madt: None,
+                    hpet: None,
                 };
 
                 for addr in acpi.rsdt.addrs.iter() {
@@ -57,6 +61,8 @@ impl Acpi {
                         acpi.ssdt = Some(ssdt);
                     } else if let Some(madt) = MADT::new(header) {
                         acpi.madt = Some(madt);
+                    } else if let Some(hpet) = HPET::new(header) {
+                        acpi.hpet = Some(hpet);
                     } else {
                         for b in header.signature.iter() {
                             debug!("{}", *b as char);
@@ -73,6 +79,21 @@ impl Acpi {
             }
         }
     }
+
+    /// The FADT, which has the power management blocks and the century register of the RTC
+    pub fn fadt(&self) -> Option<&FADT> {
+        self.fadt.as_ref()
+    }
+
+    /// The MADT, which lists the processors and interrupt controllers
+    pub fn madt(&self) -> Option<&MADT> {
+        self.madt.as_ref()
+    }
+
+    /// The HPET, which has the counter of the monotonic clock
+    pub fn hpet(&self) -> Option<&HPET> {
+        self.hpet.as_ref()
+    }
 }
 
 impl KScheme for Acpi {
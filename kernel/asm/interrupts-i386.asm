@@ -17,15 +17,25 @@ endstruc
 
 [section .text]
 [BITS 32]
+align 16
 interrupts:
+;Every stub pushes the interrupt number, after an error code. Exceptions without one push 0
 .first:
-    mov [0x100000], byte 0
+    push dword 0
+    push dword 0
     jmp dword .handle
+    align 16
 .second:
 %assign i 1
 %rep 255
-    mov [0x100000], byte i
+%if i = 8 || (i >= 10 && i <= 14) || i = 17 || i = 30
+    push dword i
+%else
+    push dword 0
+    push dword i
+%endif
     jmp dword .handle
+    align 16
 %assign i i+1
 %endrep
 .handle:
@@ -37,7 +47,7 @@ interrupts:
     push ebx
     push eax
     push esp
-    push dword [0x100000]
+    push dword [esp + 32] ;Interrupt number
 
     mov eax, gdt.kernel_data
     mov ds, eax
@@ -63,6 +73,8 @@ interrupts:
     pop esi
     pop ebp
 
+    add esp, 8 ;Skip interrupt number and error code
+
     iretd
 
 .handler: dd 0
@@ -19,15 +19,25 @@ endstruc
 
 [section .text]
 [BITS 64]
+align 16
 interrupts:
+;Every stub pushes the interrupt number, after an error code. Exceptions without one push 0
 .first:
-	mov [0x100000], byte 0
+    push qword 0
+    push qword 0
     jmp qword .handle
+    align 16
 .second:
 %assign i 1
 %rep 255
-	mov [0x100000], byte i
+%if i = 8 || (i >= 10 && i <= 14) || i = 17 || i = 30
+    push qword i
+%else
+    push qword 0
+    push qword i
+%endif
     jmp qword .handle
+    align 16
 %assign i i+1
 %endrep
 .handle:
@@ -53,7 +63,7 @@ interrupts:
     mov fs, rax
     mov gs, rax
 
-	mov rdi, qword [0x100000]
+	mov rdi, qword [rsp + 15 * 8] ;Interrupt number
 	mov rsi, rsp
 	
 		;Stack Align
@@ -87,6 +97,8 @@ interrupts:
 	pop r15
 	pop rbp
 
+    add rsp, 16 ;Skip interrupt number and error code
+
     iretq
 
 .handler: dq 0
@@ -29,6 +29,66 @@ startup:
 %include "asm/vesa.asm"
 %include "asm/initialize.asm"
 
+;Application processors jump here in real mode, with CS 0. The kernel fills in the fields
+trampoline:
+    jmp short .start
+    times 8 - ($ - trampoline) db 0
+.ready: dq 0
+.cpu: dq 0
+.page_table: dq 0
+.stack: dq 0
+.entry: dq 0
+.start:
+    cli
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov sp, .stack_end
+
+    call initialize.fpu
+    call initialize.sse
+
+    lgdt [gdtr]
+    lidt [idtr]
+    ; set protected mode bit of cr0
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    ; far jump to load CS with 32 bit segment
+    jmp gdt.kernel_code:.protected_mode
+.protected_mode:
+    use32
+
+    mov eax, gdt.kernel_data
+    mov ds, eax
+    mov es, eax
+    mov fs, eax
+    mov gs, eax
+    mov ss, eax
+
+    ; enable paging with the page tables of this processor
+    mov eax, [.page_table]
+    mov cr3, eax
+    mov eax, cr0
+    or eax, 0x80000000
+    mov cr0, eax
+
+    mov esp, [.stack]
+    push dword [.cpu]
+    mov dword [.ready], 1
+    call [.entry]
+.lp:
+    cli
+    hlt
+    jmp .lp
+
+    use16
+    ;Stack for the real mode initialization
+    times 64 db 0
+.stack_end:
+
 protected_mode:
     use32
 
@@ -46,11 +106,10 @@ protected_mode:
     ltr ax
 
     ;rust init
-    xor eax, eax
-    mov [0x100000], eax
     mov eax, [kernel_file + 0x18]
     mov [interrupts.handler], eax
     mov eax, tss
+    mov ebx, trampoline
     int 255
 .lp:
     sti
@@ -105,6 +164,9 @@ gdt:
     db 0x40 | ((tss.end-tss) >> 16) & 0xF    ; flags/(limit 16:19). flag is set to 32 bit protected mode
     db ((tss-$$+0x7C00) >> 24) & 0xFF       ; base 24:31
 
+    ;The TSS of the other processors, up to CPU_MAX in total, is set up by the kernel
+    times 15 dq 0
+
 .end equ $ - gdt
 
 struc TSS
@@ -69,6 +69,70 @@ startup:
 %include "asm/vesa.asm"
 %include "asm/initialize.asm"
 
+;Application processors jump here in real mode, with CS 0. The kernel fills in the fields
+trampoline:
+    jmp short .start
+    times 8 - ($ - trampoline) db 0
+.ready: dq 0
+.cpu: dq 0
+.page_table: dq 0
+.stack: dq 0
+.entry: dq 0
+.start:
+    cli
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov sp, .stack_end
+
+    call initialize.fpu
+    call initialize.sse
+
+    mov edi, [.page_table]
+    mov cr3, edi
+
+    mov eax, cr4
+    or eax, 1 << 5 | 1 << 4
+    mov cr4, eax
+
+    lgdt [gdtr]
+    lidt [idtr]
+
+    mov ecx, 0xC0000080               ; Read from the EFER MSR.
+    rdmsr
+    or eax, 0x00000100                ; Set the LME bit.
+    wrmsr
+
+    mov ebx, cr0                      ; Activate long mode -
+    or ebx, 0x80000001                 ; - by enabling paging and protection simultaneously.
+    mov cr0, ebx
+
+    jmp 0x08:.long_mode
+.long_mode:
+    use64
+
+    mov rax, 0x10
+    mov ds, rax
+    mov es, rax
+    mov fs, rax
+    mov gs, rax
+    mov ss, rax
+
+    mov rsp, [.stack]
+    mov rdi, [.cpu]
+    mov qword [.ready], 1
+    call [.entry]
+.lp:
+    cli
+    hlt
+    jmp .lp
+
+    use16
+    ;Stack for the real mode initialization
+    times 64 db 0
+.stack_end:
+
 long_mode:
     use64
 
@@ -101,11 +165,10 @@ long_mode:
     ltr ax
 
     ;rust init
-    xor rax, rax
-    mov [0x100000], rax
     mov eax, [kernel_file + 0x18]
     mov [interrupts.handler], rax
     mov rax, tss
+    mov rbx, trampoline
     int 255
 .lp:
     sti
@@ -161,6 +224,9 @@ gdt:
     db ((tss-$$+0x7C00) >> 24) & 0xFF       ; base 24:31
     dq 0
 
+    ;The TSS of the other processors, up to CPU_MAX in total, is set up by the kernel
+    times 15 dq 0, 0
+
 .end equ $ - gdt
 
 struc TSS
@@ -1,5 +1,7 @@
 // TODO: Doc the rest
 
+use collections::BTreeMap;
+
 use core::{cmp, intrinsics, mem};
 use core::ops::{Index, IndexMut};
 use core::ptr;
@@ -12,6 +14,10 @@ pub const CLUSTER_ADDRESS: usize = PAGE_END;
 pub const CLUSTER_COUNT: usize = 1024 * 1024; // 4 GiB
 pub const CLUSTER_SIZE: usize = 4096; // Of 4 K chunks
 
+/// References to allocations that are shared, past the first. An allocation that is not shared
+/// has one reference and is not in the map
+static mut SHARES: Option<BTreeMap<usize, usize>> = None;
+
 /// A wrapper around raw pointers
 pub struct Memory<T> {
     pub ptr: *mut T,
@@ -252,6 +258,11 @@ pub unsafe fn alloc_aligned(size: usize, align: usize) -> usize {
     0
 }
 
+/// Allocate one cluster, for a page. `alloc` would take two, as it allocates more than the size
+pub unsafe fn alloc_page() -> usize {
+    alloc(CLUSTER_SIZE - 1)
+}
+
 pub unsafe fn alloc_type<T>() -> *mut T {
     alloc(mem::size_of::<T>()) as *mut T
 }
@@ -290,6 +301,51 @@ pub unsafe fn unalloc(ptr: usize) {
     }
 }
 
+/// Use the shares with interrupts excluded
+unsafe fn with_shares<T, F: FnOnce(&mut BTreeMap<usize, usize>) -> T>(f: F) -> T {
+    let _intex = Intex::static_lock();
+    if SHARES.is_none() {
+        SHARES = Some(BTreeMap::new());
+    }
+    match SHARES {
+        Some(ref mut shares) => f(shares),
+        None => unreachable!(),
+    }
+}
+
+/// Add a reference to an allocation, for copy on write
+pub unsafe fn share(ptr: usize) {
+    if ptr > 0 {
+        with_shares(|shares| *shares.entry(ptr).or_insert(0) += 1);
+    }
+}
+
+/// Get the number of references to an allocation
+pub unsafe fn share_count(ptr: usize) -> usize {
+    with_shares(|shares| shares.get(&ptr).map_or(1, |count| count + 1))
+}
+
+/// Remove a reference to an allocation, freeing it with the last
+pub unsafe fn unshare(ptr: usize) {
+    let last = with_shares(|shares| {
+        let count = match shares.get_mut(&ptr) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+            None => return true,
+        };
+        if count == 0 {
+            shares.remove(&ptr);
+        }
+        false
+    });
+
+    if last {
+        unalloc(ptr);
+    }
+}
+
 pub unsafe fn realloc(ptr: usize, size: usize) -> usize {
     // Memory allocation must be atomic
     let _intex = Intex::static_lock();
@@ -1,5 +1,9 @@
 use core::ptr;
 
+use common::memory;
+
+use scheduler::cpu::{cpu_id, CPU_MAX};
+
 // PAGE_DIRECTORY:
 // 1024 dwords pointing to page tables
 // PAGE_TABLES:
@@ -15,10 +19,15 @@ pub const PAGE_DIRECTORY: usize = 0x200000;
 pub const PAGE_TABLES: usize = PAGE_DIRECTORY + PAGE_TABLE_SIZE * PAGE_ENTRY_SIZE;
 pub const PAGE_END: usize = PAGE_TABLES + PAGE_TABLE_SIZE * PAGE_TABLE_SIZE * PAGE_ENTRY_SIZE;
 
+/// The offset of the page tables of each processor from those of the first processor
+static mut PAGE_OFFSETS: [usize; CPU_MAX] = [0; CPU_MAX];
+
 /// A memory page
 pub struct Page {
     /// The virtual address
     virtual_address: usize,
+    /// The processor whose page tables are used
+    cpu: usize,
 }
 
 impl Page {
@@ -35,32 +44,92 @@ impl Page {
             }
         }
 
+        // Enable paging, and write protection so that the kernel also faults on shared pages
         asm!("mov cr3, $0
             mov $0, cr0
             or $0, $1
             mov cr0, $0"
             :
-            : "r"(PAGE_DIRECTORY), "r"(0x80000000 as usize)
+            : "r"(PAGE_DIRECTORY), "r"(0x80010000 as usize)
             : "memory"
             : "intel", "volatile");
     }
 
+    /// Copy the page tables for the processor `cpu`, returning the address of its page
+    /// directory, or 0 if there is no memory left. No userspace memory may be mapped while they
+    /// are copied
+    pub unsafe fn init_cpu(cpu: usize) -> usize {
+        let size = PAGE_END - PAGE_DIRECTORY;
+        let copy = memory::alloc_aligned(size, PAGE_SIZE);
+        if copy > 0 {
+            ::memcpy(copy as *mut u8, PAGE_DIRECTORY as *const u8, size);
+
+            // The directory points to the tables in the copy
+            let offset = copy - PAGE_DIRECTORY;
+            for i in 0..PAGE_TABLE_SIZE {
+                let entry = (copy + i * PAGE_ENTRY_SIZE) as *mut u32;
+                if ptr::read(entry) & 1 == 1 {
+                    ptr::write(entry, ptr::read(entry) + offset as u32);
+                }
+            }
+
+            PAGE_OFFSETS[cpu] = offset;
+        }
+        copy
+    }
+
+    /// Initialize paging on an application processor, after it enabled its page tables
+    pub unsafe fn init_ap() {
+        // Enable write protection, so that the kernel also faults on shared pages
+        asm!("mov eax, cr0
+            or eax, 1 << 16
+            mov cr0, eax"
+            :
+            :
+            : "eax", "memory"
+            : "intel", "volatile");
+    }
+
     /// Create a new memory page from a virtual address
     pub fn new(virtual_address: usize) -> Self {
-        Page { virtual_address: virtual_address }
+        Page::new_cpu(virtual_address, cpu_id())
     }
 
-    /// Get the entry address
+    /// Create a new memory page from a virtual address, in the page tables of the processor
+    /// `cpu`. Its TLB is not flushed when it is another processor, see `cpu_flush`
+    pub fn new_cpu(virtual_address: usize, cpu: usize) -> Self {
+        Page {
+            virtual_address: virtual_address,
+            cpu: cpu,
+        }
+    }
+
+    /// Flush the whole TLB of the current processor
+    pub unsafe fn flush_all() {
+        asm!("mov eax, cr3
+            mov cr3, eax"
+            :
+            :
+            : "eax", "memory"
+            : "intel", "volatile");
+    }
+
+    /// Get the entry address, in the page tables of its processor
     fn entry_address(&self) -> usize {
         let page = self.virtual_address / PAGE_SIZE;
         let table = page / PAGE_TABLE_SIZE;
         let entry = page % PAGE_TABLE_SIZE;
 
-        PAGE_TABLES + (table * PAGE_TABLE_SIZE + entry) * PAGE_ENTRY_SIZE
+        PAGE_TABLES + unsafe { PAGE_OFFSETS[self.cpu] } +
+        (table * PAGE_TABLE_SIZE + entry) * PAGE_ENTRY_SIZE
     }
 
-    /// Flush the memory page
+    /// Flush the memory page, if its processor is the current one
     unsafe fn flush(&self) {
+        if self.cpu != cpu_id() {
+            return;
+        }
+
         asm!("invlpg [$0]"
             :
             : "{eax}"(self.virtual_address)
@@ -81,8 +150,7 @@ impl Page {
     /// Map the memory page to a given physical memory address
     pub unsafe fn map(&mut self, physical_address: usize) {
         ptr::write(self.entry_address() as *mut u32,
-                   // TODO: Remove 1 << 2 which is there to allow for userspace arg access
-                   (physical_address as u32 & 0xFFFFF000) | 1 << 2 | 1); //read/write, present
+                   (physical_address as u32 & 0xFFFFF000) | 1 << 1 | 1); //read/write, present
         self.flush();
     }
 
@@ -100,6 +168,17 @@ impl Page {
         self.flush();
     }
 
+    /// Map the memory page to a given physical memory address, and allow userspace access, which
+    /// is read only unless `writeable`. Without PAE there is no no execute bit, so every page
+    /// that can be read can execute
+    pub unsafe fn map_user(&mut self, physical_address: usize, writeable: bool, _executable: bool) {
+        if writeable {
+            self.map_user_write(physical_address);
+        } else {
+            self.map_user_read(physical_address);
+        }
+    }
+
     /// Map to the virtual address
     pub unsafe fn map_identity(&mut self) {
         let physical_address = self.virtual_address;
@@ -111,4 +190,37 @@ impl Page {
         ptr::write(self.entry_address() as *mut u32, 0);
         self.flush();
     }
+
+    /// The bytes mapped by the page table of a page
+    pub fn table_size() -> usize {
+        PAGE_TABLE_SIZE * PAGE_SIZE
+    }
+
+    /// Get the address of the entry pointing to the page table, in the tables of its processor
+    fn table_entry_address(&self) -> usize {
+        let table = self.virtual_address / PAGE_SIZE / PAGE_TABLE_SIZE;
+
+        PAGE_DIRECTORY + unsafe { PAGE_OFFSETS[self.cpu] } + table * PAGE_ENTRY_SIZE
+    }
+
+    /// Check if the page table of the page is present
+    pub unsafe fn table_present(&self) -> bool {
+        ptr::read(self.table_entry_address() as *const u32) & 1 == 1
+    }
+
+    /// Map the page table of the page again, with the entries it had. The TLB is not flushed,
+    /// see `flush_all`
+    pub unsafe fn map_table(&mut self) {
+        let table = self.virtual_address / PAGE_SIZE / PAGE_TABLE_SIZE;
+        ptr::write(self.table_entry_address() as *mut u32,
+                   (PAGE_TABLES + PAGE_OFFSETS[self.cpu] +
+                    table * PAGE_TABLE_SIZE * PAGE_ENTRY_SIZE) as u32 |
+                   0b11 << 1 | 1); //Allow userspace, read/write, present
+    }
+
+    /// Unmap the whole page table of the page, so none of its pages are present, without
+    /// changing its entries. The TLB is not flushed, see `flush_all`
+    pub unsafe fn unmap_table(&mut self) {
+        ptr::write(self.table_entry_address() as *mut u32, 0);
+    }
 }
@@ -1,5 +1,9 @@
 use core::ptr;
 
+use common::memory;
+
+use scheduler::cpu::{cpu_id, CPU_MAX};
+
 // PAGE_LEVEL_4:
 // 512 qwords pointing to page directory pointers
 // PAGE_DIR_PTRS:
@@ -21,10 +25,18 @@ pub const PAGE_DIRECTORIES: usize = PAGE_DIR_PTRS + PAGE_TABLE_SIZE * PAGE_ENTRY
 pub const PAGE_TABLES: usize = PAGE_DIRECTORIES + 4 * PAGE_TABLE_SIZE * PAGE_ENTRY_SIZE;
 pub const PAGE_END: usize = PAGE_TABLES + 4 * PAGE_TABLE_SIZE * PAGE_TABLE_SIZE * PAGE_ENTRY_SIZE;
 
+/// The no execute bit of an entry, zero if the processor does not support it
+static mut PAGE_NO_EXECUTE: u64 = 0;
+
+/// The offset of the page tables of each processor from those of the first processor
+static mut PAGE_OFFSETS: [usize; CPU_MAX] = [0; CPU_MAX];
+
 /// A memory page
 pub struct Page {
     /// The virtual address
     virtual_address: usize,
+    /// The processor whose page tables are used
+    cpu: usize,
 }
 
 impl Page {
@@ -61,32 +73,119 @@ impl Page {
             }
         }
 
+        // Enable paging, and write protection so that the kernel also faults on shared pages
         asm!("mov cr3, $0
             mov $0, cr0
             or $0, $1
             mov cr0, $0"
             :
-            : "r"(PAGE_LEVEL_4), "r"(0x80000000 as usize)
+            : "r"(PAGE_LEVEL_4), "r"(0x80010000 as usize)
             : "memory"
             : "intel", "volatile");
+
+        // Enable the no execute bit in EFER, if it is supported
+        let features: u32;
+        asm!("cpuid"
+            : "={edx}"(features)
+            : "{eax}"(0x80000001u32)
+            : "ebx", "ecx"
+            : "intel", "volatile");
+
+        if features & 1 << 20 == 1 << 20 {
+            PAGE_NO_EXECUTE = 1 << 63;
+            Page::enable_no_execute();
+        }
+    }
+
+    /// Copy the page tables for the processor `cpu`, returning the address of its level 4 table,
+    /// or 0 if there is no memory left. No userspace memory may be mapped while they are copied
+    pub unsafe fn init_cpu(cpu: usize) -> usize {
+        let size = PAGE_END - PAGE_LEVEL_4;
+        let copy = memory::alloc_aligned(size, PAGE_SIZE);
+        if copy > 0 {
+            ::memcpy(copy as *mut u8, PAGE_LEVEL_4 as *const u8, size);
+
+            // The tables that point to tables point into the copy
+            let offset = copy - PAGE_LEVEL_4;
+            for i in 0..(PAGE_TABLES - PAGE_LEVEL_4) / PAGE_ENTRY_SIZE {
+                let entry = (copy + i * PAGE_ENTRY_SIZE) as *mut u64;
+                if ptr::read(entry) & 1 == 1 {
+                    ptr::write(entry, ptr::read(entry) + offset as u64);
+                }
+            }
+
+            PAGE_OFFSETS[cpu] = offset;
+        }
+        copy
+    }
+
+    /// Initialize paging on an application processor, after it enabled its page tables
+    pub unsafe fn init_ap() {
+        // Enable write protection, so that the kernel also faults on shared pages
+        asm!("mov rax, cr0
+            or rax, 1 << 16
+            mov cr0, rax"
+            :
+            :
+            : "rax", "memory"
+            : "intel", "volatile");
+
+        if PAGE_NO_EXECUTE > 0 {
+            Page::enable_no_execute();
+        }
+    }
+
+    /// Enable the no execute bit in EFER
+    unsafe fn enable_no_execute() {
+        asm!("rdmsr
+            or eax, 1 << 11
+            wrmsr"
+            :
+            : "{ecx}"(0xC0000080u32)
+            : "eax", "edx", "memory"
+            : "intel", "volatile");
     }
 
     /// Create a new memory page from a virtual address
     pub fn new(virtual_address: usize) -> Self {
-        Page { virtual_address: virtual_address }
+        Page::new_cpu(virtual_address, cpu_id())
     }
 
-    /// Get the entry address
+    /// Create a new memory page from a virtual address, in the page tables of the processor
+    /// `cpu`. Its TLB is not flushed when it is another processor, see `cpu_flush`
+    pub fn new_cpu(virtual_address: usize, cpu: usize) -> Self {
+        Page {
+            virtual_address: virtual_address,
+            cpu: cpu,
+        }
+    }
+
+    /// Flush the whole TLB of the current processor
+    pub unsafe fn flush_all() {
+        asm!("mov rax, cr3
+            mov cr3, rax"
+            :
+            :
+            : "rax", "memory"
+            : "intel", "volatile");
+    }
+
+    /// Get the entry address, in the page tables of its processor
     fn entry_address(&self) -> usize {
         let page = self.virtual_address / PAGE_SIZE;
         let table = page / PAGE_TABLE_SIZE;
         let entry = page % PAGE_TABLE_SIZE;
 
-        PAGE_TABLES + (table * PAGE_TABLE_SIZE + entry) * PAGE_ENTRY_SIZE
+        PAGE_TABLES + unsafe { PAGE_OFFSETS[self.cpu] } +
+        (table * PAGE_TABLE_SIZE + entry) * PAGE_ENTRY_SIZE
     }
 
-    /// Flush the memory page
+    /// Flush the memory page, if its processor is the current one
     unsafe fn flush(&self) {
+        if self.cpu != cpu_id() {
+            return;
+        }
+
         asm!("invlpg [$0]"
             :
             : "{eax}"(self.virtual_address)
@@ -107,7 +206,7 @@ impl Page {
     /// Map the memory page to a given physical memory address
     pub unsafe fn map(&mut self, physical_address: usize) {
         ptr::write(self.entry_address() as *mut u64,
-                   (physical_address as u64 & 0xFFFFFFFFFFFFF000) | 1); //present
+                   (physical_address as u64 & 0xFFFFFFFFFFFFF000) | 1 << 1 | 1); //read/write, present
         self.flush();
     }
 
@@ -125,6 +224,20 @@ impl Page {
         self.flush();
     }
 
+    /// Map the memory page to a given physical memory address and allow userspace access, which
+    /// is read only unless `writeable`, and can not execute unless `executable`
+    pub unsafe fn map_user(&mut self, physical_address: usize, writeable: bool, executable: bool) {
+        let mut entry = (physical_address as u64 & 0xFFFFFFFFFFFFF000) | 1 << 2 | 1; //Allow userspace, present
+        if writeable {
+            entry |= 1 << 1; //read/write
+        }
+        if !executable {
+            entry |= PAGE_NO_EXECUTE;
+        }
+        ptr::write(self.entry_address() as *mut u64, entry);
+        self.flush();
+    }
+
     /// Map to the virtual address
     pub unsafe fn map_identity(&mut self) {
         let physical_address = self.virtual_address;
@@ -136,4 +249,37 @@ impl Page {
         ptr::write(self.entry_address() as *mut u64, 0);
         self.flush();
     }
+
+    /// The bytes mapped by the page table of a page
+    pub fn table_size() -> usize {
+        PAGE_TABLE_SIZE * PAGE_SIZE
+    }
+
+    /// Get the address of the entry pointing to the page table, in the tables of its processor
+    fn table_entry_address(&self) -> usize {
+        let table = self.virtual_address / PAGE_SIZE / PAGE_TABLE_SIZE;
+
+        PAGE_DIRECTORIES + unsafe { PAGE_OFFSETS[self.cpu] } + table * PAGE_ENTRY_SIZE
+    }
+
+    /// Check if the page table of the page is present
+    pub unsafe fn table_present(&self) -> bool {
+        ptr::read(self.table_entry_address() as *const u64) & 1 == 1
+    }
+
+    /// Map the page table of the page again, with the entries it had. The TLB is not flushed,
+    /// see `flush_all`
+    pub unsafe fn map_table(&mut self) {
+        let table = self.virtual_address / PAGE_SIZE / PAGE_TABLE_SIZE;
+        ptr::write(self.table_entry_address() as *mut u64,
+                   (PAGE_TABLES + PAGE_OFFSETS[self.cpu] +
+                    table * PAGE_TABLE_SIZE * PAGE_ENTRY_SIZE) as u64 |
+                   1 << 2 | 0b11 << 1 | 1); //Allow userspace, read/write, present
+    }
+
+    /// Unmap the whole page table of the page, so none of its pages are present, without
+    /// changing its entries. The TLB is not flushed, see `flush_all`
+    pub unsafe fn unmap_table(&mut self) {
+        ptr::write(self.table_entry_address() as *mut u64, 0);
+    }
 }
@@ -1,7 +1,10 @@
-use core::cmp::Ordering;
+use core::cmp::{self, Ordering};
+use core::i64;
 use core::ops::{Add, Sub};
 
-use scheduler::context;
+use drivers::hpet::Hpet;
+
+use scheduler::timer::Timer;
 
 pub const NANOS_PER_MICRO: i32 = 1000;
 pub const NANOS_PER_MILLI: i32 = 1000000;
@@ -35,9 +38,12 @@ impl Duration {
         }
     }
 
-    /// Get the current duration
+    /// Get the current duration, from the HPET if it is used, otherwise from the ticks
     pub fn monotonic() -> Self {
-        ::env().clock_monotonic.lock().clone()
+        match Hpet::current() {
+            Some(hpet) => hpet.monotonic(),
+            None => ::env().clock_monotonic.lock().clone(),
+        }
     }
 
     /// Get the realtime
@@ -45,17 +51,10 @@ impl Duration {
         ::env().clock_realtime.lock().clone()
     }
 
-    /// Sleep the duration
+    /// Sleep the duration, with a timer. It is limited so that its deadline does not overflow
     pub fn sleep(&self) {
-        let start_time = Duration::monotonic();
-        loop {
-            let elapsed = Duration::monotonic() - start_time;
-            if elapsed > *self {
-                break;
-            } else {
-                unsafe { context::context_switch(false) };
-            }
-        }
+        let duration = Duration::new(cmp::min(self.secs, i64::MAX / 2), self.nanos);
+        Timer::new(Duration::monotonic() + duration).wait();
     }
 }
 
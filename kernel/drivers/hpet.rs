@@ -0,0 +1,83 @@
+use common::time::{Duration, NANOS_PER_SEC};
+
+use drivers::io::{Io, Mmio};
+
+/// Femtoseconds in a second, the period of the counter is in femtoseconds
+const FEMTOS_PER_SEC: u64 = 1000000000000000;
+
+/// The address of the HPET, 0 if there is none or its counter is not used
+static mut HPET_ADDRESS: usize = 0;
+/// The frequency of the counter
+static mut HPET_FREQUENCY: u64 = 0;
+/// The monotonic time when the counter was started
+static mut HPET_OFFSET: Duration = Duration { secs: 0, nanos: 0 };
+
+/// A high precision event timer, its counter is the monotonic clock
+#[derive(Copy, Clone)]
+pub struct Hpet {
+    address: usize,
+}
+
+impl Hpet {
+    /// Start the counter of the HPET at `address`, at the monotonic time `offset`. It is not
+    /// used unless the counter has 64 bits, so it does not wrap, and runs at 10 MHz to 1 GHz
+    pub unsafe fn init(address: usize, offset: Duration) {
+        let hpet = Hpet { address: address };
+
+        let capabilities = hpet.reg(0x00).read();
+        let period = hpet.reg(0x04).read() as u64;
+        if capabilities & 1 << 13 == 0 || period < 1000000 || period > 100000000 {
+            debugln!("HPET: Counter not usable");
+            return;
+        }
+
+        // Halt and reset the counter, then start it without legacy replacement
+        let config = hpet.reg(0x10).read();
+        hpet.reg(0x10).write(config & !0b11);
+        hpet.reg(0xF0).write(0);
+        hpet.reg(0xF4).write(0);
+
+        HPET_FREQUENCY = FEMTOS_PER_SEC / period;
+        HPET_OFFSET = offset;
+
+        hpet.reg(0x10).write(config & !0b11 | 1);
+
+        HPET_ADDRESS = address;
+
+        debugln!("HPET: {} Hz", HPET_FREQUENCY);
+    }
+
+    /// The HPET, if its counter is used
+    pub fn current() -> Option<Self> {
+        let address = unsafe { HPET_ADDRESS };
+        if address > 0 {
+            Some(Hpet { address: address })
+        } else {
+            None
+        }
+    }
+
+    fn reg(&self, offset: usize) -> &'static mut Mmio<u32> {
+        unsafe { &mut *((self.address + offset) as *mut Mmio<u32>) }
+    }
+
+    /// The counter, read in halves until the high one did not change
+    pub fn counter(&self) -> u64 {
+        loop {
+            let high = self.reg(0xF4).read();
+            let low = self.reg(0xF0).read();
+            if self.reg(0xF4).read() == high {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+
+    /// The monotonic time of the counter
+    pub fn monotonic(&self) -> Duration {
+        let (frequency, offset) = unsafe { (HPET_FREQUENCY, HPET_OFFSET) };
+        let counter = self.counter();
+        let secs = counter / frequency;
+        let nanos = (counter % frequency) * NANOS_PER_SEC as u64 / frequency;
+        offset + Duration::new(secs as i64, nanos as i32)
+    }
+}
@@ -0,0 +1,140 @@
+use acpi::MADT;
+
+use drivers::io::{Io, Mmio, Pio};
+
+/// The interrupt of the first ISA IRQ, the ones of the others follow it, like with the PIC
+pub const IOAPIC_IRQ: u8 = 0x20;
+
+/// The most I/O APICs that are used
+const IOAPIC_MAX: usize = 8;
+
+/// The I/O APICs of the MADT
+static mut IOAPICS: [Option<IoApic>; IOAPIC_MAX] = [None; IOAPIC_MAX];
+
+/// The global system interrupt and MADT flags of each ISA IRQ
+static mut IOAPIC_ISA: [(u32, u16); 16] = [(0, 0); 16];
+
+/// The local APIC that receives the interrupts
+static mut IOAPIC_APIC_ID: u8 = 0;
+
+/// An I/O APIC, which redirects global system interrupts to local APICs
+#[derive(Copy, Clone)]
+pub struct IoApic {
+    address: usize,
+    /// The first global system interrupt of its inputs
+    gsi_base: u32,
+    /// The number of its inputs
+    count: u32,
+}
+
+impl IoApic {
+    /// Redirect the ISA IRQs to the local APIC `apic_id` with the I/O APICs of the MADT, instead
+    /// of the PIC, which is masked
+    pub unsafe fn init(madt: &MADT, apic_id: u8) {
+        for (i, entry) in madt.io_apics.iter().take(IOAPIC_MAX).enumerate() {
+            let mut ioapic = IoApic {
+                address: entry.address as usize,
+                gsi_base: entry.gsi_base,
+                count: 0,
+            };
+            ioapic.count = (ioapic.read(0x01) >> 16 & 0xFF) + 1;
+
+            // Mask every input until it is redirected
+            for gsi in ioapic.gsi_base..ioapic.gsi_base + ioapic.count {
+                ioapic.mask(gsi);
+            }
+
+            IOAPICS[i] = Some(ioapic);
+        }
+
+        if !IoApic::enabled() {
+            return;
+        }
+
+        IOAPIC_APIC_ID = apic_id;
+        for irq in 0..16 {
+            // ISA IRQs are active high and edge triggered, unless overridden
+            let mut gsi = irq as u32;
+            let mut flags = 0;
+            for source in madt.int_source_overrides.iter() {
+                if source.bus_source == 0 && source.irq_source == irq {
+                    gsi = source.gsi;
+                    flags = source.flags;
+                }
+            }
+            IOAPIC_ISA[irq as usize] = (gsi, flags);
+
+            // IRQ 2 is the cascade of the PIC
+            if irq != 2 {
+                IoApic::redirect(gsi, IOAPIC_IRQ + irq, flags, apic_id);
+            }
+        }
+
+        Pio::<u8>::new(0x21).write(0xFF);
+        Pio::<u8>::new(0xA1).write(0xFF);
+    }
+
+    /// Redirect the ISA IRQ `irq` for the legacy interrupt of a PCI device. PCI interrupts are
+    /// active low and level triggered, unless the MADT overrides the polarity or trigger mode
+    pub unsafe fn redirect_pci(irq: u8) {
+        if irq == 2 || irq as usize >= IOAPIC_ISA.len() {
+            return;
+        }
+
+        let (gsi, mut flags) = IOAPIC_ISA[irq as usize];
+        if flags & 0b11 == 0 {
+            flags |= 0b11;
+        }
+        if flags >> 2 & 0b11 == 0 {
+            flags |= 0b11 << 2;
+        }
+
+        IoApic::redirect(gsi, IOAPIC_IRQ + irq, flags, IOAPIC_APIC_ID);
+    }
+
+    /// Indicates that interrupts come from I/O APICs, not the PIC
+    pub fn enabled() -> bool {
+        unsafe { IOAPICS.iter().any(|ioapic| ioapic.is_some()) }
+    }
+
+    /// Redirect the global system interrupt `gsi` to `vector` of the local APIC `apic_id`, with
+    /// the polarity and trigger mode of MADT `flags`
+    pub unsafe fn redirect(gsi: u32, vector: u8, flags: u16, apic_id: u8) {
+        for ioapic in IOAPICS.iter() {
+            if let Some(ioapic) = *ioapic {
+                if gsi >= ioapic.gsi_base && gsi < ioapic.gsi_base + ioapic.count {
+                    let mut low = vector as u32;
+                    if flags & 0b11 == 0b11 {
+                        low |= 1 << 13; // Active low
+                    }
+                    if flags >> 2 & 0b11 == 0b11 {
+                        low |= 1 << 15; // Level triggered
+                    }
+
+                    let reg = 0x10 + (gsi - ioapic.gsi_base) * 2;
+                    ioapic.write(reg + 1, (apic_id as u32) << 24);
+                    ioapic.write(reg, low);
+                }
+            }
+        }
+    }
+
+    fn mask(&self, gsi: u32) {
+        let reg = 0x10 + (gsi - self.gsi_base) * 2;
+        self.write(reg, 1 << 16);
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            (*(self.address as *mut Mmio<u32>)).write(reg);
+            (*((self.address + 0x10) as *mut Mmio<u32>)).read()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            (*(self.address as *mut Mmio<u32>)).write(reg);
+            (*((self.address + 0x10) as *mut Mmio<u32>)).write(value);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use drivers::io::{Io, Mmio};
+
+/// The interrupt of the local APIC when an interrupt goes away, it needs no end of interrupt
+pub const LAPIC_SPURIOUS: u8 = 0x4F;
+
+/// Interprocessor interrupt to initialize a processor
+pub const IPI_INIT: u32 = 0x4500;
+/// Interprocessor interrupt to start a processor at the real mode page `vector`
+pub const IPI_STARTUP: u32 = 0x4600;
+
+/// The address of the local APIC, the same for every processor, 0 if there is none
+static mut LAPIC_ADDRESS: usize = 0;
+
+/// A local APIC, the interrupt controller of a processor
+#[derive(Copy, Clone)]
+pub struct Lapic {
+    address: usize,
+}
+
+impl Lapic {
+    /// Set the address of the local APIC, from the MADT
+    pub unsafe fn init(address: usize) {
+        LAPIC_ADDRESS = address;
+    }
+
+    /// The local APIC of the current processor, if there is one
+    pub fn current() -> Option<Self> {
+        let address = unsafe { LAPIC_ADDRESS };
+        if address > 0 {
+            Some(Lapic { address: address })
+        } else {
+            None
+        }
+    }
+
+    fn reg(&self, offset: usize) -> &'static mut Mmio<u32> {
+        unsafe { &mut *((self.address + offset) as *mut Mmio<u32>) }
+    }
+
+    /// The ID of the local APIC
+    pub fn id(&self) -> u8 {
+        (self.reg(0x20).read() >> 24) as u8
+    }
+
+    /// Enable the local APIC, accepting every priority of interrupt
+    pub fn enable(&self) {
+        self.reg(0x80).write(0);
+
+        let svr = self.reg(0xF0).read();
+        self.reg(0xF0).write(svr & 0xFFFFFF00 | 1 << 8 | LAPIC_SPURIOUS as u32);
+    }
+
+    /// Signal the end of an interrupt
+    pub fn eoi(&self) {
+        self.reg(0xB0).write(0);
+    }
+
+    /// Count the timer down from the largest count without interrupting, to measure it
+    pub fn timer_measure(&self) {
+        self.reg(0x3E0).write(0b0011);
+        self.reg(0x320).write(1 << 16);
+        self.reg(0x380).write(0xFFFFFFFF);
+    }
+
+    /// Interrupt with `vector` once after `count` ticks of the timer, which counts the bus clock
+    /// divided by 16
+    pub fn timer_oneshot(&self, vector: u8, count: u32) {
+        self.reg(0x3E0).write(0b0011);
+        self.reg(0x320).write(vector as u32);
+        self.reg(0x380).write(count);
+    }
+
+    /// The current count of the timer
+    pub fn timer_current(&self) -> u32 {
+        self.reg(0x390).read()
+    }
+
+    /// Send an interprocessor interrupt to the local APIC `id`, waiting until it was sent
+    pub fn ipi(&self, id: u8, command: u32) {
+        self.reg(0x310).write((id as u32) << 24);
+        self.reg(0x300).write(command);
+
+        while self.reg(0x300).read() & 1 << 12 == 1 << 12 {
+            unsafe { asm!("pause" : : : : "intel", "volatile") };
+        }
+    }
+}
@@ -1,5 +1,11 @@
 extern crate io;
 
+/// HPET
+pub mod hpet;
+/// I/O APIC
+pub mod ioapic;
+/// Local APIC
+pub mod lapic;
 /// PCI
 pub mod pci;
 /// PS2
@@ -1,4 +1,14 @@
-use drivers::io::{Io, Pio};
+use drivers::io::{Io, Mmio, Pio};
+use drivers::ioapic::{IoApic, IOAPIC_IRQ};
+use drivers::lapic::Lapic;
+
+/// The first IRQ of message signaled interrupts, after the ISA IRQs
+const MSI_IRQ: u8 = 0x10;
+/// The IRQ after the last one of message signaled interrupts, its interrupt is the scheduler tick
+const MSI_IRQ_END: u8 = 0x20;
+
+/// The next IRQ of message signaled interrupts
+static mut MSI_NEXT: u8 = MSI_IRQ;
 
 /// A PCI configuration
 #[derive(Copy, Clone)]
@@ -51,5 +61,100 @@ impl PciConfig {
         self.write(offset, value);
     }
 
+    /// The offset of the capability `id`, if there is one
+    pub unsafe fn capability(&mut self, id: u8) -> Option<u8> {
+        // The status indicates a list of capabilities
+        if self.read(0x04) & 1 << 20 == 0 {
+            return None;
+        }
+
+        let mut offset = self.read(0x34) as u8 & 0xFC;
+        for _ in 0..48 {
+            if offset == 0 {
+                break;
+            }
+
+            let header = self.read(offset);
+            if header as u8 == id {
+                return Some(offset);
+            }
+            offset = (header >> 8) as u8 & 0xFC;
+        }
+
+        None
+    }
+
+    /// The IRQ of the device. It uses a message signaled interrupt to the local APIC if it can,
+    /// otherwise its legacy interrupt line, which is redirected as a PCI interrupt
+    pub unsafe fn irq(&mut self) -> u8 {
+        if IoApic::enabled() && MSI_NEXT < MSI_IRQ_END {
+            if let Some(lapic) = Lapic::current() {
+                let irq = MSI_NEXT;
+                let address = 0xFEE00000 | (lapic.id() as u32) << 12;
+                let data = (IOAPIC_IRQ + irq) as u32;
+                if self.msix(address, data) || self.msi(address, data) {
+                    MSI_NEXT += 1;
+
+                    // Disable the legacy interrupt
+                    self.flag(0x04, 1 << 10, true);
+
+                    return irq;
+                }
+            }
+        }
+
+        let irq = self.read(0x3C) as u8 & 0xF;
+        if IoApic::enabled() {
+            IoApic::redirect_pci(irq);
+        }
+        irq
+    }
+
+    /// Enable one message signaled interrupt, which writes `data` to `address`
+    unsafe fn msi(&mut self, address: u32, data: u32) -> bool {
+        if let Some(offset) = self.capability(0x05) {
+            let header = self.read(offset);
+            self.write(offset + 4, address);
+            if header & 1 << 23 == 1 << 23 {
+                // The address has 64 bits
+                self.write(offset + 8, 0);
+                self.write(offset + 12, data);
+            } else {
+                self.write(offset + 8, data);
+            }
+            self.write(offset, header & !(0b111 << 20) | 1 << 16);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Enable the first entry of the MSI-X table, which writes `data` to `address`
+    unsafe fn msix(&mut self, address: u32, data: u32) -> bool {
+        if let Some(offset) = self.capability(0x11) {
+            let table = self.read(offset + 4);
+            let bar = self.read(0x10 + (table & 0b111) as u8 * 4);
+            if bar & 1 == 1 {
+                // The table is not in memory
+                return false;
+            }
+
+            // Mask every entry while the first one is written
+            let header = self.read(offset);
+            self.write(offset, header | 1 << 31 | 1 << 30);
+
+            let entry = (bar & 0xFFFFFFF0) as usize + (table & 0xFFFFFFF8) as usize;
+            (*(entry as *mut Mmio<u32>)).write(address);
+            (*((entry + 4) as *mut Mmio<u32>)).write(0);
+            (*((entry + 8) as *mut Mmio<u32>)).write(data);
+            (*((entry + 12) as *mut Mmio<u32>)).write(0);
+
+            self.write(offset, header & !(1 << 30) | 1 << 31);
+            true
+        } else {
+            false
+        }
+    }
+
     // TODO: Write functions to get data structures
 }
@@ -49,7 +49,7 @@ pub unsafe fn pci_device(env: &mut Environment,
                 pci: pci,
                 base: base & 0xFFFFFFF0,
                 memory_mapped: base & 1 == 0,
-                irq: pci.read(0x3C) as u8 & 0xF,
+                irq: pci.irq(),
             };
             module.init();
             env.schemes.push(UnsafeCell::new(module));
@@ -66,7 +66,7 @@ pub unsafe fn pci_device(env: &mut Environment,
                         pci: pci,
                         base: base & 0xFFFFFFF0,
                         memory_mapped: base & 1 == 0,
-                        irq: pci.read(0x3C) as u8 & 0xF,
+                        irq: pci.irq(),
                     };
                     module.init();
                     env.schemes.push(UnsafeCell::new(module));
@@ -1,25 +1,58 @@
+use alloc::boxed::Box;
+
+use collections::string::ToString;
+use collections::vec::Vec;
+
 use common::time::Duration;
+use common::to_num::ToNum;
+
+use core::{cmp, str};
 
 use drivers::io::{Io, Pio};
 
+use schemes::{Result, KScheme, Resource, Url};
+
 use sync::Intex;
 
+use syscall::{Error, EINVAL};
+
 fn cvt_bcd(value: usize) -> usize {
     (value & 0xF) + ((value / 16) * 10)
 }
 
+fn to_bcd(value: usize) -> usize {
+    (value % 10) + ((value / 10) << 4)
+}
+
+/// The year, month, and day of a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, usize, usize) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as usize, day as usize)
+}
+
 /// RTC
 pub struct Rtc {
     addr: Pio<u8>,
     data: Pio<u8>,
+    /// The century register from the ACPI FADT, 0 if there is none
+    century: u8,
 }
 
 impl Rtc {
-    /// Create new empty RTC
-    pub fn new() -> Self {
+    /// Create new empty RTC, with the century register `century` or 0
+    pub fn new(century: u8) -> Self {
         return Rtc {
             addr: Pio::<u8>::new(0x70),
             data: Pio::<u8>::new(0x71),
+            century: century,
         };
     }
 
@@ -29,6 +62,12 @@ impl Rtc {
         return self.data.read();
     }
 
+    /// Write
+    unsafe fn write(&mut self, reg: u8, value: u8) {
+        self.addr.write(reg);
+        self.data.write(value);
+    }
+
     /// Wait
     unsafe fn wait(&mut self) {
         while self.read(0xA) & 0x80 != 0x80 {}
@@ -43,6 +82,7 @@ impl Rtc {
         let mut day;
         let mut month;
         let mut year;
+        let mut century;
         let register_b;
         unsafe {
             let _intex = Intex::static_lock();
@@ -53,6 +93,11 @@ impl Rtc {
             day = self.read(7) as usize;
             month = self.read(8) as usize;
             year = self.read(9) as usize;
+            century = if self.century > 0 {
+                self.read(self.century) as usize
+            } else {
+                0
+            };
             register_b = self.read(0xB);
         }
 
@@ -63,14 +108,19 @@ impl Rtc {
             day = cvt_bcd(day);
             month = cvt_bcd(month);
             year = cvt_bcd(year);
+            century = cvt_bcd(century);
         }
 
         if register_b & 2 != 2 || hour & 0x80 == 0x80 {
             hour = ((hour & 0x7F) + 12) % 24;
         }
 
-        // TODO: Century Register
-        year += 2000;
+        // Machines without a century register read garbage or zero
+        if century >= 19 && century <= 30 {
+            year += century * 100;
+        } else {
+            year += 2000;
+        }
 
         // Unix time from clock
         let mut secs: i64 = (year as i64 - 1970) * 31536000;
@@ -105,4 +155,131 @@ impl Rtc {
 
         Duration::new(secs, 0)
     }
+    /// Set time, in seconds since the Unix epoch
+    pub fn set_time(&mut self, time: Duration) {
+        let mut days = time.secs / 86400;
+        let mut secs = time.secs % 86400;
+        if secs < 0 {
+            days -= 1;
+            secs += 86400;
+        }
+
+        let (year, month, day) = civil_from_days(days);
+        let year = cmp::max(year, 0) as usize;
+
+        let mut second = (secs % 60) as usize;
+        let mut minute = (secs / 60 % 60) as usize;
+        let mut hour = (secs / 3600) as usize;
+        let mut day = day;
+        let mut month = month;
+        let mut century = year / 100;
+        let mut year = year % 100;
+
+        unsafe {
+            let _intex = Intex::static_lock();
+            let register_b = self.read(0xB);
+
+            if register_b & 2 != 2 {
+                // 12 hour format, with the high bit for PM
+                let pm = hour >= 12;
+                hour = match hour % 12 {
+                    0 => 12,
+                    hour => hour,
+                };
+                if register_b & 4 != 4 {
+                    hour = to_bcd(hour);
+                }
+                if pm {
+                    hour |= 0x80;
+                }
+            } else if register_b & 4 != 4 {
+                hour = to_bcd(hour);
+            }
+
+            if register_b & 4 != 4 {
+                second = to_bcd(second);
+                minute = to_bcd(minute);
+                day = to_bcd(day);
+                month = to_bcd(month);
+                year = to_bcd(year);
+                century = to_bcd(century);
+            }
+
+            // Stop updates while writing
+            self.write(0xB, register_b | 0x80);
+            self.write(0, second as u8);
+            self.write(2, minute as u8);
+            self.write(4, hour as u8);
+            self.write(7, day as u8);
+            self.write(8, month as u8);
+            self.write(9, year as u8);
+            if self.century > 0 {
+                self.write(self.century, century as u8);
+            }
+            self.write(0xB, register_b & 0x7F);
+        }
+    }
+}
+
+impl KScheme for Rtc {
+    fn scheme(&self) -> &str {
+        "rtc"
+    }
+
+    /// Reading returns the time of the RTC in seconds, writing seconds sets it
+    fn open(&mut self, _: &Url, _: usize) -> Result<Box<Resource>> {
+        let time = self.time();
+        Ok(box RtcResource {
+            rtc: self,
+            data: time.secs.to_string().into_bytes(),
+            seek: 0,
+        })
+    }
+}
+
+/// A resource of the RTC scheme
+pub struct RtcResource {
+    rtc: *mut Rtc,
+    /// The time when opened
+    data: Vec<u8>,
+    seek: usize,
+}
+
+impl Resource for RtcResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box RtcResource {
+            rtc: self.rtc,
+            data: self.data.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn url(&self) -> Url {
+        Url::from_str("rtc:")
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && self.seek < self.data.len() {
+            buf[i] = self.data[self.seek];
+            self.seek += 1;
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match str::from_utf8(buf) {
+            Ok(string) if !string.trim().is_empty() => {
+                let secs = string.trim().to_num_signed() as i64;
+                unsafe { (*self.rtc).set_time(Duration::new(secs, 0)) };
+                Ok(buf.len())
+            }
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
@@ -11,6 +11,7 @@ use common::time::Duration;
 use core::cell::UnsafeCell;
 
 use scheduler::context::ContextManager;
+use scheduler::timer::TimerWheel;
 
 use schemes::{Result, KScheme, Resource, VecResource, Url};
 
@@ -32,6 +33,10 @@ pub struct Environment {
     pub clock_realtime: Intex<Duration>,
     /// Monotonic clock
     pub clock_monotonic: Intex<Duration>,
+    /// Adjustment of the realtime clock that remains to be applied gradually
+    pub clock_slew: Intex<Duration>,
+    /// Timers of the monotonic clock
+    pub timers: Intex<TimerWheel>,
 
     /// Default console
     pub console: Intex<Console>,
@@ -51,6 +56,8 @@ impl Environment {
 
             clock_realtime: Intex::new(Duration::new(0, 0)),
             clock_monotonic: Intex::new(Duration::new(0, 0)),
+            clock_slew: Intex::new(Duration::new(0, 0)),
+            timers: Intex::new(TimerWheel::new()),
 
             console: Intex::new(Console::new()),
             events: Intex::new(VecDeque::new()),
@@ -1,11 +1,13 @@
 use collections::BTreeMap;
 
-use scheduler::context::context_switch;
+use sync::WaitQueue;
 
 pub struct Scheme {
     next_id: usize,
     todo: BTreeMap<usize, (usize, usize, usize, usize)>,
     done: BTreeMap<usize, (usize, usize, usize, usize)>,
+    /// Callers waiting for their request to be done, woken by `done`
+    wait: WaitQueue,
 }
 
 impl Scheme {
@@ -14,6 +16,7 @@ impl Scheme {
             next_id: 1,
             todo: BTreeMap::new(),
             done: BTreeMap::new(),
+            wait: WaitQueue::new(),
         }
     }
 
@@ -34,7 +37,13 @@ impl Scheme {
                 return
             }
 
-            unsafe { context_switch(false) } ;
+            self.wait.block();
         }
     }
+
+    /// Finish the request `id`, waking its caller
+    pub fn done(&mut self, id: usize, regs: (usize, usize, usize, usize)) {
+        self.done.insert(id, regs);
+        self.wait.wake();
+    }
 }
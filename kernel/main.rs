@@ -45,10 +45,13 @@ use core::slice::SliceExt;
 use common::event::{self, EVENT_KEY, EventOption};
 use common::memory;
 use common::paging::Page;
-use common::time::Duration;
+use common::time::{Duration, NANOS_PER_SEC};
 
 use drivers::pci;
+use drivers::hpet::Hpet;
 use drivers::io::{Io, Pio};
+use drivers::ioapic::IoApic;
+use drivers::lapic::{Lapic, LAPIC_SPURIOUS};
 use drivers::ps2::*;
 use drivers::rtc::*;
 use drivers::serial::*;
@@ -57,8 +60,11 @@ use env::Environment;
 
 use graphics::display;
 
-use scheduler::{Context, Regs, TSS};
-use scheduler::context::context_switch;
+use scheduler::{Context, Regs};
+use scheduler::context::{context_exit, context_switch};
+use scheduler::cpu::{cpu_apic, cpu_flush_pending, cpu_id, cpu_init, cpu_start, cpu_timer,
+                     cpu_timer_interrupt, CPU_FLUSH};
+use scheduler::signal::signal_deliver;
 
 use schemes::Url;
 use schemes::context::*;
@@ -66,8 +72,14 @@ use schemes::debug::*;
 use schemes::display::*;
 use schemes::interrupt::*;
 use schemes::memory::*;
+use schemes::pty::*;
 use schemes::test::*;
+use schemes::time::*;
 
+use sync::Intex;
+use sync::intex::{intex_release, intex_restore};
+
+use syscall::{SIGBUS, SIGFPE, SIGILL, SIGSEGV, SIGSYS, SIGTRAP};
 use syscall::execute::execute;
 use syscall::handle::*;
 
@@ -92,6 +104,9 @@ pub mod env;
 pub mod fs;
 /// Various graphical methods
 pub mod graphics;
+/// Network stack
+#[path="../drivers/network/mod.rs"]
+pub mod network;
 /// Panic
 pub mod panic;
 /// Schemes
@@ -105,7 +120,6 @@ pub mod syscall;
 /// USB input/output
 pub mod usb;
 
-pub static mut TSS_PTR: Option<&'static mut TSS> = None;
 pub static mut ENV_PTR: Option<&'static mut Environment> = None;
 
 pub fn env() -> &'static Environment {
@@ -118,11 +132,14 @@ pub fn env() -> &'static Environment {
 }
 
 /// Pit duration
-static PIT_DURATION: Duration = Duration {
+pub static PIT_DURATION: Duration = Duration {
     secs: 0,
     nanos: 2250286,
 };
 
+/// The realtime clock is slewed by at most one part in SLEW_RATE, 500 ppm
+const SLEW_RATE: i32 = 2000;
+
 /// Idle loop (active while idle)
 unsafe fn idle_loop() {
     loop {
@@ -139,12 +156,15 @@ unsafe fn idle_loop() {
 
 
         if halt {
+            // Other processors run the kernel while this one halts
+            let intex = intex_release();
             asm!("sti
                   hlt"
                   :
                   :
                   :
                   : "intel", "volatile");
+            intex_restore(intex);
         } else {
             asm!("sti"
                 :
@@ -240,7 +260,7 @@ static BSS_TEST_ZERO: usize = 0;
 static BSS_TEST_NONZERO: usize = usize::MAX;
 
 /// Initialize kernel
-unsafe fn init(tss_data: usize) {
+unsafe fn init(tss_data: usize, trampoline: usize) {
 
     // Test
     assume!(true);
@@ -270,33 +290,59 @@ unsafe fn init(tss_data: usize) {
     // Unmap first page to catch null pointer errors (after reading memory map)
     Page::new(0).unmap();
 
-    TSS_PTR = Some(&mut *(tss_data as *mut TSS));
+    cpu_init(tss_data);
     ENV_PTR = Some(&mut *Box::into_raw(Environment::new()));
 
     match ENV_PTR {
         Some(ref mut env) => {
-            env.contexts.lock().push(Context::root());
+            env.contexts.lock().push_idle();
             env.console.lock().draw = true;
 
             debug!("Redox {} bits\n", mem::size_of::<usize>() * 8);
 
+            let mut century = 0;
             if let Some(acpi) = Acpi::new() {
+                if let Some(fadt) = acpi.fadt() {
+                    century = fadt.century;
+                }
+
+                if let Some(hpet) = acpi.hpet() {
+                    // The registers of the HPET are in memory
+                    if hpet.address_space == 0 {
+                        Hpet::init(hpet.address as usize, Duration::monotonic());
+                    }
+                }
+
+                if let Some(madt) = acpi.madt() {
+                    cpu_apic(madt);
+
+                    let madt = madt.clone();
+                    Context::spawn("kcpu".to_string(),
+                    box move || {
+                        unsafe { cpu_start(&madt, trampoline) };
+                    });
+                }
+
                 env.schemes.push(UnsafeCell::new(acpi));
             }
 
-            *(env.clock_realtime.lock()) = Rtc::new().time();
+            *(env.clock_realtime.lock()) = Rtc::new(century).time();
+            env.schemes.push(UnsafeCell::new(box Rtc::new(century)));
 
             env.schemes.push(UnsafeCell::new(Ps2::new()));
             env.schemes.push(UnsafeCell::new(Serial::new(0x3F8, 0x4)));
 
             pci::pci_init(env);
+            network::network_init(env);
 
             env.schemes.push(UnsafeCell::new(DebugScheme::new()));
             env.schemes.push(UnsafeCell::new(box DisplayScheme));
             env.schemes.push(UnsafeCell::new(box ContextScheme));
             env.schemes.push(UnsafeCell::new(box InterruptScheme));
             env.schemes.push(UnsafeCell::new(box MemoryScheme));
+            env.schemes.push(UnsafeCell::new(PtyScheme::new()));
             env.schemes.push(UnsafeCell::new(box TestScheme));
+            env.schemes.push(UnsafeCell::new(box TimeScheme));
 
             Context::spawn("kpoll".to_string(),
             box move || {
@@ -339,12 +385,24 @@ unsafe fn init(tss_data: usize) {
 #[no_mangle]
 /// Take regs for kernel calls and exceptions
 pub extern "cdecl" fn kernel(interrupt: usize, mut regs: &mut Regs) {
+    // A flush of the TLB is asked for by a processor that holds the kernel lock, and waits
+    if interrupt == CPU_FLUSH as usize {
+        unsafe { cpu_flush_pending() };
+        if let Some(lapic) = Lapic::current() {
+            lapic.eoi();
+        }
+        return;
+    }
+
+    // The kernel runs on one processor at a time
+    let _intex = Intex::static_lock();
+
     macro_rules! exception_inner {
         ($name:expr) => ({
             {
                 let contexts = ::env().contexts.lock();
                 if let Some(context) = contexts.current() {
-                    debugln!("PID {}: {}", context.pid, context.name);
+                    debugln!("CPU {}: PID {}: {}", cpu_id(), context.pid, context.name);
                 }
             }
 
@@ -378,40 +436,53 @@ pub extern "cdecl" fn kernel(interrupt: usize, mut regs: &mut Regs) {
     };
 
     macro_rules! exception {
-        ($name:expr) => ({
+        ($name:expr, $sig:expr) => ({
             exception_inner!($name);
 
             loop {
-                do_sys_exit(usize::MAX);
+                context_exit($sig);
             }
         })
     };
 
     macro_rules! exception_error {
-        ($name:expr) => ({
-            let error = regs.ip;
-            regs.ip = regs.cs;
-            regs.cs = regs.flags;
-            regs.flags = regs.sp;
-            regs.sp = regs.ss;
-            regs.ss = 0;
-            //regs.ss = regs.error;
-
+        ($name:expr, $sig:expr) => ({
             exception_inner!($name);
-            debugln!("    ERR: {:08X}", error);
+            debugln!("    ERR: {:08X}", regs.error);
 
             loop {
-                do_sys_exit(usize::MAX);
+                context_exit($sig);
             }
         })
     };
 
-    if interrupt >= 0x20 && interrupt < 0x30 {
+    // Hardware errors and kernel bugs are not caused by the current context, so they stop the
+    // kernel instead of terminating it
+    macro_rules! exception_fatal {
+        ($name:expr) => ({
+            exception_inner!($name);
+            panic!($name);
+        })
+    };
+
+    macro_rules! exception_error_fatal {
+        ($name:expr) => ({
+            exception_inner!($name);
+            debugln!("    ERR: {:08X}", regs.error);
+            panic!($name);
+        })
+    };
+
+    if interrupt >= 0x20 && interrupt < 0x30 && !IoApic::enabled() {
         if interrupt >= 0x28 {
             Pio::<u8>::new(0xA0).write(0x20);
         }
 
         Pio::<u8>::new(0x20).write(0x20);
+    } else if interrupt >= 0x20 && interrupt < LAPIC_SPURIOUS as usize {
+        if let Some(lapic) = Lapic::current() {
+            lapic.eoi();
+        }
     }
 
     //Do not catch init interrupt
@@ -423,59 +494,138 @@ pub extern "cdecl" fn kernel(interrupt: usize, mut regs: &mut Regs) {
         0x20 => {
             {
                 let mut clock_monotonic = env().clock_monotonic.lock();
-                *clock_monotonic = *clock_monotonic + PIT_DURATION;
+                *clock_monotonic = match Hpet::current() {
+                    Some(hpet) => hpet.monotonic(),
+                    None => *clock_monotonic + PIT_DURATION,
+                };
             }
             {
+                // Apply part of the slew, at most SLEW_RATE of the tick
+                let mut clock_slew = env().clock_slew.lock();
+                let slew = clock_slew.secs
+                                     .saturating_mul(NANOS_PER_SEC as i64)
+                                     .saturating_add(clock_slew.nanos as i64);
+                let max = (PIT_DURATION.nanos / SLEW_RATE) as i64;
+                let step = if slew > max {
+                    max
+                } else if slew < -max {
+                    -max
+                } else {
+                    slew
+                };
+                *clock_slew = *clock_slew - Duration::new(0, step as i32);
+
                 let mut clock_realtime = env().clock_realtime.lock();
-                *clock_realtime = *clock_realtime + PIT_DURATION;
+                *clock_realtime = *clock_realtime + PIT_DURATION + Duration::new(0, step as i32);
             }
 
-            let switch = {
-                let mut contexts = ::env().contexts.lock();
-                if let Some(mut context) = contexts.current_mut() {
-                    context.slices -= 1;
-                    context.slice_total += 1;
-                    context.slices == 0
-                } else {
-                    false
-                }
-            };
+            // The local APIC timers tick the scheduler and fire the timers once they run
+            if !cpu_timer() {
+                env().timers.lock().fire(Duration::monotonic());
 
-            if switch {
-                unsafe { context_switch(true) };
+                let switch = ::env().contexts.lock().tick(PIT_DURATION, regs.cs & 3 == 3);
+
+                if switch {
+                    unsafe { context_switch(true) };
+                }
             }
         }
-        i @ 0x21 ... 0x2F => env().on_irq(i as u8 - 0x20),
+        i @ 0x21 ... 0x3F => env().on_irq(i as u8 - 0x20),
+        0x40 => {
+            // The tick of each processor and the timers, from its local APIC timer
+            if unsafe { cpu_timer_interrupt(Duration::monotonic()) } {
+                let switch = ::env().contexts.lock().tick(PIT_DURATION, regs.cs & 3 == 3);
+
+                if switch {
+                    unsafe { context_switch(true) };
+                }
+            }
+        }
+        0x4F => (), // Spurious interrupt of the local APIC
         0x80 => if !syscall_handle(regs) {
-            exception!("Unknown Syscall");
+            exception!("Unknown Syscall", SIGSYS);
         },
         0xFF => {
             unsafe {
-                init(regs.ax);
+                init(regs.ax, regs.bx);
                 idle_loop();
             }
         },
-        0x0 => exception!("Divide by zero exception"),
-        0x1 => exception!("Debug exception"),
-        0x2 => exception!("Non-maskable interrupt"),
-        0x3 => exception!("Breakpoint exception"),
-        0x4 => exception!("Overflow exception"),
-        0x5 => exception!("Bound range exceeded exception"),
-        0x6 => exception!("Invalid opcode exception"),
-        0x7 => exception!("Device not available exception"),
-        0x8 => exception_error!("Double fault"),
-        0x9 => exception!("Coprocessor Segment Overrun"), // legacy
-        0xA => exception_error!("Invalid TSS exception"),
-        0xB => exception_error!("Segment not present exception"),
-        0xC => exception_error!("Stack-segment fault"),
-        0xD => exception_error!("General protection fault"),
-        0xE => exception_error!("Page fault"),
-        0x10 => exception!("x87 floating-point exception"),
-        0x11 => exception_error!("Alignment check exception"),
-        0x12 => exception!("Machine check exception"),
-        0x13 => exception!("SIMD floating-point exception"),
-        0x14 => exception!("Virtualization exception"),
-        0x1E => exception_error!("Security exception"),
-        _ => exception!("Unknown Interrupt"),
+        0x0 => exception!("Divide by zero exception", SIGFPE),
+        0x1 => exception!("Debug exception", SIGTRAP),
+        0x2 => exception_fatal!("Non-maskable interrupt"),
+        0x3 => exception!("Breakpoint exception", SIGTRAP),
+        0x4 => exception!("Overflow exception", SIGSEGV),
+        0x5 => exception!("Bound range exceeded exception", SIGSEGV),
+        0x6 => exception!("Invalid opcode exception", SIGILL),
+        // The FPU state is saved and restored on every switch, so it is never unavailable
+        0x7 => exception_fatal!("Device not available exception"),
+        0x8 => exception_error_fatal!("Double fault"),
+        0x9 => exception!("Coprocessor Segment Overrun", SIGFPE), // legacy
+        0xA => exception_error!("Invalid TSS exception", SIGSEGV),
+        0xB => exception_error!("Segment not present exception", SIGBUS),
+        0xC => exception_error!("Stack-segment fault", SIGBUS),
+        0xD => exception_error!("General protection fault", SIGSEGV),
+        0xE => {
+            let error = regs.error;
+            let address: usize;
+            unsafe { asm!("mov $0, cr2" : "=r"(address) : : : "intel", "volatile") };
+
+            // Allocate pages of the current context when first used, by userspace or the kernel
+            let handled = {
+                let mut contexts = env().contexts.lock();
+                if let Some(mut current) = contexts.current_mut() {
+                    unsafe { current.page_in(address, error & 2 == 2, error & 0x10 == 0x10) }
+                } else {
+                    false
+                }
+            };
+
+            if !handled {
+                if regs.cs & 3 == 3 {
+                    // An invalid access from userspace only terminates the program
+                    {
+                        let contexts = env().contexts.lock();
+                        if let Some(context) = contexts.current() {
+                            debugln!("PID {}: {}: segmentation fault at {:X}, IP {:X}: {} {}",
+                                     context.pid,
+                                     context.name,
+                                     address,
+                                     regs.ip,
+                                     if error & 0x10 == 0x10 {
+                                         "execute"
+                                     } else if error & 2 == 2 {
+                                         "write"
+                                     } else {
+                                         "read"
+                                     },
+                                     if error & 1 == 1 {
+                                         "not allowed"
+                                     } else {
+                                         "not mapped"
+                                     });
+                        }
+                    }
+
+                    loop {
+                        context_exit(SIGSEGV);
+                    }
+                } else {
+                    exception_error!("Page fault", SIGSEGV);
+                }
+            }
+        }
+        0x10 => exception!("x87 floating-point exception", SIGFPE),
+        0x11 => exception_error!("Alignment check exception", SIGBUS),
+        0x12 => exception_fatal!("Machine check exception"),
+        0x13 => exception!("SIMD floating-point exception", SIGFPE),
+        0x14 => exception!("Virtualization exception", SIGSEGV),
+        0x1E => exception_error!("Security exception", SIGSEGV),
+        _ => exception!("Unknown Interrupt", SIGSEGV),
+    }
+
+    // Deliver signals before returning to userspace
+    if regs.cs & 3 == 3 {
+        unsafe { signal_deliver(regs) };
     }
 }
@@ -8,26 +8,46 @@ use collections::vec::Vec;
 
 use core::cell::UnsafeCell;
 use core::slice::{Iter, IterMut};
-use core::{mem, ptr};
+use core::{cmp, mem, ptr};
 use core::ops::DerefMut;
 
 use common::memory;
 use common::paging::Page;
+use common::time::Duration;
+
+use scheduler::cpu::{cpu_flush, cpu_id, cpu_tss, CPUS, CPU_MAX};
+use scheduler::session::{session_exit, Terminal};
+use scheduler::signal::{signal_notify, ContextSignals};
 
 use schemes::Resource;
 
-use syscall::{CLONE_FILES, CLONE_FS, CLONE_VM};
+use sync::Intex;
+use sync::intex::{intex_release, intex_restore};
+
+use syscall::{CLONE_FILES, CLONE_FS, CLONE_VM, PRIO_MIN};
 use syscall::handle::do_sys_exit;
 
 pub const CONTEXT_STACK_SIZE: usize = 1024 * 1024;
 pub const CONTEXT_STACK_ADDR: usize = 0x70000000;
+/// The area where memory maps are placed
+pub const CONTEXT_MMAP_ADDR: usize = 0x80000000;
+pub const CONTEXT_MMAP_SIZE: usize = 0x40000000;
+/// The largest file map, as files are read when mapped
+pub const CONTEXT_MMAP_READ: usize = 16 * 1024 * 1024;
 pub const CONTEXT_SLICES: usize = 4;
+/// Ticks between decays of the recent CPU usage of contexts
+pub const CONTEXT_DECAY_TICKS: usize = 256;
+/// The usage, in ticks, that one nice level is worth
+pub const CONTEXT_NICE_USAGE: usize = 8;
 
 pub struct ContextManager {
     pub inner: Vec<Box<Context>>,
     pub enabled: bool,
-    pub i: usize,
+    /// The index of the current context of each processor
+    pub i: [usize; CPU_MAX],
     pub next_pid: usize,
+    /// Ticks since the usage last decayed
+    pub ticks: usize,
 }
 
 impl ContextManager {
@@ -35,18 +55,19 @@ impl ContextManager {
         ContextManager {
             inner: Vec::new(),
             enabled: false,
-            i: 0,
+            i: [0; CPU_MAX],
             next_pid: 1,
+            ticks: 0,
         }
     }
 
     pub fn current(&self) -> Option<&Box<Context>> {
-        let i = self.i;
+        let i = self.i[cpu_id()];
         self.get(i)
     }
 
     pub fn current_mut(&mut self) -> Option<&mut Box<Context>> {
-        let i = self.i;
+        let i = self.i[cpu_id()];
         self.get_mut(i)
     }
 
@@ -82,29 +103,96 @@ impl ContextManager {
         self.inner.push(context);
     }
 
+    /// Push the idle context of the current processor, which is what it runs now
+    pub unsafe fn push_idle(&mut self) {
+        let cpu = cpu_id();
+        let mut context = Context::root();
+        CPUS[cpu].current = context.deref_mut() as *mut Context as usize;
+        self.inner.push(context);
+        self.i[cpu] = self.inner.len() - 1;
+    }
+
+    /// Remove the contexts that exited, except those still running
     pub unsafe fn clean(&mut self) {
-        loop {
-            if self.i >= self.len() {
-                self.i -= self.len();
+        let mut i = 0;
+        while i < self.inner.len() {
+            if self.inner[i].exited && !self.inner[i].running {
+                drop(self.inner.remove(i));
+                for current in self.i.iter_mut() {
+                    if i < *current {
+                        *current -= 1;
+                    }
+                }
+            } else {
+                i += 1;
             }
+        }
+    }
 
-            let mut remove = false;
-            if let Some(next) = self.current() {
-                if next.exited {
-                    remove = true;
-                }
+    /// Unblock the context with the PID `pid`, if it is blocked
+    pub fn unblock(&mut self, pid: usize) {
+        for context in self.inner.iter_mut() {
+            if context.pid == pid {
+                context.unblock();
             }
+        }
+    }
 
-            if remove {
-                let i = self.i;
-                drop(self.inner.remove(i));
+    /// Account a timer tick of `duration` to the current context, as user time if `user`.
+    /// Returns true if the time slices of the context ran out
+    pub fn tick(&mut self, duration: Duration, user: bool) -> bool {
+        // Every processor ticks, the usage decays with the ticks of the first
+        if cpu_id() == 0 {
+            self.ticks += 1;
+        }
+        if self.ticks >= CONTEXT_DECAY_TICKS {
+            self.ticks = 0;
+            // Usage from long ago is forgotten, so a context is not behind forever
+            for context in self.inner.iter_mut() {
+                context.usage /= 2;
+            }
+        }
+
+        if let Some(mut context) = self.current_mut() {
+            if user {
+                context.user_time = context.user_time + duration;
             } else {
-                break;
+                context.system_time = context.system_time + duration;
+            }
+            context.usage += 1;
+            context.slices = context.slices.saturating_sub(1);
+            context.slices == 0
+        } else {
+            false
+        }
+    }
+
+    /// Choose the next context to run on the current processor. Of the contexts that can run,
+    /// the one with the lowest priority value is chosen, and equal ones take turns. The current
+    /// context continues only if no other can run
+    unsafe fn schedule(&mut self) {
+        let now = Duration::monotonic();
+        let cpu = cpu_id();
+        let current = self.i[cpu];
+
+        let len = self.len();
+        let mut next: Option<(usize, usize)> = None;
+        for offset in 1..len {
+            let i = (current + offset) % len;
+            let context = &mut self.inner[i];
+            if context.exited || context.running || context.cpu.map_or(false, |id| id != cpu) ||
+               !context.runnable(now) {
+                continue;
+            }
+
+            let priority = context.priority();
+            if next.map_or(true, |(_, best)| priority < best) {
+                next = Some((i, priority));
             }
         }
 
-        if self.i >= self.len() {
-            self.i -= self.len();
+        if let Some((i, _)) = next {
+            self.i[cpu] = i;
         }
     }
 }
@@ -117,48 +205,143 @@ pub unsafe fn context_switch(interrupted: bool) {
     let mut current_ptr: *mut Context = 0 as *mut Context;
     let mut next_ptr: *mut Context = 0 as *mut Context;
 
-    let mut contexts = ::env().contexts.lock();
-    if contexts.enabled {
-        let current_i = contexts.i;
-        contexts.i += 1;
-        contexts.clean();
+    {
+        let mut contexts = ::env().contexts.lock();
+        if contexts.enabled {
+            let cpu = cpu_id();
 
-        if contexts.i != current_i {
-            if let Some(mut current) = contexts.get_mut(current_i) {
-                current.interrupted = interrupted;
+            contexts.clean();
+            let current_i = contexts.i[cpu];
+            contexts.schedule();
 
-                current.unmap();
+            if contexts.i[cpu] != current_i {
+                if let Some(mut current) = contexts.get_mut(current_i) {
+                    current.interrupted = interrupted;
 
-                current_ptr = current.deref_mut();
-            }
+                    current.unmap();
 
-            if let Some(mut next) = contexts.current_mut() {
-                next.interrupted = false;
-                next.slices = if interrupted {
-                    CONTEXT_SLICES
-                } else {
-                    CONTEXT_SLICES + 1
-                };
+                    current_ptr = current.deref_mut();
+                }
 
-                if next.kernel_stack > 0 {
-                    if let Some(ref mut tss) = ::TSS_PTR {
-                        tss.sp0 = next.kernel_stack + CONTEXT_STACK_SIZE - 128;
-                    }
-                } else {
-                    if let Some(ref mut tss) = ::TSS_PTR {
-                        tss.sp0 = 0x200000 - 128;
+                if let Some(mut next) = contexts.current_mut() {
+                    next.interrupted = false;
+                    next.running = true;
+                    next.slices = if interrupted {
+                        CONTEXT_SLICES
+                    } else {
+                        CONTEXT_SLICES + 1
+                    };
+
+                    if let Some(tss) = cpu_tss() {
+                        tss.sp0 = if next.kernel_stack > 0 {
+                            next.kernel_stack + CONTEXT_STACK_SIZE - 128
+                        } else {
+                            CPUS[cpu].stack
+                        };
                     }
-                }
 
-                next.map();
+                    next.map();
 
-                next_ptr = next.deref_mut();
+                    next_ptr = next.deref_mut();
+                }
             }
         }
     }
 
     if current_ptr as usize > 0 && next_ptr as usize > 0 {
+        let cpu = &mut CPUS[cpu_id()];
+        cpu.previous = current_ptr as usize;
+        cpu.current = next_ptr as usize;
+
+        // Other processors run the kernel while this one is in the other context
+        (*current_ptr).intex = intex_release();
+
         (*current_ptr).switch_to(&mut *next_ptr);
+
+        context_switched();
+    }
+}
+
+/// Finish a switch in the context switched to. The kernel lock is held again as the context
+/// held it, and the context switched from can run on other processors
+unsafe fn context_switched() {
+    let cpu = &mut CPUS[cpu_id()];
+
+    intex_restore((*(cpu.current as *mut Context)).intex);
+
+    let _intex = Intex::static_lock();
+    if cpu.previous > 0 {
+        (*(cpu.previous as *mut Context)).running = false;
+        cpu.previous = 0;
+    }
+}
+
+/// The start of new contexts, the call of the context returns here from `switch_to`
+pub unsafe extern "cdecl" fn context_enter() {
+    context_switched();
+}
+
+/// Block the current context until it is unblocked, or until the monotonic clock reaches `wake`.
+/// It may be unblocked early, by a signal, so the reason for blocking must be checked again
+pub unsafe fn context_block(wake: Option<Duration>) {
+    {
+        let mut contexts = ::env().contexts.lock();
+        if let Some(mut current) = contexts.current_mut() {
+            current.blocked = true;
+            current.wake = wake;
+        }
+    }
+
+    context_switch(false);
+}
+
+/// Exit the current context, `status` is reported to the parent as by `waitpid`
+pub fn context_exit(status: usize) {
+    {
+        let mut contexts = ::env().contexts.lock();
+
+        let mut statuses = Vec::new();
+        let (pid, ppid, sid) = {
+            if let Some(mut current) = contexts.current_mut() {
+                current.exited = true;
+                mem::swap(&mut statuses, &mut current.statuses);
+                (current.pid, current.ppid, current.sid)
+            } else {
+                (0, 0, 0)
+            }
+        };
+
+        for mut context in contexts.iter_mut() {
+            // Add exit status to parent, replacing reports that it stopped or continued
+            if context.pid == ppid {
+                context.statuses.retain(|status| status.pid != pid);
+                for status in statuses.iter() {
+                    context.statuses.push(ContextStatus {
+                        pid: status.pid,
+                        pgid: status.pgid,
+                        status: status.status,
+                    });
+                }
+            }
+
+            // Move children to parent
+            if context.ppid == pid {
+                context.ppid = ppid;
+            }
+        }
+
+        signal_notify(&mut contexts, pid, ppid, status);
+
+        // The controlling process of a terminal exited
+        if pid == sid {
+            session_exit(&mut contexts, sid);
+        }
+    }
+
+    loop {
+        unsafe {
+            context_switch(false);
+        }
     }
 }
 
@@ -167,7 +350,13 @@ pub unsafe fn context_switch(interrupted: bool) {
 /// Unsafe due to interrupt disabling, C memory handling, and raw pointers
 pub unsafe extern "cdecl" fn context_clone(parent_ptr: *const Context,
                                            flags: usize,
-                                           clone_pid: usize) {
+                                           clone_pid: usize,
+                                           cloned: *mut bool) {
+    // The parent must have switched away, so that its kernel stack is saved
+    while (*parent_ptr).running {
+        context_switch(false);
+    }
+
     {
         let mut contexts = ::env().contexts.lock();
 
@@ -175,38 +364,38 @@ pub unsafe extern "cdecl" fn context_clone(parent_ptr: *const Context,
         if kernel_stack > 0 {
             let parent = &*parent_ptr;
 
-            ::memcpy(kernel_stack as *mut u8,
-                     parent.kernel_stack as *const u8,
-                     CONTEXT_STACK_SIZE + 512);
+            // Only the part of the kernel stack in use, and the saved SSE and FPU registers
+            let unused = cmp::min((parent.sp - parent.kernel_stack).saturating_sub(128),
+                                  CONTEXT_STACK_SIZE) & !4095;
+            ::memcpy((kernel_stack + unused) as *mut u8,
+                     (parent.kernel_stack + unused) as *const u8,
+                     CONTEXT_STACK_SIZE + 512 - unused);
 
             let context = box Context {
                 pid: clone_pid,
                 ppid: parent.pid,
+                pgid: parent.pgid,
+                sid: parent.sid,
                 name: parent.name.clone(),
                 interrupted: parent.interrupted,
                 exited: parent.exited,
                 slices: CONTEXT_SLICES,
-                slice_total: 0,
+                nice: parent.nice,
+                usage: parent.usage,
+                user_time: Duration::new(0, 0),
+                system_time: Duration::new(0, 0),
+                blocked: false,
+                wake: None,
+                running: false,
+                cpu: None,
+                intex: parent.intex,
 
                 kernel_stack: kernel_stack,
                 sp: parent.sp - parent.kernel_stack + kernel_stack,
                 flags: parent.flags,
                 fx: kernel_stack + CONTEXT_STACK_SIZE,
                 stack: if let Some(ref entry) = parent.stack {
-                    let physical_address = memory::alloc(entry.virtual_size);
-                    if physical_address > 0 {
-                        ::memcpy(physical_address as *mut u8,
-                                 entry.physical_address as *const u8,
-                                 entry.virtual_size);
-                        Some(ContextMemory {
-                            physical_address: physical_address,
-                            virtual_address: entry.virtual_address,
-                            virtual_size: entry.virtual_size,
-                            writeable: entry.writeable,
-                        })
-                    } else {
-                        None
-                    }
+                    Some(entry.fork())
                 } else {
                     None
                 },
@@ -223,21 +412,19 @@ pub unsafe extern "cdecl" fn context_clone(parent_ptr: *const Context,
                 } else {
                     let mut mem: Vec<ContextMemory> = Vec::new();
                     for entry in (*parent.memory.get()).iter() {
-                        let physical_address = memory::alloc(entry.virtual_size);
-                        if physical_address > 0 {
-                            ::memcpy(physical_address as *mut u8,
-                                     entry.physical_address as *const u8,
-                                     entry.virtual_size);
-                            mem.push(ContextMemory {
-                                physical_address: physical_address,
-                                virtual_address: entry.virtual_address,
-                                virtual_size: entry.virtual_size,
-                                writeable: entry.writeable,
-                            });
-                        }
+                        mem.push(entry.fork());
                     }
                     Arc::new(UnsafeCell::new(mem))
                 },
+                mmap: if flags & CLONE_VM == CLONE_VM {
+                    parent.mmap.clone()
+                } else {
+                    let mut mmap: Vec<ContextMemory> = Vec::new();
+                    for entry in (*parent.mmap.get()).iter() {
+                        mmap.push(entry.fork());
+                    }
+                    Arc::new(UnsafeCell::new(mmap))
+                },
                 files: if flags & CLONE_FILES == CLONE_FILES {
                     parent.files.clone()
                 } else {
@@ -254,10 +441,21 @@ pub unsafe extern "cdecl" fn context_clone(parent_ptr: *const Context,
                 },
 
                 statuses: Vec::new(),
+                signals: parent.signals.fork(),
+                terminal: parent.terminal.clone(),
             };
 
+            // Pages of the parent that are shared now are read only for its other threads
+            if flags & CLONE_VM != CLONE_VM {
+                parent.map_others();
+            }
+
             contexts.push(context);
         }
+
+        // The parent waits until it was copied
+        *cloned = true;
+        contexts.unblock(parent.pid);
     }
 
     do_sys_exit(0);
@@ -306,36 +504,262 @@ pub unsafe extern "cdecl" fn context_box(box_fn_ptr: usize) {
     do_sys_exit(0);
 }
 
-///TODO: Investigate for double frees
+/// Memory of a context. Pages are allocated, zeroed, when first used, usually on a page fault.
+/// Pages shared with a forked context are read only, until a write copies them
 pub struct ContextMemory {
-    pub physical_address: usize,
     pub virtual_address: usize,
     pub virtual_size: usize,
     pub writeable: bool,
+    /// Userspace can read, a map that can not be read or written is not present
+    pub readable: bool,
+    /// Userspace can execute, where the processor supports it
+    pub executable: bool,
+    /// The physical address of every page that was used, 0 for pages that were not
+    pub pages: Vec<usize>,
+    /// The pages are shared with forks instead of copied when written, for `MAP_SHARED`
+    pub shared: bool,
 }
 
 impl ContextMemory {
-    pub unsafe fn map(&mut self) {
-        for i in 0..(self.virtual_size + 4095) / 4096 {
-            if self.writeable {
-                Page::new(self.virtual_address + i * 4096)
-                    .map_user_write(self.physical_address + i * 4096);
+    /// The number of pages
+    pub fn page_count(&self) -> usize {
+        (self.virtual_size + 4095) / 4096
+    }
+
+    /// The size of the pages that were allocated
+    pub fn resident(&self) -> usize {
+        self.pages.iter().filter(|&&page| page > 0).count() * 4096
+    }
+
+    /// Check if an address is in one of the pages
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.virtual_address &&
+        address - self.virtual_address < self.page_count() * 4096
+    }
+
+    /// Translate to physical, if the page of the address was allocated
+    pub fn translate(&self, address: usize) -> Option<usize> {
+        if self.contains(address) {
+            let offset = address - self.virtual_address;
+            if let Some(&page) = self.pages.get(offset / 4096) {
+                if page > 0 {
+                    return Some(page + offset % 4096);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Get the physical address of a page, allocating it if it was not used. Returns 0 if there
+    /// is no memory left
+    pub unsafe fn page(&mut self, i: usize) -> usize {
+        if i >= self.page_count() {
+            return 0;
+        }
+
+        while self.pages.len() <= i {
+            self.pages.push(0);
+        }
+        if self.pages[i] == 0 {
+            self.pages[i] = memory::alloc_page();
+        }
+        self.pages[i]
+    }
+
+    /// Get the physical address of a page that can be written, copying it if it is shared.
+    /// Returns 0 if there is no memory left
+    pub unsafe fn page_mut(&mut self, i: usize) -> usize {
+        let page = self.page(i);
+        if page > 0 && !self.shared && memory::share_count(page) > 1 {
+            let copy = memory::alloc_page();
+            if copy > 0 {
+                ::memcpy(copy as *mut u8, page as *const u8, 4096);
+                memory::unshare(page);
+                self.pages[i] = copy;
+            }
+            copy
+        } else {
+            page
+        }
+    }
+
+    /// Check if a page that was used can be written, it must not be shared unless the memory is
+    pub unsafe fn page_writeable(&self, i: usize) -> bool {
+        match self.pages.get(i) {
+            Some(&page) if page > 0 => {
+                self.writeable && (self.shared || memory::share_count(page) == 1)
+            }
+            _ => false,
+        }
+    }
+
+    /// Copy data to an offset, allocating the pages it covers
+    pub unsafe fn write(&mut self, offset: usize, data: &[u8]) -> bool {
+        let mut i = 0;
+        while i < data.len() {
+            let address = offset + i;
+            let page = self.page_mut(address / 4096);
+            if page == 0 {
+                return false;
+            }
+
+            let count = cmp::min(data.len() - i, 4096 - address % 4096);
+            ::memcpy((page + address % 4096) as *mut u8,
+                     data.as_ptr().offset(i as isize),
+                     count);
+            i += count;
+        }
+
+        true
+    }
+
+    /// Change the size, freeing the pages past the end
+    pub unsafe fn resize(&mut self, size: usize) {
+        self.virtual_size = size;
+
+        let count = self.page_count();
+        while self.pages.len() > count {
+            if let Some(page) = self.pages.pop() {
+                if page > 0 {
+                    memory::unshare(page);
+                }
+            }
+        }
+    }
+
+    /// Split at a page aligned offset, returning the memory from there on
+    pub fn split(&mut self, offset: usize) -> ContextMemory {
+        let pages = if self.pages.len() > offset / 4096 {
+            self.pages.split_off(offset / 4096)
+        } else {
+            Vec::new()
+        };
+
+        let tail = ContextMemory {
+            virtual_address: self.virtual_address + offset,
+            virtual_size: self.virtual_size - offset,
+            writeable: self.writeable,
+            readable: self.readable,
+            executable: self.executable,
+            pages: pages,
+            shared: self.shared,
+        };
+        self.virtual_size = offset;
+        tail
+    }
+
+    /// Copy on write, sharing the pages that were used. Both sides must be mapped again, so
+    /// that the shared pages are read only. Shared memory keeps using the same pages
+    pub unsafe fn fork(&self) -> ContextMemory {
+        for &page in self.pages.iter() {
+            memory::share(page);
+        }
+
+        ContextMemory {
+            virtual_address: self.virtual_address,
+            virtual_size: self.virtual_size,
+            writeable: self.writeable,
+            readable: self.readable,
+            executable: self.executable,
+            pages: self.pages.clone(),
+            shared: self.shared,
+        }
+    }
+
+    /// The physical address of the page `i` if it is present, which it is when it was used and
+    /// the memory can be accessed
+    fn present(&self, i: usize) -> Option<usize> {
+        match self.pages.get(i) {
+            Some(&physical_address) if physical_address > 0 &&
+                                       (self.readable || self.writeable) => Some(physical_address),
+            _ => None,
+        }
+    }
+
+    /// Call `f` with the first and last page in each page table, and if the table is filled by
+    /// the memory and none of its pages are present
+    fn tables<F: FnMut(usize, usize, bool)>(&self, mut f: F) {
+        let table_size = Page::table_size();
+        let end = self.virtual_address + self.page_count() * 4096;
+        let mut address = self.virtual_address;
+        while address < end {
+            let table_end = cmp::min((address / table_size + 1) * table_size, end);
+            let first = (address - self.virtual_address) / 4096;
+            let last = (table_end - self.virtual_address) / 4096;
+            let used = cmp::min(last, self.pages.len());
+            let empty = address % table_size == 0 && table_end % table_size == 0 &&
+                        (first..used).all(|i| self.present(i).is_none());
+            f(first, last, empty);
+            address = table_end;
+        }
+    }
+
+    /// Map the pages that were used in the page tables of the processor `cpu`, the others are
+    /// not present so that using them faults. Tables with no present pages are unmapped as a
+    /// whole, so that large maps that are mostly unused are quick to switch to
+    pub unsafe fn map(&mut self, cpu: usize) {
+        let mut flush = false;
+        self.tables(|first, last, empty| {
+            if empty {
+                Page::new_cpu(self.virtual_address + first * 4096, cpu).unmap_table();
+                flush = true;
             } else {
-                Page::new(self.virtual_address + i * 4096)
-                    .map_user_read(self.physical_address + i * 4096);
+                for i in first..last {
+                    self.map_page(i, cpu);
+                }
             }
+        });
+        if flush && cpu == cpu_id() {
+            Page::flush_all();
         }
     }
-    pub unsafe fn unmap(&mut self) {
-        for i in 0..(self.virtual_size + 4095) / 4096 {
-            Page::new(self.virtual_address + i * 4096).map_identity();
+
+    /// Map the page `i` on the processor `cpu` after `map`, mapping its table again with the
+    /// other pages not present if it was unmapped as a whole
+    pub unsafe fn map_page(&self, i: usize, cpu: usize) {
+        let mut page = Page::new_cpu(self.virtual_address + i * 4096, cpu);
+        if !page.table_present() {
+            let table_size = Page::table_size();
+            let table = page.virt_addr() / table_size * table_size;
+            for j in 0..table_size / 4096 {
+                Page::new_cpu(table + j * 4096, cpu).unmap();
+            }
+            page.map_table();
+        }
+
+        match self.present(i) {
+            Some(physical_address) => {
+                page.map_user(physical_address, self.page_writeable(i), self.executable)
+            }
+            None => page.unmap(),
         }
     }
+
+    /// Restore the identity mapping of the pages on the processor `cpu`, after `map`. Tables
+    /// that were unmapped as a whole still have it, and the TLB does not hold pages that are
+    /// not present, so it is not flushed
+    pub unsafe fn unmap(&mut self, cpu: usize) {
+        self.tables(|first, last, _| {
+            let mut page = Page::new_cpu(self.virtual_address + first * 4096, cpu);
+            if !page.table_present() {
+                page.map_table();
+            } else {
+                for i in first..last {
+                    Page::new_cpu(self.virtual_address + i * 4096, cpu).map_identity();
+                }
+            }
+        });
+    }
 }
 
 impl Drop for ContextMemory {
     fn drop(&mut self) {
-        unsafe { memory::unalloc(self.physical_address) };
+        for &page in self.pages.iter() {
+            if page > 0 {
+                unsafe { memory::unshare(page) };
+            }
+        }
     }
 }
 
@@ -346,6 +770,8 @@ pub struct ContextFile {
 
 pub struct ContextStatus {
     pub pid: usize,
+    /// The process group of the child when the status was reported
+    pub pgid: usize,
     pub status: usize,
 }
 
@@ -355,6 +781,10 @@ pub struct Context {
     pub pid: usize,
 /// The PID of the parent
     pub ppid: usize,
+/// The process group, the PID of its leader
+    pub pgid: usize,
+/// The session, the PID of its leader
+    pub sid: usize,
 /// The name of the context
     pub name: String,
 /// Indicates that the context was interrupted, used for prioritizing active contexts
@@ -363,8 +793,24 @@ pub struct Context {
     pub exited: bool,
 /// The number of time slices left
     pub slices: usize,
-/// The total of all used slices
-    pub slice_total: usize,
+/// The nice value, from `PRIO_MIN` to `PRIO_MAX`, lower values are scheduled first
+    pub nice: isize,
+/// Recent CPU usage in ticks, which decays over time
+    pub usage: usize,
+/// CPU time used in userspace
+    pub user_time: Duration,
+/// CPU time used in the kernel
+    pub system_time: Duration,
+/// Indicates that the context is blocked, it is not scheduled until unblocked
+    pub blocked: bool,
+/// The monotonic time when a blocked context is unblocked anyway
+    pub wake: Option<Duration>,
+/// Indicates that a processor runs the context, or is switching from it
+    pub running: bool,
+/// The processor that runs the context, if it can not run on the others
+    pub cpu: Option<usize>,
+/// The number of times the context held the kernel lock when it switched away
+    pub intex: usize,
 // }
 
 // These members control the stack and registers and are unique to each context {
@@ -389,12 +835,18 @@ pub struct Context {
     pub cwd: Arc<UnsafeCell<String>>,
 /// Program memory, cloned for threads, copied or created for processes. Modified by memory allocation
     pub memory: Arc<UnsafeCell<Vec<ContextMemory>>>,
+/// Program memory maps, cloned for threads, copied or created for processes. Modified by mmap, munmap and mprotect
+    pub mmap: Arc<UnsafeCell<Vec<ContextMemory>>>,
 /// Program files, cloned for threads, copied or created for processes. Modified by file operations
     pub files: Arc<UnsafeCell<Vec<ContextFile>>>,
 // }
 
 /// Exit statuses of children
     pub statuses: Vec<ContextStatus>,
+/// Pending and blocked signals, and their handlers
+    pub signals: ContextSignals,
+/// The controlling terminal of the session
+    pub terminal: Option<Arc<Intex<Terminal>>>,
 }
 
 impl Context {
@@ -428,14 +880,25 @@ impl Context {
     }
 
     pub unsafe fn root() -> Box<Self> {
+        let pid = Context::next_pid();
         box Context {
-            pid: Context::next_pid(),
+            pid: pid,
             ppid: 0,
+            pgid: pid,
+            sid: pid,
             name: "kidle".to_string(),
             interrupted: false,
             exited: false,
             slices: CONTEXT_SLICES,
-            slice_total: 0,
+            nice: 0,
+            usage: 0,
+            user_time: Duration::new(0, 0),
+            system_time: Duration::new(0, 0),
+            blocked: false,
+            wake: None,
+            running: true,
+            cpu: Some(cpu_id()),
+            intex: 0,
 
             kernel_stack: 0,
             sp: 0,
@@ -447,23 +910,38 @@ impl Context {
             args: Arc::new(UnsafeCell::new(Vec::new())),
             cwd: Arc::new(UnsafeCell::new(String::new())),
             memory: Arc::new(UnsafeCell::new(Vec::new())),
+            mmap: Arc::new(UnsafeCell::new(Vec::new())),
             files: Arc::new(UnsafeCell::new(Vec::new())),
 
             statuses: Vec::new(),
+            signals: ContextSignals::new(),
+            terminal: None,
         }
     }
 
     pub unsafe fn new(name: String, call: usize, args: &Vec<usize>) -> Box<Self> {
         let kernel_stack = memory::alloc(CONTEXT_STACK_SIZE + 512);
 
+        let pid = Context::next_pid();
         let mut ret = box Context {
-            pid: Context::next_pid(),
+            pid: pid,
             ppid: 0,
+            pgid: pid,
+            sid: pid,
             name: name,
             interrupted: false,
             exited: false,
             slices: CONTEXT_SLICES,
-            slice_total: 0,
+            nice: 0,
+            usage: 0,
+            user_time: Duration::new(0, 0),
+            system_time: Duration::new(0, 0),
+            blocked: false,
+            wake: None,
+            running: false,
+            cpu: None,
+            // Kernel code runs with the kernel lock
+            intex: 1,
 
             kernel_stack: kernel_stack,
             sp: kernel_stack + CONTEXT_STACK_SIZE - 128,
@@ -475,9 +953,12 @@ impl Context {
             args: Arc::new(UnsafeCell::new(Vec::new())),
             cwd: Arc::new(UnsafeCell::new(String::new())),
             memory: Arc::new(UnsafeCell::new(Vec::new())),
+            mmap: Arc::new(UnsafeCell::new(Vec::new())),
             files: Arc::new(UnsafeCell::new(Vec::new())),
 
             statuses: Vec::new(),
+            signals: ContextSignals::new(),
+            terminal: None,
         };
 
         for arg in args.iter() {
@@ -485,6 +966,7 @@ impl Context {
         }
 
         ret.push(call);
+        ret.push(context_enter as usize);
 
         ret
     }
@@ -530,6 +1012,31 @@ impl Context {
         }
     }
 
+    /// Unblock the context, if it is blocked
+    pub fn unblock(&mut self) {
+        self.blocked = false;
+        self.wake = None;
+    }
+
+    /// Check if the context can be scheduled, unblocking it if its timeout has passed
+    pub fn runnable(&mut self, now: Duration) -> bool {
+        if self.blocked {
+            if let Some(wake) = self.wake {
+                if now >= wake {
+                    self.unblock();
+                }
+            }
+        }
+
+        !self.blocked
+    }
+
+    /// The scheduling priority, lower values are scheduled first. Each nice level is worth
+    /// `CONTEXT_NICE_USAGE` ticks of usage
+    pub fn priority(&self) -> usize {
+        self.usage + (self.nice - PRIO_MIN) as usize * CONTEXT_NICE_USAGE
+    }
+
     /// Get the next available memory map address
     pub unsafe fn next_mem(&self) -> usize {
         let mut next_mem = 0;
@@ -545,23 +1052,146 @@ impl Context {
         return next_mem;
     }
 
-    /// Translate to physical if a ptr is inside of the mapped memory
+    /// Translate to physical if a ptr is inside of the mapped memory, and its page was used
     pub unsafe fn translate(&self, ptr: usize) -> Option<usize> {
+        let mems = self.stack
+                       .iter()
+                       .chain((*self.memory.get()).iter())
+                       .chain((*self.mmap.get()).iter());
+        for mem in mems {
+            if mem.contains(ptr) {
+                return mem.translate(ptr);
+            }
+        }
+
+        None
+    }
+
+    /// Make the page of an address present, allocating it if it was not used. Returns false if
+    /// the address is not in the memory of the context, or the memory does not allow the access.
+    /// This is how page faults are handled, so bss, heap and stack pages are allocated when used
+    pub unsafe fn page_in(&mut self, address: usize, write: bool, execute: bool) -> bool {
+        // Other threads may run with the memory, but not the stack
+        let cpus = if self.stack.as_ref().map_or(false, |stack| stack.contains(address)) {
+            0
+        } else {
+            self.cpus_sharing()
+        };
+
+        let mem = self.stack
+                      .iter_mut()
+                      .chain((*self.memory.get()).iter_mut())
+                      .chain((*self.mmap.get()).iter_mut())
+                      .find(|mem| mem.contains(address));
+
+        if let Some(mem) = mem {
+            if (mem.readable || mem.writeable) && (mem.writeable || !write) &&
+               (mem.executable || !execute) {
+                let i = (address - mem.virtual_address) / 4096;
+                // A write to a shared page copies it
+                let physical_address = if write {
+                    mem.page_mut(i)
+                } else {
+                    mem.page(i)
+                };
+                if physical_address > 0 {
+                    for cpu in 0..CPU_MAX {
+                        if cpu == cpu_id() || cpus & 1 << cpu == 1 << cpu {
+                            mem.map_page(i, cpu);
+                        }
+                    }
+                    cpu_flush(cpus);
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Check that neither program memory nor the stack is from `address` to `address + size`
+    pub unsafe fn program_free(&self, address: usize, size: usize) -> bool {
         if let Some(ref stack) = self.stack {
-            if ptr >= stack.virtual_address && ptr < stack.virtual_address + stack.virtual_size {
-                return Some(ptr - stack.virtual_address + stack.physical_address);
+            if address < stack.virtual_address + stack.virtual_size &&
+               stack.virtual_address < address + size {
+                return false;
             }
         }
 
         for mem in (*self.memory.get()).iter() {
-            if ptr >= mem.virtual_address && ptr < mem.virtual_address + mem.virtual_size {
-                return Some(ptr - mem.virtual_address + mem.physical_address);
+            if address < mem.virtual_address + mem.virtual_size &&
+               mem.virtual_address < address + size {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Check that no memory is mapped from `address` to `address + size`
+    pub unsafe fn mmap_free(&self, address: usize, size: usize) -> bool {
+        if !self.program_free(address, size) {
+            return false;
+        }
+
+        for mem in (*self.mmap.get()).iter() {
+            if address < mem.virtual_address + mem.virtual_size &&
+               mem.virtual_address < address + size {
+                return false;
             }
         }
 
+        true
+    }
+
+    /// Find the lowest free address in the memory map area for a map of `size`
+    pub unsafe fn mmap_find(&self, size: usize) -> Option<usize> {
+        let mut address = CONTEXT_MMAP_ADDR;
+        while address + size <= CONTEXT_MMAP_ADDR + CONTEXT_MMAP_SIZE {
+            let mut next = address;
+            for mem in (*self.mmap.get()).iter() {
+                if address < mem.virtual_address + mem.virtual_size &&
+                   mem.virtual_address < address + size {
+                    next = mem.virtual_address + mem.virtual_size;
+                    break;
+                }
+            }
+
+            if next == address {
+                return Some(address);
+            }
+            address = next;
+        }
+
         None
     }
 
+    /// Split the memory map containing a page aligned `address`, so that a map starts there
+    pub unsafe fn mmap_split(&mut self, address: usize) {
+        let mut tail = None;
+
+        for mem in (*self.mmap.get()).iter_mut() {
+            if address > mem.virtual_address && address < mem.virtual_address + mem.virtual_size {
+                let offset = address - mem.virtual_address;
+                tail = Some(mem.split(offset));
+                break;
+            }
+        }
+
+        if let Some(tail) = tail {
+            (*self.mmap.get()).push(tail);
+        }
+    }
+
+    /// Remove the memory maps from a page aligned `address` to `address + size`
+    pub unsafe fn mmap_remove(&mut self, address: usize, size: usize) {
+        self.mmap_split(address);
+        self.mmap_split(address + size);
+        (*self.mmap.get()).retain(|mem| {
+            mem.virtual_address < address || mem.virtual_address >= address + size
+        });
+    }
+
     /// Get a memory map from a pointer
     pub unsafe fn get_mem<'a>(&self, ptr: usize) -> Option<&'a ContextMemory> {
         for mem in (*self.memory.get()).iter() {
@@ -649,22 +1279,94 @@ impl Context {
         ptr::write(self.sp as *mut usize, data);
     }
 
+    /// Map the memory on the current processor, to switch to the context
     pub unsafe fn map(&mut self) {
+        let cpu = cpu_id();
+        if let Some(ref mut stack) = self.stack {
+            stack.map(cpu);
+        }
+        self.map_cpu(cpu);
+    }
+
+    /// Unmap the memory on the current processor, to switch away from the context
+    pub unsafe fn unmap(&mut self) {
+        let cpu = cpu_id();
+        self.unmap_cpu(cpu);
         if let Some(ref mut stack) = self.stack {
-            stack.map();
+            stack.unmap(cpu);
         }
+    }
+
+    /// Map the memory after it changed, also on the other processors that run a context
+    /// sharing it, which flush their TLB
+    pub unsafe fn map_shared(&mut self) {
+        self.map();
+        self.map_others();
+    }
+
+    /// Unmap the memory before it changes, also on the other processors that run a context
+    /// sharing it, which flush their TLB, so none of them uses pages that are freed
+    pub unsafe fn unmap_shared(&mut self) {
+        self.unmap_others();
+        self.unmap();
+    }
+
+    /// Map the memory, but not the stack, on the other processors that run a context sharing
+    /// it, after it changed
+    unsafe fn map_others(&self) {
+        let cpus = self.cpus_sharing();
+        for cpu in 0..CPU_MAX {
+            if cpus & 1 << cpu == 1 << cpu {
+                self.map_cpu(cpu);
+            }
+        }
+        cpu_flush(cpus);
+    }
+
+    unsafe fn unmap_others(&self) {
+        let cpus = self.cpus_sharing();
+        for cpu in 0..CPU_MAX {
+            if cpus & 1 << cpu == 1 << cpu {
+                self.unmap_cpu(cpu);
+            }
+        }
+        cpu_flush(cpus);
+    }
+
+    unsafe fn map_cpu(&self, cpu: usize) {
         for entry in (*self.memory.get()).iter_mut() {
-            entry.map();
+            entry.map(cpu);
+        }
+        for entry in (*self.mmap.get()).iter_mut() {
+            entry.map(cpu);
         }
     }
 
-    pub unsafe fn unmap(&mut self) {
+    unsafe fn unmap_cpu(&self, cpu: usize) {
+        for entry in (*self.mmap.get()).iter_mut() {
+            entry.unmap(cpu);
+        }
         for entry in (*self.memory.get()).iter_mut() {
-            entry.unmap();
+            entry.unmap(cpu);
         }
-        if let Some(ref mut stack) = self.stack {
-            stack.unmap();
+    }
+
+    /// The processors, other than the current one, that run a context with the same memory, a
+    /// bit for each index
+    unsafe fn cpus_sharing(&self) -> usize {
+        let memory: *const UnsafeCell<Vec<ContextMemory>> = &*self.memory;
+
+        let mut cpus = 0;
+        for cpu in 0..CPU_MAX {
+            let context = CPUS[cpu].current as *const Context;
+            if cpu != cpu_id() && !context.is_null() {
+                let other: *const UnsafeCell<Vec<ContextMemory>> = &*(*context).memory;
+                if other == memory {
+                    cpus |= 1 << cpu;
+                }
+            }
         }
+        cpus
     }
 
     // This function must not push or pop
@@ -0,0 +1,405 @@
+use core::intrinsics::{volatile_load, volatile_store};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use core::{ptr, u32};
+
+use acpi::MADT;
+
+use common::memory;
+use common::paging::Page;
+use common::time::{Duration, NANOS_PER_MICRO, NANOS_PER_MILLI, NANOS_PER_SEC};
+
+use drivers::ioapic::IoApic;
+use drivers::lapic::{Lapic, IPI_INIT, IPI_STARTUP};
+
+use scheduler::TSS;
+use scheduler::context::context_switch;
+
+/// The most processors that are used, the GDT has room for the TSS of each
+pub const CPU_MAX: usize = 16;
+
+/// The interrupt of the local APIC timer, for the scheduler tick of each processor and the
+/// timers of the first
+pub const CPU_TICK: u8 = 0x40;
+
+/// The interrupt that makes a processor flush its TLB, after another changed its page tables
+pub const CPU_FLUSH: u8 = 0x41;
+
+/// The processors that must flush their TLB, a bit for each index
+static CPU_FLUSHES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The ticks of the PIT the local APIC timer is measured over
+const CPU_TIMER_TICKS: u32 = 10;
+
+/// The selector of the TSS of the first processor, those of the others follow it
+const GDT_TSS: usize = 0x28;
+#[cfg(target_arch = "x86")]
+const GDT_TSS_SIZE: usize = 8;
+#[cfg(target_arch = "x86_64")]
+const GDT_TSS_SIZE: usize = 16;
+
+/// The real mode page where application processors start, it jumps to the trampoline
+const CPU_START: usize = 0x7000;
+
+/// The size of the idle stack of application processors
+const CPU_STACK_SIZE: usize = 64 * 1024;
+
+/// A processor
+#[derive(Copy, Clone)]
+pub struct Cpu {
+    /// The ID of its local APIC
+    pub apic_id: u8,
+    /// Indicates that it runs contexts
+    pub online: bool,
+    /// The address of its TSS
+    pub tss: usize,
+    /// The top of its idle stack, which idle contexts use as kernel stack
+    pub stack: usize,
+    /// The context it runs
+    pub current: usize,
+    /// The context it switched from, which can not run elsewhere until the switch finished
+    pub previous: usize,
+    /// The monotonic time of its next scheduler tick
+    pub tick: Duration,
+    /// The monotonic time its local APIC timer is armed for
+    pub deadline: Duration,
+}
+
+pub static mut CPUS: [Cpu; CPU_MAX] = [Cpu {
+    apic_id: 0,
+    online: false,
+    tss: 0,
+    stack: 0,
+    current: 0,
+    previous: 0,
+    tick: Duration { secs: 0, nanos: 0 },
+    deadline: Duration { secs: 0, nanos: 0 },
+}; CPU_MAX];
+
+/// The count of the local APIC timer for a tick of the PIT, 0 until it was measured
+static mut CPU_TIMER: u32 = 0;
+
+/// The fields of the trampoline in startup, which application processors run to enter the mode
+/// of the kernel
+#[repr(packed)]
+struct Trampoline {
+    jump: u64,
+    ready: u64,
+    cpu: u64,
+    page_table: u64,
+    stack: u64,
+    entry: u64,
+}
+
+/// The index of the current processor, from the TSS it loaded
+pub fn cpu_id() -> usize {
+    let selector: usize;
+    unsafe { asm!("str $0" : "=r"(selector) : : : "intel", "volatile") };
+    selector.saturating_sub(GDT_TSS) / GDT_TSS_SIZE
+}
+
+/// The number of processors that run contexts
+pub fn cpu_count() -> usize {
+    unsafe { CPUS.iter().filter(|cpu| cpu.online).count() }
+}
+
+/// The TSS of the current processor
+pub unsafe fn cpu_tss() -> Option<&'static mut TSS> {
+    let tss = CPUS[cpu_id()].tss;
+    if tss > 0 {
+        Some(&mut *(tss as *mut TSS))
+    } else {
+        None
+    }
+}
+
+/// Flush the TLBs of the processors in `cpus`, a bit for each index, after their page tables
+/// were changed, waiting until they did. The current processor is skipped, `Page` flushes its
+/// pages as they change
+pub unsafe fn cpu_flush(cpus: usize) {
+    let cpus = cpus & !(1 << cpu_id());
+    if cpus == 0 {
+        return;
+    }
+
+    let lapic = match Lapic::current() {
+        Some(lapic) => lapic,
+        None => return,
+    };
+
+    CPU_FLUSHES.fetch_or(cpus, Ordering::SeqCst);
+    for cpu in 0..CPU_MAX {
+        if cpus & 1 << cpu == 1 << cpu {
+            lapic.ipi(CPUS[cpu].apic_id, CPU_FLUSH as u32);
+        }
+    }
+
+    while CPU_FLUSHES.load(Ordering::SeqCst) & cpus != 0 {
+        asm!("pause" : : : : "intel", "volatile");
+    }
+}
+
+/// Flush the TLB of the current processor if another processor asked for it. This runs on the
+/// flush interrupt, and while waiting for the kernel lock, as interrupts are disabled then and
+/// the processor that asked holds it
+pub unsafe fn cpu_flush_pending() {
+    let bit = 1 << cpu_id();
+    if CPU_FLUSHES.load(Ordering::SeqCst) & bit == bit {
+        Page::flush_all();
+        CPU_FLUSHES.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Initialize the first processor, which uses the TSS and stack of startup
+pub unsafe fn cpu_init(tss: usize) {
+    CPUS[0] = Cpu {
+        apic_id: 0,
+        online: true,
+        tss: tss,
+        stack: 0x200000 - 128,
+        current: 0,
+        previous: 0,
+        tick: Duration::new(0, 0),
+        deadline: Duration::new(0, 0),
+    };
+}
+
+/// Switch the first processor from the PIC to its local APIC and the I/O APICs of the MADT
+pub unsafe fn cpu_apic(madt: &MADT) {
+    Lapic::init(madt.local_apic_address as usize);
+    if let Some(lapic) = Lapic::current() {
+        lapic.enable();
+        CPUS[0].apic_id = lapic.id();
+        IoApic::init(madt, CPUS[0].apic_id);
+    }
+}
+
+/// Indicates that the local APIC timers tick the scheduler and fire the timers, not the PIT
+pub fn cpu_timer() -> bool {
+    unsafe { CPU_TIMER > 0 }
+}
+
+/// Arm the local APIC timer of this processor for its next tick, or for the next timer if that
+/// is sooner on the first processor
+pub unsafe fn cpu_timer_arm(now: Duration) {
+    let lapic = match Lapic::current() {
+        Some(lapic) => lapic,
+        None => return,
+    };
+
+    let cpu = cpu_id();
+    let mut deadline = CPUS[cpu].tick;
+    if cpu == 0 {
+        if let Some(next) = ::env().timers.lock().next() {
+            if next < deadline {
+                deadline = next;
+            }
+        }
+    }
+    CPUS[cpu].deadline = deadline;
+
+    let delay = deadline - now;
+    let nanos = if delay.secs < 0 {
+        0
+    } else {
+        (delay.secs as u64).saturating_mul(NANOS_PER_SEC as u64).saturating_add(delay.nanos as u64)
+    };
+
+    let count = nanos.saturating_mul(CPU_TIMER as u64) / ::PIT_DURATION.nanos as u64;
+    lapic.timer_oneshot(CPU_TICK,
+                        if count < 1 {
+                            1
+                        } else if count > u32::MAX as u64 {
+                            u32::MAX
+                        } else {
+                            count as u32
+                        });
+}
+
+/// A timer was added with `deadline`, arm the local APIC timer of the first processor again if
+/// that is sooner than it was armed for
+pub unsafe fn cpu_timer_add(deadline: Duration) {
+    if cpu_timer() && deadline < CPUS[0].deadline {
+        if cpu_id() == 0 {
+            cpu_timer_arm(Duration::monotonic());
+        } else if let Some(lapic) = Lapic::current() {
+            lapic.ipi(CPUS[0].apic_id, CPU_TICK as u32);
+        }
+    }
+}
+
+/// The interrupt of the local APIC timer at the monotonic time `now`. The first processor fires
+/// the timers that are due, then the timer is armed again. Returns true if the scheduler ticks
+pub unsafe fn cpu_timer_interrupt(now: Duration) -> bool {
+    let cpu = cpu_id();
+
+    let tick = now >= CPUS[cpu].tick;
+    if tick {
+        CPUS[cpu].tick = now + ::PIT_DURATION;
+    }
+
+    if cpu == 0 {
+        ::env().timers.lock().fire(now);
+    }
+
+    cpu_timer_arm(now);
+
+    tick
+}
+
+/// Measure the local APIC timer against the PIT, then start it for the tick of this processor
+unsafe fn cpu_timer_init(lapic: Lapic) {
+    // Start at a tick of the PIT, unless the HPET counts the monotonic clock
+    let mut start = Duration::monotonic();
+    while Duration::monotonic() == start {
+        context_switch(false);
+    }
+
+    lapic.timer_measure();
+
+    start = Duration::monotonic();
+    let end = start + Duration::new(0, CPU_TIMER_TICKS as i32 * ::PIT_DURATION.nanos);
+    let mut now = start;
+    while now < end {
+        context_switch(false);
+        now = Duration::monotonic();
+    }
+
+    let count = (0xFFFFFFFF - lapic.timer_current()) as u64;
+    let elapsed = now - start;
+    let nanos = elapsed.secs as u64 * NANOS_PER_SEC as u64 + elapsed.nanos as u64;
+
+    let count = count * ::PIT_DURATION.nanos as u64 / nanos;
+    if count > 0 && count <= u32::MAX as u64 {
+        CPU_TIMER = count as u32;
+        debugln!("CPU 0: APIC timer {} per tick", count);
+
+        now = Duration::monotonic();
+        CPUS[0].tick = now + ::PIT_DURATION;
+        cpu_timer_arm(now);
+    }
+}
+
+/// Start the local APIC timer of the first processor, and the application processors in the
+/// MADT, with INIT and STARTUP interprocessor interrupts. It waits for each one, so it runs in a
+/// context of its own
+pub unsafe fn cpu_start(madt: &MADT, trampoline: usize) {
+    let lapic = match Lapic::current() {
+        Some(lapic) => lapic,
+        None => return,
+    };
+
+    cpu_timer_init(lapic);
+
+    // The start page must be page aligned, so it jumps to the trampoline
+    ptr::write(CPU_START as *mut u8, 0xEA);
+    ptr::write((CPU_START + 1) as *mut u16, trampoline as u16);
+    ptr::write((CPU_START + 3) as *mut u16, 0);
+
+    let header = &mut *(trampoline as *mut Trampoline);
+    header.entry = kstart_ap as usize as u64;
+
+    let mut cpu = 1;
+    for local_apic in madt.local_apics.iter() {
+        if cpu >= CPU_MAX {
+            break;
+        }
+
+        // Skip disabled processors and this one
+        if local_apic.flags & 1 == 0 || local_apic.id == CPUS[0].apic_id {
+            continue;
+        }
+
+        let page_table = Page::init_cpu(cpu);
+        let stack = memory::alloc(CPU_STACK_SIZE);
+        let tss = memory::alloc_type::<TSS>();
+        if page_table == 0 || stack == 0 || tss as usize == 0 {
+            debugln!("CPU {}: Out of memory", cpu);
+            break;
+        }
+
+        ptr::write(tss, ptr::read(CPUS[0].tss as *const TSS));
+        (*tss).sp0 = stack + CPU_STACK_SIZE - 128;
+        gdt_tss(cpu, tss as usize);
+
+        CPUS[cpu] = Cpu {
+            apic_id: local_apic.id,
+            online: false,
+            tss: tss as usize,
+            stack: stack + CPU_STACK_SIZE - 128,
+            current: 0,
+            previous: 0,
+            tick: Duration::new(0, 0),
+            deadline: Duration::new(0, 0),
+        };
+
+        volatile_store(&mut header.ready, 0);
+        header.cpu = cpu as u64;
+        header.page_table = page_table as u64;
+        header.stack = CPUS[cpu].stack as u64;
+
+        lapic.ipi(local_apic.id, IPI_INIT);
+        Duration::new(0, 10 * NANOS_PER_MILLI).sleep();
+
+        for _ in 0..2 {
+            if volatile_load(&header.ready) == 0 {
+                lapic.ipi(local_apic.id, IPI_STARTUP | (CPU_START >> 12) as u32);
+                Duration::new(0, 200 * NANOS_PER_MICRO).sleep();
+            }
+        }
+
+        let timeout = Duration::monotonic() + Duration::new(1, 0);
+        while volatile_load(&header.ready) == 0 && Duration::monotonic() < timeout {
+            context_switch(false);
+        }
+
+        if volatile_load(&header.ready) > 0 {
+            debugln!("CPU {}: APIC {} started", cpu, local_apic.id);
+            cpu += 1;
+        } else {
+            debugln!("CPU {}: APIC {} did not start", cpu, local_apic.id);
+        }
+    }
+}
+
+/// Write the descriptor of the TSS of a processor to the GDT, like the one of the first
+unsafe fn gdt_tss(cpu: usize, tss: usize) {
+    let mut gdtr = [0u8; 10];
+    asm!("sgdt [$0]" : : "r"(gdtr.as_mut_ptr()) : "memory" : "intel", "volatile");
+    let gdt = ptr::read(gdtr.as_ptr().offset(2) as *const usize);
+
+    let descriptor = (gdt + GDT_TSS + cpu * GDT_TSS_SIZE) as *mut u8;
+    ::memcpy(descriptor, (gdt + GDT_TSS) as *const u8, GDT_TSS_SIZE);
+
+    ptr::write(descriptor.offset(2) as *mut u16, tss as u16);
+    ptr::write(descriptor.offset(4), (tss >> 16) as u8);
+    ptr::write(descriptor.offset(7), (tss >> 24) as u8);
+    if GDT_TSS_SIZE == 16 {
+        ptr::write(descriptor.offset(8) as *mut u32, (tss as u64 >> 32) as u32);
+    }
+
+    // The TSS of the first processor is busy, this one is available until it is loaded
+    let access = ptr::read(descriptor.offset(5));
+    ptr::write(descriptor.offset(5), access & !0b10);
+}
+
+/// The entry of application processors from the trampoline, on their idle stack
+pub unsafe extern "cdecl" fn kstart_ap(cpu: usize) {
+    asm!("ltr $0" : : "r"((GDT_TSS + cpu * GDT_TSS_SIZE) as u16) : : "intel", "volatile");
+
+    Page::init_ap();
+
+    if let Some(lapic) = Lapic::current() {
+        lapic.enable();
+        if CPU_TIMER > 0 {
+            let now = Duration::monotonic();
+            CPUS[cpu].tick = now + ::PIT_DURATION;
+            cpu_timer_arm(now);
+        }
+    }
+
+    ::env().contexts.lock().push_idle();
+
+    CPUS[cpu].online = true;
+
+    ::idle_loop();
+}
@@ -4,5 +4,12 @@ pub use self::tss::TSS;
 
 /// Context
 pub mod context;
+/// Processors
+pub mod cpu;
 pub mod regs;
+/// Sessions and terminals
+pub mod session;
+pub mod signal;
+/// Timers
+pub mod timer;
 pub mod tss;
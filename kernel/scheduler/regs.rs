@@ -8,6 +8,10 @@ pub struct Regs {
     pub di: usize,
     pub si: usize,
     pub bp: usize,
+    /// The interrupt number
+    pub interrupt: usize,
+    /// The error code of an exception, 0 for those without one
+    pub error: usize,
     pub ip: usize,
     pub cs: usize,
     pub flags: usize,
@@ -33,6 +37,10 @@ pub struct Regs {
     pub r14: usize,
     pub r15: usize,
     pub bp: usize,
+    /// The interrupt number
+    pub interrupt: usize,
+    /// The error code of an exception, 0 for those without one
+    pub error: usize,
     pub ip: usize,
     pub cs: usize,
     pub flags: usize,
@@ -0,0 +1,83 @@
+use alloc::arc::Arc;
+
+use scheduler::context::ContextManager;
+use scheduler::signal::signal_group;
+
+use sync::Intex;
+
+use syscall::SIGHUP;
+
+/// A terminal, which can be the controlling terminal of a session
+pub struct Terminal {
+    /// The session it is the controlling terminal of, 0 if none
+    pub sid: usize,
+    /// The foreground process group of the session, which gets the signals of the terminal
+    pub pgid: usize,
+}
+
+impl Terminal {
+    pub fn new() -> Arc<Intex<Terminal>> {
+        Arc::new(Intex::new(Terminal { sid: 0, pgid: 0 }))
+    }
+}
+
+/// Make `terminal` the controlling terminal of the session of the current context, if it is the
+/// session leader and neither has one yet
+pub fn session_attach(contexts: &mut ContextManager, terminal: Arc<Intex<Terminal>>) {
+    let (sid, pgid) = match contexts.current() {
+        Some(current) if current.pid == current.sid && current.terminal.is_none() => {
+            (current.sid, current.pgid)
+        }
+        _ => return,
+    };
+
+    {
+        let mut inner = terminal.lock();
+        if inner.sid > 0 {
+            return;
+        }
+        inner.sid = sid;
+        inner.pgid = pgid;
+    }
+
+    for context in contexts.iter_mut() {
+        if context.sid == sid {
+            context.terminal = Some(terminal.clone());
+        }
+    }
+}
+
+/// The session leader `sid` exited, its controlling terminal is released and its foreground
+/// process group hung up
+pub fn session_exit(contexts: &mut ContextManager, sid: usize) {
+    let mut pgid = 0;
+    for context in contexts.iter_mut() {
+        if context.sid == sid {
+            if let Some(terminal) = context.terminal.take() {
+                let mut inner = terminal.lock();
+                if inner.sid == sid {
+                    pgid = inner.pgid;
+                    inner.sid = 0;
+                    inner.pgid = 0;
+                }
+            }
+        }
+    }
+
+    if pgid > 0 {
+        signal_group(contexts, pgid, SIGHUP);
+    }
+}
+
+/// Send a signal generated by a terminal, like an interrupt, to its foreground process group
+pub fn terminal_signal(terminal: &Intex<Terminal>, sig: usize) {
+    let pgid = {
+        let inner = terminal.lock();
+        if inner.sid == 0 {
+            return;
+        }
+        inner.pgid
+    };
+
+    signal_group(&mut ::env().contexts.lock(), pgid, sig);
+}
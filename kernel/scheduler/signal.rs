@@ -0,0 +1,310 @@
+use collections::vec::Vec;
+
+use core::{mem, ptr};
+
+use scheduler::Regs;
+use scheduler::context::{context_exit, context_switch, Context, ContextManager, ContextStatus};
+
+use syscall::*;
+
+/// Signals that can not be caught, blocked, or ignored
+pub const SIGNAL_UNBLOCKABLE: usize = 1 << SIGKILL | 1 << SIGSTOP;
+/// Signals that stop a context by default
+pub const SIGNAL_STOPS: usize = 1 << SIGSTOP | 1 << SIGTSTP | 1 << SIGTTIN | 1 << SIGTTOU;
+
+/// The status reported to the parent of a continued child
+pub const STATUS_CONTINUED: usize = 0xFFFF;
+
+/// What a signal does when it has no handler
+#[derive(Copy, Clone, PartialEq)]
+pub enum SignalDefault {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+impl SignalDefault {
+    pub fn new(sig: usize) -> Self {
+        match sig {
+            SIGCHLD | SIGURG | SIGWINCH => SignalDefault::Ignore,
+            SIGCONT => SignalDefault::Continue,
+            _ if SIGNAL_STOPS & 1 << sig != 0 => SignalDefault::Stop,
+            _ => SignalDefault::Terminate,
+        }
+    }
+}
+
+/// The registers and mask of the code interrupted by a handler, restored by `sys_sigreturn`
+#[derive(Copy, Clone)]
+pub struct SignalFrame {
+    pub regs: Regs,
+    pub blocked: usize,
+}
+
+/// The signal state of a context
+pub struct ContextSignals {
+    /// Signals sent but not yet delivered, as bits `1 << signal`
+    pub pending: usize,
+    /// Signals that are not delivered until unblocked
+    pub blocked: usize,
+    /// The action of every signal
+    pub actions: [SigAction; NSIG],
+    /// Frames of the handlers that are running, the last is the innermost
+    pub frames: Vec<SignalFrame>,
+    /// Stopped by a signal until continued
+    pub stopped: bool,
+}
+
+impl ContextSignals {
+    pub fn new() -> Self {
+        ContextSignals {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction {
+                sa_handler: SIG_DFL,
+                sa_mask: 0,
+                sa_flags: 0,
+                sa_restorer: 0,
+            }; NSIG],
+            frames: Vec::new(),
+            stopped: false,
+        }
+    }
+
+    /// Copy the actions and mask, for a clone. Nothing is pending in the child
+    pub fn fork(&self) -> Self {
+        ContextSignals {
+            pending: 0,
+            blocked: self.blocked,
+            actions: self.actions,
+            frames: Vec::new(),
+            stopped: false,
+        }
+    }
+
+    /// Reset handlers for a new program, ignored signals stay ignored
+    pub fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.sa_handler != SIG_IGN {
+                action.sa_handler = SIG_DFL;
+            }
+            action.sa_mask = 0;
+            action.sa_flags = 0;
+            action.sa_restorer = 0;
+        }
+        self.frames.clear();
+    }
+
+    /// Pending signals that can be delivered now
+    pub fn deliverable(&self) -> usize {
+        self.pending & !(self.blocked & !SIGNAL_UNBLOCKABLE)
+    }
+}
+
+/// Send a signal to a context, returns the status to report to the parent if it was continued
+pub fn signal_send(context: &mut Context, sig: usize) -> Option<usize> {
+    let continued = signal_pend(&mut context.signals, sig);
+
+    // A blocked context is interrupted by a signal it can take, and a stopped one by continuing
+    if context.signals.deliverable() != 0 || continued.is_some() {
+        context.unblock();
+    }
+
+    continued
+}
+
+/// Make a signal pending, unless it is discarded
+fn signal_pend(signals: &mut ContextSignals, sig: usize) -> Option<usize> {
+    if sig == SIGKILL {
+        signals.stopped = false;
+    } else if sig == SIGCONT {
+        // Continuing happens when sent, even if blocked or handled
+        signals.pending &= !SIGNAL_STOPS;
+        if signals.stopped {
+            signals.stopped = false;
+            signals.pending |= 1 << sig;
+            return Some(STATUS_CONTINUED);
+        }
+    } else if SIGNAL_STOPS & 1 << sig != 0 {
+        signals.pending &= !(1 << SIGCONT);
+    }
+
+    // Ignored signals are discarded, unless a stop signal may be caught later
+    let handler = signals.actions[sig].sa_handler;
+    let ignored = handler == SIG_IGN ||
+                  (handler == SIG_DFL && SignalDefault::new(sig) == SignalDefault::Ignore);
+    if !ignored || sig == SIGKILL || sig == SIGSTOP {
+        signals.pending |= 1 << sig;
+    }
+
+    None
+}
+
+/// Send a signal to every context in the process group `pgid`. Returns false if there is none
+pub fn signal_group(contexts: &mut ContextManager, pgid: usize, sig: usize) -> bool {
+    let mut found = false;
+    let mut continued = Vec::new();
+    for context in contexts.iter_mut() {
+        if context.pgid == pgid && !context.exited {
+            found = true;
+            if let Some(status) = signal_send(context, sig) {
+                continued.push((context.pid, context.ppid, status));
+            }
+        }
+    }
+
+    for (child, parent, status) in continued {
+        signal_notify(contexts, child, parent, status);
+    }
+
+    found
+}
+
+/// Add a status to the parent of a context, and tell it with `SIGCHLD`
+pub fn signal_notify(contexts: &mut ContextManager, pid: usize, ppid: usize, status: usize) {
+    // The process group is kept, for a parent waiting on it
+    let pgid = contexts.iter()
+                       .find(|context| context.pid == pid)
+                       .map_or(0, |context| context.pgid);
+
+    for context in contexts.iter_mut() {
+        if context.pid == ppid {
+            context.statuses.push(ContextStatus {
+                pid: pid,
+                pgid: pgid,
+                status: status,
+            });
+            signal_send(context, SIGCHLD);
+            // Wake a parent in waitpid
+            context.unblock();
+        }
+    }
+}
+
+/// What the current context does next, chosen with the contexts locked
+enum SignalStep {
+    /// Nothing left to deliver
+    Return,
+    /// A handler was entered
+    Handled,
+    /// A signal was discarded, check again
+    Next,
+    Terminate(usize),
+    /// Stopped, blocked until `SIGCONT` or `SIGKILL` is sent
+    Wait,
+}
+
+/// Deliver the pending signals of the current context before it returns to userspace. Handlers
+/// are entered by changing `regs`, default actions terminate or stop the context
+pub unsafe fn signal_deliver(regs: &mut Regs) {
+    loop {
+        let step = {
+            let mut contexts = ::env().contexts.lock();
+
+            let (pid, ppid, step, stopped) = match contexts.current_mut() {
+                Some(current) => {
+                    let (step, stopped) = signal_step(current, regs);
+                    if let SignalStep::Wait = step {
+                        // Blocked with the contexts locked, so continuing can not be missed
+                        current.blocked = true;
+                        current.wake = None;
+                    }
+                    (current.pid, current.ppid, step, stopped)
+                }
+                None => return,
+            };
+
+            if let Some(sig) = stopped {
+                signal_notify(&mut contexts, pid, ppid, sig << 8 | 0x7F);
+            }
+
+            step
+        };
+
+        match step {
+            SignalStep::Return | SignalStep::Handled => return,
+            SignalStep::Next => (),
+            SignalStep::Terminate(sig) => context_exit(sig),
+            SignalStep::Wait => context_switch(false),
+        }
+    }
+}
+
+/// Take one step of delivery, returns the signal that stopped the context, if any
+unsafe fn signal_step(current: &mut Context, regs: &mut Regs) -> (SignalStep, Option<usize>) {
+    let deliverable = current.signals.deliverable();
+
+    if current.signals.stopped {
+        if deliverable & 1 << SIGKILL != 0 {
+            return (SignalStep::Terminate(SIGKILL), None);
+        }
+        return (SignalStep::Wait, None);
+    }
+
+    if deliverable == 0 {
+        return (SignalStep::Return, None);
+    }
+
+    let sig = deliverable.trailing_zeros() as usize;
+    current.signals.pending &= !(1 << sig);
+
+    let action = current.signals.actions[sig];
+    match action.sa_handler {
+        SIG_IGN => (SignalStep::Next, None),
+        SIG_DFL => match SignalDefault::new(sig) {
+            SignalDefault::Terminate => (SignalStep::Terminate(sig), None),
+            SignalDefault::Stop => {
+                current.signals.stopped = true;
+                (SignalStep::Wait, Some(sig))
+            }
+            SignalDefault::Ignore | SignalDefault::Continue => (SignalStep::Next, None),
+        },
+        handler => {
+            if signal_enter(current, regs, sig, handler, action.sa_restorer) {
+                if action.sa_flags & SA_NODEFER != SA_NODEFER {
+                    current.signals.blocked |= 1 << sig;
+                }
+                current.signals.blocked |= action.sa_mask & !SIGNAL_UNBLOCKABLE;
+                if action.sa_flags & SA_RESETHAND == SA_RESETHAND {
+                    current.signals.actions[sig].sa_handler = SIG_DFL;
+                }
+                (SignalStep::Handled, None)
+            } else {
+                // The stack can not hold the frame
+                (SignalStep::Terminate(SIGSEGV), None)
+            }
+        }
+    }
+}
+
+/// Point the registers at a handler, pushing the restorer and signal number on the user stack
+unsafe fn signal_enter(current: &mut Context,
+                       regs: &mut Regs,
+                       sig: usize,
+                       handler: usize,
+                       restorer: usize)
+                       -> bool {
+    let size = mem::size_of::<usize>();
+    // Skip the red zone, and align so the handler sees the stack as if it was called
+    let sp = ((regs.sp - 128) & !15) - 3 * size;
+    // The pages of the stack may not be used yet
+    if !current.page_in(sp, true, false) || !current.page_in(sp + 2 * size - 1, true, false) {
+        return false;
+    }
+
+    current.signals.frames.push(SignalFrame {
+        regs: *regs,
+        blocked: current.signals.blocked,
+    });
+
+    ptr::write(sp as *mut usize, restorer);
+    ptr::write((sp + size) as *mut usize, sig);
+
+    regs.sp = sp;
+    regs.ip = handler;
+    // The first argument, on x86_64
+    regs.di = sig;
+
+    true
+}
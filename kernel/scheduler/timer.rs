@@ -0,0 +1,152 @@
+use alloc::arc::{Arc, Weak};
+
+use collections::vec::Vec;
+
+use common::time::{Duration, NANOS_PER_SEC};
+
+use scheduler::cpu::cpu_timer_add;
+
+use sync::{Intex, WaitQueue};
+
+/// The slots of the timer wheel
+const TIMER_SLOTS: u64 = 256;
+/// The slot of a deadline is its nanoseconds shifted by this, so a slot is about 65 µs
+const TIMER_SLOT_SHIFT: u64 = 16;
+
+/// The slot time of a monotonic time, which counts slots since the clock started. Times too
+/// far away to count in nanoseconds are in the last slot time
+fn slot_time(time: Duration) -> u64 {
+    if time.secs < 0 {
+        0
+    } else {
+        (time.secs as u64)
+            .saturating_mul(NANOS_PER_SEC as u64)
+            .saturating_add(time.nanos as u64) >> TIMER_SLOT_SHIFT
+    }
+}
+
+/// A timer, which wakes the contexts waiting for it when the monotonic clock reaches its
+/// deadline
+pub struct Timer {
+    /// The monotonic time it fires at
+    pub deadline: Duration,
+    /// Indicates that it fired
+    fired: Intex<bool>,
+    /// The contexts waiting for it to fire
+    wait: WaitQueue,
+}
+
+impl Timer {
+    /// Create a timer firing at `deadline`, in the timer wheel until then
+    pub fn new(deadline: Duration) -> Arc<Timer> {
+        let timer = Timer::unqueued(deadline);
+
+        ::env().timers.lock().add(&timer);
+        unsafe { cpu_timer_add(deadline) };
+
+        timer
+    }
+
+    /// Create a timer firing at `deadline` that is in no timer wheel, see `TimerWheel::add`
+    pub fn unqueued(deadline: Duration) -> Arc<Timer> {
+        Arc::new(Timer {
+            deadline: deadline,
+            fired: Intex::new(false),
+            wait: WaitQueue::new(),
+        })
+    }
+
+    /// Indicates that the timer fired
+    pub fn fired(&self) -> bool {
+        *self.fired.lock()
+    }
+
+    /// Block the current context until the timer fired
+    pub fn wait(&self) {
+        while !self.fired() {
+            self.wait.block_until(Some(self.deadline));
+        }
+    }
+
+    fn fire(&self) {
+        *self.fired.lock() = true;
+        self.wait.wake();
+    }
+}
+
+/// The timers that did not fire yet, in slots of their deadline modulo `TIMER_SLOTS`. Timers
+/// that are dropped before they fire are dropped from the wheel
+pub struct TimerWheel {
+    slots: Vec<Vec<Weak<Timer>>>,
+    /// The slot time up to which timers fired
+    fired: u64,
+}
+
+impl TimerWheel {
+    pub fn new() -> Self {
+        let mut slots = Vec::new();
+        for _ in 0..TIMER_SLOTS {
+            slots.push(Vec::new());
+        }
+
+        TimerWheel {
+            slots: slots,
+            fired: 0,
+        }
+    }
+
+    /// Add a timer, it is in the slot that fires next if its deadline passed
+    pub fn add(&mut self, timer: &Arc<Timer>) {
+        let mut time = slot_time(timer.deadline);
+        if time < self.fired {
+            time = self.fired;
+        }
+
+        self.slots[(time % TIMER_SLOTS) as usize].push(Arc::downgrade(timer));
+    }
+
+    /// The earliest deadline of the timers
+    pub fn next(&self) -> Option<Duration> {
+        let mut next: Option<Duration> = None;
+        for slot in self.slots.iter() {
+            for timer in slot.iter().filter_map(|timer| timer.upgrade()) {
+                if next.map_or(true, |deadline| timer.deadline < deadline) {
+                    next = Some(timer.deadline);
+                }
+            }
+        }
+        next
+    }
+
+    /// Fire the timers with a deadline until the monotonic time `now`, going through the slots
+    /// since the last time, or through all of them if that was more than a turn of the wheel
+    pub fn fire(&mut self, now: Duration) {
+        let now_time = slot_time(now);
+        let start = if now_time.saturating_sub(self.fired) >= TIMER_SLOTS {
+            now_time - TIMER_SLOTS + 1
+        } else {
+            self.fired
+        };
+
+        for time in start..now_time + 1 {
+            let slot = &mut self.slots[(time % TIMER_SLOTS) as usize];
+
+            let mut i = 0;
+            while i < slot.len() {
+                match slot[i].upgrade() {
+                    Some(timer) => if timer.deadline <= now {
+                        slot.remove(i);
+                        timer.fire();
+                    } else {
+                        i += 1;
+                    },
+                    None => {
+                        slot.remove(i);
+                    }
+                }
+            }
+        }
+
+        self.fired = now_time;
+    }
+}
@@ -2,10 +2,17 @@ use alloc::boxed::Box;
 
 use collections::string::String;
 
+use common::time::{Duration, NANOS_PER_MILLI};
+
 use scheduler::context;
 
 use schemes::{Result, KScheme, Resource, Url, VecResource};
 
+/// Format a CPU time as seconds, with hundredths
+fn time_string(time: Duration) -> String {
+    format!("{}.{:02}", time.secs, time.nanos / (10 * NANOS_PER_MILLI))
+}
+
 pub struct ContextScheme;
 
 impl KScheme for ContextScheme {
@@ -14,10 +21,14 @@ impl KScheme for ContextScheme {
     }
 
     fn open(&mut self, _: &Url, _: usize) -> Result<Box<Resource>> {
-        let mut string = format!("{:<6}{:<6}{:<8}{:<8}{:<6}{:<6}{}",
+        let mut string = format!("{:<6}{:<6}{:<6}{:<6}{:<4}{:<9}{:<9}{:<8}{:<6}{:<6}{}",
                                  "PID",
                                  "PPID",
-                                 "TIME",
+                                 "PGID",
+                                 "SID",
+                                 "NI",
+                                 "UTIME",
+                                 "STIME",
                                  "MEM",
                                  "FDS",
                                  "FLG",
@@ -29,12 +40,16 @@ impl KScheme for ContextScheme {
                 if context.kernel_stack > 0 {
                     memory += context::CONTEXT_STACK_SIZE;
                 }
+                // Only the pages that were used
                 if let Some(ref stack) = context.stack {
-                    memory += stack.virtual_size;
+                    memory += stack.resident();
                 }
                 unsafe {
                     for context_memory in (*context.memory.get()).iter() {
-                        memory += context_memory.virtual_size;
+                        memory += context_memory.resident();
+                    }
+                    for context_memory in (*context.mmap.get()).iter() {
+                        memory += context_memory.resident();
                     }
                 }
 
@@ -57,14 +72,24 @@ impl KScheme for ContextScheme {
                 if context.interrupted {
                     flags_string.push('I');
                 }
+                if context.running {
+                    flags_string.push('R');
+                }
+                if context.blocked {
+                    flags_string.push('S');
+                }
                 if context.exited {
                     flags_string.push('E');
                 }
 
-                let line = format!("{:<6}{:<6}{:<8}{:<8}{:<6}{:<6}{}",
+                let line = format!("{:<6}{:<6}{:<6}{:<6}{:<4}{:<9}{:<9}{:<8}{:<6}{:<6}{}",
                                    context.pid,
                                    context.ppid,
-                                   context.slice_total,
+                                   context.pgid,
+                                   context.sid,
+                                   context.nice,
+                                   time_string(context.user_time),
+                                   time_string(context.system_time),
                                    memory_string,
                                    unsafe { (*context.files.get()).len() },
                                    flags_string,
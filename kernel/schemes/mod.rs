@@ -1,5 +1,6 @@
 use common::get_slice::GetSlice;
 
+use alloc::arc::Arc;
 use alloc::boxed::Box;
 
 use collections::string::{String, ToString};
@@ -7,6 +8,10 @@ use collections::vec::Vec;
 
 use core::cmp::{min, max};
 
+use scheduler::session::Terminal;
+
+use sync::Intex;
+
 use syscall::{Error, O_CREAT, O_RDWR, O_TRUNC, EBADF, ENOENT};
 use env;
 
@@ -24,8 +29,12 @@ pub mod interrupt;
 pub mod memory;
 /// Pipes
 pub mod pipe;
+/// Pseudo terminals
+pub mod pty;
 /// Tests
 pub mod test;
+/// Timers
+pub mod time;
 
 pub type Result<T> = ::core::result::Result<T, Error>;
 
@@ -93,6 +102,11 @@ pub trait Resource {
         Err(Error::new(EBADF))
     }
 
+    /// The terminal, if the resource is one, which can be the controlling terminal of a session
+    fn terminal(&self) -> Option<Arc<Intex<Terminal>>> {
+        None
+    }
+
     // Helper functions
     fn read_to_end(&mut self, vec: &mut Vec<u8>) -> Result<usize> {
         let mut read = 0;
@@ -5,15 +5,15 @@ use collections::vec_deque::VecDeque;
 
 use schemes::{Result, Resource, Url};
 
-use scheduler::context::context_switch;
-
-use sync::Intex;
+use sync::{Intex, WaitQueue};
 
 use syscall::{Error, EPIPE};
 
 /// Read side of a pipe
 pub struct PipeRead {
     vec: Arc<Intex<VecDeque<u8>>>,
+    /// Readers waiting for data or the end of the pipe
+    wait: Arc<WaitQueue>,
     eof_toggle: bool,
 }
 
@@ -21,6 +21,7 @@ impl PipeRead {
     pub fn new() -> Self {
         PipeRead {
             vec: Arc::new(Intex::new(VecDeque::new())),
+            wait: Arc::new(WaitQueue::new()),
             eof_toggle: false,
         }
     }
@@ -30,6 +31,7 @@ impl Resource for PipeRead {
     fn dup(&self) -> Result<Box<Resource>> {
         Ok(box PipeRead {
             vec: self.vec.clone(),
+            wait: self.wait.clone(),
             eof_toggle: self.eof_toggle,
         })
     }
@@ -67,7 +69,7 @@ impl Resource for PipeRead {
                 }
             }
 
-            unsafe { context_switch(false) };
+            self.wait.block();
         }
     }
 }
@@ -75,12 +77,14 @@ impl Resource for PipeRead {
 /// Read side of a pipe
 pub struct PipeWrite {
     vec: Weak<Intex<VecDeque<u8>>>,
+    wait: Arc<WaitQueue>,
 }
 
 impl PipeWrite {
     pub fn new(read: &PipeRead) -> Self {
         PipeWrite {
             vec: Arc::downgrade(&read.vec),
+            wait: read.wait.clone(),
         }
     }
 }
@@ -89,6 +93,7 @@ impl Resource for PipeWrite {
     fn dup(&self) -> Result<Box<Resource>> {
         Ok(box PipeWrite {
             vec: self.vec.clone(),
+            wait: self.wait.clone(),
         })
     }
 
@@ -105,9 +110,17 @@ impl Resource for PipeWrite {
                     vec.push_back(buf[i]);
                     i += 1;
                 }
+                self.wait.wake();
                 Ok(i)
             },
             None => Err(Error::new(EPIPE))
         }
     }
 }
+
+impl Drop for PipeWrite {
+    /// Wake the readers, so they see the end of the pipe if this was the last writer
+    fn drop(&mut self) {
+        self.wait.wake();
+    }
+}
@@ -0,0 +1,288 @@
+use alloc::arc::{Arc, Weak};
+use alloc::boxed::Box;
+
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use scheduler::session::{terminal_signal, Terminal};
+
+use schemes::{Result, KScheme, Resource, Url};
+
+use sync::{Intex, WaitQueue};
+
+use syscall::{Error, EINTR, ENOENT, EPIPE, SIGINT, SIGQUIT, SIGTSTP};
+
+/// The state of a pseudo terminal
+struct PtyState {
+    /// Written by the master, read by the slaves
+    input: VecDeque<u8>,
+    /// Written by the slaves, read by the master
+    output: VecDeque<u8>,
+    /// The number of open masters
+    masters: usize,
+    /// The number of open slaves
+    slaves: usize,
+    /// Indicates that a slave was opened, the master reads the end once the last one is closed
+    opened: bool,
+}
+
+/// A pseudo terminal. The master side is a terminal program, the slave side is the terminal of
+/// the programs it runs, which is the controlling terminal of their session
+struct Pty {
+    id: usize,
+    state: Intex<PtyState>,
+    /// Slaves waiting for input
+    input_wait: WaitQueue,
+    /// Masters waiting for output
+    output_wait: WaitQueue,
+    terminal: Arc<Intex<Terminal>>,
+}
+
+/// Indicates that the current context has a signal to take, so a blocking read returns
+fn interrupted() -> bool {
+    ::env().contexts.lock().current().map_or(false, |current| current.signals.deliverable() != 0)
+}
+
+/// The master side of a pseudo terminal. Writing the interrupt, quit and suspend characters
+/// sends their signals to the foreground process group instead
+pub struct PtyMaster {
+    pty: Arc<Pty>,
+    eof_toggle: bool,
+}
+
+impl Resource for PtyMaster {
+    fn dup(&self) -> Result<Box<Resource>> {
+        self.pty.state.lock().masters += 1;
+        Ok(box PtyMaster {
+            pty: self.pty.clone(),
+            eof_toggle: self.eof_toggle,
+        })
+    }
+
+    fn url(&self) -> Url {
+        Url::from_string(format!("pty:{}", self.pty.id))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.eof_toggle {
+            self.eof_toggle = false;
+            return Ok(0);
+        }
+
+        loop {
+            {
+                let mut state = self.pty.state.lock();
+                if !state.output.is_empty() {
+                    let mut i = 0;
+                    while i < buf.len() {
+                        match state.output.pop_front() {
+                            Some(b) => {
+                                buf[i] = b;
+                                i += 1;
+                            }
+                            None => break,
+                        }
+                    }
+                    self.eof_toggle = true;
+                    return Ok(i);
+                } else if state.opened && state.slaves == 0 {
+                    return Ok(0);
+                }
+            }
+
+            if interrupted() {
+                return Err(Error::new(EINTR));
+            }
+
+            self.pty.output_wait.block();
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut signals = Vec::new();
+        {
+            let mut state = self.pty.state.lock();
+            for &b in buf.iter() {
+                let sig = match b {
+                    0x03 => SIGINT,
+                    0x1A => SIGTSTP,
+                    0x1C => SIGQUIT,
+                    _ => {
+                        state.input.push_back(b);
+                        continue;
+                    }
+                };
+
+                // Input that was not read yet is discarded with the signal
+                state.input.clear();
+                signals.push(sig);
+            }
+        }
+        self.pty.input_wait.wake();
+
+        for sig in signals {
+            terminal_signal(&self.pty.terminal, sig);
+        }
+
+        Ok(buf.len())
+    }
+}
+
+impl Drop for PtyMaster {
+    /// Wake the slaves, so they read the end if this was the last master
+    fn drop(&mut self) {
+        self.pty.state.lock().masters -= 1;
+        self.pty.input_wait.wake();
+    }
+}
+
+/// The slave side of a pseudo terminal
+pub struct PtySlave {
+    pty: Arc<Pty>,
+    eof_toggle: bool,
+}
+
+impl Resource for PtySlave {
+    fn dup(&self) -> Result<Box<Resource>> {
+        self.pty.state.lock().slaves += 1;
+        Ok(box PtySlave {
+            pty: self.pty.clone(),
+            eof_toggle: self.eof_toggle,
+        })
+    }
+
+    fn url(&self) -> Url {
+        Url::from_string(format!("pty:{}", self.pty.id))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.eof_toggle {
+            self.eof_toggle = false;
+            return Ok(0);
+        }
+
+        loop {
+            {
+                let mut state = self.pty.state.lock();
+                if !state.input.is_empty() {
+                    let mut i = 0;
+                    while i < buf.len() {
+                        match state.input.pop_front() {
+                            Some(b) => {
+                                buf[i] = b;
+                                i += 1;
+                            }
+                            None => break,
+                        }
+                    }
+                    self.eof_toggle = true;
+                    return Ok(i);
+                } else if state.masters == 0 {
+                    return Ok(0);
+                }
+            }
+
+            if interrupted() {
+                return Err(Error::new(EINTR));
+            }
+
+            self.pty.input_wait.block();
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        {
+            let mut state = self.pty.state.lock();
+            if state.masters == 0 {
+                return Err(Error::new(EPIPE));
+            }
+            for &b in buf.iter() {
+                state.output.push_back(b);
+            }
+        }
+        self.pty.output_wait.wake();
+
+        Ok(buf.len())
+    }
+
+    fn terminal(&self) -> Option<Arc<Intex<Terminal>>> {
+        Some(self.pty.terminal.clone())
+    }
+}
+
+impl Drop for PtySlave {
+    /// Wake the masters, so they read the end if this was the last slave
+    fn drop(&mut self) {
+        self.pty.state.lock().slaves -= 1;
+        self.pty.output_wait.wake();
+    }
+}
+
+/// The pseudo terminal scheme. Opening `pty:` creates a pseudo terminal and returns its master,
+/// opening the path of the master, `pty:N`, returns a slave
+pub struct PtyScheme {
+    next_id: usize,
+    ptys: Vec<Weak<Pty>>,
+}
+
+impl PtyScheme {
+    pub fn new() -> Box<Self> {
+        box PtyScheme {
+            next_id: 1,
+            ptys: Vec::new(),
+        }
+    }
+}
+
+impl KScheme for PtyScheme {
+    fn scheme(&self) -> &str {
+        "pty"
+    }
+
+    fn open(&mut self, url: &Url, _: usize) -> Result<Box<Resource>> {
+        self.ptys.retain(|pty| pty.upgrade().is_some());
+
+        let reference = url.reference();
+        if reference.is_empty() {
+            let pty = Arc::new(Pty {
+                id: self.next_id,
+                state: Intex::new(PtyState {
+                    input: VecDeque::new(),
+                    output: VecDeque::new(),
+                    masters: 1,
+                    slaves: 0,
+                    opened: false,
+                }),
+                input_wait: WaitQueue::new(),
+                output_wait: WaitQueue::new(),
+                terminal: Terminal::new(),
+            });
+            self.next_id += 1;
+            self.ptys.push(Arc::downgrade(&pty));
+
+            return Ok(box PtyMaster {
+                pty: pty,
+                eof_toggle: false,
+            });
+        }
+
+        if let Ok(id) = reference.parse::<usize>() {
+            for pty in self.ptys.iter().filter_map(|pty| pty.upgrade()) {
+                if pty.id == id {
+                    {
+                        let mut state = pty.state.lock();
+                        state.slaves += 1;
+                        state.opened = true;
+                    }
+
+                    return Ok(box PtySlave {
+                        pty: pty,
+                        eof_toggle: false,
+                    });
+                }
+            }
+        }
+
+        Err(Error::new(ENOENT))
+    }
+}
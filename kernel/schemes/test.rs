@@ -46,7 +46,10 @@ fn meta_test_woah() -> bool {
 
 mod tests {
     // Add your test here!
+    pub mod filter;
     pub mod get_slice;
+    pub mod pcap;
+    pub mod timer;
 }
 
 impl KScheme for TestScheme {
@@ -116,6 +119,9 @@ impl KScheme for TestScheme {
         reg_test!(meta_test_woah, "Testing the testing (wut)");
         reg_test!(!meta_test_woah_fail, "Testing the fail testing (wut)");
         reg_test!(tests::get_slice::test, "GetSlice");
+        reg_test!(tests::pcap::test, "Packet capture format");
+        reg_test!(tests::filter::test, "Packet filter rules");
+        reg_test!(tests::timer::test, "Timer wheel");
 
         Ok(box VecResource::new(Url::from_str("test:"), string.into_bytes()))
     }
@@ -0,0 +1,67 @@
+pub fn test() -> bool {
+    use collections::string::ToString;
+    use collections::vec::Vec;
+
+    use network::common::Ipv4Addr;
+    use network::filter::{Filter, FilterAction, FilterDirection, FilterRule};
+
+    let rule = |string: &str| FilterRule::from_str(string).map(|rule| rule.to_string());
+
+    test!(rule("accept") == Some("accept".to_string()));
+    test!(rule("reject in proto tcp to 10.85.85.2 dport 1-1023") ==
+          Some("reject in proto tcp to 10.85.85.2/32 dport 1-1023".to_string()));
+    test!(rule("drop  out proto 47 from 10.0.0.0/8 sport 53") ==
+          Some("drop out proto 47 from 10.0.0.0/8 sport 53".to_string()));
+    test!(rule("accept from any") == Some("accept from 0.0.0.0/0".to_string()));
+
+    // Malformed rules are rejected instead of matching more than asked
+    test!(rule("").is_none());
+    test!(rule("allow").is_none());
+    test!(rule("drop proto").is_none());
+    test!(rule("drop proto 256").is_none());
+    test!(rule("drop proto sctp").is_none());
+    test!(rule("drop from 10.0.0/8").is_none());
+    test!(rule("drop from 10.0.0.256").is_none());
+    test!(rule("drop from 10.0.0.1/33").is_none());
+    test!(rule("drop from 10.0.0.1/x").is_none());
+    test!(rule("drop to 10.a.0.1").is_none());
+    test!(rule("drop sport 80-").is_none());
+    test!(rule("drop sport 90-80").is_none());
+    test!(rule("drop dport 65536").is_none());
+    test!(rule("drop dport http").is_none());
+    test!(rule("drop dport 123456").is_none());
+    test!(rule("drop via eth0").is_none());
+
+    let mut filter = Filter {
+        rules: Vec::new(),
+        policy: FilterAction::Accept,
+        policy_hits: 0,
+    };
+    test!(filter.command("add drop in proto udp"));
+    test!(filter.command("insert 0 accept in proto udp dport 53"));
+    test!(!filter.command("insert 3 drop"));
+    test!(!filter.command("insert x drop"));
+    test!(!filter.command("delete 2"));
+    test!(!filter.command("delete"));
+    test!(!filter.command("policy allow"));
+    test!(!filter.command("add drop from"));
+    test!(filter.rules.len() == 2);
+
+    let src = Ipv4Addr::from_string(&"10.0.0.1".to_string());
+    let dst = Ipv4Addr::from_string(&"10.0.0.2".to_string());
+    let udp = 0x11;
+    test!(filter.check(FilterDirection::In, udp, src, dst, Some((1024, 53))) ==
+          FilterAction::Accept);
+    test!(filter.check(FilterDirection::In, udp, src, dst, Some((1024, 54))) ==
+          FilterAction::Drop);
+    // Later fragments have no ports, so port rules do not match them
+    test!(filter.check(FilterDirection::In, udp, src, dst, None) == FilterAction::Drop);
+    test!(filter.check(FilterDirection::Out, udp, src, dst, None) == FilterAction::Accept);
+    test!(filter.rules[0].hits == 1 && filter.rules[1].hits == 2 && filter.policy_hits == 1);
+
+    test!(filter.command("delete 0"));
+    test!(filter.command("zero"));
+    test!(filter.rules.len() == 1 && filter.rules[0].hits == 0 && filter.policy_hits == 0);
+
+    succ!();
+}
@@ -0,0 +1,47 @@
+pub fn test() -> bool {
+    use common::time::Duration;
+    use network::schemes::pcap::{pcap_header, pcap_matches, pcap_record};
+
+    let header = pcap_header();
+    test!(header.len() == 24);
+    test!(&header[..4] == &[0xD4, 0xC3, 0xB2, 0xA1]);
+    test!(&header[4..8] == &[2, 0, 4, 0]);
+    test!(&header[16..20] == &[0xFF, 0xFF, 0, 0]);
+    test!(&header[20..] == &[1, 0, 0, 0]);
+
+    let record = pcap_record(&[1, 2, 3], Duration::new(5, 7000));
+    test!(record.len() == 16 + 3);
+    test!(&record[..4] == &[5, 0, 0, 0]);
+    test!(&record[4..8] == &[7, 0, 0, 0]);
+    test!(&record[8..12] == &[3, 0, 0, 0]);
+    test!(&record[12..16] == &[3, 0, 0, 0]);
+    test!(&record[16..] == &[1, 2, 3]);
+
+    // Frames past the snapshot length are truncated, the original length is kept
+    let record = pcap_record(&vec![0xAB; 65540], Duration::new(0, 0));
+    test!(record.len() == 16 + 65535);
+    test!(&record[8..12] == &[0xFF, 0xFF, 0, 0]);
+    test!(&record[12..16] == &[4, 0, 1, 0]);
+
+    let mut arp = vec![0; 28];
+    arp[12] = 0x08;
+    arp[13] = 0x06;
+    test!(pcap_matches(&arp, None, None));
+    test!(pcap_matches(&arp, Some(0x806), None));
+    test!(!pcap_matches(&arp, Some(0x800), None));
+    test!(!pcap_matches(&arp, None, Some(6)));
+
+    let mut tcp = vec![0; 14 + 20];
+    tcp[12] = 0x08;
+    tcp[14] = 0x45;
+    tcp[17] = 20;
+    tcp[23] = 6;
+    test!(pcap_matches(&tcp, Some(0x800), Some(6)));
+    test!(!pcap_matches(&tcp, None, Some(17)));
+
+    // Frames too short for the headers do not match a filter
+    test!(!pcap_matches(&vec![0x08; 10], Some(0x808), None));
+    test!(!pcap_matches(&tcp[..20].to_vec(), None, Some(6)));
+
+    succ!();
+}
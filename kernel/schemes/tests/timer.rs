@@ -0,0 +1,60 @@
+pub fn test() -> bool {
+    use core::i64;
+
+    use common::time::Duration;
+    use scheduler::timer::{Timer, TimerWheel};
+
+    // The nanoseconds in a slot of the wheel, which has 256 of them
+    let slot = 1 << 16;
+
+    let mut wheel = TimerWheel::new();
+    test!(wheel.next().is_none());
+
+    let start = Duration::new(100, 0);
+    wheel.fire(start);
+
+    // Deadlines that passed, even before the clock started, fire on the next check. Deadlines
+    // too far away to count in slots do not
+    let past = Timer::unqueued(Duration::new(1, 0));
+    let negative = Timer::unqueued(Duration::new(-5, 0));
+    let far = Timer::unqueued(Duration::new(i64::MAX, 999999999));
+    wheel.add(&past);
+    wheel.add(&negative);
+    wheel.add(&far);
+    test!(wheel.next() == Some(negative.deadline));
+    wheel.fire(start);
+    test!(past.fired());
+    test!(negative.fired());
+    test!(!far.fired());
+    test!(wheel.next() == Some(far.deadline));
+
+    // A deadline a turn of the wheel later is in the same slot, but does not fire with it
+    let soon = Timer::unqueued(start + Duration::new(0, 10 * slot));
+    let turn = Timer::unqueued(start + Duration::new(0, (256 + 10) * slot));
+    wheel.add(&soon);
+    wheel.add(&turn);
+    wheel.fire(soon.deadline);
+    test!(soon.fired());
+    test!(!turn.fired());
+
+    // A check more than a turn later goes through every slot
+    wheel.fire(start + Duration::new(1, 0));
+    test!(turn.fired());
+
+    // Dropped timers leave the wheel
+    let dropped = Timer::unqueued(start + Duration::new(2, 0));
+    wheel.add(&dropped);
+    drop(dropped);
+    test!(wheel.next() == Some(far.deadline));
+
+    // A clock that goes back fires nothing
+    let later = Timer::unqueued(start + Duration::new(3, 0));
+    wheel.add(&later);
+    wheel.fire(start);
+    test!(!later.fired());
+    wheel.fire(later.deadline);
+    test!(later.fired());
+    test!(!far.fired());
+
+    succ!();
+}
@@ -0,0 +1,77 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use core::{mem, ptr};
+
+use common::time::{Duration, NANOS_PER_SEC};
+
+use scheduler::timer::Timer;
+
+use schemes::{Result, KScheme, Resource, Url};
+
+use syscall::{Error, TimeSpec, EINVAL};
+
+/// The time scheme, its resources are timers of the monotonic clock
+pub struct TimeScheme;
+
+impl KScheme for TimeScheme {
+    fn scheme(&self) -> &str {
+        "time"
+    }
+
+    fn open(&mut self, _: &Url, _: usize) -> Result<Box<Resource>> {
+        Ok(box TimeResource { timer: None })
+    }
+}
+
+/// A timer of the monotonic clock. Writing a `TimeSpec` sets the monotonic time it fires at,
+/// reading one blocks until it fired, then returns the monotonic time
+pub struct TimeResource {
+    timer: Option<Arc<Timer>>,
+}
+
+impl Resource for TimeResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box TimeResource { timer: self.timer.clone() })
+    }
+
+    fn url(&self) -> Url {
+        Url::from_str("time:")
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < mem::size_of::<TimeSpec>() {
+            return Err(Error::new(EINVAL));
+        }
+
+        if let Some(timer) = self.timer.take() {
+            timer.wait();
+        }
+
+        let time = Duration::monotonic();
+        unsafe {
+            ptr::write(buf.as_mut_ptr() as *mut TimeSpec,
+                       TimeSpec {
+                           tv_sec: time.secs,
+                           tv_nsec: time.nanos,
+                       });
+        }
+
+        Ok(mem::size_of::<TimeSpec>())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.len() < mem::size_of::<TimeSpec>() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let time = unsafe { ptr::read(buf.as_ptr() as *const TimeSpec) };
+        if time.tv_sec < 0 || time.tv_nsec < 0 || time.tv_nsec >= NANOS_PER_SEC {
+            return Err(Error::new(EINVAL));
+        }
+
+        self.timer = Some(Timer::new(Duration::new(time.tv_sec, time.tv_nsec)));
+
+        Ok(mem::size_of::<TimeSpec>())
+    }
+}
@@ -1,16 +1,80 @@
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut, Drop};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 
-pub static mut intex_count: usize = 0;
+use scheduler::cpu::{cpu_flush_pending, cpu_id, CPU_MAX};
 
-/// An Intex, interrupt exclusion during value usage
+/// The next ticket of the lock that every Intex shares
+static INTEX_NEXT: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The ticket of the processor that holds the lock
+static INTEX_SERVING: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The number of times each processor locked it, zero if it does not hold it
+static mut INTEX_COUNTS: [usize; CPU_MAX] = [0; CPU_MAX];
+
+/// Release the lock, no matter how many times this processor locked it, when it stops running
+/// kernel code, to switch contexts or to halt. Returns the count for `intex_restore`
+pub unsafe fn intex_release() -> usize {
+    let cpu = cpu_id();
+    let count = INTEX_COUNTS[cpu];
+    if count > 0 {
+        INTEX_COUNTS[cpu] = 0;
+        INTEX_SERVING.fetch_add(1, Ordering::SeqCst);
+    }
+    count
+}
+
+/// Lock again as many times as the lock was held before `intex_release`
+pub unsafe fn intex_restore(count: usize) {
+    if count > 0 {
+        intex_lock();
+        INTEX_COUNTS[cpu_id()] = count;
+    }
+}
+
+/// Disable interrupts and take the lock, waiting for other processors in turn
+unsafe fn intex_lock() {
+    asm!("cli" : : : : "intel", "volatile");
+
+    let cpu = cpu_id();
+    if INTEX_COUNTS[cpu] == 0 {
+        let ticket = INTEX_NEXT.fetch_add(1, Ordering::SeqCst);
+        while INTEX_SERVING.load(Ordering::SeqCst) != ticket {
+            // The holder may wait for this processor to flush its TLB
+            cpu_flush_pending();
+            asm!("pause" : : : : "intel", "volatile");
+        }
+    }
+    INTEX_COUNTS[cpu] += 1;
+}
+
+unsafe fn intex_unlock() {
+    let cpu = cpu_id();
+    INTEX_COUNTS[cpu] -= 1;
+    if INTEX_COUNTS[cpu] == 0 {
+        INTEX_SERVING.fetch_add(1, Ordering::SeqCst);
+        //asm!("sti");
+    }
+}
+
+/// An Intex, interrupt exclusion during value usage.
+///
+/// The kernel relied on disabled interrupts to run alone, so every Intex shares one spinlock,
+/// which keeps the kernel on one processor at a time while userspace runs on all of them. A
+/// processor can lock it again while it holds it, and a context switch releases it.
+///
+/// Only userspace runs in parallel: system calls and interrupts take this lock on entry, and
+/// most of the state of the kernel, such as schemes and the files of contexts, is used under it
+/// without an Intex of its own.
+///
+/// TODO: Give each Intex a lock of its own or make the value per processor, and protect the
+/// rest of the state of the kernel, so that the kernel runs on several processors at once
 pub struct Intex<T: ?Sized> {
     value: UnsafeCell<T>,
 }
 
 impl Intex<()> {
     pub fn static_lock() -> StaticIntexGuard {
-        StaticIntexGuard
+        StaticIntexGuard::new()
     }
 }
 
@@ -66,21 +130,13 @@ pub struct StaticIntexGuard;
 
 impl StaticIntexGuard {
     fn new() -> Self {
-        unsafe {
-            asm!("cli");
-            intex_count += 1;
-        }
+        unsafe { intex_lock() };
         StaticIntexGuard
     }
 }
 
 impl Drop for StaticIntexGuard {
     fn drop(&mut self) {
-        unsafe {
-            intex_count -= 1;
-            if intex_count == 0 {
-                //asm!("sti");
-            }
-        }
+        unsafe { intex_unlock() };
     }
 }
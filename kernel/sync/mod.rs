@@ -2,6 +2,7 @@ pub use self::intex::Intex;
 pub use self::mutex::Mutex;
 pub use self::recursive_mutex::RecursiveMutex;
 pub use self::rwlock::RwLock;
+pub use self::wait_queue::WaitQueue;
 
 /// Interrupt exclution - use carefully
 pub mod intex;
@@ -11,3 +12,5 @@ pub mod mutex;
 pub mod recursive_mutex;
 /// Readers-writer lock - use with caution
 pub mod rwlock;
+/// Queue of blocked contexts - wake after changing what they wait for
+pub mod wait_queue;
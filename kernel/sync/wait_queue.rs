@@ -0,0 +1,54 @@
+use collections::vec::Vec;
+
+use core::mem;
+
+use common::time::Duration;
+
+use scheduler::context::context_block;
+
+use sync::Intex;
+
+/// A queue of contexts that are blocked until an event, they are not scheduled until woken or
+/// timed out. A woken context must check the reason it blocked again
+pub struct WaitQueue {
+    pids: Intex<Vec<usize>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        WaitQueue { pids: Intex::new(Vec::new()) }
+    }
+
+    /// Block the current context until woken
+    pub fn block(&self) {
+        self.block_until(None);
+    }
+
+    /// Block the current context until woken, or until the monotonic clock reaches `wake`
+    pub fn block_until(&self, wake: Option<Duration>) {
+        let pid = match ::env().contexts.lock().current() {
+            Some(current) => current.pid,
+            None => return,
+        };
+
+        {
+            let mut pids = self.pids.lock();
+            if !pids.contains(&pid) {
+                pids.push(pid);
+            }
+        }
+
+        unsafe { context_block(wake) };
+    }
+
+    /// Wake every context in the queue
+    pub fn wake(&self) {
+        let pids = mem::replace(&mut *self.pids.lock(), Vec::new());
+        if !pids.is_empty() {
+            let mut contexts = ::env().contexts.lock();
+            for pid in pids.iter() {
+                contexts.unblock(*pid);
+            }
+        }
+    }
+}
@@ -5,13 +5,12 @@ use collections::vec::Vec;
 
 use core::cell::UnsafeCell;
 use core::ops::DerefMut;
-use core::{mem, ptr};
+use core::{mem, slice};
 
 use common::elf::Elf;
-use common::memory;
 
-use scheduler::context::{CONTEXT_STACK_SIZE, CONTEXT_STACK_ADDR, context_switch,
-context_userspace, Context, ContextMemory};
+use scheduler::context::{CONTEXT_STACK_SIZE, CONTEXT_STACK_ADDR, context_block, context_enter,
+context_switch, context_userspace, Context, ContextMemory};
 
 use schemes::Url;
 
@@ -40,26 +39,21 @@ pub fn execute(url: Url, mut args: Vec<String>) -> bool {
                 //TODO: Warning: Investigate this hack!
                 let hack = virtual_address % 4096;
 
-                let physical_address = memory::alloc(virtual_size + hack);
-
-                if physical_address > 0 {
-                    // Copy progbits
-                    ::memcpy((physical_address + hack) as *mut u8,
-                             (executable.data + segment.off as usize) as *const u8,
-                             segment.file_len as usize);
-                    // Zero bss
-                    if segment.mem_len > segment.file_len {
-                        ::memset((physical_address + hack + segment.file_len as usize) as *mut u8,
-                                0,
-                                segment.mem_len as usize - segment.file_len as usize);
-                    }
-
-                    memory.push(ContextMemory {
-                        physical_address: physical_address,
-                        virtual_address: virtual_address - hack,
-                        virtual_size: virtual_size + hack,
-                        writeable: segment.flags & 2 == 2
-                    });
+                let mut mem = ContextMemory {
+                    virtual_address: virtual_address - hack,
+                    virtual_size: virtual_size + hack,
+                    writeable: segment.flags & 2 == 2,
+                    readable: segment.flags & 4 == 4,
+                    executable: segment.flags & 1 == 1,
+                    pages: Vec::new(),
+                    shared: false,
+                };
+
+                // Copy progbits, the pages of bss are zeroed when first used
+                let data = (executable.data + segment.off as usize) as *const u8;
+                let progbits = slice::from_raw_parts(data, segment.file_len as usize);
+                if mem.write(hack, progbits) {
+                    memory.push(mem);
                 }
             }
         }
@@ -68,17 +62,24 @@ pub fn execute(url: Url, mut args: Vec<String>) -> bool {
             args.insert(0, url.to_string());
 
             let mut contexts = ::env().contexts.lock();
+            let mut ppid = 0;
             if let Some(mut context) = contexts.current_mut() {
                 context.name = url.string;
                 context.args = Arc::new(UnsafeCell::new(args));
                 context.cwd = Arc::new(UnsafeCell::new(unsafe { (*context.cwd.get()).clone() }));
+                context.signals.exec();
 
                 unsafe { context.unmap() };
                 context.memory = Arc::new(UnsafeCell::new(memory));
+                context.mmap = Arc::new(UnsafeCell::new(Vec::new()));
                 unsafe { context.map() };
 
                 context_ptr = context.deref_mut();
+                ppid = context.ppid;
             }
+
+            // A parent in vfork waits for the memory to be released
+            contexts.unblock(ppid);
         } else {
             debug!("{}: Invalid memory or entry\n", url.string);
         }
@@ -90,8 +91,28 @@ pub fn execute(url: Url, mut args: Vec<String>) -> bool {
         Context::spawn("kexec".to_string(), box move || {
             let _intex = Intex::static_lock();
 
+            // The context must have switched away before its kernel stack is replaced
+            while unsafe { (*context_ptr).running } {
+                unsafe { context_switch(false) };
+            }
+
             let context = unsafe { &mut *context_ptr };
 
+            let mut stack = ContextMemory {
+                virtual_address: CONTEXT_STACK_ADDR,
+                virtual_size: CONTEXT_STACK_SIZE,
+                writeable: true,
+                readable: true,
+                executable: false,
+                pages: Vec::new(),
+                shared: false,
+            };
+
+            // The arguments are copied to the top of the stack, so only the pages they use are
+            // allocated now. The stack grows into the pages below as they are used
+            let mut sp = CONTEXT_STACK_ADDR + CONTEXT_STACK_SIZE - 128;
+            let mut copied = true;
+
             let mut context_args: Vec<usize> = Vec::new();
             context_args.push(0); // ENVP
             context_args.push(0); // ARGV NULL
@@ -102,32 +123,36 @@ pub fn execute(url: Url, mut args: Vec<String>) -> bool {
                     if ! arg.ends_with('\0') {
                         arg.push('\0');
                     }
-                    context_args.push(arg.as_ptr() as usize);
+                    sp -= arg.len();
+                    copied &= unsafe { stack.write(sp - CONTEXT_STACK_ADDR, arg.as_bytes()) };
+                    context_args.push(sp);
                     argc += 1;
                 }
             }
             context_args.push(argc);
 
-            context.sp = context.kernel_stack + CONTEXT_STACK_SIZE - 128;
+            sp -= sp % mem::size_of::<usize>();
+            for arg in context_args.iter() {
+                sp -= mem::size_of::<usize>();
+                let bytes = unsafe {
+                    slice::from_raw_parts(arg as *const usize as *const u8,
+                                          mem::size_of::<usize>())
+                };
+                copied &= unsafe { stack.write(sp - CONTEXT_STACK_ADDR, bytes) };
+            }
 
-            context.stack = Some(ContextMemory {
-                physical_address: unsafe { memory::alloc(CONTEXT_STACK_SIZE) },
-                virtual_address: CONTEXT_STACK_ADDR,
-                virtual_size: CONTEXT_STACK_SIZE,
-                writeable: true
-            });
-
-            let user_sp = if let Some(ref stack) = context.stack {
-                let mut sp = stack.physical_address + stack.virtual_size - 128;
-                for arg in context_args.iter() {
-                    sp -= mem::size_of::<usize>();
-                    unsafe { ptr::write(sp as *mut usize, *arg) };
-                }
-                sp - stack.physical_address + stack.virtual_address
+            let user_sp = if copied {
+                sp
             } else {
                 0
             };
 
+            context.sp = context.kernel_stack + CONTEXT_STACK_SIZE - 128;
+            // Userspace runs without the kernel lock
+            context.intex = 0;
+
+            context.stack = Some(stack);
+
             unsafe {
                 context.push(0x20 | 3);
                 context.push(user_sp);
@@ -135,11 +160,15 @@ pub fn execute(url: Url, mut args: Vec<String>) -> bool {
                 context.push(0x18 | 3);
                 context.push(entry);
                 context.push(context_userspace as usize);
+                context.push(context_enter as usize);
             }
+
+            context.unblock();
         });
 
+        // Resumes in userspace when kexec is done
         loop {
-            unsafe { context_switch(false) };
+            unsafe { context_block(None) };
         }
     }
     false
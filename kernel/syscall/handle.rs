@@ -4,16 +4,18 @@ use collections::string::{String, ToString};
 use collections::vec::Vec;
 
 use core::ops::Deref;
-use core::{mem, ptr, slice, str, usize};
+use core::{cmp, ptr, slice, str, usize};
 
-use common::memory;
-use common::time::Duration;
+use common::time::{Duration, NANOS_PER_SEC};
 
 use drivers::io::{Io, Pio};
 
 use scheduler::Regs;
-use scheduler::context::{context_clone, context_switch, Context, ContextMemory, ContextFile,
-                         ContextStatus};
+use scheduler::context::{context_block, context_clone, context_exit, context_switch, Context,
+                         ContextMemory, ContextFile, CONTEXT_MMAP_ADDR, CONTEXT_MMAP_READ,
+                         CONTEXT_MMAP_SIZE};
+use scheduler::session::session_attach;
+use scheduler::signal::{signal_notify, signal_send, SIGNAL_UNBLOCKABLE};
 
 use schemes::{Resource, ResourceSeek, Url};
 use schemes::pipe::{PipeRead, PipeWrite};
@@ -81,25 +83,28 @@ pub fn do_sys_brk(addr: usize) -> usize {
     let mut contexts = ::env().contexts.lock();
     if let Some(mut current) = contexts.current_mut() {
         unsafe {
-            current.unmap();
+            current.unmap_shared();
         }
 
+        // The old break is returned if it can not move
         ret = unsafe { current.next_mem() };
 
         // TODO: Make this smarter, currently it attempt to resize the entire data segment
         if let Some(mut mem) = unsafe { (*current.memory.get()).last_mut() } {
             if mem.writeable {
-                if addr >= mem.virtual_address {
-                    let size = addr - mem.virtual_address;
-                    let physical_address = unsafe { memory::realloc(mem.physical_address, size) };
-                    if physical_address > 0 {
-                        mem.physical_address = physical_address;
-                        mem.virtual_size = size;
-                        ret = mem.virtual_address + mem.virtual_size;
-                    } else {
-                        mem.virtual_size = 0;
-                        debug!("BRK: Realloc failed {:X}, {}\n", mem.virtual_address, size);
-                    }
+                // Growing must not reach the stack, other memory, or the memory map area
+                let end = mem.virtual_address + mem.virtual_size;
+                let free = addr <= end ||
+                           unsafe { current.mmap_free(end, addr - end) } &&
+                           (addr <= CONTEXT_MMAP_ADDR ||
+                            end >= CONTEXT_MMAP_ADDR + CONTEXT_MMAP_SIZE);
+
+                if addr >= mem.virtual_address && free {
+                    // New pages are allocated when first used
+                    unsafe { mem.resize(addr - mem.virtual_address) };
+                    ret = mem.virtual_address + mem.virtual_size;
+                } else if addr >= mem.virtual_address {
+                    debug!("BRK: {:X} overlaps other memory\n", addr);
                 }
             } else {
                 debug!("BRK: End segment not writeable\n");
@@ -108,7 +113,7 @@ pub fn do_sys_brk(addr: usize) -> usize {
 
         unsafe {
             current.clean_mem();
-            current.map();
+            current.map_shared();
         }
     } else {
         debug!("BRK: Context not found\n");
@@ -135,6 +140,7 @@ pub extern "cdecl" fn do_sys_chdir(path: *const u8) -> usize {
 pub fn do_sys_clone(flags: usize) -> usize {
     let mut clone_pid = usize::MAX;
     let mut mem_count = 0;
+    let mut cloned = false;
 
     {
         let mut contexts = ::env().contexts.lock();
@@ -146,6 +152,7 @@ pub fn do_sys_clone(flags: usize) -> usize {
             let parent_ptr: *const Context = parent.deref();
 
             let mut context_clone_args: Vec<usize> = Vec::new();
+            context_clone_args.push(&mut cloned as *mut bool as usize);
             context_clone_args.push(clone_pid);
             context_clone_args.push(flags);
             context_clone_args.push(parent_ptr as usize);
@@ -165,8 +172,19 @@ pub fn do_sys_clone(flags: usize) -> usize {
         }
     }
 
-    unsafe {
-        context_switch(false);
+    // Woken when the parent was copied, which resumes the child here too
+    loop {
+        {
+            let contexts = ::env().contexts.lock();
+            match contexts.current() {
+                Some(current) if !cloned && current.pid != clone_pid => (),
+                _ => break,
+            }
+        }
+
+        unsafe {
+            context_block(None);
+        }
     }
 
     Error::mux(if clone_pid != usize::MAX {
@@ -176,9 +194,10 @@ pub fn do_sys_clone(flags: usize) -> usize {
                 Ok(0)
             } else {
                 if flags & CLONE_VFORK == CLONE_VFORK {
+                    // Woken when the child execs or exits
                     while Arc::strong_count(&current.memory) > mem_count {
                         unsafe {
-                            context_switch(false);
+                            context_block(None);
                         }
                     }
                 }
@@ -236,7 +255,7 @@ pub fn do_sys_clock_gettime(clock: usize, tp: *mut TimeSpec) -> usize {
                 Ok(0)
             }
             CLOCK_MONOTONIC => {
-                let clock_monotonic = ::env().clock_monotonic.lock();
+                let clock_monotonic = Duration::monotonic();
                 unsafe {
                     (*tp).tv_sec = clock_monotonic.secs;
                     (*tp).tv_nsec = clock_monotonic.nanos;
@@ -250,6 +269,54 @@ pub fn do_sys_clock_gettime(clock: usize, tp: *mut TimeSpec) -> usize {
     })
 }
 
+pub fn do_sys_clock_settime(clock: usize, tp: *const TimeSpec) -> usize {
+    let _intex = Intex::static_lock();
+
+    Error::mux(if tp as usize > 0 {
+        match clock {
+            CLOCK_REALTIME => {
+                let time = unsafe { Duration::new((*tp).tv_sec, (*tp).tv_nsec) };
+                *::env().clock_realtime.lock() = time;
+                // Setting the clock cancels a slew in progress
+                *::env().clock_slew.lock() = Duration::new(0, 0);
+                Ok(0)
+            }
+            _ => Err(Error::new(EINVAL)),
+        }
+    } else {
+        Err(Error::new(EFAULT))
+    })
+}
+
+/// The largest slew in seconds, either way
+const ADJTIME_MAX: i64 = 2145;
+
+/// Slew the realtime clock by `delta`, returning the slew that was remaining in `olddelta`. The
+/// delta is at most `ADJTIME_MAX` seconds
+pub fn do_sys_adjtime(delta: *const TimeSpec, olddelta: *mut TimeSpec) -> usize {
+    if delta as usize > 0 {
+        let (secs, nanos) = unsafe { ((*delta).tv_sec, (*delta).tv_nsec) };
+        if secs < -ADJTIME_MAX || secs > ADJTIME_MAX || nanos < 0 || nanos >= NANOS_PER_SEC {
+            return Error::mux(Err(Error::new(EINVAL)));
+        }
+    }
+
+    let _intex = Intex::static_lock();
+
+    let mut clock_slew = ::env().clock_slew.lock();
+    if olddelta as usize > 0 {
+        unsafe {
+            (*olddelta).tv_sec = clock_slew.secs;
+            (*olddelta).tv_nsec = clock_slew.nanos;
+        }
+    }
+    if delta as usize > 0 {
+        *clock_slew = unsafe { Duration::new((*delta).tv_sec, (*delta).tv_nsec) };
+    }
+
+    0
+}
+
 pub fn do_sys_dup(fd: usize) -> usize {
     let contexts = ::env().contexts.lock();
     Error::mux(if let Some(current) = contexts.current() {
@@ -334,51 +401,9 @@ pub fn do_sys_spawnve(path: *const u8, args: *const *const u8) -> usize {
                           });
 }
 
-/// Exit context
-///
-/// Unsafe due to interrupt disabling and raw pointers
+/// Exit context with an exit code
 pub fn do_sys_exit(status: usize) {
-    {
-        let mut contexts = ::env().contexts.lock();
-
-        let mut statuses = Vec::new();
-        let (pid, ppid) = {
-            if let Some(mut current) = contexts.current_mut() {
-                current.exited = true;
-                mem::swap(&mut statuses, &mut current.statuses);
-                (current.pid, current.ppid)
-            } else {
-                (0, 0)
-            }
-        };
-
-        for mut context in contexts.iter_mut() {
-            // Add exit status to parent
-            if context.pid == ppid {
-                context.statuses.push(ContextStatus {
-                    pid: pid,
-                    status: status,
-                });
-                for status in statuses.iter() {
-                    context.statuses.push(ContextStatus {
-                        pid: status.pid,
-                        status: status.status,
-                    });
-                }
-            }
-
-            // Move children to parent
-            if context.ppid == pid {
-                context.ppid = ppid;
-            }
-        }
-    }
-
-    loop {
-        unsafe {
-            context_switch(false);
-        }
-    }
+    context_exit((status & 0xFF) << 8);
 }
 
 pub fn do_sys_fpath(fd: usize, buf: *mut u8, len: usize) -> usize {
@@ -448,6 +473,254 @@ pub fn do_sys_getpid() -> usize {
     })
 }
 
+/// Get the process group of a context, with a `pid` of 0 as the current context
+pub fn do_sys_getpgid(pid: usize) -> usize {
+    let contexts = ::env().contexts.lock();
+    let pid = if pid == 0 {
+        contexts.current().map_or(0, |current| current.pid)
+    } else {
+        pid
+    };
+
+    Error::mux(match contexts.iter().find(|context| context.pid == pid && !context.exited) {
+        Some(context) => Ok(context.pgid),
+        None => Err(Error::new(ESRCH)),
+    })
+}
+
+/// Move the current context or one of its children to the process group `pgid`, in the same
+/// session. A `pid` of 0 is the current context and a `pgid` of 0 creates a group led by it
+pub fn do_sys_setpgid(pid: usize, pgid: usize) -> usize {
+    let mut contexts = ::env().contexts.lock();
+
+    let (current_pid, current_sid) = match contexts.current() {
+        Some(current) => (current.pid, current.sid),
+        None => return Error::mux(Err(Error::new(ESRCH))),
+    };
+
+    let pid = if pid == 0 {
+        current_pid
+    } else {
+        pid
+    };
+
+    let pgid = if pgid == 0 {
+        pid
+    } else {
+        pgid
+    };
+
+    // A group other than its own must already exist in the session
+    let group = contexts.iter().any(|context| {
+        context.pgid == pgid && context.sid == current_sid && !context.exited
+    });
+    if pgid != pid && !group {
+        return Error::mux(Err(Error::new(EPERM)));
+    }
+
+    Error::mux(match contexts.iter_mut().find(|context| {
+        context.pid == pid && !context.exited &&
+        (context.pid == current_pid || context.ppid == current_pid)
+    }) {
+        Some(context) => if context.sid != current_sid {
+            Err(Error::new(ESRCH))
+        } else if context.pid == context.sid {
+            // A session leader can not leave its group
+            Err(Error::new(EPERM))
+        } else {
+            context.pgid = pgid;
+            Ok(0)
+        },
+        None => Err(Error::new(ESRCH)),
+    })
+}
+
+/// Create a session and a process group led by the current context, without a controlling
+/// terminal
+pub fn do_sys_setsid() -> usize {
+    let mut contexts = ::env().contexts.lock();
+
+    let current_pid = match contexts.current() {
+        Some(current) => current.pid,
+        None => return Error::mux(Err(Error::new(ESRCH))),
+    };
+
+    // A group leader can not leave its group
+    if contexts.iter().any(|context| context.pgid == current_pid && !context.exited) {
+        return Error::mux(Err(Error::new(EPERM)));
+    }
+
+    Error::mux(if let Some(mut current) = contexts.current_mut() {
+        current.sid = current.pid;
+        current.pgid = current.pid;
+        current.terminal = None;
+        Ok(current.sid)
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
+/// Get the foreground process group of the controlling terminal `fd`
+pub fn do_sys_tcgetpgrp(fd: usize) -> usize {
+    let contexts = ::env().contexts.lock();
+    Error::mux(if let Some(current) = contexts.current() {
+        if let Some(resource) = unsafe { current.get_file(fd) } {
+            match resource.terminal() {
+                Some(terminal) => {
+                    let inner = terminal.lock();
+                    if inner.sid == current.sid {
+                        Ok(inner.pgid)
+                    } else {
+                        Err(Error::new(ENOTTY))
+                    }
+                }
+                None => Err(Error::new(ENOTTY)),
+            }
+        } else {
+            Err(Error::new(EBADF))
+        }
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
+/// Set the foreground process group of the controlling terminal `fd` to a group in the session
+pub fn do_sys_tcsetpgrp(fd: usize, pgid: usize) -> usize {
+    let contexts = ::env().contexts.lock();
+    Error::mux(if let Some(current) = contexts.current() {
+        if let Some(resource) = unsafe { current.get_file(fd) } {
+            match resource.terminal() {
+                Some(terminal) => {
+                    let mut inner = terminal.lock();
+                    if inner.sid != current.sid {
+                        Err(Error::new(ENOTTY))
+                    } else if !contexts.iter().any(|context| {
+                        context.pgid == pgid && context.sid == current.sid && !context.exited
+                    }) {
+                        Err(Error::new(EPERM))
+                    } else {
+                        inner.pgid = pgid;
+                        Ok(0)
+                    }
+                }
+                None => Err(Error::new(ENOTTY)),
+            }
+        } else {
+            Err(Error::new(EBADF))
+        }
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
+/// Limit a nice value to `PRIO_MIN` to `PRIO_MAX`
+fn nice_limit(nice: isize) -> isize {
+    cmp::max(PRIO_MIN, cmp::min(nice, PRIO_MAX))
+}
+
+/// Get the nice value of a context as `20 - nice`, so that it is never negative. Only
+/// `PRIO_PROCESS` is supported, with a `who` of 0 as the current context
+pub fn do_sys_getpriority(which: usize, who: usize) -> usize {
+    let contexts = ::env().contexts.lock();
+    Error::mux(if which == PRIO_PROCESS {
+        let pid = if who == 0 {
+            contexts.current().map_or(0, |current| current.pid)
+        } else {
+            who
+        };
+
+        match contexts.iter().find(|context| context.pid == pid && !context.exited) {
+            Some(context) => Ok((20 - context.nice) as usize),
+            None => Err(Error::new(ESRCH)),
+        }
+    } else {
+        Err(Error::new(EINVAL))
+    })
+}
+
+/// Set the nice value of a context, limited to `PRIO_MIN` to `PRIO_MAX`
+pub fn do_sys_setpriority(which: usize, who: usize, prio: isize) -> usize {
+    let mut contexts = ::env().contexts.lock();
+    Error::mux(if which == PRIO_PROCESS {
+        let pid = if who == 0 {
+            contexts.current().map_or(0, |current| current.pid)
+        } else {
+            who
+        };
+
+        match contexts.iter_mut().find(|context| context.pid == pid && !context.exited) {
+            Some(context) => {
+                context.nice = nice_limit(prio);
+                Ok(0)
+            }
+            None => Err(Error::new(ESRCH)),
+        }
+    } else {
+        Err(Error::new(EINVAL))
+    })
+}
+
+/// Add `inc` to the nice value of the current context
+pub fn do_sys_nice(inc: isize) -> usize {
+    let mut contexts = ::env().contexts.lock();
+    Error::mux(if let Some(mut current) = contexts.current_mut() {
+        current.nice = nice_limit(current.nice.saturating_add(inc));
+        Ok(0)
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
+/// Send a signal to a context, to every other context with a `pid` of -1, to the process group
+/// of the current context with a `pid` of 0, or to the process group `-pid`. A signal of 0 only
+/// checks that the context exists
+pub fn do_sys_kill(pid: isize, sig: usize) -> usize {
+    if sig >= NSIG {
+        return Error::mux(Err(Error::new(EINVAL)));
+    }
+
+    let mut contexts = ::env().contexts.lock();
+
+    let (current_pid, current_pgid) = match contexts.current() {
+        Some(current) => (current.pid, current.pgid),
+        None => return Error::mux(Err(Error::new(ESRCH))),
+    };
+
+    let mut found = false;
+    let mut continued = Vec::new();
+    for context in contexts.iter_mut() {
+        let target = if pid > 0 {
+            context.pid == pid as usize
+        } else if pid == -1 {
+            // Every context that runs a program, except the sender and init
+            context.pid != current_pid && context.pid > 1 && context.stack.is_some()
+        } else if pid == 0 {
+            context.pgid == current_pgid
+        } else {
+            context.pgid == pid.wrapping_neg() as usize
+        };
+
+        if target && !context.exited {
+            found = true;
+            if sig > 0 {
+                if let Some(status) = signal_send(context, sig) {
+                    continued.push((context.pid, context.ppid, status));
+                }
+            }
+        }
+    }
+
+    for (child, parent, status) in continued {
+        signal_notify(&mut contexts, child, parent, status);
+    }
+
+    Error::mux(if found {
+        Ok(0)
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
 // TODO: link
 
 pub fn do_sys_lseek(fd: usize, offset: isize, whence: usize) -> usize {
@@ -474,9 +747,210 @@ pub fn do_sys_mkdir(_: *const u8, _: usize) -> usize {
     usize::MAX
 }
 
+/// Map memory, anonymous maps are zeroed and file maps are read from the resource at `offset`.
+/// Changes to a file map are not written back, so a shared file map can not be writeable. Forks
+/// use the same pages as a shared map
+pub fn do_sys_mmap(args: *const MmapArgs) -> usize {
+    if args as usize == 0 {
+        return Error::mux(Err(Error::new(EFAULT)));
+    }
+    let args = unsafe { ptr::read(args) };
+
+    let mut contexts = ::env().contexts.lock();
+    Error::mux(if let Some(mut current) = contexts.current_mut() {
+        unsafe { mmap(current, &args) }
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
+unsafe fn mmap(current: &mut Context, args: &MmapArgs) -> Result {
+    let sharing = args.flags & (MAP_SHARED | MAP_PRIVATE);
+    if args.len == 0 || args.len > CONTEXT_MMAP_SIZE || args.addr % 4096 != 0 ||
+       args.offset % 4096 != 0 || (sharing != MAP_SHARED && sharing != MAP_PRIVATE) {
+        return Err(Error::new(EINVAL));
+    }
+
+    let anonymous = args.flags & MAP_ANONYMOUS == MAP_ANONYMOUS;
+    if !anonymous && sharing == MAP_SHARED && args.prot & PROT_WRITE == PROT_WRITE {
+        return Err(Error::new(EACCES));
+    }
+
+    let size = (args.len + 4095) / 4096 * 4096;
+
+    // Maps are only placed in the memory map area
+    let fixed = args.flags & MAP_FIXED == MAP_FIXED;
+    let in_area = args.addr >= CONTEXT_MMAP_ADDR &&
+                  args.addr - CONTEXT_MMAP_ADDR <= CONTEXT_MMAP_SIZE - size;
+    if fixed && !in_area {
+        return Err(Error::new(EINVAL));
+    }
+
+    // Anonymous pages are allocated when first used, the address is chosen later
+    let mut mem = ContextMemory {
+        virtual_address: 0,
+        virtual_size: size,
+        writeable: args.prot & PROT_WRITE == PROT_WRITE,
+        readable: args.prot & PROT_READ == PROT_READ,
+        executable: args.prot & PROT_EXEC == PROT_EXEC,
+        pages: Vec::new(),
+        shared: sharing == MAP_SHARED,
+    };
+
+    // Read the file into the pages before changing any map, leaving the file offset as it was.
+    // TODO: Read pages when they are first used, instead of limiting the size of file maps
+    if !anonymous {
+        if args.len > CONTEXT_MMAP_READ {
+            return Err(Error::new(ENOMEM));
+        }
+
+        let resource = match current.get_file_mut(args.fd) {
+            Some(resource) => resource,
+            None => return Err(Error::new(EBADF)),
+        };
+
+        let position = resource.seek(ResourceSeek::Current(0));
+        if let Err(err) = resource.seek(ResourceSeek::Start(args.offset)) {
+            if args.offset > 0 {
+                return Err(err);
+            }
+        }
+
+        let mut buf = [0; 4096];
+        let mut offset = 0;
+        while offset < args.len {
+            let count = try!(resource.read(&mut buf));
+            if count == 0 {
+                break;
+            }
+            let count = cmp::min(count, args.len - offset);
+            if !mem.write(offset, &buf[..count]) {
+                return Err(Error::new(ENOMEM));
+            }
+            offset += count;
+        }
+
+        if let Ok(position) = position {
+            let _ = resource.seek(ResourceSeek::Start(position));
+        }
+    }
+
+    // Shared pages are allocated now, so that forks use the same ones
+    if mem.shared {
+        for i in 0..mem.page_count() {
+            if mem.page(i) == 0 {
+                return Err(Error::new(ENOMEM));
+            }
+        }
+    }
+
+    // A fixed map replaces the maps in its way, but not program memory or the stack
+    if fixed && !current.program_free(args.addr, size) {
+        return Err(Error::new(ENOMEM));
+    }
+
+    current.unmap_shared();
+
+    // Otherwise the address is a hint
+    if fixed {
+        current.mmap_remove(args.addr, size);
+    }
+    let address = if in_area && current.mmap_free(args.addr, size) {
+        Some(args.addr)
+    } else if fixed {
+        None
+    } else {
+        current.mmap_find(size)
+    };
+
+    let ret = match address {
+        Some(address) => {
+            mem.virtual_address = address;
+            (*current.mmap.get()).push(mem);
+            Ok(address)
+        }
+        None => Err(Error::new(ENOMEM)),
+    };
+
+    current.map_shared();
+
+    ret
+}
+
+/// Change the protection of the memory maps from `addr` to `addr + len`, which must all be mapped.
+/// Program memory and the stack can not be changed
+pub fn do_sys_mprotect(addr: usize, len: usize, prot: usize) -> usize {
+    if addr % 4096 != 0 || len == 0 || len > CONTEXT_MMAP_SIZE ||
+       addr > usize::MAX - CONTEXT_MMAP_SIZE {
+        return Error::mux(Err(Error::new(EINVAL)));
+    }
+    let size = (len + 4095) / 4096 * 4096;
+
+    let mut contexts = ::env().contexts.lock();
+    Error::mux(if let Some(mut current) = contexts.current_mut() {
+        // Nothing is changed unless all of it is mapped
+        let mapped = unsafe { (*current.mmap.get()).iter() }.fold(0, |mapped, mem| {
+            let start = cmp::max(mem.virtual_address, addr);
+            let end = cmp::min(mem.virtual_address + mem.virtual_size, addr + size);
+            if start < end {
+                mapped + end - start
+            } else {
+                mapped
+            }
+        });
+
+        if mapped == size {
+            unsafe {
+                current.unmap_shared();
+                current.mmap_split(addr);
+                current.mmap_split(addr + size);
+                for mem in (*current.mmap.get()).iter_mut() {
+                    if mem.virtual_address >= addr && mem.virtual_address < addr + size {
+                        mem.writeable = prot & PROT_WRITE == PROT_WRITE;
+                        mem.readable = prot & PROT_READ == PROT_READ;
+                        mem.executable = prot & PROT_EXEC == PROT_EXEC;
+                    }
+                }
+                current.map_shared();
+            }
+            Ok(0)
+        } else {
+            Err(Error::new(ENOMEM))
+        }
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
+/// Unmap the memory maps from `addr` to `addr + len`, splitting maps that cross the ends
+pub fn do_sys_munmap(addr: usize, len: usize) -> usize {
+    if addr % 4096 != 0 || len == 0 || len > CONTEXT_MMAP_SIZE ||
+       addr > usize::MAX - CONTEXT_MMAP_SIZE {
+        return Error::mux(Err(Error::new(EINVAL)));
+    }
+    let size = (len + 4095) / 4096 * 4096;
+
+    let mut contexts = ::env().contexts.lock();
+    Error::mux(if let Some(mut current) = contexts.current_mut() {
+        unsafe {
+            current.unmap_shared();
+            current.mmap_remove(addr, size);
+            current.map_shared();
+        }
+        Ok(0)
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
 pub fn do_sys_nanosleep(req: *const TimeSpec, rem: *mut TimeSpec) -> usize {
     Error::mux(if req as usize > 0 {
-        Duration::new(unsafe { (*req).tv_sec }, unsafe { (*req).tv_nsec }).sleep();
+        let (secs, nanos) = unsafe { ((*req).tv_sec, (*req).tv_nsec) };
+        if secs < 0 || nanos < 0 || nanos >= NANOS_PER_SEC {
+            return Error::mux(Err(Error::new(EINVAL)));
+        }
+
+        Duration::new(secs, nanos).sleep();
 
         if rem as usize > 0 {
             unsafe {
@@ -494,8 +968,8 @@ pub fn do_sys_nanosleep(req: *const TimeSpec, rem: *mut TimeSpec) -> usize {
 }
 
 pub fn do_sys_open(path: *const u8, flags: usize) -> usize {
-    let contexts = ::env().contexts.lock();
-    Error::mux(if let Some(current) = contexts.current() {
+    let mut contexts = ::env().contexts.lock();
+    let result = if let Some(current) = contexts.current() {
         let path_string = unsafe {
             current.canonicalize(str::from_utf8_unchecked(c_string_to_slice(path)))
         };
@@ -503,6 +977,7 @@ pub fn do_sys_open(path: *const u8, flags: usize) -> usize {
         match (::env()).open(&Url::from_string(path_string), flags) {
             Ok(resource) => {
                 let fd = unsafe { current.next_fd() };
+                let terminal = resource.terminal();
 
                 unsafe {
                     (*current.files.get()).push(ContextFile {
@@ -511,13 +986,25 @@ pub fn do_sys_open(path: *const u8, flags: usize) -> usize {
                     });
                 }
 
-                Ok(fd)
+                Ok((fd, terminal))
             }
             Err(err) => Err(err),
         }
     } else {
         Err(Error::new(ESRCH))
-    })
+    };
+
+    let (fd, terminal) = match result {
+        Ok(result) => result,
+        Err(err) => return Error::mux(Err(err)),
+    };
+
+    // A session leader without a controlling terminal gets the first one it opens
+    if let Some(terminal) = terminal {
+        session_attach(&mut contexts, terminal);
+    }
+
+    Error::mux(Ok(fd))
 }
 
 pub fn do_sys_pipe2(fds: *mut usize, _flags: usize) -> usize {
@@ -563,6 +1050,75 @@ pub fn do_sys_read(fd: usize, buf: *mut u8, count: usize) -> usize {
     })
 }
 
+pub fn do_sys_sigaction(sig: usize, act: *const SigAction, oldact: *mut SigAction) -> usize {
+    let mut contexts = ::env().contexts.lock();
+    Error::mux(if let Some(mut current) = contexts.current_mut() {
+        if sig == 0 || sig >= NSIG || (act as usize > 0 && SIGNAL_UNBLOCKABLE & 1 << sig != 0) {
+            Err(Error::new(EINVAL))
+        } else {
+            if oldact as usize > 0 {
+                unsafe { ptr::write(oldact, current.signals.actions[sig]) };
+            }
+            if act as usize > 0 {
+                let action = unsafe { ptr::read(act) };
+                current.signals.actions[sig] = action;
+                if action.sa_handler == SIG_IGN {
+                    current.signals.pending &= !(1 << sig);
+                }
+            }
+            Ok(0)
+        }
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
+pub fn do_sys_sigprocmask(how: usize, set: *const usize, oldset: *mut usize) -> usize {
+    let mut contexts = ::env().contexts.lock();
+    Error::mux(if let Some(mut current) = contexts.current_mut() {
+        if oldset as usize > 0 {
+            unsafe { ptr::write(oldset, current.signals.blocked) };
+        }
+
+        if set as usize > 0 {
+            let set = unsafe { ptr::read(set) } & !SIGNAL_UNBLOCKABLE;
+            match how {
+                SIG_BLOCK => {
+                    current.signals.blocked |= set;
+                    Ok(0)
+                }
+                SIG_UNBLOCK => {
+                    current.signals.blocked &= !set;
+                    Ok(0)
+                }
+                SIG_SETMASK => {
+                    current.signals.blocked = set;
+                    Ok(0)
+                }
+                _ => Err(Error::new(EINVAL)),
+            }
+        } else {
+            Ok(0)
+        }
+    } else {
+        Err(Error::new(ESRCH))
+    })
+}
+
+/// Return from a signal handler, restoring the registers and mask saved when it was entered
+pub fn do_sys_sigreturn(regs: &mut Regs) {
+    let mut contexts = ::env().contexts.lock();
+    if let Some(mut current) = contexts.current_mut() {
+        if let Some(frame) = current.signals.frames.pop() {
+            *regs = frame.regs;
+            current.signals.blocked = frame.blocked;
+            return;
+        }
+    }
+
+    regs.ax = Error::mux(Err(Error::new(EINVAL)));
+}
+
 pub fn do_sys_unlink(path: *const u8) -> usize {
     let contexts = ::env().contexts.lock();
     Error::mux(if let Some(current) = contexts.current() {
@@ -585,21 +1141,52 @@ pub fn do_sys_waitpid(pid: isize, status: *mut usize, options: usize) -> usize {
     loop {
         {
             let mut contexts = ::env().contexts.lock();
+
+            let (current_pid, current_pgid) = match contexts.current() {
+                Some(current) => (current.pid, current.pgid),
+                None => break,
+            };
+
+            let children = contexts.iter().any(|context| {
+                context.ppid == current_pid && !context.exited &&
+                if pid > 0 {
+                    context.pid == pid as usize
+                } else if pid == -1 {
+                    true
+                } else if pid == 0 {
+                    context.pgid == current_pgid
+                } else {
+                    context.pgid == pid.wrapping_neg() as usize
+                }
+            });
+
             if let Some(mut current) = contexts.current_mut() {
                 let mut found = false;
                 let mut i = 0;
                 while i < current.statuses.len() {
                     if let Some(current_status) = current.statuses.get(i) {
-                        if pid > 0 && pid as usize == current_status.pid {
+                        let reported = if wifstopped(current_status.status) {
+                            options & WUNTRACED == WUNTRACED
+                        } else if wifcontinued(current_status.status) {
+                            options & WCONTINUED == WCONTINUED
+                        } else {
+                            true
+                        };
+
+                        if !reported {
+                            // Reports that were not asked for
+                        } else if pid > 0 && pid as usize == current_status.pid {
                             // Specific child
                             found = true;
                         } else if pid == 0 {
-                            // TODO Any child whose PGID is equal to this process
+                            // Any child in the process group of this process
+                            found = current_status.pgid == current_pgid;
                         } else if pid == -1 {
                             // Any child
                             found = true;
-                        } else {
-                            // TODO Any child whose PGID is equal to abs(pid)
+                        } else if pid < -1 {
+                            // Any child in the process group abs(pid)
+                            found = current_status.pgid == pid.wrapping_neg() as usize;
                         }
                     }
                     if found {
@@ -620,13 +1207,29 @@ pub fn do_sys_waitpid(pid: isize, status: *mut usize, options: usize) -> usize {
                 if found {
                     break;
                 }
+
+                if !children {
+                    ret = Err(Error::new(ECHILD));
+                    break;
+                }
+
+                if options & WNOHANG == WNOHANG {
+                    ret = Ok(0);
+                    break;
+                }
+
+                if current.signals.deliverable() != 0 {
+                    ret = Err(Error::new(EINTR));
+                    break;
+                }
             } else {
                 break;
             }
         }
 
+        // Woken by a status of a child, or a signal
         unsafe {
-            context_switch(false);
+            context_block(None);
         }
     }
 
@@ -652,23 +1255,26 @@ pub fn do_sys_alloc(size: usize) -> usize {
     let mut contexts = ::env().contexts.lock();
     if let Some(mut current) = contexts.current_mut() {
         unsafe {
-            current.unmap();
+            current.unmap_shared();
         }
-        let physical_address = unsafe { memory::alloc(size) };
-        if physical_address > 0 {
+        // The pages are allocated when first used
+        if size > 0 {
             ret = unsafe { current.next_mem() };
             unsafe {
                 (*current.memory.get()).push(ContextMemory {
-                    physical_address: physical_address,
                     virtual_address: ret,
                     virtual_size: size,
                     writeable: true,
+                    readable: true,
+                    executable: false,
+                    pages: Vec::new(),
+                    shared: false,
                 });
             }
         }
         unsafe {
             current.clean_mem();
-            current.map();
+            current.map_shared();
         }
     }
 
@@ -681,21 +1287,17 @@ pub fn do_sys_realloc(ptr: usize, size: usize) -> usize {
     let mut contexts = ::env().contexts.lock();
     if let Some(mut current) = contexts.current_mut() {
         unsafe {
-            current.unmap();
+            current.unmap_shared();
         }
         if let Some(mut mem) = unsafe { current.get_mem_mut(ptr) } {
-            let physical_address = unsafe { memory::realloc(mem.physical_address, size) };
-            if physical_address > 0 {
-                mem.physical_address = physical_address;
-                mem.virtual_size = size;
+            unsafe { mem.resize(size) };
+            if size > 0 {
                 ret = mem.virtual_address;
-            } else {
-                mem.virtual_size = 0;
             }
         }
         unsafe {
             current.clean_mem();
-            current.map();
+            current.map_shared();
         }
     }
 
@@ -708,15 +1310,16 @@ pub fn do_sys_realloc_inplace(ptr: usize, size: usize) -> usize {
     let mut contexts = ::env().contexts.lock();
     if let Some(mut current) = contexts.current_mut() {
         unsafe {
-            current.unmap();
+            current.unmap_shared();
         }
         if let Some(mut mem) = unsafe { current.get_mem_mut(ptr) } {
-            mem.virtual_size = unsafe { memory::realloc_inplace(mem.physical_address, size) };
+            let capacity = mem.page_count() * 4096;
+            unsafe { mem.resize(cmp::min(size, capacity)) };
             ret = mem.virtual_size;
         }
         unsafe {
             current.clean_mem();
-            current.map();
+            current.map_shared();
         }
     }
 
@@ -727,14 +1330,14 @@ pub fn do_sys_unalloc(ptr: usize) {
     let mut contexts = ::env().contexts.lock();
     if let Some(mut current) = contexts.current_mut() {
         unsafe {
-            current.unmap();
+            current.unmap_shared();
         }
         if let Some(mut mem) = unsafe { current.get_mem_mut(ptr) } {
             mem.virtual_size = 0;
         }
         unsafe {
             current.clean_mem();
-            current.map();
+            current.map_shared();
         }
     }
 }
@@ -748,6 +1351,10 @@ pub fn syscall_handle(regs: &mut Regs) -> bool {
         SYS_CLONE => regs.ax = do_sys_clone(regs.bx),
         SYS_CLOSE => regs.ax = do_sys_close(regs.bx),
         SYS_CLOCK_GETTIME => regs.ax = do_sys_clock_gettime(regs.bx, regs.cx as *mut TimeSpec),
+        SYS_CLOCK_SETTIME =>
+            regs.ax = do_sys_clock_settime(regs.bx, regs.cx as *const TimeSpec),
+        SYS_ADJTIME =>
+            regs.ax = do_sys_adjtime(regs.bx as *const TimeSpec, regs.cx as *mut TimeSpec),
         SYS_DUP => regs.ax = do_sys_dup(regs.bx),
         SYS_EXECVE => regs.ax = do_sys_execve(regs.bx as *const u8, regs.cx as *const *const u8),
         SYS_SPAWNVE => regs.ax = do_sys_spawnve(regs.bx as *const u8, regs.cx as *const *const u8),
@@ -756,15 +1363,33 @@ pub fn syscall_handle(regs: &mut Regs) -> bool {
         // TODO: fstat
         SYS_FSYNC => regs.ax = do_sys_fsync(regs.bx),
         SYS_FTRUNCATE => regs.ax = do_sys_ftruncate(regs.bx, regs.cx),
+        SYS_GETPGID => regs.ax = do_sys_getpgid(regs.bx),
         SYS_GETPID => regs.ax = do_sys_getpid(),
+        SYS_GETPRIORITY => regs.ax = do_sys_getpriority(regs.bx, regs.cx),
+        SYS_KILL => regs.ax = do_sys_kill(regs.bx as isize, regs.cx),
         // TODO: link
         SYS_LSEEK => regs.ax = do_sys_lseek(regs.bx, regs.cx as isize, regs.dx),
         SYS_MKDIR => regs.ax = do_sys_mkdir(regs.bx as *const u8, regs.cx),
+        SYS_MMAP => regs.ax = do_sys_mmap(regs.bx as *const MmapArgs),
+        SYS_MPROTECT => regs.ax = do_sys_mprotect(regs.bx, regs.cx, regs.dx),
+        SYS_MUNMAP => regs.ax = do_sys_munmap(regs.bx, regs.cx),
         SYS_NANOSLEEP =>
             regs.ax = do_sys_nanosleep(regs.bx as *const TimeSpec, regs.cx as *mut TimeSpec),
+        SYS_NICE => regs.ax = do_sys_nice(regs.bx as isize),
         SYS_OPEN => regs.ax = do_sys_open(regs.bx as *const u8, regs.cx), //regs.cx as isize, regs.dx as isize),
         SYS_PIPE2 => regs.ax = do_sys_pipe2(regs.bx as *mut usize, regs.cx),
         SYS_READ => regs.ax = do_sys_read(regs.bx, regs.cx as *mut u8, regs.dx),
+        SYS_SETPGID => regs.ax = do_sys_setpgid(regs.bx, regs.cx),
+        SYS_SETPRIORITY => regs.ax = do_sys_setpriority(regs.bx, regs.cx, regs.dx as isize),
+        SYS_SETSID => regs.ax = do_sys_setsid(),
+        SYS_SIGACTION => regs.ax = do_sys_sigaction(regs.bx,
+                                                    regs.cx as *const SigAction,
+                                                    regs.dx as *mut SigAction),
+        SYS_SIGPROCMASK =>
+            regs.ax = do_sys_sigprocmask(regs.bx, regs.cx as *const usize, regs.dx as *mut usize),
+        SYS_SIGRETURN => do_sys_sigreturn(regs),
+        SYS_TCGETPGRP => regs.ax = do_sys_tcgetpgrp(regs.bx),
+        SYS_TCSETPGRP => regs.ax = do_sys_tcsetpgrp(regs.bx, regs.cx),
         SYS_UNLINK => regs.ax = do_sys_unlink(regs.bx as *const u8),
         SYS_WAITPID => regs.ax = do_sys_waitpid(regs.bx as isize, regs.cx as *mut usize, regs.dx),
         SYS_WRITE => regs.ax = do_sys_write(regs.bx, regs.cx as *mut u8, regs.dx),
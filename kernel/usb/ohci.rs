@@ -127,7 +127,7 @@ impl Ohci {
                 done_head: 0,
                 reserved: [0; 116],
             },
-            irq: pci.read(0x3C) as u8 & 0xF,
+            irq: pci.irq(),
         };
 
         module.init();
@@ -56,7 +56,7 @@ impl Uhci {
 
         let mut module = box Uhci {
             base: pci.read(0x20) as usize & 0xFFFFFFF0,
-            irq: pci.read(0x3C) as u8 & 0xF,
+            irq: pci.irq(),
             frame_list: Memory::new(1024).unwrap(),
         };
 
@@ -4,6 +4,7 @@ use {fmt, str};
 use string::String;
 use vec::{IntoIter, Vec};
 pub use system::error::Error;
+use system::error::EIO;
 use system::syscall::{sys_read, sys_write};
 
 pub mod prelude;
@@ -62,6 +63,18 @@ pub trait Write {
     /// Write to the file
     fn write(&mut self, buf: &[u8]) -> Result<usize>;
 
+    /// Write all of a buffer, calling `write` until it is written
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        let mut i = 0;
+        while i < buf.len() {
+            match try!(self.write(&buf[i..])) {
+                0 => return Err(Error::new(EIO)),
+                count => i += count,
+            }
+        }
+        Ok(())
+    }
+
     /// Write a format to the file
     fn write_fmt(&mut self, args: fmt::Arguments) -> Result<()> {
         match self.write(fmt::format(args).as_bytes()) {
@@ -75,6 +88,14 @@ pub trait Write {
     }
 }
 
+/// Writing to a vector appends to it
+impl Write for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.push_all(buf);
+        Ok(buf.len())
+    }
+}
+
 /// Seek Location
 pub enum SeekFrom {
     /// The start point
@@ -0,0 +1,166 @@
+use io::Result;
+use option;
+use string::{String, ToString};
+use to_num::ToNum;
+
+use system::error::{Error, EINVAL, ENOENT};
+
+use super::IPv4Addr;
+
+/// An IPv4 address and a port
+#[derive(Copy, Clone)]
+pub struct SocketAddr {
+    ip: IPv4Addr,
+    port: u16,
+}
+
+impl SocketAddr {
+    pub fn new(ip: IPv4Addr, port: u16) -> Self {
+        SocketAddr {
+            ip: ip,
+            port: port,
+        }
+    }
+
+    pub fn ip(&self) -> IPv4Addr {
+        self.ip
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn set_ip(&mut self, ip: IPv4Addr) {
+        self.ip = ip;
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    pub fn equals(&self, other: Self) -> bool {
+        self.ip.equals(other.ip) && self.port == other.port
+    }
+
+    /// Parse `host:port`, resolving the host name if it is not numeric
+    pub fn from_str(string: &str) -> Result<Self> {
+        let mut parts = string.rsplitn(2, ':');
+        let port_string = parts.next().unwrap_or("");
+        let host = match parts.next() {
+            Some(host) => host,
+            None => return Err(Error::new(EINVAL)),
+        };
+
+        if port_string.is_empty() || port_string.len() > 5 ||
+           !port_string.chars().all(|c| c >= '0' && c <= '9') {
+            return Err(Error::new(EINVAL));
+        }
+        let port = port_string.to_string().to_num();
+        if port > 65535 {
+            return Err(Error::new(EINVAL));
+        }
+
+        match IPv4Addr::resolve(host) {
+            Some(ip) => Ok(SocketAddr::new(ip, port as u16)),
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        format!("{}:{}", self.ip.to_string(), self.port)
+    }
+}
+
+/// Values that can be converted into socket addresses
+pub trait ToSocketAddrs {
+    type Iter: Iterator<Item = SocketAddr>;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter>;
+}
+
+impl ToSocketAddrs for SocketAddr {
+    type Iter = option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter> {
+        Ok(Some(*self).into_iter())
+    }
+}
+
+impl ToSocketAddrs for (IPv4Addr, u16) {
+    type Iter = option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter> {
+        Ok(Some(SocketAddr::new(self.0, self.1)).into_iter())
+    }
+}
+
+impl<'a> ToSocketAddrs for (&'a str, u16) {
+    type Iter = option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter> {
+        match IPv4Addr::resolve(self.0) {
+            Some(ip) => Ok(Some(SocketAddr::new(ip, self.1)).into_iter()),
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+}
+
+impl ToSocketAddrs for str {
+    type Iter = option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter> {
+        SocketAddr::from_str(self).map(|addr| Some(addr).into_iter())
+    }
+}
+
+impl ToSocketAddrs for String {
+    type Iter = option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter> {
+        (&self[..]).to_socket_addrs()
+    }
+}
+
+impl<'a, T: ToSocketAddrs + ?Sized> ToSocketAddrs for &'a T {
+    type Iter = T::Iter;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter> {
+        (**self).to_socket_addrs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use system::error::{Error, EINVAL};
+
+    use super::SocketAddr;
+
+    /// The error number of parsing, or zero
+    fn errno(string: &str) -> usize {
+        Error::mux(SocketAddr::from_str(string).map(|_| 0))
+    }
+
+    #[test]
+    fn socket_addr() {
+        let addr = SocketAddr::from_str("10.0.2.2:8080").unwrap();
+        assert_eq!(addr.ip().bytes, [10, 0, 2, 2]);
+        assert_eq!(addr.port(), 8080);
+        assert_eq!(SocketAddr::from_str("1.2.3.4:0").unwrap().port(), 0);
+        assert_eq!(SocketAddr::from_str("1.2.3.4:65535").unwrap().port(), 65535);
+    }
+
+    #[test]
+    fn socket_addr_bad_port() {
+        let invalid = Error::mux(Err(Error::new(EINVAL)));
+        assert_eq!(errno("1.2.3.4"), invalid);
+        assert_eq!(errno("1.2.3.4:"), invalid);
+        assert_eq!(errno("1.2.3.4:65536"), invalid);
+        assert_eq!(errno("1.2.3.4:100000"), invalid);
+        assert_eq!(errno("1.2.3.4:99999999999999999999"), invalid);
+        assert_eq!(errno("1.2.3.4:-1"), invalid);
+        assert_eq!(errno("1.2.3.4:+80"), invalid);
+        assert_eq!(errno("1.2.3.4:8o"), invalid);
+        assert_eq!(errno("1.2.3.4: 80"), invalid);
+        assert_eq!(errno("1.2.3.4:80:"), invalid);
+    }
+}
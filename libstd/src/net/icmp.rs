@@ -0,0 +1,59 @@
+use system::error::{ECONNREFUSED, EHOSTUNREACH, ENETUNREACH, EPROTO};
+
+use super::{IPv4Addr, n16, n32};
+
+/// An echo reply
+pub const ICMP_ECHO_REPLY: u8 = 0x00;
+/// A destination was unreachable
+pub const ICMP_UNREACHABLE: u8 = 0x03;
+/// The time to live of a datagram was exceeded
+pub const ICMP_TIME_EXCEEDED: u8 = 0x0B;
+/// A datagram had a bad header
+pub const ICMP_PARAMETER_PROBLEM: u8 = 0x0C;
+/// The type of an echo that was not answered in time
+pub const ICMP_ECHO_TIMEOUT: u8 = 0xFF;
+
+/// The result of an echo, read from an `icmp:echo/` resource before the echoed data
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct IcmpEcho {
+    pub sequence: n16,
+    /// `ICMP_ECHO_REPLY`, an error type, or `ICMP_ECHO_TIMEOUT`
+    pub _type: u8,
+    pub code: u8,
+    /// Round trip time, in microseconds
+    pub rtt: n32,
+}
+
+/// An error about a datagram sent with some protocol, read from an `icmp:error/` resource
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct IcmpError {
+    pub _type: u8,
+    pub code: u8,
+    /// The host that reported the error
+    pub reporter: IPv4Addr,
+    /// The source and destination of the datagram
+    pub src: IPv4Addr,
+    pub dst: IPv4Addr,
+    /// The ports of the datagram
+    pub src_port: n16,
+    pub dst_port: n16,
+}
+
+impl IcmpError {
+    /// The error number reported to the user
+    pub fn errno(&self) -> isize {
+        match (self._type, self.code) {
+            (ICMP_UNREACHABLE, 0) => ENETUNREACH,
+            (ICMP_UNREACHABLE, 2) | (ICMP_UNREACHABLE, 3) => ECONNREFUSED,
+            (ICMP_PARAMETER_PROBLEM, _) => EPROTO,
+            _ => EHOSTUNREACH,
+        }
+    }
+
+    /// Protocol and port unreachable are hard errors, which abort a connection (RFC 1122)
+    pub fn is_hard(&self) -> bool {
+        self._type == ICMP_UNREACHABLE && (self.code == 2 || self.code == 3)
+    }
+}
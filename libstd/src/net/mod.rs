@@ -1,9 +1,22 @@
 //! A module dealing with network connections
 
+use fs::File;
+use io::Read;
 use string::{String, ToString};
 use to_num::ToNum;
 use vec::Vec;
 
+pub use self::addr::{SocketAddr, ToSocketAddrs};
+pub use self::icmp::{ICMP_ECHO_REPLY, ICMP_ECHO_TIMEOUT, ICMP_PARAMETER_PROBLEM,
+                     ICMP_TIME_EXCEEDED, ICMP_UNREACHABLE, IcmpEcho, IcmpError};
+pub use self::tcp::{Incoming, Shutdown, TcpListener, TcpStream};
+pub use self::udp::{UdpMeta, UdpSocket};
+
+mod addr;
+mod icmp;
+mod tcp;
+mod udp;
+
 pub trait FromBytes {
     fn from_bytes(bytes: Vec<u8>) -> Option<Self> where Self: Sized;
 }
@@ -123,6 +136,20 @@ impl IPv4Addr {
         true
     }
 
+    /// Check if the address is in 127.0.0.0/8, which is routed to the loopback interface
+    pub fn is_loopback(&self) -> bool {
+        self.bytes[0] == 127
+    }
+
+    /// The local address used to reach this address, loopback traffic uses the peer address
+    pub fn host_addr(&self) -> Self {
+        if self.is_loopback() {
+            *self
+        } else {
+            IP_ADDR
+        }
+    }
+
     pub fn from_string(string: &String) -> Self {
         let mut addr = IPv4Addr { bytes: [0, 0, 0, 0] };
 
@@ -142,6 +169,56 @@ impl IPv4Addr {
         addr
     }
 
+    /// Parse a numeric address, four decimal octets separated by dots
+    pub fn parse(string: &str) -> Option<Self> {
+        let mut addr = IPv4Addr { bytes: [0, 0, 0, 0] };
+
+        let mut parts = string.split('.');
+        for i in 0..4 {
+            match parts.next() {
+                Some(part) if !part.is_empty() && part.len() <= 3 &&
+                              part.chars().all(|c| c >= '0' && c <= '9') => {
+                    let octet = part.to_string().to_num();
+                    if octet > 255 {
+                        return None;
+                    }
+                    addr.bytes[i] = octet as u8;
+                }
+                _ => return None,
+            }
+        }
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(addr)
+    }
+
+    /// Resolve a numeric address or a host name, using the `dns:` scheme for names
+    pub fn resolve(host: &str) -> Option<Self> {
+        if host.is_empty() {
+            return None;
+        }
+
+        if let Some(addr) = IPv4Addr::parse(host) {
+            return Some(addr);
+        }
+
+        if let Ok(mut file) = File::open(&format!("dns://{}", host)) {
+            let mut string = String::new();
+            if file.read_to_string(&mut string).is_ok() {
+                if let Some(line) = string.lines().next() {
+                    if !line.is_empty() {
+                        return Some(IPv4Addr::from_string(&line.to_string()));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
     pub fn to_string(&self) -> String {
         format!("{}.{}.{}.{}",
                 self.bytes[0],
@@ -156,6 +233,8 @@ pub struct IPv6Addr {
     pub bytes: [u8; 16],
 }
 
+pub static ANY_IP_ADDR: IPv4Addr = IPv4Addr { bytes: [0, 0, 0, 0] };
+
 pub static BROADCAST_IP_ADDR: IPv4Addr = IPv4Addr { bytes: [10, 85, 85, 255] };
 
 pub static IP_ADDR: IPv4Addr = IPv4Addr { bytes: [10, 85, 85, 2] };
@@ -217,3 +296,23 @@ impl Checksum {
         0xFFFF - (sum as u16)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::IPv4Addr;
+
+    #[test]
+    fn ipv4_parse() {
+        assert_eq!(IPv4Addr::parse("10.85.85.2").map(|addr| addr.bytes), Some([10, 85, 85, 2]));
+        assert_eq!(IPv4Addr::parse("0.0.0.0").map(|addr| addr.bytes), Some([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn ipv4_parse_malformed() {
+        for string in ["", "1.2.3", "1.2.3.4.5", "1.2.3.", ".1.2.3", "1..2.3", "256.0.0.1",
+                       "1.2.3.0400", "1.2.3.-4", "1.2.3.a", "1.2.3.4 ", "99999999999.1.1.1"]
+                          .iter() {
+            assert!(IPv4Addr::parse(string).is_none(), "{}", string);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use fs::File;
+use io::{Read, Result, Write};
+use to_num::ToNum;
+use url::Url;
+
+use system::error::{Error, EINVAL, ENOENT};
+
+use super::{IP_ADDR, SocketAddr, ToSocketAddrs};
+
+/// How to shut down a connection
+#[derive(Copy, Clone, PartialEq)]
+pub enum Shutdown {
+    Read,
+    Write,
+    Both,
+}
+
+/// Parse the path of a `tcp:` or `udp:` resource, `scheme://peer:port/host_port`
+pub fn parse_path(file: &File) -> Result<(SocketAddr, u16)> {
+    let path = try!(file.path());
+    let url = Url::from_string(path.to_string());
+
+    let peer = try!(SocketAddr::from_str(&format!("{}:{}", url.host(), url.port())));
+    let host_port = url.path().to_num() as u16;
+
+    Ok((peer, host_port))
+}
+
+/// A TCP connection, using the `tcp:` scheme
+pub struct TcpStream {
+    file: File,
+}
+
+impl TcpStream {
+    /// Connect to the first address that accepts the connection
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpStream> {
+        let mut last_err = Error::new(EINVAL);
+        for addr in try!(addr.to_socket_addrs()) {
+            match File::open(&format!("tcp://{}", addr.to_string())) {
+                Ok(file) => return Ok(TcpStream { file: file }),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        parse_path(&self.file).map(|(peer, _)| peer)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        parse_path(&self.file).map(|(peer, host_port)| {
+            SocketAddr::new(peer.ip().host_addr(), host_port)
+        })
+    }
+
+    /// Shut down reading, writing, or both, for every handle of the connection. Reads return
+    /// end of file once reading is shut down, and the peer reads end of file once writing is.
+    /// The `tcp:` scheme takes the direction as the length to truncate the connection to
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        self.file.set_len(match how {
+            Shutdown::Read => 0,
+            Shutdown::Write => 1,
+            Shutdown::Both => 2,
+        })
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// A TCP listening socket, using the `tcp:` scheme
+pub struct TcpListener {
+    file: File,
+}
+
+impl TcpListener {
+    /// Listen on the port of the first address, connections are accepted on all local addresses
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<TcpListener> {
+        match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => {
+                let file = try!(File::open(&format!("tcp:///{}", addr.port())));
+                Ok(TcpListener { file: file })
+            }
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+
+    /// Wait for a connection, duplicating the listener accepts it
+    pub fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+        let stream = TcpStream { file: try!(self.file.dup()) };
+        let addr = try!(stream.peer_addr());
+        Ok((stream, addr))
+    }
+
+    /// Iterate over the connections, as they are accepted
+    pub fn incoming(&self) -> Incoming {
+        Incoming { listener: self }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let path = try!(self.file.path());
+        let url = Url::from_string(path.to_string());
+        Ok(SocketAddr::new(IP_ADDR, url.path().to_num() as u16))
+    }
+}
+
+/// The connections of a listener, never ends
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = Result<TcpStream>;
+
+    fn next(&mut self) -> Option<Result<TcpStream>> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
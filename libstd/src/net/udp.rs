@@ -0,0 +1,203 @@
+use fs::File;
+use io::{Read, Result, Write};
+use mem;
+use ptr;
+use rand_old::rand;
+use slice;
+use string::ToString;
+use thread;
+use time::Duration;
+use vec::Vec;
+
+use system::error::{Error, EADDRINUSE, EINVAL, ENOENT, ENOTCONN, ETIMEDOUT};
+use system::syscall::{sys_open, O_NONBLOCK, O_RDWR};
+
+use super::{IP_ADDR, IPv4Addr, n16, SocketAddr, ToSocketAddrs};
+
+/// The most data a datagram carries, what fits in an IPv4 packet after the headers
+const UDP_MAX_DATA: usize = 65507;
+
+/// The peer of a datagram on an unconnected `udp:` resource, before the data of every read and
+/// write. When reading, `len` is the length of the datagram, which was truncated if the read
+/// returned less
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct UdpMeta {
+    pub addr: IPv4Addr,
+    pub port: n16,
+    pub len: n16,
+}
+
+/// A UDP socket, using the `udp:` scheme
+pub struct UdpSocket {
+    /// The unconnected resource, bound to the local port
+    file: File,
+    host_port: u16,
+    /// The resource of the connected peer
+    connected: Option<(SocketAddr, File)>,
+    /// How long `recv` waits for a datagram
+    read_timeout: Option<Duration>,
+}
+
+/// Open a `udp:` resource, when `nonblock` is set reads return zero when nothing is waiting
+fn open_udp(path: &str, nonblock: bool) -> Result<File> {
+    unsafe { File::from_fd(open_udp_fd(path, nonblock)) }
+}
+
+/// Open a `udp:` resource, returning the result of the system call
+fn open_udp_fd(path: &str, nonblock: bool) -> usize {
+    let path_c = path.to_string() + "\0";
+    let flags = if nonblock { O_RDWR | O_NONBLOCK } else { O_RDWR };
+    unsafe { sys_open(path_c.as_ptr(), flags, 0) }
+}
+
+impl UdpSocket {
+    /// Bind to the port of the first address, port 0 chooses a free port
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UdpSocket> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        if addr.port() > 0 {
+            let fd = open_udp_fd(&format!("udp:///{}", addr.port()), false);
+            return UdpSocket::from_fd(fd, addr.port());
+        }
+
+        // Try the ephemeral ports from a random one, until one is not in use
+        let start = rand() % 32768;
+        for i in 0..32768 {
+            let host_port = ((start + i) % 32768 + 32768) as u16;
+            let fd = open_udp_fd(&format!("udp:///{}", host_port), false);
+            if fd != (-EADDRINUSE) as usize {
+                return UdpSocket::from_fd(fd, host_port);
+            }
+        }
+
+        Err(Error::new(EADDRINUSE))
+    }
+
+    /// A socket from the result of opening `udp:///host_port`
+    fn from_fd(fd: usize, host_port: u16) -> Result<UdpSocket> {
+        Ok(UdpSocket {
+            file: try!(unsafe { File::from_fd(fd) }),
+            host_port: host_port,
+            connected: None,
+            read_timeout: None,
+        })
+    }
+
+    /// Receive a datagram, returning its size and source. Data that does not fit is discarded
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let meta_size = mem::size_of::<UdpMeta>();
+        let mut bytes = vec![0; meta_size + buf.len()];
+        let count = try!(self.file.read(&mut bytes));
+        if count < meta_size {
+            return Err(Error::new(EINVAL));
+        }
+
+        let meta = unsafe { ptr::read(bytes.as_ptr() as *const UdpMeta) };
+        for (b, d) in buf.iter_mut().zip(bytes[meta_size..count].iter()) {
+            *b = *d;
+        }
+        Ok((count - meta_size, SocketAddr::new(meta.addr, meta.port.get())))
+    }
+
+    /// Send a datagram to an address, failing with `EINVAL` if it does not fit in one
+    pub fn send_to<A: ToSocketAddrs>(&mut self, buf: &[u8], addr: A) -> Result<usize> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        if buf.len() > UDP_MAX_DATA {
+            return Err(Error::new(EINVAL));
+        }
+
+        let meta = UdpMeta {
+            addr: addr.ip(),
+            port: n16::new(addr.port()),
+            len: n16::new(buf.len() as u16),
+        };
+        let mut bytes = unsafe {
+            Vec::from(slice::from_raw_parts(&meta as *const UdpMeta as *const u8,
+                                            mem::size_of::<UdpMeta>()))
+        };
+        bytes.push_all(buf);
+
+        try!(self.file.write(&bytes));
+        Ok(buf.len())
+    }
+
+    /// Set the peer used by `send` and `recv`
+    pub fn connect<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        let file = try!(open_udp(&format!("udp://{}/{}", addr.to_string(), self.host_port),
+                                 self.read_timeout.is_some()));
+        self.connected = Some((addr, file));
+        Ok(())
+    }
+
+    /// Set how long `recv` waits for a datagram from the connected peer before failing with
+    /// `ETIMEDOUT`, `None` waits forever. Empty datagrams are not seen while a timeout is set
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let blocking = self.read_timeout.is_none();
+        self.read_timeout = timeout;
+
+        if blocking != self.read_timeout.is_none() {
+            if let Some((addr, _)) = self.connected {
+                self.connected = None;
+                try!(self.connect(addr));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send a datagram to the connected peer
+    pub fn send(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.connected {
+            Some((_, ref mut file)) => file.write(buf),
+            None => Err(Error::new(ENOTCONN)),
+        }
+    }
+
+    /// Receive a datagram from the connected peer
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let deadline = self.read_timeout.map(|timeout| Duration::monotonic() + timeout);
+        match self.connected {
+            Some((_, ref mut file)) => {
+                match deadline {
+                    Some(deadline) => {
+                        loop {
+                            let count = try!(file.read(buf));
+                            if count > 0 {
+                                return Ok(count);
+                            } else if Duration::monotonic() >= deadline {
+                                return Err(Error::new(ETIMEDOUT));
+                            }
+                            thread::yield_now();
+                        }
+                    }
+                    None => file.read(buf),
+                }
+            }
+            None => Err(Error::new(ENOTCONN)),
+        }
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        match self.connected {
+            Some((addr, _)) => Ok(addr),
+            None => Err(Error::new(ENOTCONN)),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(SocketAddr::new(IP_ADDR, self.host_port))
+    }
+}
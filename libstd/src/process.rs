@@ -3,7 +3,9 @@ use string::{String, ToString};
 use vec::Vec;
 
 use system::error::Error;
-use system::syscall::{sys_clone, sys_execve, sys_spawnve, sys_exit, sys_waitpid, CLONE_VM, CLONE_VFORK};
+use system::syscall::{sys_clone, sys_execve, sys_spawnve, sys_exit, sys_kill, sys_waitpid,
+                      wexitstatus, wifexited, wifsignaled, wtermsig, CLONE_VM, CLONE_VFORK,
+                      SIGKILL};
 
 pub struct ExitStatus {
     status: usize,
@@ -14,8 +16,22 @@ impl ExitStatus {
         self.status == 0
     }
 
+    /// The exit code, if the process exited
     pub fn code(&self) -> Option<i32> {
-        Some(self.status as i32)
+        if wifexited(self.status) {
+            Some(wexitstatus(self.status) as i32)
+        } else {
+            None
+        }
+    }
+
+    /// The signal that terminated the process, if any
+    pub fn signal(&self) -> Option<i32> {
+        if wifsignaled(self.status) {
+            Some(wtermsig(self.status) as i32)
+        } else {
+            None
+        }
     }
 }
 
@@ -28,6 +44,16 @@ impl Child {
         self.pid as u32
     }
 
+    /// Terminate the process with `SIGKILL`
+    pub fn kill(&mut self) -> Result<()> {
+        let result = unsafe { sys_kill(self.pid, SIGKILL) } as isize;
+        if result >= 0 {
+            Ok(())
+        } else {
+            Err(Error::new(-result))
+        }
+    }
+
     pub fn wait(&mut self) -> Result<ExitStatus> {
         let mut status: usize = 0;
         let result = unsafe { sys_waitpid(self.pid, &mut status, 0) } as isize;